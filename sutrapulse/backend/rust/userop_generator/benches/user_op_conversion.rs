@@ -0,0 +1,52 @@
+//! Owned vs borrowed conversion of ops into the EntryPoint tuple, as done
+//! when building `handleOps` and `getUserOpHash` calls.
+//!
+//! Run with `cargo bench --bench user_op_conversion`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ethers::types::{Address, Bytes, U256};
+use userop_generator::contracts::UserOperationCall;
+use userop_generator::UserOperation;
+
+const BATCH_SIZE: usize = 64;
+
+/// A sponsored op with a realistic amount of calldata.
+fn sponsored_user_op(index: usize) -> UserOperation {
+    let mut user_op = UserOperation::new(Address::from_low_u64_be(index as u64 + 1))
+        .with_nonce(U256::from(index))
+        .with_call_data(Bytes::from(vec![0xab; 1024]))
+        .with_signature(Bytes::from(vec![0x11; 65]));
+    user_op.paymaster_and_data = Bytes::from(vec![0x22; 149]);
+    user_op.call_gas_limit = U256::from(100_000);
+    user_op.verification_gas_limit = U256::from(150_000);
+    user_op.pre_verification_gas = U256::from(21_000);
+    user_op.max_fee_per_gas = U256::from(30_000_000_000u64);
+    user_op.max_priority_fee_per_gas = U256::from(1_000_000_000u64);
+    user_op
+}
+
+fn conversion(c: &mut Criterion) {
+    let batch: Vec<UserOperation> = (0..BATCH_SIZE).map(sponsored_user_op).collect();
+
+    let mut group = c.benchmark_group("user_op_to_call");
+    group.bench_function("owned", |b| {
+        b.iter(|| {
+            black_box(&batch)
+                .iter()
+                .map(|user_op| UserOperationCall::from(user_op.clone()))
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| {
+            black_box(&batch)
+                .iter()
+                .map(UserOperationCall::from)
+                .collect::<Vec<_>>()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, conversion);
+criterion_main!(benches);
@@ -0,0 +1,320 @@
+use dashmap::DashMap;
+use ethers::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use crate::error::{Result, UserOpError};
+use crate::gas::GasParams;
+use crate::userop::UserOperation;
+
+/// Gas limits returned by `eth_estimateUserOperationGas`. The paymaster
+/// limits are only sent by v0.7-aware bundlers such as Alchemy and Pimlico.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationGasEstimate {
+    pub pre_verification_gas: U256,
+    pub verification_gas_limit: U256,
+    pub call_gas_limit: U256,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_verification_gas_limit: Option<U256>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_post_op_gas_limit: Option<U256>,
+}
+
+impl UserOperationGasEstimate {
+    /// Combines the bundler's limits with the given fees.
+    pub fn into_gas_params(self, max_fee_per_gas: U256, max_priority_fee_per_gas: U256) -> GasParams {
+        GasParams {
+            call_gas_limit: self.call_gas_limit,
+            verification_gas_limit: self.verification_gas_limit,
+            pre_verification_gas: self.pre_verification_gas,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            paymaster_verification_gas_limit: self.paymaster_verification_gas_limit,
+            paymaster_post_op_gas_limit: self.paymaster_post_op_gas_limit,
+        }
+    }
+}
+
+/// Outcome of an included op, as returned by `eth_getUserOperationReceipt`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationReceipt {
+    pub user_op_hash: H256,
+    pub entry_point: Address,
+    pub sender: Address,
+    pub nonce: U256,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster: Option<Address>,
+    pub actual_gas_cost: U256,
+    pub actual_gas_used: U256,
+    pub success: bool,
+    /// The bundle transaction that included the op.
+    pub receipt: TransactionReceipt,
+}
+
+/// JSON-RPC client for an ERC-4337 bundler.
+pub struct BundlerClient<P = Http> {
+    provider: Provider<P>,
+    require_entry_point_support: bool,
+    min_pre_verification_gas: HashMap<u64, U256>,
+    /// Highest `preVerificationGas` the bundler has estimated per chain.
+    discovered_pre_verification_gas: DashMap<u64, U256>,
+}
+
+impl BundlerClient<Http> {
+    pub fn new(url: &str) -> Result<Self> {
+        let provider = Provider::<Http>::try_from(url)
+            .map_err(|e| UserOpError::Config(format!("Invalid bundler URL: {}", e)))?;
+        Ok(Self::with_provider(provider))
+    }
+}
+
+impl<P: JsonRpcClient> BundlerClient<P> {
+    pub fn with_provider(provider: Provider<P>) -> Self {
+        Self {
+            provider,
+            require_entry_point_support: true,
+            min_pre_verification_gas: HashMap::new(),
+            discovered_pre_verification_gas: DashMap::new(),
+        }
+    }
+
+    /// Minimum `preVerificationGas` this bundler accepts on `chain_id`.
+    pub fn with_min_pre_verification_gas(mut self, chain_id: u64, min: U256) -> Self {
+        self.min_pre_verification_gas.insert(chain_id, min);
+        self
+    }
+
+    /// The effective `preVerificationGas` floor on `chain_id`: the
+    /// configured minimum, or the highest value the bundler has estimated
+    /// there if that is higher.
+    pub fn min_pre_verification_gas(&self, chain_id: u64) -> U256 {
+        let configured = self.min_pre_verification_gas.get(&chain_id).copied().unwrap_or_default();
+        let discovered = self.discovered_pre_verification_gas
+            .get(&chain_id)
+            .map(|discovered| *discovered)
+            .unwrap_or_default();
+        configured.max(discovered)
+    }
+
+    /// Raises the op's `pre_verification_gas` to this bundler's floor on
+    /// `chain_id`. Must run before signing since it changes the userOpHash.
+    pub fn apply_min_pre_verification_gas(&self, mut user_op: UserOperation, chain_id: u64) -> UserOperation {
+        user_op.pre_verification_gas = user_op.pre_verification_gas.max(self.min_pre_verification_gas(chain_id));
+        user_op
+    }
+
+    /// Whether `send_user_operation` first confirms the bundler supports
+    /// the target EntryPoint.
+    pub fn with_entry_point_check(mut self, enabled: bool) -> Self {
+        self.require_entry_point_support = enabled;
+        self
+    }
+
+    pub async fn supported_entry_points(&self) -> Result<Vec<Address>> {
+        self.provider
+            .request("eth_supportedEntryPoints", ())
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))
+    }
+
+    pub async fn supports_entry_point(&self, entry_point: Address) -> Result<bool> {
+        Ok(self.supported_entry_points().await?.contains(&entry_point))
+    }
+
+    pub async fn ensure_entry_point_supported(&self, entry_point: Address) -> Result<()> {
+        let supported = self.supported_entry_points().await?;
+        if !supported.contains(&entry_point) {
+            return Err(UserOpError::Config(format!(
+                "Bundler does not support EntryPoint {:?}; supported: {:?}",
+                entry_point, supported
+            )));
+        }
+        Ok(())
+    }
+
+    /// Asks the bundler to estimate the op's gas limits. The returned
+    /// `preVerificationGas` raises the bundler's minimum on `chain_id`; a
+    /// lower estimate never lowers it.
+    pub async fn estimate_user_operation_gas(
+        &self,
+        user_op: &UserOperation,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> Result<UserOperationGasEstimate> {
+        let estimate: UserOperationGasEstimate = self.provider
+            .request("eth_estimateUserOperationGas", (user_op, entry_point))
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+
+        self.discovered_pre_verification_gas
+            .entry(chain_id)
+            .and_modify(|discovered| *discovered = (*discovered).max(estimate.pre_verification_gas))
+            .or_insert(estimate.pre_verification_gas);
+        Ok(estimate)
+    }
+
+    /// Submits `user_op` via `eth_sendUserOperation`, returning its userOpHash.
+    pub async fn send_user_operation(
+        &self,
+        user_op: &UserOperation,
+        entry_point: Address,
+    ) -> Result<H256> {
+        if self.require_entry_point_support {
+            self.ensure_entry_point_supported(entry_point).await?;
+        }
+
+        self.provider
+            .request("eth_sendUserOperation", (user_op, entry_point))
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))
+    }
+
+    /// The op's receipt via `eth_getUserOperationReceipt`, `None` until it
+    /// is included.
+    pub async fn get_user_operation_receipt(&self, user_op_hash: H256) -> Result<Option<UserOperationReceipt>> {
+        self.provider
+            .request("eth_getUserOperationReceipt", [user_op_hash])
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const ENTRY_POINT_V06: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+    const ENTRY_POINT_V07: &str = "0x0000000071727De22E5E9d8BAf0edAc6f37da032";
+
+    #[tokio::test]
+    async fn test_supported_entry_points() {
+        let (provider, mock) = Provider::mocked();
+        let entry_points = vec![
+            Address::from_str(ENTRY_POINT_V06).unwrap(),
+            Address::from_str(ENTRY_POINT_V07).unwrap(),
+        ];
+        mock.push::<Vec<Address>, _>(entry_points.clone()).unwrap();
+        mock.push::<Vec<Address>, _>(entry_points.clone()).unwrap();
+
+        let bundler = BundlerClient::with_provider(provider);
+
+        assert_eq!(bundler.supported_entry_points().await.unwrap(), entry_points);
+        assert!(bundler.supports_entry_point(entry_points[1]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_early_for_unsupported_entry_point() {
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Vec<Address>, _>(vec![Address::from_str(ENTRY_POINT_V07).unwrap()]).unwrap();
+
+        let bundler = BundlerClient::with_provider(provider);
+        let user_op = UserOperation::new(Address::zero());
+
+        let result = bundler
+            .send_user_operation(&user_op, Address::from_str(ENTRY_POINT_V06).unwrap())
+            .await;
+
+        assert!(matches!(result, Err(UserOpError::Config(_))));
+        mock.assert_request("eth_supportedEntryPoints", ()).unwrap();
+        assert!(mock.assert_request("eth_sendUserOperation", ()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pending_op_has_no_receipt() {
+        let (provider, mock) = Provider::mocked();
+        mock.push(serde_json::Value::Null).unwrap();
+
+        let bundler = BundlerClient::with_provider(provider);
+        let user_op_hash = H256::repeat_byte(0xab);
+
+        assert_eq!(bundler.get_user_operation_receipt(user_op_hash).await.unwrap(), None);
+        mock.assert_request("eth_getUserOperationReceipt", [user_op_hash]).unwrap();
+    }
+
+    #[test]
+    fn test_min_pre_verification_gas_is_a_floor() {
+        let (provider, _mock) = Provider::mocked();
+        let bundler = BundlerClient::with_provider(provider)
+            .with_min_pre_verification_gas(1, U256::from(50_000));
+
+        let mut user_op = UserOperation::new(Address::zero());
+        user_op.pre_verification_gas = U256::from(21_000);
+        assert_eq!(bundler.apply_min_pre_verification_gas(user_op.clone(), 1).pre_verification_gas, U256::from(50_000));
+        // Other chains have their own floor
+        assert_eq!(bundler.apply_min_pre_verification_gas(user_op.clone(), 137).pre_verification_gas, U256::from(21_000));
+
+        user_op.pre_verification_gas = U256::from(60_000);
+        assert_eq!(bundler.apply_min_pre_verification_gas(user_op, 1).pre_verification_gas, U256::from(60_000));
+    }
+
+    #[tokio::test]
+    async fn test_discovered_minimum_overrides_lower_configured_one() {
+        let (provider, mock) = Provider::mocked();
+        // LIFO: 60_000, then 40_000
+        mock.push(serde_json::json!({
+            "preVerificationGas": "0x9c40",
+            "verificationGasLimit": "0x186a0",
+            "callGasLimit": "0x30d40",
+        })).unwrap();
+        mock.push(serde_json::json!({
+            "preVerificationGas": "0xea60",
+            "verificationGasLimit": "0x186a0",
+            "callGasLimit": "0x30d40",
+        })).unwrap();
+
+        let bundler = BundlerClient::with_provider(provider)
+            .with_min_pre_verification_gas(1, U256::from(50_000));
+        let user_op = UserOperation::new(Address::zero());
+        let entry_point = Address::from_str(ENTRY_POINT_V06).unwrap();
+
+        let estimate = bundler.estimate_user_operation_gas(&user_op, entry_point, 1).await.unwrap();
+        assert_eq!(estimate.pre_verification_gas, U256::from(60_000));
+        assert_eq!(bundler.min_pre_verification_gas(1), U256::from(60_000));
+        assert_eq!(bundler.apply_min_pre_verification_gas(user_op.clone(), 1).pre_verification_gas, U256::from(60_000));
+        assert_eq!(bundler.min_pre_verification_gas(137), U256::zero());
+
+        // A lower estimate keeps the floor where it was
+        bundler.estimate_user_operation_gas(&user_op, entry_point, 1).await.unwrap();
+        assert_eq!(bundler.min_pre_verification_gas(1), U256::from(60_000));
+    }
+
+    #[tokio::test]
+    async fn test_pimlico_style_estimate_maps_paymaster_limits() {
+        let (provider, mock) = Provider::mocked();
+        mock.push(serde_json::json!({
+            "preVerificationGas": "0xc350",
+            "verificationGasLimit": "0x186a0",
+            "callGasLimit": "0x30d40",
+            "paymasterVerificationGasLimit": "0x7530",
+            "paymasterPostOpGasLimit": "0x2710",
+        })).unwrap();
+
+        let bundler = BundlerClient::with_provider(provider);
+        let estimate = bundler
+            .estimate_user_operation_gas(&UserOperation::new(Address::zero()), Address::from_str(ENTRY_POINT_V07).unwrap(), 1)
+            .await
+            .unwrap();
+        let params = estimate.into_gas_params(U256::from(10), U256::from(2));
+
+        assert_eq!(params.call_gas_limit, U256::from(200_000));
+        assert_eq!(params.verification_gas_limit, U256::from(100_000));
+        assert_eq!(params.pre_verification_gas, U256::from(50_000));
+        assert_eq!(params.paymaster_verification_gas_limit, Some(U256::from(30_000)));
+        assert_eq!(params.paymaster_post_op_gas_limit, Some(U256::from(10_000)));
+        assert_eq!(params.max_gas_cost(), U256::from(390_000 * 10));
+    }
+
+    #[test]
+    fn test_estimate_without_paymaster_limits_still_parses() {
+        let estimate: UserOperationGasEstimate = serde_json::from_value(serde_json::json!({
+            "preVerificationGas": "0xc350",
+            "verificationGasLimit": "0x186a0",
+            "callGasLimit": "0x30d40",
+        })).unwrap();
+
+        assert_eq!(estimate.paymaster_verification_gas_limit, None);
+        assert_eq!(estimate.paymaster_post_op_gas_limit, None);
+    }
+}
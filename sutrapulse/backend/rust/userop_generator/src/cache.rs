@@ -1,12 +1,33 @@
+use dashmap::DashMap;
 use ethers::prelude::*;
 use moka::future::Cache;
-use std::time::Duration;
-use crate::error::{Result, UserOpError};
+use moka::ops::compute::{CompResult, Op};
+use moka::notification::RemovalCause;
+use moka::policy::EvictionPolicy;
+use moka::Expiry;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::error::Result;
+use crate::gas::GasParams;
+use crate::metrics::Metrics;
+use crate::provider::{rpc_provider, ReconnectingProvider, RpcClient};
+use std::collections::HashMap;
 
 pub struct GasCache {
     base_fee_cache: Cache<u64, U256>,
     priority_fee_cache: Cache<u64, U256>,
     nonce_cache: Cache<(u64, Address), U256>,
+    staleness_check: bool,
+    /// Block each chain's cached fees were fetched at.
+    fee_blocks: DashMap<u64, u64>,
+    /// Latest block number seen per chain.
+    latest_blocks: DashMap<u64, u64>,
+}
+
+impl Default for GasCache {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GasCache {
@@ -24,19 +45,70 @@ impl GasCache {
                 .time_to_live(Duration::from_secs(5)) // Shorter TTL for nonces
                 .time_to_idle(Duration::from_secs(10))
                 .build(),
+            staleness_check: false,
+            fee_blocks: DashMap::new(),
+            latest_blocks: DashMap::new(),
+        }
+    }
+
+    /// Records a `gas_cache_stale` metric whenever a cached base fee is read
+    /// more than one block after it was fetched. Needs fees stored with
+    /// `set_base_fee_at` and the head kept current via `set_latest_block`,
+    /// which `GasEstimator::spawn_refresher` does on every tick.
+    pub fn with_staleness_check(mut self, enabled: bool) -> Self {
+        self.staleness_check = enabled;
+        self
+    }
+
+    pub fn staleness_check(&self) -> bool {
+        self.staleness_check
+    }
+
+    pub fn set_latest_block(&self, chain_id: u64, block_number: u64) {
+        let mut latest = self.latest_blocks.entry(chain_id).or_insert(block_number);
+        *latest = (*latest).max(block_number);
+    }
+
+    /// Whether the chain's cached fees were fetched more than one block
+    /// before the latest known block.
+    pub fn is_stale(&self, chain_id: u64) -> bool {
+        match (self.fee_blocks.get(&chain_id), self.latest_blocks.get(&chain_id)) {
+            (Some(fetched), Some(latest)) => latest.saturating_sub(*fetched) > 1,
+            _ => false,
         }
     }
 
+    /// Like `set_base_fee`, remembering the block the fee was read from.
+    pub async fn set_base_fee_at(&self, chain_id: u64, value: U256, block_number: u64) {
+        self.base_fee_cache.insert(chain_id, value).await;
+        self.fee_blocks.insert(chain_id, block_number);
+        self.set_latest_block(chain_id, block_number);
+    }
+
     pub async fn get_base_fee(&self, chain_id: u64) -> Option<U256> {
-        self.base_fee_cache.get(&chain_id)
+        let base_fee = self.base_fee_cache.get(&chain_id).await;
+        if self.staleness_check && base_fee.is_some() && self.is_stale(chain_id) {
+            Metrics::record_gas_cache_stale(chain_id);
+        }
+        base_fee
     }
 
+    /// Stores a fee with no known block, which the staleness check skips.
     pub async fn set_base_fee(&self, chain_id: u64, value: U256) {
+        self.fee_blocks.remove(&chain_id);
         self.base_fee_cache.insert(chain_id, value).await;
     }
 
+    /// Drops the chain's cached base and priority fee, e.g. when the fees
+    /// are about to be priced a different way.
+    pub async fn invalidate_fees(&self, chain_id: u64) {
+        self.fee_blocks.remove(&chain_id);
+        self.base_fee_cache.invalidate(&chain_id).await;
+        self.priority_fee_cache.invalidate(&chain_id).await;
+    }
+
     pub async fn get_priority_fee(&self, chain_id: u64) -> Option<U256> {
-        self.priority_fee_cache.get(&chain_id)
+        self.priority_fee_cache.get(&chain_id).await
     }
 
     pub async fn set_priority_fee(&self, chain_id: u64, value: U256) {
@@ -44,7 +116,7 @@ impl GasCache {
     }
 
     pub async fn get_nonce(&self, chain_id: u64, address: Address) -> Option<U256> {
-        self.nonce_cache.get(&(chain_id, address))
+        self.nonce_cache.get(&(chain_id, address)).await
     }
 
     pub async fn set_nonce(&self, chain_id: u64, address: Address, value: U256) {
@@ -54,32 +126,263 @@ impl GasCache {
     pub async fn invalidate_nonce(&self, chain_id: u64, address: Address) {
         self.nonce_cache.invalidate(&(chain_id, address)).await;
     }
+
+    /// Atomically bumps the cached nonce to `expected + 1` if it still equals
+    /// `expected`, returning the new value. Returns `None` if another task got
+    /// there first or nothing is cached.
+    pub async fn compare_and_increment_nonce(&self, chain_id: u64, address: Address, expected: U256) -> Option<U256> {
+        let result = self.nonce_cache
+            .entry((chain_id, address))
+            .and_compute_with(|current| async move {
+                match current {
+                    Some(entry) if *entry.value() == expected => Op::Put(expected + 1),
+                    _ => Op::Nop,
+                }
+            })
+            .await;
+
+        match result {
+            CompResult::ReplacedWith(entry) => Some(*entry.value()),
+            _ => None,
+        }
+    }
+}
+
+/// Approximate block time of a chain, used to expire per-block cache entries.
+pub fn block_time(chain_id: u64) -> Duration {
+    match chain_id {
+        1 => Duration::from_secs(12),
+        42161 => Duration::from_secs(1),
+        _ => Duration::from_secs(2),
+    }
+}
+
+struct BlockTimeExpiry;
+
+impl Expiry<(u64, H256), GasParams> for BlockTimeExpiry {
+    fn expire_after_create(&self, key: &(u64, H256), _value: &GasParams, _created_at: Instant) -> Option<Duration> {
+        Some(block_time(key.0))
+    }
+}
+
+/// Full gas estimates keyed by chain and op fingerprint, each kept for
+/// about one block on its chain.
+pub struct EstimateCache {
+    cache: Cache<(u64, H256), GasParams>,
+}
+
+impl Default for EstimateCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EstimateCache {
+    pub fn new() -> Self {
+        Self {
+            cache: Cache::builder()
+                .max_capacity(10_000)
+                .expire_after(BlockTimeExpiry)
+                .build(),
+        }
+    }
+
+    pub async fn get(&self, chain_id: u64, fingerprint: H256) -> Option<GasParams> {
+        self.cache.get(&(chain_id, fingerprint)).await
+    }
+
+    pub async fn insert(&self, chain_id: u64, fingerprint: H256, params: GasParams) {
+        self.cache.insert((chain_id, fingerprint), params).await;
+    }
+}
+
+fn record_capacity_eviction<V>(_url: Arc<String>, _provider: V, cause: RemovalCause) {
+    if cause == RemovalCause::Size {
+        Metrics::record_provider_evicted();
+    }
 }
 
 #[derive(Clone)]
 pub struct RpcCache {
-    provider_cache: Cache<String, Provider<Http>>,
+    provider_cache: Cache<String, Provider<RpcClient>>,
+    reconnecting_cache: Cache<String, Arc<ReconnectingProvider>>,
+    compression: bool,
+    log_requests: bool,
+}
+
+/// Providers `RpcCache::new` keeps per pool before evicting.
+pub const DEFAULT_MAX_PROVIDERS: u64 = 256;
+
+impl Default for RpcCache {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RpcCache {
     pub fn new() -> Self {
+        Self::with_max_providers(DEFAULT_MAX_PROVIDERS)
+    }
+
+    /// Bounds each provider pool to `max_providers`, evicting the least
+    /// recently used provider when it is full.
+    pub fn with_max_providers(max_providers: u64) -> Self {
         Self {
             provider_cache: Cache::builder()
                 .time_to_live(Duration::from_secs(3600)) // Cache providers for 1 hour
                 .time_to_idle(Duration::from_secs(7200)) // Remove if not accessed for 2 hours
+                .max_capacity(max_providers)
+                .eviction_policy(EvictionPolicy::lru())
+                .eviction_listener(record_capacity_eviction)
+                .build(),
+            reconnecting_cache: Cache::builder()
+                .time_to_live(Duration::from_secs(3600))
+                .time_to_idle(Duration::from_secs(7200))
+                .max_capacity(max_providers)
+                .eviction_policy(EvictionPolicy::lru())
+                .eviction_listener(record_capacity_eviction)
                 .build(),
+            compression: false,
+            log_requests: false,
         }
     }
 
-    pub async fn get_provider(&self, url: &str) -> Result<Provider<Http>> {
-        if let Some(provider) = self.provider_cache.get(url) {
+    /// Builds new providers with gzip/deflate response compression enabled.
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Builds new providers that log each request at debug level.
+    pub fn with_request_logging(mut self, enabled: bool) -> Self {
+        self.log_requests = enabled;
+        self
+    }
+
+    pub async fn get_provider(&self, url: &str) -> Result<Provider<RpcClient>> {
+        if let Some(provider) = self.provider_cache.get(url).await {
+            Metrics::record_provider_reused();
             return Ok(provider);
         }
 
-        let provider = Provider::<Http>::try_from(url)
-            .map_err(|e| UserOpError::RPC(e.to_string()))?;
-        
+        let provider = rpc_provider(url, self.compression, self.log_requests, &HashMap::new())?;
+
         self.provider_cache.insert(url.to_string(), provider.clone()).await;
+        self.provider_cache.run_pending_tasks().await;
+        Metrics::record_provider_created();
+        Metrics::record_provider_pool_size(self.pool_size());
+        Ok(provider)
+    }
+
+    /// Number of providers currently pooled.
+    pub fn pool_size(&self) -> u64 {
+        self.provider_cache.entry_count()
+    }
+
+    pub async fn contains_provider(&self, url: &str) -> bool {
+        self.provider_cache.contains_key(url)
+    }
+
+    /// Returns a shared provider for `url` that reconnects after transport errors.
+    pub async fn get_reconnecting_provider(&self, url: &str) -> Result<Arc<ReconnectingProvider>> {
+        if let Some(provider) = self.reconnecting_cache.get(url).await {
+            return Ok(provider);
+        }
+
+        let provider = Arc::new(ReconnectingProvider::new(url, self.compression, self.log_requests)?);
+        self.reconnecting_cache.insert(url.to_string(), provider.clone()).await;
+        self.reconnecting_cache.run_pending_tasks().await;
         Ok(provider)
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::tests::{counter_value, test_handle};
+
+    #[tokio::test]
+    async fn test_base_fee_read_across_blocks_is_stale() {
+        let handle = test_handle();
+        let cache = GasCache::new().with_staleness_check(true);
+        let chain_id = 8453;
+
+        cache.set_base_fee_at(chain_id, U256::from(10), 100).await;
+        cache.set_latest_block(chain_id, 101);
+        assert!(!cache.is_stale(chain_id));
+        cache.get_base_fee(chain_id).await;
+        assert!(!handle.render().contains(r#"gas_cache_stale{chain="8453"}"#));
+
+        cache.set_latest_block(chain_id, 102);
+        assert!(cache.is_stale(chain_id));
+        assert_eq!(cache.get_base_fee(chain_id).await, Some(U256::from(10)));
+        assert!(handle.render().contains(r#"gas_cache_stale{chain="8453"} 1"#));
+
+        // A fresh fetch clears it
+        cache.set_base_fee_at(chain_id, U256::from(11), 102).await;
+        assert!(!cache.is_stale(chain_id));
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_increment_nonce() {
+        let cache = GasCache::new();
+        let sender = Address::from_low_u64_be(1);
+
+        assert_eq!(cache.compare_and_increment_nonce(1, sender, U256::zero()).await, None);
+
+        cache.set_nonce(1, sender, U256::from(4)).await;
+        assert_eq!(cache.compare_and_increment_nonce(1, sender, U256::from(3)).await, None);
+        assert_eq!(cache.compare_and_increment_nonce(1, sender, U256::from(4)).await, Some(U256::from(5)));
+        assert_eq!(cache.get_nonce(1, sender).await, Some(U256::from(5)));
+    }
+
+    #[tokio::test]
+    async fn test_provider_reuse_is_counted() {
+        let handle = test_handle();
+        let cache = RpcCache::new();
+        let url = "https://eth-mainnet.g.alchemy.com/v2/reuse-test";
+
+        cache.get_provider(url).await.unwrap();
+        let before = counter_value(&handle.render(), "rpc_provider_reuses_total");
+        cache.get_provider(url).await.unwrap();
+        let after = counter_value(&handle.render(), "rpc_provider_reuses_total");
+
+        assert_eq!(after - before, 1.0);
+        assert_eq!(cache.pool_size(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pool_evicts_least_recently_used() {
+        let handle = test_handle();
+        let cache = RpcCache::with_max_providers(2);
+        let first = "https://eth-mainnet.g.alchemy.com/v2/first";
+        let second = "https://eth-mainnet.g.alchemy.com/v2/second";
+        let third = "https://eth-mainnet.g.alchemy.com/v2/third";
+
+        cache.get_provider(first).await.unwrap();
+        cache.get_provider(second).await.unwrap();
+        // Touch `first` so `second` becomes least recently used
+        cache.get_provider(first).await.unwrap();
+        let before = counter_value(&handle.render(), "rpc_provider_evictions_total");
+        cache.get_provider(third).await.unwrap();
+        let after = counter_value(&handle.render(), "rpc_provider_evictions_total");
+
+        assert!(cache.contains_provider(first).await);
+        assert!(!cache.contains_provider(second).await);
+        assert!(cache.contains_provider(third).await);
+        assert_eq!(cache.pool_size(), 2);
+        // The recorder is shared with tests evicting from their own pools
+        assert!(after - before >= 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_blockless_base_fee_is_not_judged_stale() {
+        let gas_cache = GasCache::new().with_staleness_check(true);
+        gas_cache.set_base_fee_at(1, U256::from(10), 100).await;
+        gas_cache.set_latest_block(1, 105);
+        assert!(gas_cache.is_stale(1));
+
+        gas_cache.set_base_fee(1, U256::from(20)).await;
+        assert!(!gas_cache.is_stale(1));
+    }
+}
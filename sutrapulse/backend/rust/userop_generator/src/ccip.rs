@@ -0,0 +1,348 @@
+use ethers::abi::{ParamType, Token};
+use ethers::prelude::*;
+use ethers::providers::MiddlewareError;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::debug;
+use crate::error::{Result, UserOpError};
+
+/// How many `OffchainLookup` rounds `CcipReader` follows for one call by
+/// default before giving up.
+pub const MAX_CCIP_REDIRECTS: usize = 4;
+
+/// How long `CcipReader::new` waits to connect to a gateway.
+pub const DEFAULT_CCIP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long `CcipReader::new` gives a gateway request end to end.
+pub const DEFAULT_CCIP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Selector of `OffchainLookup(address,string[],bytes,bytes4,bytes)`.
+pub const OFFCHAIN_LOOKUP_SELECTOR: [u8; 4] = [0x55, 0x6f, 0x18, 0x30];
+
+/// The EIP-3668 `OffchainLookup` revert: `sender` asks for `call_data` to be
+/// resolved by one of `urls` and the result passed to `callback_function`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffchainLookup {
+    pub sender: Address,
+    pub urls: Vec<String>,
+    pub call_data: Bytes,
+    pub callback_function: [u8; 4],
+    pub extra_data: Bytes,
+}
+
+impl OffchainLookup {
+    /// Decodes `revert_data`, or `None` if it isn't an `OffchainLookup`.
+    pub fn decode(revert_data: &[u8]) -> Option<Self> {
+        if revert_data.len() < 4 || revert_data[..4] != OFFCHAIN_LOOKUP_SELECTOR {
+            return None;
+        }
+        let mut tokens = ethers::abi::decode(
+            &[
+                ParamType::Address,
+                ParamType::Array(Box::new(ParamType::String)),
+                ParamType::Bytes,
+                ParamType::FixedBytes(4),
+                ParamType::Bytes,
+            ],
+            &revert_data[4..],
+        )
+        .ok()?
+        .into_iter();
+
+        let sender = tokens.next()?.into_address()?;
+        let urls = tokens
+            .next()?
+            .into_array()?
+            .into_iter()
+            .map(Token::into_string)
+            .collect::<Option<Vec<_>>>()?;
+        let call_data = Bytes::from(tokens.next()?.into_bytes()?);
+        let callback_function = tokens.next()?.into_fixed_bytes()?.try_into().ok()?;
+        let extra_data = Bytes::from(tokens.next()?.into_bytes()?);
+
+        Some(Self { sender, urls, call_data, callback_function, extra_data })
+    }
+
+    /// Calldata for `callbackFunction(response, extraData)`.
+    pub fn callback_data(&self, response: Bytes) -> Bytes {
+        let args = ethers::abi::encode(&[Token::Bytes(response.to_vec()), Token::Bytes(self.extra_data.to_vec())]);
+        [self.callback_function.as_slice(), &args].concat().into()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GatewayRequest<'a> {
+    data: &'a Bytes,
+    sender: Address,
+}
+
+#[derive(Debug, Deserialize)]
+struct GatewayResponse {
+    data: Bytes,
+}
+
+/// Makes `eth_call`s that follow EIP-3668 (CCIP-read): when the contract
+/// reverts with `OffchainLookup`, the data is fetched from its gateways and
+/// the call retried through the callback, up to `max_redirects` times.
+#[derive(Debug, Clone)]
+pub struct CcipReader {
+    http: reqwest::Client,
+    max_redirects: usize,
+}
+
+impl Default for CcipReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CcipReader {
+    pub fn new() -> Self {
+        Self::with_timeouts(DEFAULT_CCIP_CONNECT_TIMEOUT, DEFAULT_CCIP_TIMEOUT)
+    }
+
+    /// Gives up on a gateway that takes longer than `connect_timeout` to
+    /// connect to or `timeout` to answer.
+    pub fn with_timeouts(connect_timeout: Duration, timeout: Duration) -> Self {
+        let http = reqwest::Client::builder()
+            .connect_timeout(connect_timeout)
+            .timeout(timeout)
+            .build()
+            .expect("gateway client config is valid");
+        Self::with_client(http)
+    }
+
+    /// Fetches gateway data through `http`, e.g. one with a proxy set.
+    pub fn with_client(http: reqwest::Client) -> Self {
+        Self {
+            http,
+            max_redirects: MAX_CCIP_REDIRECTS,
+        }
+    }
+
+    /// Sets how many lookups one call may go through. 0 fails on the first.
+    pub fn with_max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// `eth_call`s `tx`, resolving any `OffchainLookup` it reverts with.
+    pub async fn call<M: Middleware>(&self, client: &M, mut tx: TypedTransaction) -> Result<Bytes> {
+        let mut redirects = 0;
+        loop {
+            let error = match client.call(&tx, None).await {
+                Ok(output) => return Ok(output),
+                Err(e) => e,
+            };
+            let lookup = match error
+                .as_error_response()
+                .and_then(|response| response.as_revert_data())
+                .and_then(|data| OffchainLookup::decode(&data))
+            {
+                Some(lookup) => lookup,
+                None => return Err(UserOpError::RPC(error.to_string())),
+            };
+
+            if tx.to_addr() != Some(&lookup.sender) {
+                return Err(UserOpError::Contract(format!(
+                    "OffchainLookup sender {:?} is not the called contract {:?}",
+                    lookup.sender, tx.to_addr()
+                )));
+            }
+            if redirects == self.max_redirects {
+                return Err(UserOpError::Contract(format!(
+                    "Gave up on {:?} after {} offchain lookups",
+                    lookup.sender, redirects
+                )));
+            }
+            redirects += 1;
+
+            let response = self.fetch(&lookup).await?;
+            tx.set_data(lookup.callback_data(response));
+        }
+    }
+
+    /// Asks `lookup`'s gateways in order until one answers. URLs with a
+    /// `{data}` placeholder are fetched with GET, others are POSTed the
+    /// request as JSON. Server errors move on to the next gateway, client
+    /// errors fail the lookup.
+    async fn fetch(&self, lookup: &OffchainLookup) -> Result<Bytes> {
+        let sender = format!("{:?}", lookup.sender);
+        let data = lookup.call_data.to_string();
+        let mut last_error = format!("{:?} listed no gateways", lookup.sender);
+
+        for template in &lookup.urls {
+            let url = template.replace("{sender}", &sender).replace("{data}", &data);
+            let request = if template.contains("{data}") {
+                self.http.get(&url)
+            } else {
+                self.http.post(&url).json(&GatewayRequest { data: &lookup.call_data, sender: lookup.sender })
+            };
+
+            let response = match request.send().await {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!("Gateway {} unreachable: {}", url, e);
+                    last_error = e.to_string();
+                    continue;
+                }
+            };
+            let status = response.status();
+            if status.is_client_error() {
+                return Err(UserOpError::RPC(format!("Gateway {} rejected the lookup: {}", url, status)));
+            }
+            if !status.is_success() {
+                debug!("Gateway {} failed with {}", url, status);
+                last_error = format!("Gateway {} failed with {}", url, status);
+                continue;
+            }
+            return response
+                .json::<GatewayResponse>()
+                .await
+                .map(|body| body.data)
+                .map_err(|e| UserOpError::RPC(format!("Invalid response from gateway {}: {}", url, e)));
+        }
+
+        Err(UserOpError::RPC(last_error))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ethers::providers::{JsonRpcError, MockResponse};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    pub(crate) fn offchain_lookup_revert(sender: Address, urls: &[String], call_data: &[u8], extra_data: &[u8]) -> MockResponse {
+        let args = ethers::abi::encode(&[
+            Token::Address(sender),
+            Token::Array(urls.iter().cloned().map(Token::String).collect()),
+            Token::Bytes(call_data.to_vec()),
+            Token::FixedBytes(vec![0xf4, 0xd4, 0xd2, 0xf8]),
+            Token::Bytes(extra_data.to_vec()),
+        ]);
+        let revert = Bytes::from([OFFCHAIN_LOOKUP_SELECTOR.as_slice(), &args].concat());
+        MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: Some(serde_json::Value::String(revert.to_string())),
+        })
+    }
+
+    /// Answers one HTTP request on a local socket with `data`, returning the
+    /// gateway URL template and the request line it received.
+    pub(crate) async fn gateway(data: Bytes) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/{{sender}}/{{data}}.json", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            let body = format!(r#"{{"data":"{}"}}"#, data);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                body.len(), body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).lines().next().unwrap_or_default().to_string()
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn test_offchain_lookup_selector() {
+        assert_eq!(ethers::utils::id("OffchainLookup(address,string[],bytes,bytes4,bytes)"), OFFCHAIN_LOOKUP_SELECTOR);
+        assert_eq!(OffchainLookup::decode(&[0x08, 0xc3, 0x79, 0xa0]), None);
+    }
+
+    #[tokio::test]
+    async fn test_signature_validation_follows_offchain_lookup() {
+        let wallet = Address::from_low_u64_be(0x1234);
+        let gateway_data = Bytes::from(vec![0xaa; 65]);
+        let (url, server) = gateway(gateway_data.clone()).await;
+
+        let (provider, mock) = Provider::mocked();
+        // MockProvider pops responses LIFO: the callback, then the lookup
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::Bool(true)]))).unwrap();
+        mock.push_response(offchain_lookup_revert(wallet, &[url], &[0xc0, 0xde], &[0xee]));
+
+        let contract = crate::contracts::ISmartWallet::new(wallet, Arc::new(provider));
+        let original = contract.is_valid_signature([0x11; 32], Bytes::from(vec![0u8; 65])).tx;
+        let output = CcipReader::new().call(contract.client_ref(), original.clone()).await.unwrap();
+
+        assert_eq!(ethers::abi::decode(&[ParamType::Bool], &output).unwrap(), vec![Token::Bool(true)]);
+        let request_line = server.await.unwrap();
+        assert!(
+            request_line.starts_with(&format!("GET /{:?}/0xc0de.json", wallet)),
+            "{}", request_line
+        );
+
+        // The retry called the callback with the gateway's response
+        let lookup = OffchainLookup {
+            sender: wallet,
+            urls: vec![],
+            call_data: Bytes::from(vec![0xc0, 0xde]),
+            callback_function: [0xf4, 0xd4, 0xd2, 0xf8],
+            extra_data: Bytes::from(vec![0xee]),
+        };
+        let mut callback = original.clone();
+        callback.set_data(lookup.callback_data(gateway_data));
+        mock.assert_request("eth_call", [serde_json::to_value(&original).unwrap(), serde_json::json!("latest")])
+            .unwrap();
+        mock.assert_request("eth_call", [serde_json::to_value(&callback).unwrap(), serde_json::json!("latest")])
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_offchain_lookups_are_bounded() {
+        let wallet = Address::from_low_u64_be(0x1234);
+        let (url, server) = gateway(Bytes::from(vec![0xaa])).await;
+
+        let (provider, mock) = Provider::mocked();
+        mock.push_response(offchain_lookup_revert(wallet, std::slice::from_ref(&url), &[0x02], &[]));
+        mock.push_response(offchain_lookup_revert(wallet, &[url], &[0x01], &[]));
+
+        let contract = crate::contracts::ISmartWallet::new(wallet, Arc::new(provider));
+        let tx = contract.is_valid_signature([0x11; 32], Bytes::new()).tx;
+        let result = CcipReader::new().with_max_redirects(1).call(contract.client_ref(), tx).await;
+
+        assert!(matches!(result, Err(UserOpError::Contract(_))), "{:?}", result);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_gateway_times_out() {
+        let wallet = Address::from_low_u64_be(0x1234);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/{{data}}.json", listener.local_addr().unwrap());
+        // Accepts the connection but never answers
+        let server = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+        let (provider, mock) = Provider::mocked();
+        mock.push_response(offchain_lookup_revert(wallet, &[url], &[0x01], &[]));
+
+        let contract = crate::contracts::ISmartWallet::new(wallet, Arc::new(provider));
+        let tx = contract.is_valid_signature([0x11; 32], Bytes::new()).tx;
+        let reader = CcipReader::with_timeouts(Duration::from_millis(100), Duration::from_millis(200));
+        let result = reader.call(contract.client_ref(), tx).await;
+
+        assert!(matches!(result, Err(UserOpError::RPC(_))), "{:?}", result);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_offchain_lookup_from_another_contract_is_rejected() {
+        let wallet = Address::from_low_u64_be(0x1234);
+        let (provider, mock) = Provider::mocked();
+        mock.push_response(offchain_lookup_revert(Address::from_low_u64_be(0x9999), &[], &[], &[]));
+
+        let contract = crate::contracts::ISmartWallet::new(wallet, Arc::new(provider));
+        let tx = contract.is_valid_signature([0x11; 32], Bytes::new()).tx;
+        let result = CcipReader::new().call(contract.client_ref(), tx).await;
+
+        assert!(matches!(result, Err(UserOpError::Contract(_))), "{:?}", result);
+    }
+}
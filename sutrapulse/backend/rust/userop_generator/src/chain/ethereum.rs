@@ -1,5 +1,5 @@
 use ethers::prelude::*;
-use super::{Chain, ChainConfig, ChainProvider};
+use super::{Chain, ChainConfig};
 use crate::error::Result;
 
 pub fn create_ethereum_chain(entry_point: Address, provider_url: String) -> Result<Chain> {
@@ -8,6 +8,7 @@ pub fn create_ethereum_chain(entry_point: Address, provider_url: String) -> Resu
         entry_point,
         provider_url,
         confirmations: 12, // Standard Ethereum confirmation count
+        fast_finality: false,
     };
 
     Chain::new(config)
@@ -16,6 +17,7 @@ pub fn create_ethereum_chain(entry_point: Address, provider_url: String) -> Resu
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chain::ChainProvider;
 
     #[test]
     fn test_ethereum_chain() {
@@ -25,6 +27,7 @@ mod tests {
             entry_point,
             provider_url: "https://eth-mainnet.g.alchemy.com/v2/your-api-key".to_string(),
             confirmations: 12,
+            fast_finality: false,
         }).unwrap();
 
         assert_eq!(chain.get_chain_id(), 1);
@@ -1,6 +1,8 @@
+use async_trait::async_trait;
 use ethers::prelude::*;
 use std::sync::Arc;
-use crate::error::Result;
+use crate::cache::GasCache;
+use crate::error::{Result, UserOpError};
 
 pub mod ethereum;
 pub mod polygon;
@@ -12,13 +14,64 @@ pub struct ChainConfig {
     pub entry_point: Address,
     pub provider_url: String,
     pub confirmations: u64,
+    /// Blocks are final once accepted, so `confirmations` may be 0.
+    pub fast_finality: bool,
 }
 
-pub trait ChainProvider {
-    fn get_provider(&self) -> Arc<Provider<Http>>;
+impl ChainConfig {
+    /// Rejects `confirmations: 0` unless the chain has fast finality.
+    pub fn validate(&self) -> Result<()> {
+        validate_confirmations(self.chain_id, self.confirmations, self.fast_finality)
+    }
+}
+
+pub fn validate_confirmations(chain_id: u64, confirmations: u64, fast_finality: bool) -> Result<()> {
+    if confirmations == 0 && !fast_finality {
+        return Err(UserOpError::ChainConfig(format!(
+            "Chain {} requires at least one confirmation; 0 is only allowed for fast-finality chains",
+            chain_id
+        )));
+    }
+    Ok(())
+}
+
+#[async_trait]
+pub trait ChainProvider<P: JsonRpcClient = Http>: Send + Sync {
+    fn get_provider(&self) -> Arc<Provider<P>>;
     fn get_chain_id(&self) -> u64;
     fn get_entry_point(&self) -> Address;
     fn get_confirmations(&self) -> u64;
+
+    fn is_fast_finality(&self) -> bool {
+        false
+    }
+
+    async fn latest_block_number(&self) -> Result<u64> {
+        self.get_provider()
+            .get_block_number()
+            .await
+            .map(|number| number.as_u64())
+            .map_err(|e| UserOpError::RPC(e.to_string()))
+    }
+
+    /// Fetches the latest block's base fee and stores it in `gas_cache`
+    /// along with the block it came from.
+    async fn latest_base_fee(&self, gas_cache: &GasCache) -> Result<U256> {
+        let block = self.get_provider()
+            .get_block(BlockNumber::Latest)
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?
+            .ok_or_else(|| UserOpError::RPC("Latest block not found".to_string()))?;
+        let base_fee = block.base_fee_per_gas.ok_or_else(|| {
+            UserOpError::ChainConfig(format!("Chain {} does not report a base fee", self.get_chain_id()))
+        })?;
+
+        match block.number {
+            Some(number) => gas_cache.set_base_fee_at(self.get_chain_id(), base_fee, number.as_u64()).await,
+            None => gas_cache.set_base_fee(self.get_chain_id(), base_fee).await,
+        }
+        Ok(base_fee)
+    }
 }
 
 pub struct Chain {
@@ -28,6 +81,7 @@ pub struct Chain {
 
 impl Chain {
     pub fn new(config: ChainConfig) -> Result<Self> {
+        config.validate()?;
         let provider = Provider::<Http>::try_from(&config.provider_url)
             .map_err(|e| crate::error::UserOpError::ChainConfig(e.to_string()))?;
         
@@ -54,4 +108,89 @@ impl ChainProvider for Chain {
     fn get_confirmations(&self) -> u64 {
         self.config.confirmations
     }
+
+    fn is_fast_finality(&self) -> bool {
+        self.config.fast_finality
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockChain {
+        provider: Arc<Provider<MockProvider>>,
+    }
+
+    impl ChainProvider<MockProvider> for MockChain {
+        fn get_provider(&self) -> Arc<Provider<MockProvider>> {
+            self.provider.clone()
+        }
+
+        fn get_chain_id(&self) -> u64 {
+            1
+        }
+
+        fn get_entry_point(&self) -> Address {
+            Address::zero()
+        }
+
+        fn get_confirmations(&self) -> u64 {
+            12
+        }
+    }
+
+    fn mock_chain() -> (MockChain, MockProvider) {
+        let (provider, mock) = Provider::mocked();
+        (MockChain { provider: Arc::new(provider) }, mock)
+    }
+
+    #[tokio::test]
+    async fn test_latest_block_number() {
+        let (chain, mock) = mock_chain();
+        mock.push(U64::from(19_000_000u64)).unwrap();
+
+        assert_eq!(chain.latest_block_number().await.unwrap(), 19_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_latest_base_fee_is_cached() {
+        let (chain, mock) = mock_chain();
+        let block = Block::<H256> {
+            number: Some(U64::from(19_000_000u64)),
+            base_fee_per_gas: Some(U256::from(25_000_000_000u64)),
+            ..Default::default()
+        };
+        mock.push(block).unwrap();
+        let gas_cache = GasCache::new();
+
+        let base_fee = chain.latest_base_fee(&gas_cache).await.unwrap();
+
+        assert_eq!(base_fee, U256::from(25_000_000_000u64));
+        assert_eq!(gas_cache.get_base_fee(1).await, Some(base_fee));
+    }
+
+    #[tokio::test]
+    async fn test_latest_base_fee_requires_london_block() {
+        let (chain, mock) = mock_chain();
+        mock.push(Block::<H256>::default()).unwrap();
+
+        let result = chain.latest_base_fee(&GasCache::new()).await;
+        assert!(matches!(result, Err(UserOpError::ChainConfig(_))));
+    }
+
+    #[test]
+    fn test_zero_confirmations_requires_fast_finality() {
+        let mut config = ChainConfig {
+            chain_id: 1,
+            entry_point: Address::zero(),
+            provider_url: "https://eth-mainnet.g.alchemy.com/v2/your-api-key".to_string(),
+            confirmations: 0,
+            fast_finality: false,
+        };
+        assert!(matches!(Chain::new(config.clone()), Err(UserOpError::ChainConfig(_))));
+
+        config.fast_finality = true;
+        assert!(Chain::new(config).is_ok());
+    }
 }
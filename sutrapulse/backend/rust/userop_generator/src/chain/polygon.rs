@@ -1,5 +1,5 @@
 use ethers::prelude::*;
-use super::{Chain, ChainConfig, ChainProvider};
+use super::{Chain, ChainConfig};
 use crate::error::Result;
 
 pub fn create_polygon_chain(entry_point: Address, provider_url: String) -> Result<Chain> {
@@ -8,6 +8,7 @@ pub fn create_polygon_chain(entry_point: Address, provider_url: String) -> Resul
         entry_point,
         provider_url,
         confirmations: 256, // Polygon's recommended confirmation count
+        fast_finality: false,
     };
 
     Chain::new(config)
@@ -16,6 +17,7 @@ pub fn create_polygon_chain(entry_point: Address, provider_url: String) -> Resul
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chain::ChainProvider;
 
     #[test]
     fn test_polygon_chain() {
@@ -25,6 +27,7 @@ mod tests {
             entry_point,
             provider_url: "https://polygon-mainnet.g.alchemy.com/v2/your-api-key".to_string(),
             confirmations: 256,
+            fast_finality: false,
         }).unwrap();
 
         assert_eq!(chain.get_chain_id(), 137);
@@ -1,18 +1,95 @@
 use ethers::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
 use std::str::FromStr;
 use crate::error::{Result, UserOpError};
+use crate::gas::{CapBehavior, GasDefaults, MAX_FEE_HISTORY_BLOCKS};
+use crate::policy::SenderPolicy;
+use crate::provider::{rpc_provider, RpcClient};
 
 const ENV_PREFIX: &str = "env";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Chains the crate has built-in support for.
+pub const KNOWN_CHAIN_IDS: [u64; 3] = [1, 137, 42161];
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChainConfig {
     pub chain_id: u64,
     pub rpc_url: String,
     pub entry_point_address: String,
     pub wallet_factory_address: String,
     pub paymaster_address: String,
+    #[serde(default)]
+    pub default_verification_gas_limit: Option<u64>,
+    #[serde(default)]
+    pub default_pre_verification_gas: Option<u64>,
+    #[serde(default)]
+    pub default_paymaster_post_op_gas_limit: Option<u64>,
+    /// Priority fee floor in wei; overrides the chain's built-in floor.
+    #[serde(default)]
+    pub min_priority_fee: Option<u64>,
+    /// Ceiling on `max_fee_per_gas` in wei.
+    #[serde(default)]
+    pub max_fee_cap: Option<u64>,
+    #[serde(default)]
+    pub cap_behavior: CapBehavior,
+    /// Blocks sampled by `eth_feeHistory`; overrides the chain's built-in window.
+    #[serde(default)]
+    pub fee_history_blocks: Option<u64>,
+    /// Receives this chain's `handleOps` refunds when the caller doesn't
+    /// name a beneficiary; overrides `Config::default_beneficiary`.
+    #[serde(default)]
+    pub default_beneficiary: Option<String>,
+    /// Extra HTTP headers sent with every RPC request, e.g. an API key.
+    /// Values are redacted from `Debug` output.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl std::fmt::Debug for ChainConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let headers: HashMap<&str, &str> = self.headers
+            .keys()
+            .map(|name| (name.as_str(), "<redacted>"))
+            .collect();
+
+        f.debug_struct("ChainConfig")
+            .field("chain_id", &self.chain_id)
+            .field("rpc_url", &self.rpc_url)
+            .field("entry_point_address", &self.entry_point_address)
+            .field("wallet_factory_address", &self.wallet_factory_address)
+            .field("paymaster_address", &self.paymaster_address)
+            .field("default_verification_gas_limit", &self.default_verification_gas_limit)
+            .field("default_pre_verification_gas", &self.default_pre_verification_gas)
+            .field("default_paymaster_post_op_gas_limit", &self.default_paymaster_post_op_gas_limit)
+            .field("min_priority_fee", &self.min_priority_fee)
+            .field("max_fee_cap", &self.max_fee_cap)
+            .field("cap_behavior", &self.cap_behavior)
+            .field("fee_history_blocks", &self.fee_history_blocks)
+            .field("default_beneficiary", &self.default_beneficiary)
+            .field("headers", &headers)
+            .finish()
+    }
+}
+
+impl ChainConfig {
+    /// Gas defaults for this chain, falling back to the built-in values
+    /// for any limit that isn't configured.
+    pub fn gas_defaults(&self) -> GasDefaults {
+        let builtin = GasDefaults::for_chain(self.chain_id);
+        GasDefaults {
+            verification_gas_limit: self.default_verification_gas_limit
+                .map(U256::from)
+                .unwrap_or(builtin.verification_gas_limit),
+            pre_verification_gas: self.default_pre_verification_gas
+                .map(U256::from)
+                .unwrap_or(builtin.pre_verification_gas),
+            paymaster_post_op_gas_limit: self.default_paymaster_post_op_gas_limit
+                .map(U256::from)
+                .unwrap_or(builtin.paymaster_post_op_gas_limit),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -37,9 +114,32 @@ impl TryFrom<&ChainConfig> for ContractAddresses {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Config {
     pub chains: HashMap<u64, ChainConfig>,
+    /// Receives `handleOps` gas refunds when the caller doesn't name a beneficiary.
+    #[serde(default)]
+    pub default_beneficiary: Option<String>,
+    /// Negotiates gzip/deflate with RPC providers.
+    #[serde(default)]
+    pub rpc_compression: bool,
+    /// Logs each RPC request and response at debug level, with the params
+    /// of signing methods redacted.
+    #[serde(default)]
+    pub rpc_log_requests: bool,
+    /// Chain IDs accepted by `validate` besides `KNOWN_CHAIN_IDS`.
+    #[serde(default)]
+    pub allowed_chain_ids: Vec<u64>,
+    /// Senders ops are processed for; empty allows everyone not denied.
+    #[serde(default)]
+    pub sender_allowlist: Vec<String>,
+    /// Senders that are always rejected.
+    #[serde(default)]
+    pub sender_denylist: Vec<String>,
+    /// Fewest confirmations any chain waits for, raising lower per-chain
+    /// settings. 0 leaves every chain's own setting alone.
+    #[serde(default)]
+    pub min_confirmations: u64,
 }
 
 impl Config {
@@ -54,6 +154,98 @@ impl Config {
         std::env::var(&var_name).unwrap_or_else(|_| default.to_string())
     }
 
+    fn get_cap_behavior(section: &str, key: &str) -> Result<CapBehavior> {
+        match Self::get_env_var_optional(section, key, "clamp").to_lowercase().as_str() {
+            "clamp" => Ok(CapBehavior::Clamp),
+            "fail" => Ok(CapBehavior::Fail),
+            other => Err(UserOpError::Config(format!("Invalid cap behavior: {}", other))),
+        }
+    }
+
+    fn get_env_u64_optional(section: &str, key: &str) -> Result<Option<u64>> {
+        let var_name = format!("{}.{}§{}", ENV_PREFIX, section, key);
+        match std::env::var(&var_name) {
+            Ok(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|e| UserOpError::Config(format!("Invalid value for {}: {}", var_name, e))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Reads the per-chain gas settings stored under `GAS.<prefix>_*`.
+    fn chain_from_env(
+        chain_id: u64,
+        rpc_url: String,
+        entry_point: &str,
+        wallet_factory_address: String,
+        paymaster_address: String,
+        gas_prefix: &str,
+    ) -> Result<ChainConfig> {
+        let gas_key = |key: &str| format!("{}_{}", gas_prefix, key);
+
+        Ok(ChainConfig {
+            chain_id,
+            rpc_url,
+            entry_point_address: entry_point.to_string(),
+            wallet_factory_address,
+            paymaster_address,
+            default_verification_gas_limit: Self::get_env_u64_optional("GAS", &gas_key("VERIFICATION_GAS_LIMIT"))?,
+            default_pre_verification_gas: Self::get_env_u64_optional("GAS", &gas_key("PRE_VERIFICATION_GAS"))?,
+            default_paymaster_post_op_gas_limit: Self::get_env_u64_optional("GAS", &gas_key("PAYMASTER_POST_OP_GAS_LIMIT"))?,
+            min_priority_fee: Self::get_env_u64_optional("GAS", &gas_key("MIN_PRIORITY_FEE"))?,
+            max_fee_cap: Self::get_env_u64_optional("GAS", &gas_key("MAX_FEE_CAP"))?,
+            cap_behavior: Self::get_cap_behavior("GAS", &gas_key("CAP_BEHAVIOR"))?,
+            fee_history_blocks: Self::get_env_u64_optional("GAS", &gas_key("FEE_HISTORY_BLOCKS"))?,
+            default_beneficiary: Self::get_env_var("SUBMIT", &format!("{}_DEFAULT_BENEFICIARY", gas_prefix)).ok(),
+            headers: HashMap::new(),
+        })
+    }
+
+    /// Ethereum, Polygon and Arbitrum, each registered when its RPC URL is set.
+    fn builtin_chains_from_env(entry_point: &str) -> Result<HashMap<u64, ChainConfig>> {
+        let mut chains = HashMap::new();
+
+        for (chain_id, name) in [(1, "ETH"), (137, "POLYGON"), (42161, "ARBITRUM")] {
+            if let Ok(rpc_url) = Self::get_env_var("RPC", &format!("{}_PROVIDER_URL", name)) {
+                chains.insert(chain_id, Self::chain_from_env(
+                    chain_id,
+                    rpc_url,
+                    entry_point,
+                    Self::get_env_var("CONTRACTS", &format!("{}_WALLET_FACTORY", name))?,
+                    Self::get_env_var("CONTRACTS", &format!("{}_PAYMASTER", name))?,
+                    name,
+                )?);
+            }
+        }
+
+        Ok(chains)
+    }
+
+    /// Chains listed in `CHAINS.LIST` (comma-separated IDs), each read from
+    /// `CHAINS.<ID>_RPC_URL`, `CHAINS.<ID>_WALLET_FACTORY` and
+    /// `CHAINS.<ID>_PAYMASTER`, with gas settings under `GAS.<ID>_*`.
+    fn registry_chains_from_env(list: &str, entry_point: &str) -> Result<HashMap<u64, ChainConfig>> {
+        let mut chains = HashMap::new();
+
+        for id in list.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+            let chain_id = id
+                .parse::<u64>()
+                .map_err(|e| UserOpError::Config(format!("Invalid chain ID {:?} in CHAINS: {}", id, e)))?;
+
+            chains.insert(chain_id, Self::chain_from_env(
+                chain_id,
+                Self::get_env_var("CHAINS", &format!("{}_RPC_URL", chain_id))?,
+                entry_point,
+                Self::get_env_var("CHAINS", &format!("{}_WALLET_FACTORY", chain_id))?,
+                Self::get_env_var("CHAINS", &format!("{}_PAYMASTER", chain_id))?,
+                id,
+            )?);
+        }
+
+        Ok(chains)
+    }
+
     pub fn from_env() -> Result<Self> {
         dotenv::dotenv().ok();
 
@@ -64,46 +256,172 @@ impl Config {
             "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789"
         );
 
-        let mut chains = HashMap::new();
+        // Chains listed in the registry are explicitly allowed
+        let (chains, allowed_chain_ids) = match std::env::var(format!("{}.CHAINS§LIST", ENV_PREFIX)) {
+            Ok(list) => {
+                let chains = Self::registry_chains_from_env(&list, &entry_point)?;
+                let allowed = chains.keys().copied().collect();
+                (chains, allowed)
+            }
+            Err(_) => (Self::builtin_chains_from_env(&entry_point)?, Vec::new()),
+        };
 
-        // Ethereum Mainnet (Chain ID: 1)
-        if let Ok(eth_rpc) = Self::get_env_var("RPC", "ETH_PROVIDER_URL") {
-            chains.insert(1, ChainConfig {
-                chain_id: 1,
-                rpc_url: eth_rpc,
-                entry_point_address: entry_point.clone(),
-                wallet_factory_address: Self::get_env_var("CONTRACTS", "ETH_WALLET_FACTORY")?,
-                paymaster_address: Self::get_env_var("CONTRACTS", "ETH_PAYMASTER")?,
-            });
+        if chains.is_empty() {
+            return Err(UserOpError::Config("No chain configurations found in environment variables".to_string()));
         }
 
-        // Polygon Mainnet (Chain ID: 137)
-        if let Ok(polygon_rpc) = Self::get_env_var("RPC", "POLYGON_PROVIDER_URL") {
-            chains.insert(137, ChainConfig {
-                chain_id: 137,
-                rpc_url: polygon_rpc,
-                entry_point_address: entry_point.clone(),
-                wallet_factory_address: Self::get_env_var("CONTRACTS", "POLYGON_WALLET_FACTORY")?,
-                paymaster_address: Self::get_env_var("CONTRACTS", "POLYGON_PAYMASTER")?,
-            });
+        let default_beneficiary = std::env::var(format!("{}.SUBMIT§DEFAULT_BENEFICIARY", ENV_PREFIX)).ok();
+
+        let rpc_compression = Self::get_env_var_optional("RPC", "COMPRESSION", "false")
+            .parse::<bool>()
+            .map_err(|e| UserOpError::Config(format!("Invalid RPC compression flag: {}", e)))?;
+        let rpc_log_requests = Self::get_env_var_optional("RPC", "LOG_REQUESTS", "false")
+            .parse::<bool>()
+            .map_err(|e| UserOpError::Config(format!("Invalid RPC request logging flag: {}", e)))?;
+
+        let sender_list = |key: &str| -> Vec<String> {
+            Self::get_env_var_optional("SENDERS", key, "")
+                .split(',')
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let sender_allowlist = sender_list("ALLOWLIST");
+        let sender_denylist = sender_list("DENYLIST");
+
+        let min_confirmations = Self::get_env_u64_optional("SUBMIT", "MIN_CONFIRMATIONS")?.unwrap_or(0);
+
+        let config = Config {
+            chains,
+            default_beneficiary,
+            rpc_compression,
+            rpc_log_requests,
+            allowed_chain_ids,
+            sender_allowlist,
+            sender_denylist,
+            min_confirmations,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Loads and validates a config file, picking the format from the
+    /// extension: `.json`, or `.yaml`/`.yml`. Each format needs its feature.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        match path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            #[cfg(feature = "json-config")]
+            Some("json") => Self::from_json_file(path),
+            #[cfg(feature = "yaml-config")]
+            Some("yaml" | "yml") => Self::from_yaml_file(path),
+            _ => Err(UserOpError::Config(format!(
+                "Unsupported config format for {}; enabled formats: {}",
+                path.display(),
+                Self::enabled_formats()
+            ))),
         }
+    }
 
-        // Arbitrum Mainnet (Chain ID: 42161)
-        if let Ok(arbitrum_rpc) = Self::get_env_var("RPC", "ARBITRUM_PROVIDER_URL") {
-            chains.insert(42161, ChainConfig {
-                chain_id: 42161,
-                rpc_url: arbitrum_rpc,
-                entry_point_address: entry_point.clone(),
-                wallet_factory_address: Self::get_env_var("CONTRACTS", "ARBITRUM_WALLET_FACTORY")?,
-                paymaster_address: Self::get_env_var("CONTRACTS", "ARBITRUM_PAYMASTER")?,
-            });
+    fn enabled_formats() -> String {
+        let formats: &[&str] = &[
+            #[cfg(feature = "json-config")]
+            "json",
+            #[cfg(feature = "yaml-config")]
+            "yaml",
+        ];
+        if formats.is_empty() {
+            "none".to_string()
+        } else {
+            formats.join(", ")
         }
+    }
 
-        if chains.is_empty() {
-            return Err(UserOpError::Config("No chain configurations found in environment variables".to_string()));
+    #[cfg(any(feature = "json-config", feature = "yaml-config"))]
+    fn read_file(path: &Path) -> Result<String> {
+        std::fs::read_to_string(path)
+            .map_err(|e| UserOpError::Config(format!("Failed to read {}: {}", path.display(), e)))
+    }
+
+    #[cfg(feature = "json-config")]
+    pub fn from_json_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let config: Config = serde_json::from_str(&Self::read_file(path)?)
+            .map_err(|e| UserOpError::Config(format!("Invalid JSON config {}: {}", path.display(), e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[cfg(feature = "yaml-config")]
+    pub fn from_yaml_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let config: Config = serde_yaml::from_str(&Self::read_file(path)?)
+            .map_err(|e| UserOpError::Config(format!("Invalid YAML config {}: {}", path.display(), e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks every chain's RPC URL scheme, contract addresses and chain ID,
+    /// reporting all problems in a single error.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        let mut chain_ids: Vec<_> = self.chains.keys().copied().collect();
+        chain_ids.sort_unstable();
+        for chain_id in chain_ids {
+            let chain = &self.chains[&chain_id];
+
+            if chain.chain_id != chain_id {
+                problems.push(format!("chain {} is registered under ID {}", chain.chain_id, chain_id));
+            }
+            if !KNOWN_CHAIN_IDS.contains(&chain_id) && !self.allowed_chain_ids.contains(&chain_id) {
+                problems.push(format!("chain {} is not recognized or explicitly allowed", chain_id));
+            }
+
+            let scheme = chain.rpc_url.split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase());
+            if !matches!(scheme.as_deref(), Some("http" | "https" | "ws" | "wss")) {
+                problems.push(format!("chain {}: RPC URL {:?} must use http, https, ws or wss", chain_id, chain.rpc_url));
+            }
+
+            for (name, address) in [
+                ("entry point", &chain.entry_point_address),
+                ("wallet factory", &chain.wallet_factory_address),
+                ("paymaster", &chain.paymaster_address),
+            ] {
+                if Address::from_str(address).is_err() {
+                    problems.push(format!("chain {}: invalid {} address {:?}", chain_id, name, address));
+                }
+            }
+
+            if let Some(blocks) = chain.fee_history_blocks {
+                if !(1..=MAX_FEE_HISTORY_BLOCKS).contains(&blocks) {
+                    problems.push(format!(
+                        "chain {}: fee history window {} must be between 1 and {}",
+                        chain_id, blocks, MAX_FEE_HISTORY_BLOCKS
+                    ));
+                }
+            }
+
+            if let Some(problem) = beneficiary_problem(chain.default_beneficiary.as_deref()) {
+                problems.push(format!("chain {}: {}", chain_id, problem));
+            }
+        }
+
+        if let Some(problem) = beneficiary_problem(self.default_beneficiary.as_deref()) {
+            problems.push(problem);
+        }
+
+        for (list, addresses) in [("allowlist", &self.sender_allowlist), ("denylist", &self.sender_denylist)] {
+            for address in addresses.iter().filter(|address| Address::from_str(address).is_err()) {
+                problems.push(format!("invalid sender {} entry {:?}", list, address));
+            }
         }
 
-        Ok(Config { chains })
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(UserOpError::Config(format!("Invalid configuration: {}", problems.join("; "))))
+        }
     }
 
     pub fn get_chain_config(&self, chain_id: u64) -> Result<&ChainConfig> {
@@ -112,9 +430,9 @@ impl Config {
             .ok_or_else(|| UserOpError::Config(format!("Chain ID {} not found in config", chain_id)))
     }
 
-    pub fn get_provider(&self, chain_id: u64) -> Result<Provider<Http>> {
+    pub fn get_provider(&self, chain_id: u64) -> Result<Provider<RpcClient>> {
         let config = self.get_chain_config(chain_id)?;
-        Provider::<Http>::try_from(&config.rpc_url)
+        rpc_provider(&config.rpc_url, self.rpc_compression, self.rpc_log_requests, &config.headers)
             .map_err(|e| UserOpError::Config(format!("Failed to create provider: {}", e)))
     }
 
@@ -123,6 +441,34 @@ impl Config {
         ContractAddresses::try_from(config)
     }
 
+    pub fn get_default_beneficiary(&self) -> Result<Option<Address>> {
+        parse_beneficiary(self.default_beneficiary.as_deref())
+    }
+
+    /// The beneficiary for `chain_id`'s refunds when the caller names none:
+    /// the chain's own default, else the global one.
+    pub fn get_chain_beneficiary(&self, chain_id: u64) -> Result<Option<Address>> {
+        let chain = self.get_chain_config(chain_id)?;
+        match parse_beneficiary(chain.default_beneficiary.as_deref())? {
+            Some(beneficiary) => Ok(Some(beneficiary)),
+            None => self.get_default_beneficiary(),
+        }
+    }
+
+    /// The configured sender allow and deny lists.
+    pub fn sender_policy(&self) -> Result<SenderPolicy> {
+        let parse = |addresses: &[String]| -> Result<Vec<Address>> {
+            addresses
+                .iter()
+                .map(|address| {
+                    Address::from_str(address)
+                        .map_err(|e| UserOpError::Config(format!("Invalid sender address {:?}: {}", address, e)))
+                })
+                .collect()
+        };
+        Ok(SenderPolicy::new(parse(&self.sender_allowlist)?, parse(&self.sender_denylist)?))
+    }
+
     pub fn get_signer(&self, chain_id: u64) -> Result<LocalWallet> {
         let private_key = Self::get_env_var("KEYS", "PRIVATE_KEY")?;
         
@@ -133,16 +479,35 @@ impl Config {
     }
 }
 
+fn parse_beneficiary(address: Option<&str>) -> Result<Option<Address>> {
+    address
+        .map(|address| {
+            Address::from_str(address)
+                .map_err(|e| UserOpError::Config(format!("Invalid default beneficiary: {}", e)))
+        })
+        .transpose()
+}
+
+/// Why `address` can't receive refunds, if it can't. Refunds sent to the
+/// zero address are lost.
+fn beneficiary_problem(address: Option<&str>) -> Option<String> {
+    match parse_beneficiary(address) {
+        Ok(Some(beneficiary)) if beneficiary.is_zero() => Some("default beneficiary is the zero address".to_string()),
+        Ok(_) => None,
+        Err(_) => Some(format!("invalid default beneficiary {:?}", address.unwrap_or_default())),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     fn setup_test_env() {
-        std::env::set_var("env.ETH_PROVIDER_URL");
-        std::env::set_var("ENTRY_POINT_ADDRESS", "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789");
-        std::env::set_var("env.PRIVATE_KEY", "0000000000000000000000000000000000000000000000000000000000000001");
-        std::env::set_var("env.ETH_WALLET_FACTORY", "0x1234567890123456789012345678901234567890");
-        std::env::set_var("env.ETH_PAYMASTER", "0x1234567890123456789012345678901234567890");
+        std::env::set_var("env.RPC§ETH_PROVIDER_URL", "https://eth-mainnet.g.alchemy.com/v2/test");
+        std::env::set_var("env.CONTRACTS§ENTRY_POINT_ADDRESS", "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789");
+        std::env::set_var("env.KEYS§PRIVATE_KEY", "0000000000000000000000000000000000000000000000000000000000000001");
+        std::env::set_var("env.CONTRACTS§ETH_WALLET_FACTORY", "0x1234567890123456789012345678901234567890");
+        std::env::set_var("env.CONTRACTS§ETH_PAYMASTER", "0x1234567890123456789012345678901234567890");
     }
 
     #[test]
@@ -170,4 +535,247 @@ mod tests {
         let result = config.get_signer(1);
         assert!(result.is_ok());
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_chain_gas_defaults_override() {
+        let mut chain = ChainConfig {
+            chain_id: 137,
+            rpc_url: "https://polygon-mainnet.g.alchemy.com/v2/your-api-key".to_string(),
+            entry_point_address: "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789".to_string(),
+            wallet_factory_address: "0x1234567890123456789012345678901234567890".to_string(),
+            paymaster_address: "0x1234567890123456789012345678901234567890".to_string(),
+            default_verification_gas_limit: None,
+            default_pre_verification_gas: None,
+            default_paymaster_post_op_gas_limit: None,
+            min_priority_fee: None,
+            max_fee_cap: None,
+            cap_behavior: CapBehavior::default(),
+            fee_history_blocks: None,
+            default_beneficiary: None,
+            headers: HashMap::new(),
+        };
+        assert_eq!(chain.gas_defaults(), GasDefaults::new(200000, 40000));
+
+        chain.default_verification_gas_limit = Some(250000);
+        assert_eq!(chain.gas_defaults(), GasDefaults::new(250000, 40000));
+    }
+
+    #[test]
+    fn test_registry_registers_extra_chain() {
+        std::env::set_var("env.CHAINS§8453_RPC_URL", "https://base-mainnet.g.alchemy.com/v2/your-api-key");
+        std::env::set_var("env.CHAINS§8453_WALLET_FACTORY", "0x1234567890123456789012345678901234567890");
+        std::env::set_var("env.CHAINS§8453_PAYMASTER", "0x2234567890123456789012345678901234567890");
+        std::env::set_var("env.GAS§8453_MIN_PRIORITY_FEE", "1000000");
+
+        let chains = Config::registry_chains_from_env(" 8453 ,", "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789").unwrap();
+
+        assert_eq!(chains.len(), 1);
+        let base = &chains[&8453];
+        assert_eq!(base.chain_id, 8453);
+        assert_eq!(base.rpc_url, "https://base-mainnet.g.alchemy.com/v2/your-api-key");
+        assert_eq!(base.min_priority_fee, Some(1_000_000));
+        assert!(ContractAddresses::try_from(base).is_ok());
+
+        let result = Config::registry_chains_from_env("base", "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789");
+        assert!(matches!(result, Err(UserOpError::Config(_))));
+    }
+
+    fn valid_chain(chain_id: u64) -> ChainConfig {
+        ChainConfig {
+            chain_id,
+            rpc_url: "https://eth-mainnet.g.alchemy.com/v2/your-api-key".to_string(),
+            entry_point_address: "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789".to_string(),
+            wallet_factory_address: "0x1234567890123456789012345678901234567890".to_string(),
+            paymaster_address: "0x2234567890123456789012345678901234567890".to_string(),
+            default_verification_gas_limit: None,
+            default_pre_verification_gas: None,
+            default_paymaster_post_op_gas_limit: None,
+            min_priority_fee: None,
+            max_fee_cap: None,
+            cap_behavior: CapBehavior::default(),
+            fee_history_blocks: None,
+            default_beneficiary: None,
+            headers: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_header_values_are_redacted_from_debug() {
+        let mut chain = valid_chain(1);
+        chain.headers.insert("X-Api-Key".to_string(), "super-secret".to_string());
+
+        let debug = format!("{:?}", chain);
+        assert!(debug.contains("X-Api-Key"));
+        assert!(!debug.contains("super-secret"));
+    }
+
+    #[test]
+    fn test_validate_accepts_valid_config() {
+        let config = Config {
+            chains: HashMap::from([(1, valid_chain(1)), (8453, valid_chain(8453))]),
+            default_beneficiary: Some("0x3234567890123456789012345678901234567890".to_string()),
+            rpc_compression: false,
+            rpc_log_requests: false,
+            allowed_chain_ids: vec![8453],
+            sender_allowlist: vec!["0x1234567890123456789012345678901234567890".to_string()],
+            sender_denylist: Vec::new(),
+            min_confirmations: 0,
+        };
+
+        assert!(config.validate().is_ok());
+        let policy = config.sender_policy().unwrap();
+        assert!(policy.is_allowed(Address::from_str("0x1234567890123456789012345678901234567890").unwrap()));
+        assert!(!policy.is_allowed(Address::from_str("0x3234567890123456789012345678901234567890").unwrap()));
+    }
+
+    #[test]
+    fn test_chain_beneficiary_overrides_global_default() {
+        let mut base = valid_chain(8453);
+        base.default_beneficiary = Some("0x4234567890123456789012345678901234567890".to_string());
+        let config = Config {
+            chains: HashMap::from([(1, valid_chain(1)), (8453, base)]),
+            default_beneficiary: Some("0x3234567890123456789012345678901234567890".to_string()),
+            rpc_compression: false,
+            rpc_log_requests: false,
+            allowed_chain_ids: vec![8453],
+            sender_allowlist: Vec::new(),
+            sender_denylist: Vec::new(),
+            min_confirmations: 0,
+        };
+
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.get_chain_beneficiary(8453).unwrap(),
+            Some(Address::from_str("0x4234567890123456789012345678901234567890").unwrap())
+        );
+        assert_eq!(config.get_chain_beneficiary(1).unwrap(), config.get_default_beneficiary().unwrap());
+        assert!(matches!(config.get_chain_beneficiary(137), Err(UserOpError::Config(_))));
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let mut bad_scheme = valid_chain(1);
+        bad_scheme.rpc_url = "ftp://example.com".to_string();
+        let mut bad_address = valid_chain(137);
+        bad_address.paymaster_address = "0x1234".to_string();
+        bad_address.fee_history_blocks = Some(0);
+        bad_scheme.default_beneficiary = Some(format!("{:?}", Address::zero()));
+
+        let config = Config {
+            chains: HashMap::from([(1, bad_scheme), (137, bad_address), (8453, valid_chain(8453))]),
+            default_beneficiary: None,
+            rpc_compression: false,
+            rpc_log_requests: false,
+            allowed_chain_ids: Vec::new(),
+            sender_allowlist: Vec::new(),
+            sender_denylist: vec!["0xnot-an-address".to_string()],
+            min_confirmations: 0,
+        };
+
+        match config.validate() {
+            Err(UserOpError::Config(message)) => {
+                assert!(message.contains("chain 1: RPC URL"), "{}", message);
+                assert!(message.contains("chain 1: default beneficiary is the zero address"), "{}", message);
+                assert!(message.contains("chain 137: invalid paymaster address"), "{}", message);
+                assert!(message.contains("chain 137: fee history window 0"), "{}", message);
+                assert!(message.contains("chain 8453 is not recognized"), "{}", message);
+                assert!(message.contains("invalid sender denylist entry"), "{}", message);
+            }
+            other => panic!("Expected config error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "json-config")]
+    const JSON_CONFIG: &str = r#"{
+        "chains": {
+            "1": {
+                "chain_id": 1,
+                "rpc_url": "https://eth-mainnet.g.alchemy.com/v2/your-api-key",
+                "entry_point_address": "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789",
+                "wallet_factory_address": "0x1234567890123456789012345678901234567890",
+                "paymaster_address": "0x2234567890123456789012345678901234567890",
+                "max_fee_cap": 500000000000,
+                "cap_behavior": "fail"
+            }
+        },
+        "rpc_compression": true,
+        "rpc_log_requests": true,
+        "min_confirmations": 3
+    }"#;
+
+    #[cfg(feature = "yaml-config")]
+    const YAML_CONFIG: &str = r#"
+chains:
+  1:
+    chain_id: 1
+    rpc_url: https://eth-mainnet.g.alchemy.com/v2/your-api-key
+    entry_point_address: "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789"
+    wallet_factory_address: "0x1234567890123456789012345678901234567890"
+    paymaster_address: "0x2234567890123456789012345678901234567890"
+    max_fee_cap: 500000000000
+    cap_behavior: fail
+rpc_compression: true
+rpc_log_requests: true
+min_confirmations: 3
+"#;
+
+    #[cfg(any(feature = "json-config", feature = "yaml-config"))]
+    fn write_temp(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("userop-config-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[cfg(feature = "json-config")]
+    #[test]
+    fn test_from_file_reads_json() {
+        let path = write_temp("config.json", JSON_CONFIG);
+        let config = Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut expected_chain = valid_chain(1);
+        expected_chain.max_fee_cap = Some(500_000_000_000);
+        expected_chain.cap_behavior = CapBehavior::Fail;
+        assert_eq!(config.chains, HashMap::from([(1, expected_chain)]));
+        assert!(config.rpc_compression);
+        assert!(config.rpc_log_requests);
+        assert_eq!(config.min_confirmations, 3);
+        assert_eq!(config.default_beneficiary, None);
+    }
+
+    #[cfg(feature = "yaml-config")]
+    #[test]
+    fn test_from_file_reads_yaml() {
+        let path = write_temp("config.yaml", YAML_CONFIG);
+        let config = Config::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut expected_chain = valid_chain(1);
+        expected_chain.max_fee_cap = Some(500_000_000_000);
+        expected_chain.cap_behavior = CapBehavior::Fail;
+        assert_eq!(config.chains, HashMap::from([(1, expected_chain)]));
+        assert!(config.rpc_compression);
+        assert!(config.rpc_log_requests);
+        assert_eq!(config.min_confirmations, 3);
+    }
+
+    #[cfg(all(feature = "json-config", feature = "yaml-config"))]
+    #[test]
+    fn test_json_and_yaml_configs_match() {
+        let json_path = write_temp("match.json", JSON_CONFIG);
+        let yaml_path = write_temp("match.yml", YAML_CONFIG);
+
+        let from_json = Config::from_file(&json_path).unwrap();
+        let from_yaml = Config::from_file(&yaml_path).unwrap();
+        std::fs::remove_file(&json_path).unwrap();
+        std::fs::remove_file(&yaml_path).unwrap();
+
+        assert_eq!(from_json, from_yaml);
+    }
+
+    #[test]
+    fn test_from_file_rejects_unknown_extension() {
+        let result = Config::from_file("config.ini");
+        assert!(matches!(result, Err(UserOpError::Config(msg)) if msg.contains("Unsupported config format")));
+    }
+}
@@ -1,31 +1,64 @@
+use ethers::abi::{AbiEncode, Token};
 use ethers::prelude::*;
-use std::sync::Arc;
+use ethers::providers::call_raw::{spoof, RawCall};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::utils::rlp::RlpStream;
+use futures::{Stream, StreamExt};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::warn;
+use crate::ccip::CcipReader;
+use crate::config::Config;
 use crate::error::{Result, UserOpError};
-use crate::userop::UserOperation;
+use crate::gas::bump_fee;
+use crate::metrics::Metrics;
+use crate::relay::{RelayClient, SubmissionRoute};
+use crate::provider::RpcClient;
+use crate::userop::{Authorization, HashSigner, UserOperation};
 
 abigen!(
     IEntryPoint,
     r#"[
-        function getUserOpHash(
-            (address sender, uint256 nonce, bytes initCode, bytes callData, uint256 callGasLimit,
-            uint256 verificationGasLimit, uint256 preVerificationGas, uint256 maxFeePerGas,
-            uint256 maxPriorityFeePerGas, bytes paymasterAndData, bytes signature) calldata userOp
-        ) external view returns (bytes32)
-        function handleOps((address sender, uint256 nonce, bytes initCode, bytes callData, uint256 callGasLimit, uint256 verificationGasLimit, uint256 preVerificationGas, uint256 maxFeePerGas, uint256 maxPriorityFeePerGas, bytes paymasterAndData, bytes signature)[] calldata ops, address payable beneficiary) external
+        struct UserOperationCall { address sender; uint256 nonce; bytes initCode; bytes callData; uint256 callGasLimit; uint256 verificationGasLimit; uint256 preVerificationGas; uint256 maxFeePerGas; uint256 maxPriorityFeePerGas; bytes paymasterAndData; bytes signature; }
+        function getUserOpHash(UserOperationCall calldata userOp) external view returns (bytes32)
+        function handleOps(UserOperationCall[] calldata ops, address payable beneficiary) external
         function deposits(address) external view returns (uint256)
+        function getNonce(address sender, uint192 key) external view returns (uint256 nonce)
+        function simulateValidation(UserOperationCall calldata userOp) external
+        event UserOperationEvent(bytes32 indexed userOpHash, address indexed sender, address indexed paymaster, uint256 nonce, bool success, uint256 actualGasCost, uint256 actualGasUsed)
     ]"#
 );
 
+/// State overrides applied to an `eth_call`, e.g. wallet bytecode at a
+/// counterfactual sender address.
+pub type StateOverride = spoof::State;
+
 abigen!(
     ISmartWallet,
     r#"[
         function initialize(address owner, address entryPoint) external
         function execute(address target, uint256 value, bytes calldata data) external returns (bool)
+        function executeBatch(address[] calldata dest, uint256[] calldata value, bytes[] calldata func) external returns (bool[])
         function getNonce() external view returns (uint256)
         function isValidSignature(bytes32 hash, bytes memory signature) external view returns (bool)
     ]"#
 );
 
+abigen!(
+    ICodeSizeProbe,
+    r#"[
+        function codeSize(address account) external view returns (uint256)
+    ]"#
+);
+
+abigen!(
+    IWalletFactory,
+    r#"[
+        function createAccount(address owner, uint256 salt) external returns (address)
+        function getAddress(address owner, uint256 salt) external view returns (address)
+    ]"#
+);
+
 abigen!(
     IPaymaster,
     r#"[
@@ -34,17 +67,190 @@ abigen!(
     ]"#
 );
 
+/// EntryPoint releases whose op and `paymasterAndData` layouts differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EntryPointVersion {
+    V06,
+    V07,
+    V08,
+}
+
+/// Canonical EntryPoint deployments, at the same address on every chain.
+pub const ENTRY_POINT_V06_ADDRESS: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+pub const ENTRY_POINT_V07_ADDRESS: &str = "0x0000000071727De22E5E9d8BAf0edAc6f37da032";
+pub const ENTRY_POINT_V08_ADDRESS: &str = "0x4337084D9E255Ff0702461CF8895CE9E3b5Ff108";
+
+impl EntryPointVersion {
+    /// The version deployed at `address` if it is a canonical EntryPoint.
+    pub fn from_canonical_address(address: Address) -> Option<Self> {
+        [
+            (ENTRY_POINT_V06_ADDRESS, EntryPointVersion::V06),
+            (ENTRY_POINT_V07_ADDRESS, EntryPointVersion::V07),
+            (ENTRY_POINT_V08_ADDRESS, EntryPointVersion::V08),
+        ]
+        .into_iter()
+        .find(|(canonical, _)| canonical.parse::<Address>().ok() == Some(address))
+        .map(|(_, version)| version)
+    }
+}
+
+/// A `handleOps` transaction together with the EIP-7702 authorizations its
+/// ops rely on, which must be sent as the type-4 transaction's
+/// `authorization_list`.
+#[derive(Debug, Clone)]
+pub struct BundleTransaction {
+    pub tx: TypedTransaction,
+    pub authorization_list: Vec<Authorization>,
+}
+
+/// EIP-2718 type byte of an EIP-7702 set-code transaction.
+pub const EIP7702_TX_TYPE: u8 = 0x04;
+/// Gas charged per authorization tuple in a type-4 transaction, on top of
+/// what the call itself uses.
+pub const EIP7702_PER_AUTH_GAS: u64 = 25_000;
+
+impl BundleTransaction {
+    /// Whether the bundle has to go out as a type-4 transaction.
+    pub fn is_eip7702(&self) -> bool {
+        !self.authorization_list.is_empty()
+    }
+
+    /// The hash the bundler signs for the type-4 transaction:
+    /// `keccak256(0x04 || rlp([chainId, nonce, maxPriorityFeePerGas,
+    /// maxFeePerGas, gas, to, value, data, accessList, authorizationList]))`.
+    pub fn sighash(&self) -> Result<H256> {
+        let mut rlp = RlpStream::new_list(10);
+        self.rlp_append_unsigned(&mut rlp)?;
+        Ok(ethers::utils::keccak256([&[EIP7702_TX_TYPE], rlp.as_raw()].concat()).into())
+    }
+
+    /// The signed type-4 transaction, ready for `eth_sendRawTransaction`.
+    pub fn rlp_signed(&self, signature: &Signature) -> Result<Bytes> {
+        let y_parity = match signature.v {
+            0 | 1 => signature.v,
+            27 | 28 => signature.v - 27,
+            v => return Err(UserOpError::Signature(format!("Unexpected signature v {} for a type-4 transaction", v))),
+        };
+
+        let mut rlp = RlpStream::new_list(13);
+        self.rlp_append_unsigned(&mut rlp)?;
+        rlp.append(&y_parity);
+        rlp.append(&signature.r);
+        rlp.append(&signature.s);
+        Ok([&[EIP7702_TX_TYPE], rlp.as_raw()].concat().into())
+    }
+
+    fn rlp_append_unsigned(&self, rlp: &mut RlpStream) -> Result<()> {
+        let TypedTransaction::Eip1559(tx) = &self.tx else {
+            return Err(UserOpError::Validation(
+                "EIP-7702 bundles must be built as EIP-1559 transactions".to_string(),
+            ));
+        };
+        let missing = |field: &str| UserOpError::Validation(format!("Bundle transaction has no {}", field));
+        let Some(NameOrAddress::Address(to)) = tx.to else {
+            return Err(missing("recipient address"));
+        };
+
+        rlp.append(&tx.chain_id.ok_or_else(|| missing("chain id"))?);
+        rlp.append(&tx.nonce.ok_or_else(|| missing("nonce"))?);
+        rlp.append(&tx.max_priority_fee_per_gas.ok_or_else(|| missing("max priority fee"))?);
+        rlp.append(&tx.max_fee_per_gas.ok_or_else(|| missing("max fee"))?);
+        rlp.append(&tx.gas.ok_or_else(|| missing("gas limit"))?);
+        rlp.append(&to);
+        rlp.append(&tx.value.unwrap_or_default());
+        rlp.append(&tx.data.as_ref().map(|data| data.to_vec()).unwrap_or_default());
+        rlp.append(&tx.access_list);
+        rlp.begin_list(self.authorization_list.len());
+        for auth in &self.authorization_list {
+            rlp.begin_list(6);
+            rlp.append(&auth.chain_id);
+            rlp.append(&auth.address);
+            rlp.append(&auth.nonce);
+            rlp.append(&auth.y_parity);
+            rlp.append(&auth.r);
+            rlp.append(&auth.s);
+        }
+        Ok(())
+    }
+}
+
+/// How long `watch_user_op` waits for the op by default.
+pub const DEFAULT_WATCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Most ops `submit_user_ops` puts in one `handleOps` by default.
+pub const DEFAULT_MAX_BATCH_OPS: usize = 32;
+/// Most combined op gas in one `handleOps` by default, half of a 30M block.
+pub const DEFAULT_MAX_BATCH_GAS: u64 = 15_000_000;
+
+/// Caps on a single `handleOps` bundle. A bundle whose ops together need
+/// more gas than the block allows always reverts, so larger batches are
+/// split across several transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchLimits {
+    /// Most ops per bundle.
+    pub max_ops: usize,
+    /// Most combined `UserOperation::max_gas` per bundle.
+    pub max_gas: U256,
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        Self {
+            max_ops: DEFAULT_MAX_BATCH_OPS,
+            max_gas: U256::from(DEFAULT_MAX_BATCH_GAS),
+        }
+    }
+}
+
+/// How far back `Contracts::user_op_inclusion` searches for an op's event.
+pub const USER_OP_LOOKUP_BLOCKS: u64 = 2_048;
+
+/// An op's execution as reported by the EntryPoint's `UserOperationEvent`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserOpReceipt {
+    pub user_op_hash: H256,
+    pub sender: Address,
+    /// Zero when the op wasn't sponsored.
+    pub paymaster: Address,
+    pub nonce: U256,
+    pub success: bool,
+    pub actual_gas_cost: U256,
+    pub actual_gas_used: U256,
+    pub transaction_hash: Option<H256>,
+    pub block_number: Option<U64>,
+}
+
+/// What a client needs to know about a wallet before submitting for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalletState {
+    /// The EntryPoint's next nonce for the wallet under key 0.
+    pub nonce: U256,
+    /// The wallet's EntryPoint deposit.
+    pub deposit: U256,
+    /// Whether the wallet is deployed; ops for it need no `init_code` if so.
+    pub code_exists: bool,
+}
+
 #[derive(Clone)]
 pub struct Contracts {
-    entry_point: Arc<IEntryPoint<Provider<Http>>>,
-    wallet_factory: Arc<ISmartWallet<Provider<Http>>>,
-    paymaster: Arc<IPaymaster<Provider<Http>>>,
+    entry_point: Arc<IEntryPoint<Provider<RpcClient>>>,
+    wallet_factory: Arc<IWalletFactory<Provider<RpcClient>>>,
+    paymaster: Arc<IPaymaster<Provider<RpcClient>>>,
     chain_id: u64,
+    submission_route: SubmissionRoute,
+    signature_preflight: bool,
+    prefund_preflight: bool,
+    confirmations: u64,
+    min_confirmations: u64,
+    watch_timeout: Duration,
+    batch_limits: BatchLimits,
+    ccip_reader: Option<CcipReader>,
+    entry_point_version: Arc<OnceLock<EntryPointVersion>>,
 }
 
 impl Contracts {
     pub fn new(
-        provider: Provider<Http>,
+        provider: Provider<RpcClient>,
         entry_point_address: Address,
         wallet_factory_address: Address,
         paymaster_address: Address,
@@ -52,25 +258,180 @@ impl Contracts {
     ) -> Self {
         Self {
             entry_point: Arc::new(IEntryPoint::new(entry_point_address, Arc::new(provider.clone()))),
-            wallet_factory: Arc::new(ISmartWallet::new(wallet_factory_address, Arc::new(provider.clone()))),
+            wallet_factory: Arc::new(IWalletFactory::new(wallet_factory_address, Arc::new(provider.clone()))),
             paymaster: Arc::new(IPaymaster::new(paymaster_address, Arc::new(provider))),
             chain_id,
+            submission_route: SubmissionRoute::default(),
+            signature_preflight: true,
+            prefund_preflight: false,
+            confirmations: 1,
+            min_confirmations: 0,
+            watch_timeout: DEFAULT_WATCH_TIMEOUT,
+            batch_limits: BatchLimits::default(),
+            ccip_reader: None,
+            entry_point_version: Arc::new(OnceLock::new()),
         }
     }
 
+    /// Contracts for `chain_id` over the provider and addresses `config`
+    /// has for it, waiting for at least `Config::min_confirmations`.
+    pub fn from_config(config: &Config, chain_id: u64) -> Result<Self> {
+        let addresses = config.get_contract_addresses(chain_id)?;
+        let contracts = Self::new(
+            config.get_provider(chain_id)?,
+            addresses.entry_point,
+            addresses.wallet_factory,
+            addresses.paymaster,
+            chain_id,
+        );
+        Ok(contracts.with_min_confirmations(config.min_confirmations))
+    }
+
+    /// Sets how many blocks `wait_for_receipt` waits for. 0 returns as soon
+    /// as the transaction is accepted and is only allowed on fast-finality
+    /// chains.
+    pub fn with_confirmations(mut self, confirmations: u64, fast_finality: bool) -> Result<Self> {
+        crate::chain::validate_confirmations(self.chain_id, confirmations, fast_finality)?;
+        self.confirmations = confirmations;
+        Ok(self)
+    }
+
+    /// Floor on the confirmations `wait_for_receipt` waits for, applied over
+    /// `with_confirmations`; typically `Config::min_confirmations`.
+    pub fn with_min_confirmations(mut self, min_confirmations: u64) -> Self {
+        self.min_confirmations = min_confirmations;
+        self
+    }
+
+    /// Confirmations `wait_for_receipt` waits for: the chain's own setting,
+    /// raised to the floor if lower.
+    pub fn confirmations(&self) -> u64 {
+        self.confirmations.max(self.min_confirmations)
+    }
+
+    /// How long `watch_user_op` streams events before giving up.
+    pub fn with_watch_timeout(mut self, timeout: Duration) -> Self {
+        self.watch_timeout = timeout;
+        self
+    }
+
+    /// How `submit_user_ops` splits batches into `handleOps` bundles.
+    pub fn with_batch_limits(mut self, limits: BatchLimits) -> Self {
+        self.batch_limits = limits;
+        self
+    }
+
+    /// Lets signature checks resolve EIP-3668 offchain lookups through
+    /// `ccip_reader`. Off by default, since following a lookup fetches
+    /// whatever URLs the wallet names.
+    pub fn with_ccip_reader(mut self, ccip_reader: CcipReader) -> Self {
+        self.ccip_reader = Some(ccip_reader);
+        self
+    }
+
+    /// Enables checking that the op's prefund is covered before submitting.
+    pub fn with_prefund_preflight(mut self, enabled: bool) -> Self {
+        self.prefund_preflight = enabled;
+        self
+    }
+
+    /// Enables or disables checking `isValidSignature` before submitting.
+    /// Ops carrying `init_code` always skip the check since the wallet
+    /// isn't deployed yet.
+    pub fn with_signature_preflight(mut self, enabled: bool) -> Self {
+        self.signature_preflight = enabled;
+        self
+    }
+
+    /// Like `new`, but first checks that every contract address has code
+    /// deployed, so a wrong address fails here instead of on first use.
+    pub async fn connect(
+        provider: Provider<RpcClient>,
+        entry_point_address: Address,
+        wallet_factory_address: Address,
+        paymaster_address: Address,
+        chain_id: u64,
+    ) -> Result<Self> {
+        ensure_deployed(&provider, &[
+            ("EntryPoint", entry_point_address),
+            ("wallet factory", wallet_factory_address),
+            ("paymaster", paymaster_address),
+        ]).await?;
+
+        Ok(Self::new(provider, entry_point_address, wallet_factory_address, paymaster_address, chain_id))
+    }
+
+    pub fn with_submission_route(mut self, route: SubmissionRoute) -> Self {
+        self.submission_route = route;
+        self
+    }
+
+    pub fn submission_route(&self) -> &SubmissionRoute {
+        &self.submission_route
+    }
+
+    /// `EntryPoint.getUserOpHash` for `user_op`. A result that differs from
+    /// the locally computed hash fails with `UserOpError::Validation`, since
+    /// ops signed over the local hash would be rejected on chain.
     pub async fn get_user_op_hash(&self, user_op: &UserOperation) -> Result<H256> {
-        self.entry_point
-            .get_user_op_hash(user_op.into())
-            .call()
-            .await
-            .map_err(|e| UserOpError::RPC(e.to_string()))
+        self.verify_user_op_hash(user_op).await
+    }
+
+    /// Computes the userOpHash locally and checks it against
+    /// `EntryPoint.getUserOpHash`, returning the hash if they agree.
+    pub async fn verify_user_op_hash(&self, user_op: &UserOperation) -> Result<H256> {
+        let version = self.hashing_version(user_op).await?;
+        cross_check_user_op_hash(&self.entry_point, user_op, version, self.chain_id).await
+    }
+
+    /// The EntryPoint version `user_op`'s hash depends on. Versions only
+    /// hash EIP-7702 senders differently, so other ops skip the probe.
+    async fn hashing_version(&self, user_op: &UserOperation) -> Result<EntryPointVersion> {
+        if user_op.is_eip7702() {
+            self.detect_entry_point_version().await
+        } else {
+            Ok(EntryPointVersion::V06)
+        }
+    }
+
+    /// Runs `simulateValidation` through `eth_call` with the given state
+    /// overrides, so ops for wallets that are not deployed yet can be checked.
+    pub async fn simulate_with_overrides(
+        &self,
+        user_op: &UserOperation,
+        overrides: StateOverride,
+    ) -> Result<Bytes> {
+        let tx = self.entry_point
+            .simulate_validation(user_op.into())
+            .tx;
+
+        call_with_overrides(self.entry_point.client().as_ref(), &tx, &overrides).await
+    }
+
+    /// Traces `simulateValidation` with `debug_traceCall` and the call
+    /// tracer, returning every call frame depth-first. Useful when
+    /// simulation reverts without a decodable reason; needs a provider that
+    /// exposes the `debug` namespace.
+    #[cfg(feature = "debug-trace")]
+    pub async fn trace_validation(&self, user_op: &UserOperation) -> Result<Vec<TraceFrame>> {
+        let tx = self.entry_point
+            .simulate_validation(user_op.into())
+            .tx;
+
+        trace_call(self.entry_point.client().as_ref(), tx).await
     }
 
+    /// Sends `handleOps` through the provider's `eth_sendTransaction`, so
+    /// the node must hold the bundler key. Use `submit_user_op_signed` to
+    /// sign locally instead.
     pub async fn submit_user_op(
         &self,
         user_op: UserOperation,
         beneficiary: Address,
     ) -> Result<H256> {
+        reject_eip7702(&user_op)?;
+        self.preflight_submit(&user_op, beneficiary).await?;
+
         let tx = self.entry_point
             .handle_ops(vec![user_op.into()], beneficiary);
 
@@ -82,6 +443,252 @@ impl Contracts {
         Ok(pending_tx.tx_hash())
     }
 
+    /// Submits `user_op` like `submit_user_op`, but as a replacement for the
+    /// pending bundle `replaced`: same transaction nonce, with its fees
+    /// raised by `bump_percent` so the node accepts the swap. Falls back to
+    /// a fresh submit when the node no longer knows `replaced`.
+    pub async fn resubmit_user_op(
+        &self,
+        user_op: UserOperation,
+        beneficiary: Address,
+        replaced: H256,
+        bump_percent: u64,
+    ) -> Result<H256> {
+        let previous = self.entry_point
+            .client()
+            .get_transaction(replaced)
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+        let Some(previous) = previous else {
+            return self.submit_user_op(user_op, beneficiary).await;
+        };
+        reject_eip7702(&user_op)?;
+        self.preflight_submit(&user_op, beneficiary).await?;
+
+        let mut tx = self.entry_point
+            .handle_ops(vec![user_op.into()], beneficiary);
+        replace_fees(&mut tx.tx, &previous, bump_percent);
+
+        let pending_tx = tx
+            .send()
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+
+        Ok(pending_tx.tx_hash())
+    }
+
+    /// Submits `ops` through `eth_sendTransaction` like `submit_user_op`,
+    /// split by `split_batch` into as many `handleOps` bundles as the batch
+    /// limits need. Bundles are sent in order, stopping at the first that
+    /// fails; the transaction hashes of those sent are returned in order.
+    pub async fn submit_user_ops(
+        &self,
+        ops: Vec<UserOperation>,
+        beneficiary: Address,
+    ) -> Result<Vec<H256>> {
+        if ops.is_empty() {
+            return Err(UserOpError::Validation("No ops to submit".to_string()));
+        }
+        for user_op in &ops {
+            reject_eip7702(user_op)?;
+            self.preflight_submit(user_op, beneficiary).await?;
+        }
+
+        let version = self.detect_entry_point_version().await?;
+        let batches = split_batch(ops, &self.batch_limits, version)?;
+        send_batches(&self.entry_point, batches, beneficiary).await
+    }
+
+    /// Like `submit_user_op`, but fills the `handleOps` transaction's nonce,
+    /// fees and gas limit for `signer`, signs it locally and broadcasts the
+    /// raw transaction along the configured submission route. EIP-7702 ops
+    /// go out as a type-4 transaction carrying their authorization.
+    pub async fn submit_user_op_signed<S: HashSigner>(
+        &self,
+        user_op: UserOperation,
+        beneficiary: Address,
+        signer: &S,
+    ) -> Result<H256> {
+        self.preflight_submit(&user_op, beneficiary).await?;
+
+        let bundle = self.build_bundle_tx(vec![user_op], beneficiary)?;
+        let raw_tx = self.sign_bundle_tx(bundle, signer).await?;
+        self.submit_signed_bundle(raw_tx).await
+    }
+
+    async fn preflight_submit(&self, user_op: &UserOperation, beneficiary: Address) -> Result<()> {
+        if beneficiary.is_zero() {
+            return Err(UserOpError::Validation("Beneficiary cannot be the zero address".to_string()));
+        }
+        if self.signature_preflight && user_op.init_code.is_empty() {
+            preflight_signature(&self.entry_point, self.ccip_reader.as_ref(), user_op).await?;
+        }
+        if self.prefund_preflight {
+            self.check_prefund(user_op).await?;
+        }
+        Ok(())
+    }
+
+    /// Fails with `UserOpError::Validation` when the funds backing `user_op`
+    /// can't cover its required prefund, which would revert with AA21/AA31.
+    pub async fn check_prefund(&self, user_op: &UserOperation) -> Result<()> {
+        let version = self.detect_entry_point_version().await?;
+        check_prefund_with(&self.entry_point, user_op, version).await
+    }
+
+    /// Waits for `tx_hash` to reach `confirmations()`, failing if it
+    /// reverted. Returns `None` without waiting when that is 0.
+    pub async fn wait_for_receipt(&self, tx_hash: H256) -> Result<Option<TransactionReceipt>> {
+        await_receipt(self.entry_point.client().as_ref(), tx_hash, self.confirmations()).await
+    }
+
+    /// Streams the `UserOperationEvent`s the EntryPoint emits for
+    /// `user_op_hash`, subscribing to its logs over `ws` since the HTTP
+    /// provider can't push. The stream ends once the watch timeout passes.
+    pub async fn watch_user_op<'a, C: PubsubClient>(
+        &self,
+        ws: &'a Provider<C>,
+        user_op_hash: H256,
+    ) -> Result<impl Stream<Item = UserOpReceipt> + 'a> {
+        let filter = Filter::new()
+            .address(self.entry_point.address())
+            .topic0(UserOperationEventFilter::signature())
+            .topic1(user_op_hash);
+        let logs = ws
+            .subscribe_logs(&filter)
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+
+        Ok(user_op_events(logs, user_op_hash, self.watch_timeout))
+    }
+
+    /// Which EntryPoint version is deployed at the configured address,
+    /// probed on chain once and cached afterwards.
+    pub async fn detect_entry_point_version(&self) -> Result<EntryPointVersion> {
+        if let Some(version) = self.entry_point_version.get() {
+            return Ok(*version);
+        }
+        let version = detect_entry_point_version(&self.entry_point).await?;
+        Ok(*self.entry_point_version.get_or_init(|| version))
+    }
+
+    /// Hash of the block `tx_hash` is currently included in, `None` if it
+    /// has no receipt.
+    pub async fn receipt_block_hash(&self, tx_hash: H256) -> Result<Option<H256>> {
+        let receipt = self.entry_point
+            .client()
+            .get_transaction_receipt(tx_hash)
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+        Ok(receipt.and_then(|receipt| receipt.block_hash))
+    }
+
+    /// Transaction and block hash of the `UserOperationEvent` for
+    /// `user_op_hash` within the last `USER_OP_LOOKUP_BLOCKS` blocks, `None`
+    /// if the op isn't included there. Finds the op even if the bundle that
+    /// carries it isn't the one it was first submitted in.
+    pub async fn user_op_inclusion(&self, user_op_hash: H256) -> Result<Option<(H256, H256)>> {
+        let client = self.entry_point.client();
+        let latest = client
+            .get_block_number()
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+        let filter = Filter::new()
+            .address(self.entry_point.address())
+            .topic0(UserOperationEventFilter::signature())
+            .topic1(user_op_hash)
+            .from_block(latest.saturating_sub(U64::from(USER_OP_LOOKUP_BLOCKS)));
+        let logs = client
+            .get_logs(&filter)
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+        Ok(logs
+            .into_iter()
+            .rev()
+            .find(|log| log.removed != Some(true))
+            .and_then(|log| Some((log.transaction_hash?, log.block_hash?))))
+    }
+
+    /// Builds the unsent `handleOps` transaction for `ops`, targeting the
+    /// EntryPoint, so it can be signed and broadcast elsewhere. Ops are put
+    /// in `order_user_ops` order first.
+    pub fn build_handle_ops_tx(
+        &self,
+        ops: Vec<UserOperation>,
+        beneficiary: Address,
+    ) -> Result<TypedTransaction> {
+        let ops = order_user_ops(ops)?;
+        let mut tx = self.entry_point
+            .handle_ops(ops.into_iter().map(Into::into).collect(), beneficiary)
+            .tx;
+        tx.set_chain_id(self.chain_id);
+        Ok(tx)
+    }
+
+    /// Like `build_handle_ops_tx`, also collecting the ops' EIP-7702
+    /// authorizations, once each.
+    pub fn build_bundle_tx(&self, ops: Vec<UserOperation>, beneficiary: Address) -> Result<BundleTransaction> {
+        let mut authorization_list: Vec<Authorization> = Vec::new();
+        for auth in ops.iter().filter_map(|op| op.eip7702_auth.as_ref()) {
+            if !authorization_list.contains(auth) {
+                authorization_list.push(auth.clone());
+            }
+        }
+
+        Ok(BundleTransaction {
+            tx: self.build_handle_ops_tx(ops, beneficiary)?,
+            authorization_list,
+        })
+    }
+
+    /// Fills `bundle`'s nonce, fees and gas limit for `signer` and signs it:
+    /// as a type-4 transaction carrying the authorization list if any op
+    /// needs one, else as a plain EIP-1559 transaction.
+    pub async fn sign_bundle_tx<S: HashSigner>(&self, bundle: BundleTransaction, signer: &S) -> Result<Bytes> {
+        sign_bundle_tx(self.entry_point.client().as_ref(), bundle, signer).await
+    }
+
+    /// Broadcasts an already signed `handleOps` transaction along the
+    /// configured submission route.
+    pub async fn submit_signed_bundle(&self, raw_tx: Bytes) -> Result<H256> {
+        match &self.submission_route {
+            SubmissionRoute::Public => {
+                let client = self.entry_point.client();
+                let pending_tx = client
+                    .send_raw_transaction(raw_tx)
+                    .await
+                    .map_err(|e| UserOpError::RPC(e.to_string()))?;
+
+                Ok(pending_tx.tx_hash())
+            }
+            SubmissionRoute::PrivateRelay { url } => {
+                RelayClient::new(url)?
+                    .send_private_transaction(raw_tx)
+                    .await
+            }
+        }
+    }
+
+    /// Calldata for `createAccount(owner, salt)` on the wallet factory.
+    pub fn encode_create_account(&self, owner: Address, salt: U256) -> Bytes {
+        self.wallet_factory
+            .create_account(owner, salt)
+            .calldata()
+            .unwrap_or_default()
+    }
+
+    /// `initCode` for deploying the wallet: factory address followed by the
+    /// `createAccount` calldata.
+    pub fn init_code(&self, owner: Address, salt: U256) -> Bytes {
+        let calldata = self.encode_create_account(owner, salt);
+        Bytes::from([self.wallet_factory.address().as_bytes(), calldata.as_ref()].concat())
+    }
+
+    /// Resolves the counterfactual wallet address for `owner` and `salt`.
+    pub async fn get_account_address(&self, owner: Address, salt: U256) -> Result<Address> {
+        resolve_account_address(&self.wallet_factory, owner, salt).await
+    }
+
     pub async fn get_wallet_nonce(&self, wallet_address: Address) -> Result<U256> {
         let wallet = ISmartWallet::new(wallet_address, self.entry_point.client());
         
@@ -92,6 +699,15 @@ impl Contracts {
             .map_err(|e| UserOpError::RPC(e.to_string()))
     }
 
+    /// The EntryPoint's next 2D nonce for `sender` under `key`.
+    pub async fn get_entry_point_nonce(&self, sender: Address, key: U256) -> Result<U256> {
+        self.entry_point
+            .get_nonce(sender, key)
+            .call()
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))
+    }
+
     pub async fn validate_signature(
         &self,
         wallet_address: Address,
@@ -99,12 +715,7 @@ impl Contracts {
         signature: Bytes,
     ) -> Result<bool> {
         let wallet = ISmartWallet::new(wallet_address, self.entry_point.client());
-        
-        wallet
-            .is_valid_signature(hash, signature)
-            .call()
-            .await
-            .map_err(|e| UserOpError::RPC(e.to_string()))
+        is_valid_signature(&wallet, self.ccip_reader.as_ref(), hash.into(), signature).await
     }
 
     pub async fn validate_paymaster(
@@ -119,6 +730,13 @@ impl Contracts {
             .map_err(|e| UserOpError::RPC(e.to_string()))
     }
 
+    /// Fetches `wallet`'s nonce, deposit and deployment status in a single
+    /// Multicall3 `eth_call`, falling back to one call each on chains
+    /// without Multicall3 or nodes without state overrides.
+    pub async fn get_wallet_state(&self, wallet: Address) -> Result<WalletState> {
+        fetch_wallet_state(&self.entry_point, self.chain_id, wallet).await
+    }
+
     pub async fn get_entry_point_deposit(&self, address: Address) -> Result<U256> {
         self.entry_point
             .deposits(address)
@@ -136,72 +754,686 @@ impl Contracts {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::str::FromStr;
-    use ethers::types::U256;
-
-    // Official ERC-4337 EntryPoint contract address (same across all chains)
-    const ENTRY_POINT: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
-    
-    // Test addresses - replace with your actual contract addresses in production
-    const TEST_WALLET_FACTORY: &str = "0x1234567890123456789012345678901234567890";
-    const TEST_PAYMASTER: &str = "0x2234567890123456789012345678901234567890";
-    
-    // Replace with your actual RPC URL
-    const TEST_RPC_URL: &str = "https://eth-mainnet.g.alchemy.com/v2/your-api-key";
-
-    async fn setup_contracts() -> Contracts {
-        let provider = Provider::<Http>::try_from(TEST_RPC_URL).unwrap();
-        
-        Contracts::new(
-            provider,
-            Address::from_str(ENTRY_POINT).unwrap(),
-            Address::from_str(TEST_WALLET_FACTORY).unwrap(),
-            Address::from_str(TEST_PAYMASTER).unwrap(),
-            1, // Ethereum mainnet
-        )
+/// Calldata for `executeBatch(dest, value, func)`, for use as a UserOperation's
+/// `call_data`. All three arrays must have the same, non-zero length.
+pub fn encode_execute_batch(dest: Vec<Address>, value: Vec<U256>, func: Vec<Bytes>) -> Result<Bytes> {
+    if dest.is_empty() {
+        return Err(UserOpError::Validation("executeBatch requires at least one call".to_string()));
+    }
+    if dest.len() != value.len() || dest.len() != func.len() {
+        return Err(UserOpError::Validation(format!(
+            "executeBatch array lengths differ: {} targets, {} values, {} calldatas",
+            dest.len(), value.len(), func.len()
+        )));
     }
 
-    #[tokio::test]
-    async fn test_get_user_op_hash() {
-        let contracts = setup_contracts().await;
-        let user_op = UserOperation {
-            sender: Address::from_str("0x1234567890123456789012345678901234567890").unwrap(),
-            nonce: U256::zero(),
-            init_code: Bytes::default(),
-            call_data: Bytes::default(),
-            call_gas_limit: U256::from(100000),
-            verification_gas_limit: U256::from(100000),
-            pre_verification_gas: U256::from(21000),
-            max_fee_per_gas: U256::from(1000000000),
-            max_priority_fee_per_gas: U256::from(1000000000),
-            paymaster_and_data: Bytes::default(),
-            signature: Bytes::default(),
-        };
+    Ok(ExecuteBatchCall { dest, value, func }.encode().into())
+}
 
-        let result = contracts.get_user_op_hash(&user_op).await;
-        assert!(result.is_ok(), "Failed to get user op hash: {:?}", result.err());
+/// Sorts a batch by sender, then nonce, as bundlers require. Two ops with
+/// the same sender and nonce can't both be included, so such batches fail
+/// with `UserOpError::Validation`.
+pub fn order_user_ops(mut ops: Vec<UserOperation>) -> Result<Vec<UserOperation>> {
+    ops.sort_by_key(|op| (op.sender, op.nonce));
+
+    let duplicate = ops
+        .windows(2)
+        .find(|pair| pair[0].sender == pair[1].sender && pair[0].nonce == pair[1].nonce);
+    if let Some(pair) = duplicate {
+        return Err(UserOpError::Validation(format!(
+            "Batch contains more than one op from {:?} with nonce {}",
+            pair[0].sender, pair[0].nonce
+        )));
     }
 
-    #[tokio::test]
-    async fn test_get_wallet_nonce() {
-        let contracts = setup_contracts().await;
-        let wallet_address = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+    Ok(ops)
+}
 
-        let result = contracts.get_wallet_nonce(wallet_address).await;
-        assert!(result.is_ok(), "Failed to get wallet nonce: {:?}", result.err());
+/// Puts `ops` in `order_user_ops` order and cuts them into consecutive
+/// bundles within `limits`, counting each op's gas as EntryPoint `version`
+/// would. An op that alone needs more than `limits.max_gas` can never be
+/// included and fails the whole batch with `UserOpError::Validation`.
+pub fn split_batch(
+    ops: Vec<UserOperation>,
+    limits: &BatchLimits,
+    version: EntryPointVersion,
+) -> Result<Vec<Vec<UserOperation>>> {
+    if limits.max_ops == 0 {
+        return Err(UserOpError::Validation("Batch limits must allow at least one op".to_string()));
     }
 
-    #[tokio::test]
-    async fn test_validate_paymaster() {
-        let contracts = setup_contracts().await;
-        let sender = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
-        let required_prefund = U256::from(1000000000);
-
-        let result = contracts.validate_paymaster(sender, required_prefund).await;
-        assert!(result.is_ok(), "Failed to validate paymaster: {:?}", result.err());
+    let mut batches: Vec<Vec<UserOperation>> = Vec::new();
+    let mut current: Vec<UserOperation> = Vec::new();
+    let mut current_gas = U256::zero();
+    for user_op in order_user_ops(ops)? {
+        let gas = user_op.max_gas(version);
+        if gas > limits.max_gas {
+            return Err(UserOpError::Validation(format!(
+                "Op from {:?} with nonce {} needs {} gas, over the {} bundle gas limit",
+                user_op.sender, user_op.nonce, gas, limits.max_gas
+            )));
+        }
+        if !current.is_empty() && (current.len() == limits.max_ops || current_gas + gas > limits.max_gas) {
+            batches.push(std::mem::take(&mut current));
+            current_gas = U256::zero();
+        }
+        current_gas += gas;
+        current.push(user_op);
+    }
+    if !current.is_empty() {
+        batches.push(current);
+    }
+
+    Ok(batches)
+}
+
+/// Sends each batch as its own `handleOps` transaction, in order.
+async fn send_batches<M: Middleware>(
+    entry_point: &IEntryPoint<M>,
+    batches: Vec<Vec<UserOperation>>,
+    beneficiary: Address,
+) -> Result<Vec<H256>> {
+    let mut tx_hashes = Vec::with_capacity(batches.len());
+    for batch in batches {
+        let call = entry_point.handle_ops(batch.into_iter().map(Into::into).collect(), beneficiary);
+        let pending_tx = call
+            .send()
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+        tx_hashes.push(pending_tx.tx_hash());
+    }
+    Ok(tx_hashes)
+}
+
+/// Calls `isValidSignature` on `wallet`. With a `ccip_reader`, any EIP-3668
+/// offchain lookup the wallet defers the check to is followed; without one
+/// the lookup's revert fails the check.
+async fn is_valid_signature<M: Middleware>(
+    wallet: &ISmartWallet<M>,
+    ccip_reader: Option<&CcipReader>,
+    hash: [u8; 32],
+    signature: Bytes,
+) -> Result<bool> {
+    let call = wallet.is_valid_signature(hash, signature);
+    let output = match ccip_reader {
+        Some(ccip_reader) => ccip_reader.call(wallet.client_ref(), call.tx).await?,
+        None => wallet
+            .client_ref()
+            .call(&call.tx, None)
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?,
+    };
+    ethers::abi::decode(&[ethers::abi::ParamType::Bool], &output)
+        .ok()
+        .and_then(|mut tokens| tokens.pop()?.into_bool())
+        .ok_or_else(|| UserOpError::Contract(format!("Invalid isValidSignature output {}", output)))
+}
+
+/// Rejects `user_op` if its wallet doesn't accept the signature over the
+/// EntryPoint's userOpHash.
+async fn preflight_signature<M: Middleware>(
+    entry_point: &IEntryPoint<M>,
+    ccip_reader: Option<&CcipReader>,
+    user_op: &UserOperation,
+) -> Result<()> {
+    let user_op_hash = entry_point
+        .get_user_op_hash(user_op.into())
+        .call()
+        .await
+        .map_err(|e| UserOpError::RPC(e.to_string()))?;
+
+    let wallet = ISmartWallet::new(user_op.sender, entry_point.client());
+    let valid = is_valid_signature(&wallet, ccip_reader, user_op_hash, user_op.signature.clone()).await?;
+
+    if !valid {
+        return Err(UserOpError::Signature(format!(
+            "Wallet {:?} rejected signature for userOpHash {:?}",
+            user_op.sender, H256::from(user_op_hash)
+        )));
+    }
+
+    Ok(())
+}
+
+/// Calls v0.7's `getUserOpHash(PackedUserOperation)`, which v0.6 doesn't
+/// have and reverts on. v0.8 keeps the packed layout, so it is told apart
+/// from v0.7 by its canonical address. An address without code fails,
+/// since calls to it succeed and would pass for v0.7. If the chain can't be
+/// reached, canonical EntryPoint addresses are still recognised.
+async fn detect_entry_point_version<M: Middleware>(entry_point: &IEntryPoint<M>) -> Result<EntryPointVersion> {
+    let unreachable = |e: String| match EntryPointVersion::from_canonical_address(entry_point.address()) {
+        Some(version) => {
+            warn!("EntryPoint version probe failed, using canonical address mapping: {}", e);
+            Ok(version)
+        }
+        None => Err(UserOpError::RPC(e)),
+    };
+
+    let code = match entry_point.client().get_code(entry_point.address(), None).await {
+        Ok(code) => code,
+        Err(e) => return unreachable(e.to_string()),
+    };
+    if code.is_empty() {
+        return Err(UserOpError::Contract(format!("No code at EntryPoint address {:?}", entry_point.address())));
+    }
+
+    let selector = ethers::utils::id(
+        "getUserOpHash((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes))",
+    );
+    let packed_op = Token::Tuple(vec![
+        Token::Address(Address::zero()),
+        Token::Uint(U256::zero()),
+        Token::Bytes(Vec::new()),
+        Token::Bytes(Vec::new()),
+        Token::FixedBytes(vec![0; 32]),
+        Token::Uint(U256::zero()),
+        Token::FixedBytes(vec![0; 32]),
+        Token::Bytes(Vec::new()),
+        Token::Bytes(Vec::new()),
+    ]);
+    let probe: TypedTransaction = TransactionRequest::new()
+        .to(entry_point.address())
+        .data([selector.as_slice(), &ethers::abi::encode(&[packed_op])].concat())
+        .into();
+
+    match entry_point.client().call(&probe, None).await {
+        Ok(_) => match EntryPointVersion::from_canonical_address(entry_point.address()) {
+            Some(EntryPointVersion::V08) => Ok(EntryPointVersion::V08),
+            _ => Ok(EntryPointVersion::V07),
+        },
+        Err(e) if e.as_error_response().is_some() => Ok(EntryPointVersion::V06),
+        Err(e) => unreachable(e.to_string()),
+    }
+}
+
+async fn cross_check_user_op_hash<M: Middleware>(
+    entry_point: &IEntryPoint<M>,
+    user_op: &UserOperation,
+    version: EntryPointVersion,
+    chain_id: u64,
+) -> Result<H256> {
+    let local = user_op.hash_for_version(version, entry_point.address(), chain_id);
+    let on_chain = H256::from(
+        entry_point
+            .get_user_op_hash(user_op.into())
+            .call()
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?,
+    );
+
+    if local != on_chain {
+        Metrics::record_user_op_hash_mismatch(chain_id);
+        return Err(UserOpError::Validation(format!(
+            "Local userOpHash {:?} does not match EntryPoint hash {:?}",
+            local, on_chain
+        )));
+    }
+
+    Ok(local)
+}
+
+/// Fills in the sender, pending nonce, fees and gas limit `tx` is missing
+/// and returns it RLP-encoded with `signer`'s signature.
+async fn sign_handle_ops_tx<M: Middleware, S: Signer>(
+    client: &M,
+    mut tx: TypedTransaction,
+    signer: &S,
+) -> Result<Bytes> {
+    fill_handle_ops_tx(client, &mut tx, signer).await?;
+
+    let signature = signer
+        .sign_transaction(&tx)
+        .await
+        .map_err(|e| UserOpError::Signature(e.to_string()))?;
+    Ok(tx.rlp_signed(&signature))
+}
+
+async fn sign_bundle_tx<M: Middleware, S: HashSigner>(
+    client: &M,
+    mut bundle: BundleTransaction,
+    signer: &S,
+) -> Result<Bytes> {
+    if !bundle.is_eip7702() {
+        return sign_handle_ops_tx(client, bundle.tx, signer).await;
+    }
+
+    fill_handle_ops_tx(client, &mut bundle.tx, signer).await?;
+    // The node estimates the call alone; each authorization costs extra
+    if let Some(gas) = bundle.tx.gas().copied() {
+        let auth_gas = U256::from(EIP7702_PER_AUTH_GAS) * bundle.authorization_list.len();
+        bundle.tx.set_gas(gas.saturating_add(auth_gas));
+    }
+
+    let signature = signer.sign_digest(bundle.sighash()?).await?;
+    bundle.rlp_signed(&signature)
+}
+
+/// Sets `tx`'s sender to `signer` and fills its nonce, fees and gas limit,
+/// after checking the signer is for the transaction's chain.
+async fn fill_handle_ops_tx<M: Middleware, S: Signer>(
+    client: &M,
+    tx: &mut TypedTransaction,
+    signer: &S,
+) -> Result<()> {
+    if tx.chain_id().is_some_and(|chain_id| chain_id.as_u64() != signer.chain_id()) {
+        return Err(UserOpError::Signature(format!(
+            "Signer is for chain {} but the transaction targets chain {}",
+            signer.chain_id(),
+            tx.chain_id().unwrap_or_default()
+        )));
+    }
+
+    let from = signer.address();
+    tx.set_from(from);
+    if tx.nonce().is_none() {
+        let nonce = client
+            .get_transaction_count(from, Some(BlockNumber::Pending.into()))
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+        tx.set_nonce(nonce);
+    }
+    client
+        .fill_transaction(tx, None)
+        .await
+        .map_err(|e| UserOpError::RPC(e.to_string()))
+}
+
+/// `handleOps` sent through `eth_sendTransaction` can't carry an
+/// authorization list, so EIP-7702 ops have to be signed locally.
+fn reject_eip7702(user_op: &UserOperation) -> Result<()> {
+    if user_op.eip7702_auth.is_some() {
+        return Err(UserOpError::Validation(
+            "EIP-7702 ops need a type-4 transaction; use submit_user_op_signed".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Points `tx` at `previous`'s nonce with its fees bumped by
+/// `bump_percent`, so it replaces `previous` in the mempool.
+fn replace_fees(tx: &mut TypedTransaction, previous: &Transaction, bump_percent: u64) {
+    tx.set_nonce(previous.nonce);
+    let bump = |fee: Option<U256>| fee.or(previous.gas_price).map(|fee| bump_fee(fee, bump_percent));
+    match tx {
+        TypedTransaction::Eip1559(tx) => {
+            tx.max_fee_per_gas = bump(previous.max_fee_per_gas);
+            tx.max_priority_fee_per_gas = bump(previous.max_priority_fee_per_gas);
+        }
+        tx => {
+            if let Some(gas_price) = bump(previous.gas_price) {
+                tx.set_gas_price(gas_price);
+            }
+        }
+    }
+}
+
+/// Decodes the `UserOperationEvent`s for `user_op_hash` out of `logs`,
+/// skipping anything else, until `timeout` passes.
+fn user_op_events<'a>(
+    logs: impl Stream<Item = Log> + 'a,
+    user_op_hash: H256,
+    timeout: Duration,
+) -> impl Stream<Item = UserOpReceipt> + 'a {
+    logs.take_until(tokio::time::sleep(timeout))
+        .filter_map(move |log| async move {
+            let (transaction_hash, block_number) = (log.transaction_hash, log.block_number);
+            let event = match ethers::contract::parse_log::<UserOperationEventFilter>(log) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Skipping undecodable EntryPoint log: {}", e);
+                    return None;
+                }
+            };
+            let event_hash = H256::from(event.user_op_hash);
+            (event_hash == user_op_hash).then_some(UserOpReceipt {
+                user_op_hash: event_hash,
+                sender: event.sender,
+                paymaster: event.paymaster,
+                nonce: event.nonce,
+                success: event.success,
+                actual_gas_cost: event.actual_gas_cost,
+                actual_gas_used: event.actual_gas_used,
+                transaction_hash,
+                block_number,
+            })
+        })
+}
+
+async fn await_receipt<M: Middleware>(
+    client: &M,
+    tx_hash: H256,
+    confirmations: u64,
+) -> Result<Option<TransactionReceipt>> {
+    if confirmations == 0 {
+        return Ok(None);
+    }
+
+    let receipt = PendingTransaction::new(tx_hash, client.provider())
+        .confirmations(confirmations as usize)
+        .await
+        .map_err(|e| UserOpError::RPC(e.to_string()))?
+        .ok_or_else(|| UserOpError::RPC(format!("Transaction {:?} was dropped", tx_hash)))?;
+
+    if receipt.status != Some(U64::from(1)) {
+        return Err(UserOpError::Contract(format!("Transaction {:?} reverted", tx_hash)));
+    }
+
+    Ok(Some(receipt))
+}
+
+async fn ensure_deployed<M: Middleware>(client: &M, contracts: &[(&str, Address)]) -> Result<()> {
+    for (name, address) in contracts {
+        let code = client
+            .get_code(*address, None)
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?;
+
+        if code.is_empty() {
+            return Err(UserOpError::Contract(format!("No code at {} address {:?}", name, address)));
+        }
+    }
+    Ok(())
+}
+
+async fn fetch_wallet_state<M: Middleware>(
+    entry_point: &IEntryPoint<M>,
+    chain_id: u64,
+    wallet: Address,
+) -> Result<WalletState> {
+    match multicall_wallet_state(entry_point, chain_id, wallet).await {
+        Ok(state) => Ok(state),
+        Err(e) => {
+            warn!("Multicall unavailable on chain {}, fetching wallet state call by call: {}", chain_id, e);
+            sequential_wallet_state(entry_point, wallet).await
+        }
+    }
+}
+
+/// Where `CODE_SIZE_PROBE_CODE` is placed by state override; nothing is
+/// deployed there.
+const CODE_SIZE_PROBE: Address = H160([
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xc0, 0xde, 0x51, 0x2e,
+]);
+
+/// Returns `extcodesize` of its first argument, ignoring the selector:
+/// `PUSH1 4 CALLDATALOAD EXTCODESIZE PUSH1 0 MSTORE PUSH1 32 PUSH1 0 RETURN`.
+const CODE_SIZE_PROBE_CODE: [u8; 12] = [
+    0x60, 0x04, 0x35, 0x3b, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+];
+
+async fn multicall_wallet_state<M: Middleware>(
+    entry_point: &IEntryPoint<M>,
+    chain_id: u64,
+    wallet: Address,
+) -> Result<WalletState> {
+    let client = entry_point.client();
+    // Multicall3 can't read code, so a probe that can is overridden into
+    // the same eth_call
+    let mut probe_code = StateOverride::default();
+    probe_code.account(CODE_SIZE_PROBE).code(Bytes::from_static(&CODE_SIZE_PROBE_CODE));
+    let mut multicall = Multicall::new_with_chain_id(client.clone(), None, Some(chain_id))
+        .map_err(|e| UserOpError::RPC(e.to_string()))?
+        .state(probe_code);
+    multicall
+        .add_call(entry_point.get_nonce(wallet, U256::zero()), false)
+        .add_call(entry_point.deposits(wallet), false)
+        .add_call(ICodeSizeProbe::new(CODE_SIZE_PROBE, client).code_size(wallet), false);
+
+    let results = multicall
+        .call_raw()
+        .await
+        .map_err(|e| UserOpError::RPC(e.to_string()))?;
+    let uint = |index: usize, name: &str| match results.get(index) {
+        Some(Ok(Token::Uint(value))) => Ok(*value),
+        _ => Err(UserOpError::RPC(format!("Multicall returned no {} for {:?}", name, wallet))),
+    };
+
+    Ok(WalletState {
+        nonce: uint(0, "nonce")?,
+        deposit: uint(1, "deposit")?,
+        code_exists: !uint(2, "code size")?.is_zero(),
+    })
+}
+
+async fn sequential_wallet_state<M: Middleware>(entry_point: &IEntryPoint<M>, wallet: Address) -> Result<WalletState> {
+    let nonce = entry_point
+        .get_nonce(wallet, U256::zero())
+        .call()
+        .await
+        .map_err(|e| UserOpError::RPC(e.to_string()))?;
+    let deposit = entry_point
+        .deposits(wallet)
+        .call()
+        .await
+        .map_err(|e| UserOpError::RPC(e.to_string()))?;
+    let code = entry_point
+        .client()
+        .get_code(wallet, None)
+        .await
+        .map_err(|e| UserOpError::RPC(e.to_string()))?;
+
+    Ok(WalletState { nonce, deposit, code_exists: !code.is_empty() })
+}
+
+/// Sponsored ops are paid from the paymaster's EntryPoint deposit; otherwise
+/// the wallet pays from its deposit and tops up the rest from its balance.
+async fn check_prefund_with<M: Middleware>(
+    entry_point: &IEntryPoint<M>,
+    user_op: &UserOperation,
+    version: EntryPointVersion,
+) -> Result<()> {
+    let required = user_op.required_prefund(version);
+
+    let available = match user_op.paymaster() {
+        Some(paymaster) => entry_point
+            .deposits(paymaster)
+            .call()
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))?,
+        None => {
+            let deposit = entry_point
+                .deposits(user_op.sender)
+                .call()
+                .await
+                .map_err(|e| UserOpError::RPC(e.to_string()))?;
+            let balance = entry_point
+                .client()
+                .get_balance(user_op.sender, None)
+                .await
+                .map_err(|e| UserOpError::RPC(e.to_string()))?;
+            deposit.saturating_add(balance)
+        }
+    };
+
+    if available < required {
+        return Err(UserOpError::Validation(format!(
+            "insufficient prefund: required {}, available {}",
+            required, available
+        )));
+    }
+
+    Ok(())
+}
+
+async fn resolve_account_address<M: Middleware>(
+    factory: &IWalletFactory<M>,
+    owner: Address,
+    salt: U256,
+) -> Result<Address> {
+    factory
+        .get_address(owner, salt)
+        .call()
+        .await
+        .map_err(|e| UserOpError::Contract(e.to_string()))
+}
+
+/// Builds a state override that places `code` at `sender`.
+pub fn wallet_code_override(sender: Address, code: Bytes) -> StateOverride {
+    let mut overrides = StateOverride::default();
+    overrides.account(sender).code(code);
+    overrides
+}
+
+async fn call_with_overrides<P: JsonRpcClient>(
+    provider: &Provider<P>,
+    tx: &TypedTransaction,
+    overrides: &StateOverride,
+) -> Result<Bytes> {
+    provider
+        .call_raw(tx)
+        .state(overrides)
+        .await
+        .map_err(|e| UserOpError::RPC(e.to_string()))
+}
+
+/// One call frame from a `callTracer` trace, flattened out of the tree.
+#[cfg(feature = "debug-trace")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// 0 for the top-level call.
+    pub depth: usize,
+    pub call_type: String,
+    pub from: Address,
+    pub to: Option<Address>,
+    pub input: Bytes,
+    pub output: Option<Bytes>,
+    pub error: Option<String>,
+    /// The `Error(string)` message, if the frame reverted with one.
+    pub revert_reason: Option<String>,
+}
+
+#[cfg(feature = "debug-trace")]
+async fn trace_call<M: Middleware>(client: &M, tx: TypedTransaction) -> Result<Vec<TraceFrame>> {
+    let options = GethDebugTracingCallOptions {
+        tracing_options: GethDebugTracingOptions {
+            tracer: Some(GethDebugTracerType::BuiltInTracer(GethDebugBuiltInTracerType::CallTracer)),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let trace = client
+        .debug_trace_call(tx, None, options)
+        .await
+        .map_err(|e| UserOpError::RPC(e.to_string()))?;
+
+    let root = match trace {
+        GethTrace::Known(GethTraceFrame::CallTracer(frame)) => frame,
+        GethTrace::Unknown(value) => serde_json::from_value(value)
+            .map_err(|e| UserOpError::RPC(format!("Invalid callTracer output: {}", e)))?,
+        other => return Err(UserOpError::RPC(format!("Unexpected tracer output: {:?}", other))),
+    };
+
+    Ok(flatten_call_frames(&root))
+}
+
+/// Flattens a `callTracer` tree into frames in depth-first call order.
+#[cfg(feature = "debug-trace")]
+pub fn flatten_call_frames(root: &CallFrame) -> Vec<TraceFrame> {
+    fn walk(frame: &CallFrame, depth: usize, out: &mut Vec<TraceFrame>) {
+        out.push(TraceFrame {
+            depth,
+            call_type: frame.typ.clone(),
+            from: frame.from,
+            to: frame.to.as_ref().and_then(|to| to.as_address().copied()),
+            input: frame.input.clone(),
+            output: frame.output.clone(),
+            error: frame.error.clone(),
+            revert_reason: frame.output.as_ref().and_then(|output| decode_revert_reason(output)),
+        });
+        for call in frame.calls.iter().flatten() {
+            walk(call, depth + 1, out);
+        }
+    }
+
+    let mut frames = Vec::new();
+    walk(root, 0, &mut frames);
+    frames
+}
+
+#[cfg(feature = "debug-trace")]
+fn decode_revert_reason(output: &[u8]) -> Option<String> {
+    const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+    if output.len() < 4 || output[..4] != ERROR_SELECTOR {
+        return None;
+    }
+    ethers::abi::decode(&[ethers::abi::ParamType::String], &output[4..])
+        .ok()?
+        .pop()?
+        .into_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use ethers::abi::{AbiDecode, Token};
+    use ethers::types::U256;
+
+    // Official ERC-4337 EntryPoint contract address (same across all chains)
+    const ENTRY_POINT: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+    
+    // Test addresses - replace with your actual contract addresses in production
+    const TEST_WALLET_FACTORY: &str = "0x1234567890123456789012345678901234567890";
+    const TEST_PAYMASTER: &str = "0x2234567890123456789012345678901234567890";
+    
+    // Replace with your actual RPC URL
+    const TEST_RPC_URL: &str = "https://eth-mainnet.g.alchemy.com/v2/your-api-key";
+
+    async fn setup_contracts() -> Contracts {
+        let provider = crate::provider::rpc_provider(TEST_RPC_URL, false, false, &Default::default()).unwrap();
+        
+        Contracts::new(
+            provider,
+            Address::from_str(ENTRY_POINT).unwrap(),
+            Address::from_str(TEST_WALLET_FACTORY).unwrap(),
+            Address::from_str(TEST_PAYMASTER).unwrap(),
+            1, // Ethereum mainnet
+        )
+    }
+
+    #[tokio::test]
+    async fn test_get_user_op_hash() {
+        let contracts = setup_contracts().await;
+        let user_op = UserOperation {
+            sender: Address::from_str("0x1234567890123456789012345678901234567890").unwrap(),
+            nonce: U256::zero(),
+            init_code: Bytes::default(),
+            call_data: Bytes::default(),
+            call_gas_limit: U256::from(100000),
+            verification_gas_limit: U256::from(100000),
+            pre_verification_gas: U256::from(21000),
+            max_fee_per_gas: U256::from(1000000000),
+            max_priority_fee_per_gas: U256::from(1000000000),
+            paymaster_and_data: Bytes::default(),
+            signature: Bytes::default(),
+            eip7702_auth: None,
+            access_list: None,
+            paymaster_gas_limits_folded: false,
+        };
+
+        let result = contracts.get_user_op_hash(&user_op).await;
+        assert!(result.is_ok(), "Failed to get user op hash: {:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn test_get_wallet_nonce() {
+        let contracts = setup_contracts().await;
+        let wallet_address = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+
+        let result = contracts.get_wallet_nonce(wallet_address).await;
+        assert!(result.is_ok(), "Failed to get wallet nonce: {:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn test_validate_paymaster() {
+        let contracts = setup_contracts().await;
+        let sender = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+        let required_prefund = U256::from(1000000000);
+
+        let result = contracts.validate_paymaster(sender, required_prefund).await;
+        assert!(result.is_ok(), "Failed to validate paymaster: {:?}", result.err());
     }
 
     #[tokio::test]
@@ -215,4 +1447,762 @@ mod tests {
         let paymaster_result = contracts.get_paymaster_deposit(address).await;
         assert!(paymaster_result.is_ok(), "Failed to get paymaster deposit: {:?}", paymaster_result.err());
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_simulate_with_overrides_sends_state() {
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(Bytes::default()).unwrap();
+
+        let sender = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+        let overrides = wallet_code_override(sender, Bytes::from(vec![0x60, 0x80, 0x60, 0x40]));
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(Address::from_str(ENTRY_POINT).unwrap())
+            .into();
+
+        let result = call_with_overrides(&provider, &tx, &overrides).await;
+        assert!(result.is_ok(), "Failed to call with overrides: {:?}", result.err());
+
+        mock.assert_request("eth_call", (&tx, BlockId::from(BlockNumber::Latest), &overrides))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bundle_tx_carries_eip7702_authorization() {
+        let contracts = setup_contracts().await;
+        let beneficiary = Address::from_str("0x3234567890123456789012345678901234567890").unwrap();
+        let auth = Authorization {
+            chain_id: U256::one(),
+            address: Address::from_str("0x4234567890123456789012345678901234567890").unwrap(),
+            nonce: U256::from(3),
+            y_parity: U64::one(),
+            r: U256::from(11),
+            s: U256::from(12),
+        };
+        let delegated = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+            .with_eip7702_auth(auth.clone());
+        let plain = UserOperation::new(Address::from_str("0x5234567890123456789012345678901234567890").unwrap());
+
+        let bundle = contracts
+            .build_bundle_tx(vec![delegated.clone(), plain, delegated.with_nonce(U256::one())], beneficiary)
+            .unwrap();
+
+        assert_eq!(bundle.authorization_list, vec![auth]);
+        assert_eq!(bundle.tx.to_addr(), Some(&Address::from_str(ENTRY_POINT).unwrap()));
+        assert_eq!(HandleOpsCall::decode(bundle.tx.data().unwrap()).unwrap().ops.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_build_handle_ops_tx() {
+        let contracts = setup_contracts().await;
+        let beneficiary = Address::from_str("0x3234567890123456789012345678901234567890").unwrap();
+        let user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+            .with_nonce(U256::from(7))
+            .with_call_data(Bytes::from(vec![0xde, 0xad, 0xbe, 0xef]));
+
+        let tx = contracts.build_handle_ops_tx(vec![user_op.clone()], beneficiary).unwrap();
+
+        assert_eq!(tx.to_addr(), Some(&Address::from_str(ENTRY_POINT).unwrap()));
+        assert_eq!(tx.chain_id(), Some(U64::from(1)));
+
+        let decoded = HandleOpsCall::decode(tx.data().unwrap()).unwrap();
+        assert_eq!(decoded.beneficiary, beneficiary);
+        assert_eq!(decoded.ops, vec![user_op.into()]);
+    }
+
+    #[tokio::test]
+    async fn test_sign_handle_ops_tx_produces_signed_raw_tx() {
+        let contracts = setup_contracts().await;
+        let beneficiary = Address::from_str("0x3234567890123456789012345678901234567890").unwrap();
+        let user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap());
+        let wallet = LocalWallet::from_str("0000000000000000000000000000000000000000000000000000000000000001")
+            .unwrap()
+            .with_chain_id(1u64);
+
+        let mut tx = contracts.build_handle_ops_tx(vec![user_op], beneficiary).unwrap();
+        if let TypedTransaction::Eip1559(inner) = &mut tx {
+            inner.max_fee_per_gas = Some(U256::from(30_000_000_000u64));
+            inner.max_priority_fee_per_gas = Some(U256::from(1_000_000_000u64));
+        }
+
+        // LIFO: getTransactionCount, then estimateGas
+        let (provider, mock) = Provider::mocked();
+        mock.push(U256::from(250_000)).unwrap(); // eth_estimateGas
+        mock.push(U256::from(9)).unwrap(); // eth_getTransactionCount
+
+        let raw_tx = sign_handle_ops_tx(&provider, tx, &wallet).await.unwrap();
+
+        let (signed, signature) = TypedTransaction::decode_signed(&ethers::utils::rlp::Rlp::new(&raw_tx)).unwrap();
+        assert_eq!(signed.nonce(), Some(&U256::from(9)));
+        assert_eq!(signed.gas(), Some(&U256::from(250_000)));
+        assert_eq!(signed.to_addr(), Some(&Address::from_str(ENTRY_POINT).unwrap()));
+        assert_eq!(signature.recover(signed.sighash()).unwrap(), wallet.address());
+    }
+
+    #[tokio::test]
+    async fn test_sign_bundle_tx_builds_type_4_transaction() {
+        let contracts = setup_contracts().await;
+        let beneficiary = Address::from_str("0x3234567890123456789012345678901234567890").unwrap();
+        let auth = Authorization {
+            chain_id: U256::one(),
+            address: Address::from_str("0x4234567890123456789012345678901234567890").unwrap(),
+            nonce: U256::from(3),
+            y_parity: U64::one(),
+            r: U256::from(11),
+            s: U256::from(12),
+        };
+        let user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+            .with_eip7702_auth(auth.clone());
+        let wallet = LocalWallet::from_str("0000000000000000000000000000000000000000000000000000000000000001")
+            .unwrap()
+            .with_chain_id(1u64);
+
+        let mut bundle = contracts.build_bundle_tx(vec![user_op], beneficiary).unwrap();
+        if let TypedTransaction::Eip1559(inner) = &mut bundle.tx {
+            inner.max_fee_per_gas = Some(U256::from(30_000_000_000u64));
+            inner.max_priority_fee_per_gas = Some(U256::from(1_000_000_000u64));
+        }
+
+        // LIFO: getTransactionCount, then estimateGas
+        let (provider, mock) = Provider::mocked();
+        mock.push(U256::from(250_000)).unwrap(); // eth_estimateGas
+        mock.push(U256::from(9)).unwrap(); // eth_getTransactionCount
+
+        let raw_tx = sign_bundle_tx(&provider, bundle.clone(), &wallet).await.unwrap();
+        assert_eq!(raw_tx[0], EIP7702_TX_TYPE);
+
+        let rlp = ethers::utils::rlp::Rlp::new(&raw_tx[1..]);
+        assert_eq!(rlp.item_count().unwrap(), 13);
+        assert_eq!(rlp.val_at::<U256>(1).unwrap(), U256::from(9));
+        assert_eq!(rlp.val_at::<U256>(4).unwrap(), U256::from(250_000 + EIP7702_PER_AUTH_GAS));
+        assert_eq!(rlp.val_at::<Address>(5).unwrap(), Address::from_str(ENTRY_POINT).unwrap());
+
+        let auths = rlp.at(9).unwrap();
+        assert_eq!(auths.item_count().unwrap(), 1);
+        assert_eq!(auths.at(0).unwrap().val_at::<Address>(1).unwrap(), auth.address);
+        assert_eq!(auths.at(0).unwrap().val_at::<U256>(2).unwrap(), auth.nonce);
+
+        // The signature recovers to the bundler over the type-4 sighash
+        if let TypedTransaction::Eip1559(inner) = &mut bundle.tx {
+            inner.nonce = Some(U256::from(9));
+            inner.gas = Some(U256::from(250_000 + EIP7702_PER_AUTH_GAS));
+            inner.chain_id = Some(U64::one());
+        }
+        let signature = Signature {
+            r: rlp.val_at(11).unwrap(),
+            s: rlp.val_at(12).unwrap(),
+            v: rlp.val_at::<u64>(10).unwrap() + 27,
+        };
+        assert_eq!(signature.recover(bundle.sighash().unwrap()).unwrap(), wallet.address());
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_submit_rejects_eip7702_ops() {
+        let contracts = setup_contracts().await;
+        let beneficiary = Address::from_str("0x3234567890123456789012345678901234567890").unwrap();
+        let user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+            .with_eip7702_auth(Authorization {
+                chain_id: U256::one(),
+                address: Address::from_str("0x4234567890123456789012345678901234567890").unwrap(),
+                nonce: U256::zero(),
+                y_parity: U64::zero(),
+                r: U256::from(1),
+                s: U256::from(2),
+            });
+
+        assert!(matches!(
+            contracts.submit_user_op(user_op, beneficiary).await,
+            Err(UserOpError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn test_replace_fees_reuses_nonce_and_bumps_fees() {
+        let previous = Transaction {
+            nonce: U256::from(7),
+            max_fee_per_gas: Some(U256::from(100)),
+            max_priority_fee_per_gas: Some(U256::from(10)),
+            ..Default::default()
+        };
+
+        let mut tx = TypedTransaction::Eip1559(Eip1559TransactionRequest::new());
+        replace_fees(&mut tx, &previous, 10);
+        let TypedTransaction::Eip1559(tx) = tx else { unreachable!() };
+        assert_eq!(tx.nonce, Some(U256::from(7)));
+        assert_eq!(tx.max_fee_per_gas, Some(U256::from(110)));
+        assert_eq!(tx.max_priority_fee_per_gas, Some(U256::from(11)));
+
+        let legacy = Transaction { gas_price: Some(U256::from(50)), ..previous };
+        let mut tx = TypedTransaction::Legacy(TransactionRequest::new());
+        replace_fees(&mut tx, &legacy, 10);
+        assert_eq!(tx.gas_price(), Some(U256::from(55)));
+    }
+
+    #[tokio::test]
+    async fn test_sign_handle_ops_tx_rejects_wrong_chain_signer() {
+        let contracts = setup_contracts().await;
+        let beneficiary = Address::from_str("0x3234567890123456789012345678901234567890").unwrap();
+        let user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap());
+        let wallet = LocalWallet::from_str("0000000000000000000000000000000000000000000000000000000000000001")
+            .unwrap()
+            .with_chain_id(137u64);
+        let (provider, _mock) = Provider::mocked();
+
+        let tx = contracts.build_handle_ops_tx(vec![user_op], beneficiary).unwrap();
+        assert!(matches!(
+            sign_handle_ops_tx(&provider, tx, &wallet).await,
+            Err(UserOpError::Signature(_))
+        ));
+    }
+
+    fn user_op_event_log(user_op_hash: H256, success: bool) -> Log {
+        let sender = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+        Log {
+            address: Address::from_str(ENTRY_POINT).unwrap(),
+            topics: vec![
+                UserOperationEventFilter::signature(),
+                user_op_hash,
+                H256::from(sender),
+                H256::zero(),
+            ],
+            data: Bytes::from(ethers::abi::encode(&[
+                Token::Uint(U256::from(4)),
+                Token::Bool(success),
+                Token::Uint(U256::from(2_100_000)),
+                Token::Uint(U256::from(210_000)),
+            ])),
+            transaction_hash: Some(H256::repeat_byte(0x77)),
+            block_number: Some(U64::from(19_000_000)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_user_op_events_decode_matching_logs() {
+        let user_op_hash = H256::repeat_byte(0x01);
+        let logs = futures::stream::iter(vec![
+            user_op_event_log(H256::repeat_byte(0x02), true),
+            Log { topics: vec![H256::repeat_byte(0x03)], ..Default::default() },
+            user_op_event_log(user_op_hash, true),
+        ])
+        .chain(futures::stream::pending());
+
+        let receipts: Vec<_> = user_op_events(logs, user_op_hash, Duration::from_secs(30)).collect().await;
+
+        assert_eq!(receipts, vec![UserOpReceipt {
+            user_op_hash,
+            sender: Address::from_str("0x1234567890123456789012345678901234567890").unwrap(),
+            paymaster: Address::zero(),
+            nonce: U256::from(4),
+            success: true,
+            actual_gas_cost: U256::from(2_100_000),
+            actual_gas_used: U256::from(210_000),
+            transaction_hash: Some(H256::repeat_byte(0x77)),
+            block_number: Some(U64::from(19_000_000)),
+        }]);
+    }
+
+    #[tokio::test]
+    async fn test_create_account_encoding() {
+        let contracts = setup_contracts().await;
+        let owner = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+        let salt = U256::from(5);
+
+        let calldata = contracts.encode_create_account(owner, salt);
+        let selector = &ethers::utils::keccak256("createAccount(address,uint256)")[..4];
+        assert_eq!(&calldata[..4], selector);
+        assert_eq!(&calldata[4..], ethers::abi::encode(&[Token::Address(owner), Token::Uint(salt)]).as_slice());
+
+        let init_code = contracts.init_code(owner, salt);
+        assert_eq!(&init_code[..20], Address::from_str(TEST_WALLET_FACTORY).unwrap().as_bytes());
+        assert_eq!(&init_code[20..], calldata.as_ref());
+    }
+
+    #[tokio::test]
+    async fn test_get_address_resolution() {
+        let (provider, mock) = Provider::mocked();
+        let expected = Address::from_str("0x4234567890123456789012345678901234567890").unwrap();
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::Address(expected)]))).unwrap();
+
+        let factory = IWalletFactory::new(
+            Address::from_str(TEST_WALLET_FACTORY).unwrap(),
+            Arc::new(provider),
+        );
+        let owner = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+
+        let result = resolve_account_address(&factory, owner, U256::zero()).await.unwrap();
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_order_user_ops_sorts_by_sender_then_nonce() {
+        let (a, b) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let op = |sender: Address, nonce: u64| UserOperation::new(sender).with_nonce(U256::from(nonce));
+
+        let ordered = order_user_ops(vec![op(b, 0), op(a, 2), op(b, 1), op(a, 0)]).unwrap();
+
+        let keys: Vec<_> = ordered.iter().map(|op| (op.sender, op.nonce.as_u64())).collect();
+        assert_eq!(keys, vec![(a, 0), (a, 2), (b, 0), (b, 1)]);
+    }
+
+    #[test]
+    fn test_order_user_ops_rejects_duplicate_nonce() {
+        let sender = Address::from_low_u64_be(1);
+        let first = UserOperation::new(sender).with_nonce(U256::from(4));
+        let replacement = first.clone().with_call_data(Bytes::from(vec![0x01]));
+
+        let result = order_user_ops(vec![first, UserOperation::new(Address::from_low_u64_be(2)), replacement]);
+        assert!(matches!(result, Err(UserOpError::Validation(_))), "Expected duplicate rejection: {:?}", result);
+    }
+
+    fn op_with_gas(sender: u64, gas: u64) -> UserOperation {
+        let mut user_op = UserOperation::new(Address::from_low_u64_be(sender));
+        user_op.call_gas_limit = U256::from(gas);
+        user_op
+    }
+
+    #[test]
+    fn test_split_batch_respects_count_and_gas() {
+        let limits = BatchLimits { max_ops: 2, max_gas: U256::from(1_000) };
+
+        let batches = split_batch(vec![op_with_gas(1, 100), op_with_gas(2, 100), op_with_gas(3, 100)], &limits, EntryPointVersion::V06).unwrap();
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 1]);
+
+        let batches = split_batch(vec![op_with_gas(1, 600), op_with_gas(2, 500), op_with_gas(3, 300)], &limits, EntryPointVersion::V06).unwrap();
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), vec![1, 2]);
+
+        let result = split_batch(vec![op_with_gas(1, 100), op_with_gas(2, 1_001)], &limits, EntryPointVersion::V06);
+        assert!(matches!(result, Err(UserOpError::Validation(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_over_limit_batch_is_sent_as_two_bundles() {
+        let (provider, mock) = Provider::mocked();
+        let beneficiary = Address::from_str("0x3234567890123456789012345678901234567890").unwrap();
+        let block = Block::<TxHash> { base_fee_per_gas: Some(U256::from(10)), ..Default::default() };
+        let fee_history = FeeHistory {
+            base_fee_per_gas: vec![U256::from(10)],
+            gas_used_ratio: vec![0.5],
+            oldest_block: U256::from(100),
+            reward: vec![vec![U256::from(1)]],
+        };
+        // LIFO, per bundle: eth_getBlockByNumber, eth_feeHistory,
+        // eth_estimateGas, eth_sendTransaction
+        for tx_hash in [H256::from_low_u64_be(2), H256::from_low_u64_be(1)] {
+            mock.push(tx_hash).unwrap();
+            mock.push(U256::from(500_000)).unwrap();
+            mock.push(fee_history.clone()).unwrap();
+            mock.push(block.clone()).unwrap();
+        }
+
+        let limits = BatchLimits { max_ops: 32, max_gas: U256::from(1_000) };
+        let batches = split_batch(vec![op_with_gas(1, 600), op_with_gas(2, 600)], &limits, EntryPointVersion::V06).unwrap();
+        let entry_point = IEntryPoint::new(Address::from_str(ENTRY_POINT).unwrap(), Arc::new(provider));
+        let tx_hashes = send_batches(&entry_point, batches, beneficiary).await.unwrap();
+
+        assert_eq!(tx_hashes, vec![H256::from_low_u64_be(1), H256::from_low_u64_be(2)]);
+    }
+
+    #[test]
+    fn test_encode_execute_batch() {
+        let dest = vec![
+            Address::from_str("0x1234567890123456789012345678901234567890").unwrap(),
+            Address::from_str("0x2234567890123456789012345678901234567890").unwrap(),
+        ];
+        let value = vec![U256::from(1), U256::zero()];
+        let func = vec![Bytes::default(), Bytes::from(vec![0xa9, 0x05, 0x9c, 0xbb])];
+
+        let encoded = encode_execute_batch(dest.clone(), value.clone(), func.clone()).unwrap();
+
+        let mut expected = ethers::utils::keccak256("executeBatch(address[],uint256[],bytes[])")[..4].to_vec();
+        expected.extend(ethers::abi::encode(&[
+            Token::Array(dest.into_iter().map(Token::Address).collect()),
+            Token::Array(value.into_iter().map(Token::Uint).collect()),
+            Token::Array(func.into_iter().map(|f| Token::Bytes(f.to_vec())).collect()),
+        ]));
+        assert_eq!(encoded.to_vec(), expected);
+    }
+
+    #[test]
+    fn test_encode_execute_batch_rejects_bad_lengths() {
+        let dest = vec![Address::zero(), Address::zero()];
+        let result = encode_execute_batch(dest, vec![U256::zero()], vec![Bytes::default(), Bytes::default()]);
+        assert!(matches!(result, Err(UserOpError::Validation(_))));
+
+        let result = encode_execute_batch(vec![], vec![], vec![]);
+        assert!(matches!(result, Err(UserOpError::Validation(_))));
+    }
+
+    #[tokio::test]
+    async fn test_offchain_lookups_are_not_followed_by_default() {
+        use crate::ccip::tests::{gateway, offchain_lookup_revert};
+
+        let wallet = Address::from_low_u64_be(0x1234);
+        let (url, server) = gateway(Bytes::from(vec![0xaa; 65])).await;
+        let (provider, mock) = Provider::mocked();
+        mock.push_response(offchain_lookup_revert(wallet, &[url], &[0x01], &[]));
+
+        let contract = ISmartWallet::new(wallet, Arc::new(provider));
+        let result = is_valid_signature(&contract, None, [0x11; 32], Bytes::new()).await;
+
+        assert!(matches!(result, Err(UserOpError::RPC(_))), "{:?}", result);
+        assert!(!server.is_finished(), "the gateway was fetched");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_signature_preflight_rejects_invalid_signature() {
+        let (provider, mock) = Provider::mocked();
+        // MockProvider pops responses LIFO: isValidSignature, then getUserOpHash
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::Bool(false)]))).unwrap();
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::FixedBytes(vec![0xab; 32])]))).unwrap();
+
+        let entry_point = IEntryPoint::new(Address::from_str(ENTRY_POINT).unwrap(), Arc::new(provider));
+        let user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+            .with_signature(Bytes::from(vec![0u8; 65]));
+
+        let result = preflight_signature(&entry_point, None, &user_op).await;
+        assert!(matches!(result, Err(UserOpError::Signature(_))), "Expected signature error: {:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_cross_check_user_op_hash() {
+        crate::metrics::tests::test_handle();
+        let mismatches = || Metrics::snapshot().counter("user_op_hash_mismatch", &[("chain", "137")]);
+        let entry_point_address = Address::from_str(ENTRY_POINT).unwrap();
+        let user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+            .with_call_data(Bytes::from(vec![0xde, 0xad, 0xbe, 0xef]));
+        let local = user_op.hash(entry_point_address, 1);
+
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::FixedBytes(local.as_bytes().to_vec())]))).unwrap();
+        let entry_point = IEntryPoint::new(entry_point_address, Arc::new(provider));
+
+        let result = cross_check_user_op_hash(&entry_point, &user_op, EntryPointVersion::V06, 1).await;
+        assert_eq!(result.unwrap(), local);
+
+        // The same op checked against the wrong chain must not match
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::FixedBytes(local.as_bytes().to_vec())]))).unwrap();
+        let entry_point = IEntryPoint::new(entry_point_address, Arc::new(provider));
+        let before = mismatches();
+
+        let result = cross_check_user_op_hash(&entry_point, &user_op, EntryPointVersion::V06, 137).await;
+        assert!(matches!(result, Err(UserOpError::Validation(_))), "Expected mismatch: {:?}", result);
+        assert!(mismatches() >= before + 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_detect_entry_point_version() {
+        let v07 = Address::from_str(ENTRY_POINT_V07_ADDRESS).unwrap();
+        let custom = Address::from_low_u64_be(0xe7);
+        assert_eq!(EntryPointVersion::from_canonical_address(v07), Some(EntryPointVersion::V07));
+        assert_eq!(
+            EntryPointVersion::from_canonical_address(Address::from_str(ENTRY_POINT).unwrap()),
+            Some(EntryPointVersion::V06)
+        );
+        assert_eq!(EntryPointVersion::from_canonical_address(custom), None);
+
+        // LIFO: the code lookup, then the probe. The packed getUserOpHash
+        // answers on v0.7
+        let code = Bytes::from(vec![0x60, 0x80]);
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(Bytes::from(vec![0u8; 32])).unwrap();
+        mock.push::<Bytes, _>(code.clone()).unwrap();
+        let entry_point = IEntryPoint::new(custom, Arc::new(provider));
+        assert_eq!(detect_entry_point_version(&entry_point).await.unwrap(), EntryPointVersion::V07);
+
+        // and on v0.8, told apart by its address
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(Bytes::from(vec![0u8; 32])).unwrap();
+        mock.push::<Bytes, _>(code.clone()).unwrap();
+        let entry_point = IEntryPoint::new(Address::from_str(ENTRY_POINT_V08_ADDRESS).unwrap(), Arc::new(provider));
+        assert_eq!(detect_entry_point_version(&entry_point).await.unwrap(), EntryPointVersion::V08);
+
+        // and reverts on v0.6
+        let (provider, mock) = Provider::mocked();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: None,
+        }));
+        mock.push::<Bytes, _>(code).unwrap();
+        let entry_point = IEntryPoint::new(custom, Arc::new(provider));
+        assert_eq!(detect_entry_point_version(&entry_point).await.unwrap(), EntryPointVersion::V06);
+
+        // Nothing deployed: the probe would succeed, so it isn't made
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(Bytes::default()).unwrap();
+        let entry_point = IEntryPoint::new(v07, Arc::new(provider));
+        assert!(matches!(detect_entry_point_version(&entry_point).await, Err(UserOpError::Contract(_))));
+
+        // An unreachable node still resolves the canonical v0.7 address
+        let (provider, _mock) = Provider::mocked();
+        let entry_point = IEntryPoint::new(v07, Arc::new(provider));
+        assert_eq!(detect_entry_point_version(&entry_point).await.unwrap(), EntryPointVersion::V07);
+
+        let (provider, _mock) = Provider::mocked();
+        let entry_point = IEntryPoint::new(custom, Arc::new(provider));
+        assert!(matches!(detect_entry_point_version(&entry_point).await, Err(UserOpError::RPC(_))));
+    }
+
+    #[tokio::test]
+    async fn test_zero_confirmations_skips_waiting() {
+        let (provider, mock) = Provider::mocked();
+
+        let receipt = await_receipt(&provider, H256::repeat_byte(0x01), 0).await.unwrap();
+
+        assert!(receipt.is_none());
+        assert!(mock.assert_request("eth_getTransactionReceipt", [H256::repeat_byte(0x01)]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_zero_confirmations_rejected_without_fast_finality() {
+        let contracts = setup_contracts().await;
+        let result = contracts.clone().with_confirmations(0, false);
+        assert!(matches!(result, Err(UserOpError::ChainConfig(_))));
+
+        assert!(contracts.with_confirmations(0, true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_config_confirmation_floor_applies_when_confirming() {
+        use crate::service::Submitter;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let tx_hash = H256::repeat_byte(0x01);
+        let tx = Transaction { hash: tx_hash, block_number: Some(U64::from(100)), ..Default::default() };
+        let receipt = TransactionReceipt {
+            transaction_hash: tx_hash,
+            block_number: Some(U64::from(100)),
+            status: Some(U64::from(1)),
+            ..Default::default()
+        };
+        let head = Arc::new(AtomicU64::new(100));
+        let polled = head.clone();
+        let url = crate::provider::tests::serve_rpc(move |method| match method {
+            "eth_getTransactionByHash" => serde_json::to_value(&tx).unwrap(),
+            "eth_getTransactionReceipt" => serde_json::to_value(&receipt).unwrap(),
+            // Each poll sees one more block
+            "eth_blockNumber" => serde_json::to_value(U64::from(polled.fetch_add(1, Ordering::SeqCst))).unwrap(),
+            _ => serde_json::Value::Null,
+        })
+        .await;
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "chains": {
+                "1": {
+                    "chain_id": 1,
+                    "rpc_url": url,
+                    "entry_point_address": ENTRY_POINT,
+                    "wallet_factory_address": TEST_WALLET_FACTORY,
+                    "paymaster_address": TEST_PAYMASTER
+                }
+            },
+            "min_confirmations": 3
+        }))
+        .unwrap();
+
+        let contracts = Contracts::from_config(&config, 1).unwrap().with_confirmations(1, false).unwrap();
+        assert_eq!(contracts.confirmations(), 3);
+        contracts.wait_for_confirmation(tx_hash).await.unwrap();
+        // Heads 100 to 102 are too shallow; 103 gives the third confirmation
+        assert_eq!(head.load(Ordering::SeqCst), 104);
+
+        // Chains already above the floor keep their own setting
+        let deep = Contracts::from_config(&config, 1).unwrap().with_confirmations(12, false).unwrap();
+        assert_eq!(deep.confirmations(), 12);
+    }
+
+    fn prefund_user_op() -> UserOperation {
+        let mut user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap());
+        user_op.call_gas_limit = U256::from(100_000);
+        user_op.verification_gas_limit = U256::from(100_000);
+        user_op.pre_verification_gas = U256::from(50_000);
+        user_op.max_fee_per_gas = U256::from(1_000_000_000u64);
+        user_op
+    }
+
+    #[tokio::test]
+    async fn test_check_prefund_sufficient_deposit() {
+        let (provider, mock) = Provider::mocked();
+        let user_op = prefund_user_op();
+        assert_eq!(user_op.required_prefund(EntryPointVersion::V06), U256::from(250_000_000_000_000u64));
+
+        // LIFO: wallet balance, then wallet deposit
+        mock.push(U256::from(50_000_000_000_000u64)).unwrap();
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::Uint(U256::from(200_000_000_000_000u64))]))).unwrap();
+
+        let entry_point = IEntryPoint::new(Address::from_str(ENTRY_POINT).unwrap(), Arc::new(provider));
+        assert!(check_prefund_with(&entry_point, &user_op, EntryPointVersion::V06).await.is_ok());
+    }
+
+    fn aggregate3_result(results: Vec<(bool, Vec<u8>)>) -> Bytes {
+        let results = results
+            .into_iter()
+            .map(|(success, data)| Token::Tuple(vec![Token::Bool(success), Token::Bytes(data)]))
+            .collect();
+        Bytes::from(ethers::abi::encode(&[Token::Array(results)]))
+    }
+
+    #[tokio::test]
+    async fn test_wallet_state_in_one_multicall() {
+        let (provider, mock) = Provider::mocked();
+        let wallet = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+        mock.push::<Bytes, _>(aggregate3_result(vec![
+            (true, ethers::abi::encode(&[Token::Uint(U256::from(7))])),
+            (true, ethers::abi::encode(&[Token::Uint(U256::from(1_000_000u64))])),
+            (true, ethers::abi::encode(&[Token::Uint(U256::from(2_048))])),
+        ])).unwrap();
+
+        let entry_point = IEntryPoint::new(Address::from_str(ENTRY_POINT).unwrap(), Arc::new(provider));
+        // Only one response is queued, so any further request would fail
+        let state = fetch_wallet_state(&entry_point, 1, wallet).await.unwrap();
+
+        assert_eq!(state, WalletState {
+            nonce: U256::from(7),
+            deposit: U256::from(1_000_000u64),
+            code_exists: true,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_wallet_state_without_code_in_one_multicall() {
+        let (provider, mock) = Provider::mocked();
+        let wallet = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+        mock.push::<Bytes, _>(aggregate3_result(vec![
+            (true, ethers::abi::encode(&[Token::Uint(U256::zero())])),
+            (true, ethers::abi::encode(&[Token::Uint(U256::from(500))])),
+            (true, ethers::abi::encode(&[Token::Uint(U256::zero())])),
+        ])).unwrap();
+
+        let entry_point = IEntryPoint::new(Address::from_str(ENTRY_POINT).unwrap(), Arc::new(provider));
+        let state = fetch_wallet_state(&entry_point, 1, wallet).await.unwrap();
+
+        assert!(!state.code_exists);
+    }
+
+    #[tokio::test]
+    async fn test_wallet_state_falls_back_without_multicall() {
+        let (provider, mock) = Provider::mocked();
+        let wallet = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+        // LIFO: nonce, deposit, then code
+        mock.push::<Bytes, _>(Bytes::default()).unwrap();
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::Uint(U256::from(500))]))).unwrap();
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::Uint(U256::zero())]))).unwrap();
+
+        // Multicall3 isn't deployed on this chain
+        let entry_point = IEntryPoint::new(Address::from_str(ENTRY_POINT).unwrap(), Arc::new(provider));
+        let state = fetch_wallet_state(&entry_point, 999_999, wallet).await.unwrap();
+
+        assert_eq!(state, WalletState { nonce: U256::zero(), deposit: U256::from(500), code_exists: false });
+    }
+
+    #[tokio::test]
+    async fn test_check_prefund_insufficient_paymaster_deposit() {
+        let (provider, mock) = Provider::mocked();
+        let user_op = prefund_user_op()
+            .with_paymaster(Address::from_str(TEST_PAYMASTER).unwrap(), Bytes::default());
+        // Verification gas counts three times for paymaster ops
+        assert_eq!(user_op.required_prefund(EntryPointVersion::V06), U256::from(450_000_000_000_000u64));
+
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::Uint(U256::from(300_000_000_000_000u64))]))).unwrap();
+
+        let entry_point = IEntryPoint::new(Address::from_str(ENTRY_POINT).unwrap(), Arc::new(provider));
+        match check_prefund_with(&entry_point, &user_op, EntryPointVersion::V06).await {
+            Err(UserOpError::Validation(msg)) => assert!(msg.contains("insufficient prefund")),
+            other => panic!("Expected insufficient prefund, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_prefund_uses_v07_paymaster_limits() {
+        let (provider, mock) = Provider::mocked();
+        let mut gas_params = prefund_user_op().gas_params();
+        gas_params.paymaster_verification_gas_limit = Some(U256::from(30_000));
+        gas_params.paymaster_post_op_gas_limit = Some(U256::from(20_000));
+        let user_op = prefund_user_op()
+            .with_paymaster(Address::from_str(TEST_PAYMASTER).unwrap(), Bytes::default())
+            .with_paymaster_gas_limits(&gas_params, EntryPointVersion::V07)
+            .unwrap();
+        // v0.7 adds the paymaster limits instead of tripling verification gas
+        assert_eq!(user_op.required_prefund(EntryPointVersion::V07), U256::from(300_000_000_000_000u64));
+
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::Uint(U256::from(350_000_000_000_000u64))]))).unwrap();
+
+        let entry_point = IEntryPoint::new(Address::from_str(ENTRY_POINT).unwrap(), Arc::new(provider));
+        assert!(check_prefund_with(&entry_point, &user_op, EntryPointVersion::V07).await.is_ok());
+
+        let limits = BatchLimits { max_ops: 32, max_gas: U256::from(300_000) };
+        assert!(split_batch(vec![user_op.clone()], &limits, EntryPointVersion::V07).is_ok());
+        assert!(split_batch(vec![user_op], &limits, EntryPointVersion::V06).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_rejects_paymaster_without_code() {
+        let (provider, mock) = Provider::mocked();
+        // LIFO: paymaster, wallet factory, EntryPoint
+        mock.push::<Bytes, _>(Bytes::default()).unwrap();
+        mock.push::<Bytes, _>(Bytes::from(vec![0x60, 0x80])).unwrap();
+        mock.push::<Bytes, _>(Bytes::from(vec![0x60, 0x80])).unwrap();
+
+        let paymaster = Address::from_str(TEST_PAYMASTER).unwrap();
+        let result = ensure_deployed(&provider, &[
+            ("EntryPoint", Address::from_str(ENTRY_POINT).unwrap()),
+            ("wallet factory", Address::from_str(TEST_WALLET_FACTORY).unwrap()),
+            ("paymaster", paymaster),
+        ]).await;
+
+        match result {
+            Err(UserOpError::Contract(msg)) => {
+                assert!(msg.contains("paymaster"));
+                assert!(msg.contains(&format!("{:?}", paymaster)));
+            }
+            other => panic!("Expected missing code error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "debug-trace")]
+    #[test]
+    fn test_flatten_call_tracer_output() {
+        let wallet = "0x1234567890123456789012345678901234567890";
+        let mut revert = vec![0x08, 0xc3, 0x79, 0xa0];
+        revert.extend(ethers::abi::encode(&[Token::String("AA23 reverted: bad signature".to_string())]));
+
+        // Trimmed callTracer output for a simulateValidation whose wallet
+        // reverted inside validateUserOp.
+        let trace = serde_json::json!({
+            "type": "CALL",
+            "from": "0x0000000000000000000000000000000000000000",
+            "to": ENTRY_POINT,
+            "gas": "0x1e8480",
+            "gasUsed": "0x7a120",
+            "input": "0xee219423",
+            "output": "0x",
+            "error": "execution reverted",
+            "calls": [{
+                "type": "CALL",
+                "from": ENTRY_POINT,
+                "to": wallet,
+                "gas": "0x186a0",
+                "gasUsed": "0x5208",
+                "input": "0x3a871cdd",
+                "output": Bytes::from(revert),
+                "error": "execution reverted"
+            }]
+        });
+        let root: CallFrame = serde_json::from_value(trace).unwrap();
+
+        let frames = flatten_call_frames(&root);
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].depth, 0);
+        assert_eq!(frames[0].to, Some(Address::from_str(ENTRY_POINT).unwrap()));
+        assert_eq!(frames[0].revert_reason, None);
+
+        assert_eq!(frames[1].depth, 1);
+        assert_eq!(frames[1].call_type, "CALL");
+        assert_eq!(frames[1].from, Address::from_str(ENTRY_POINT).unwrap());
+        assert_eq!(frames[1].to, Some(Address::from_str(wallet).unwrap()));
+        assert_eq!(frames[1].error.as_deref(), Some("execution reverted"));
+        assert_eq!(frames[1].revert_reason.as_deref(), Some("AA23 reverted: bad signature"));
+    }
+}
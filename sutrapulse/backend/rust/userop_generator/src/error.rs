@@ -1,3 +1,4 @@
+use std::time::Duration;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, UserOpError>;
@@ -22,8 +23,12 @@ pub enum UserOpError {
     #[error("Cache error: {0}")]
     Cache(String),
 
-    #[error("Rate limit exceeded: {0}")]
-    RateLimit(String),
+    #[error("Rate limit exceeded: {message}")]
+    RateLimit {
+        message: String,
+        /// Delay the server asked for via `Retry-After`, if it sent one.
+        retry_after: Option<Duration>,
+    },
 
     #[error("Retry error: {0}")]
     Retry(String),
@@ -34,6 +39,246 @@ pub enum UserOpError {
     #[error("Chain error: {0}")]
     Chain(String),
 
+    #[error("Unsupported chain: {0}")]
+    UnsupportedChain(String),
+
+    #[error("Chain configuration error: {0}")]
+    ChainConfig(String),
+
+    #[error("Validation error: {0}")]
+    Validation(String),
+
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
+
+impl UserOpError {
+    /// The EntryPoint failure this error reports, if its message carries an
+    /// `AAxx` code, e.g. a bundler's `AA25 invalid account nonce` rejection
+    /// or a `FailedOp` revert.
+    pub fn aa_error(&self) -> Option<AaError> {
+        AaError::parse(&self.to_string())
+    }
+}
+
+/// An EntryPoint `AAxx` failure. The first digit names the stage: 1x
+/// account creation, 2x account validation, 3x paymaster validation, 4x
+/// verification gas, 5x postOp and 9x bundle-level errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AaError {
+    /// AA10: `init_code` was set for a sender that already exists.
+    SenderAlreadyConstructed,
+    /// AA13: the factory call reverted or ran out of gas.
+    InitCodeFailed,
+    /// AA14: the factory returned a different address than the sender.
+    InitCodeWrongSender,
+    /// AA15: the factory didn't deploy code at the sender.
+    InitCodeNoCode,
+    /// AA20: no `init_code` and the sender has no code.
+    AccountNotDeployed,
+    /// AA21: the account's deposit can't cover the prefund.
+    PrefundNotPaid,
+    /// AA22: the account's signature is expired or not yet valid.
+    AccountExpired,
+    /// AA23: `validateUserOp` reverted or ran out of gas.
+    AccountReverted,
+    /// AA24: the account rejected the signature.
+    AccountSignature,
+    /// AA25: the nonce was already used or is out of sequence.
+    InvalidNonce,
+    /// AA26: account validation used more than `verification_gas_limit`.
+    AccountOverVerificationGas,
+    /// AA30: the paymaster has no code.
+    PaymasterNotDeployed,
+    /// AA31: the paymaster's deposit can't cover the prefund.
+    PaymasterDepositTooLow,
+    /// AA32: the paymaster's signature is expired or not yet valid.
+    PaymasterExpired,
+    /// AA33: `validatePaymasterUserOp` reverted or ran out of gas.
+    PaymasterReverted,
+    /// AA34: the paymaster rejected the signature.
+    PaymasterSignature,
+    /// AA36: paymaster validation used more than its gas limit.
+    PaymasterOverVerificationGas,
+    /// AA40: validation used more than `verification_gas_limit`.
+    OverVerificationGas,
+    /// AA41: too little verification gas left for the paymaster.
+    VerificationGasTooLow,
+    /// AA50: `postOp` reverted.
+    PostOpReverted,
+    /// AA51: the prefund didn't cover the actual gas cost.
+    PrefundBelowActualCost,
+    /// AA90: `handleOps` was called with the zero beneficiary.
+    InvalidBeneficiary,
+    /// AA91: the refund couldn't be sent to the beneficiary.
+    BeneficiaryTransferFailed,
+    /// AA92: an EntryPoint-internal function was called externally.
+    InternalCallOnly,
+    /// AA93: `paymaster_and_data` is too short to hold an address.
+    InvalidPaymasterAndData,
+    /// AA94: a gas field doesn't fit in 120 bits.
+    GasValuesOverflow,
+    /// AA95: the bundle transaction ran out of gas.
+    OutOfGas,
+    /// AA96: the op names an aggregator it isn't grouped under.
+    InvalidAggregator,
+    /// A code this crate doesn't know.
+    Other(u8),
+}
+
+impl AaError {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            10 => Self::SenderAlreadyConstructed,
+            13 => Self::InitCodeFailed,
+            14 => Self::InitCodeWrongSender,
+            15 => Self::InitCodeNoCode,
+            20 => Self::AccountNotDeployed,
+            21 => Self::PrefundNotPaid,
+            22 => Self::AccountExpired,
+            23 => Self::AccountReverted,
+            24 => Self::AccountSignature,
+            25 => Self::InvalidNonce,
+            26 => Self::AccountOverVerificationGas,
+            30 => Self::PaymasterNotDeployed,
+            31 => Self::PaymasterDepositTooLow,
+            32 => Self::PaymasterExpired,
+            33 => Self::PaymasterReverted,
+            34 => Self::PaymasterSignature,
+            36 => Self::PaymasterOverVerificationGas,
+            40 => Self::OverVerificationGas,
+            41 => Self::VerificationGasTooLow,
+            50 => Self::PostOpReverted,
+            51 => Self::PrefundBelowActualCost,
+            90 => Self::InvalidBeneficiary,
+            91 => Self::BeneficiaryTransferFailed,
+            92 => Self::InternalCallOnly,
+            93 => Self::InvalidPaymasterAndData,
+            94 => Self::GasValuesOverflow,
+            95 => Self::OutOfGas,
+            96 => Self::InvalidAggregator,
+            other => Self::Other(other),
+        }
+    }
+
+    /// Finds the first `AAxx` code in a revert reason or error message.
+    pub fn parse(reason: &str) -> Option<Self> {
+        let bytes = reason.as_bytes();
+        (0..bytes.len().saturating_sub(3)).find_map(|i| {
+            let starts_word = i == 0 || !bytes[i - 1].is_ascii_alphanumeric();
+            let ends_word = bytes.get(i + 4).is_none_or(|b| !b.is_ascii_alphanumeric());
+            match &bytes[i..i + 4] {
+                &[b'A', b'A', tens, units] if starts_word && ends_word && tens.is_ascii_digit() && units.is_ascii_digit() => {
+                    Some(Self::from_code((tens - b'0') * 10 + (units - b'0')))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::SenderAlreadyConstructed => 10,
+            Self::InitCodeFailed => 13,
+            Self::InitCodeWrongSender => 14,
+            Self::InitCodeNoCode => 15,
+            Self::AccountNotDeployed => 20,
+            Self::PrefundNotPaid => 21,
+            Self::AccountExpired => 22,
+            Self::AccountReverted => 23,
+            Self::AccountSignature => 24,
+            Self::InvalidNonce => 25,
+            Self::AccountOverVerificationGas => 26,
+            Self::PaymasterNotDeployed => 30,
+            Self::PaymasterDepositTooLow => 31,
+            Self::PaymasterExpired => 32,
+            Self::PaymasterReverted => 33,
+            Self::PaymasterSignature => 34,
+            Self::PaymasterOverVerificationGas => 36,
+            Self::OverVerificationGas => 40,
+            Self::VerificationGasTooLow => 41,
+            Self::PostOpReverted => 50,
+            Self::PrefundBelowActualCost => 51,
+            Self::InvalidBeneficiary => 90,
+            Self::BeneficiaryTransferFailed => 91,
+            Self::InternalCallOnly => 92,
+            Self::InvalidPaymasterAndData => 93,
+            Self::GasValuesOverflow => 94,
+            Self::OutOfGas => 95,
+            Self::InvalidAggregator => 96,
+            Self::Other(code) => *code,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::SenderAlreadyConstructed => "sender already constructed",
+            Self::InitCodeFailed => "initCode failed or ran out of gas",
+            Self::InitCodeWrongSender => "initCode must return sender",
+            Self::InitCodeNoCode => "initCode must create sender",
+            Self::AccountNotDeployed => "account not deployed",
+            Self::PrefundNotPaid => "account didn't pay prefund",
+            Self::AccountExpired => "account signature expired or not due",
+            Self::AccountReverted => "account validation reverted or ran out of gas",
+            Self::AccountSignature => "account signature error",
+            Self::InvalidNonce => "invalid account nonce",
+            Self::AccountOverVerificationGas => "account validation over verificationGasLimit",
+            Self::PaymasterNotDeployed => "paymaster not deployed",
+            Self::PaymasterDepositTooLow => "paymaster deposit too low",
+            Self::PaymasterExpired => "paymaster signature expired or not due",
+            Self::PaymasterReverted => "paymaster validation reverted or ran out of gas",
+            Self::PaymasterSignature => "paymaster signature error",
+            Self::PaymasterOverVerificationGas => "paymaster validation over paymasterVerificationGasLimit",
+            Self::OverVerificationGas => "validation over verificationGasLimit",
+            Self::VerificationGasTooLow => "too little verificationGas to handle postOp",
+            Self::PostOpReverted => "postOp reverted",
+            Self::PrefundBelowActualCost => "prefund below actual gas cost",
+            Self::InvalidBeneficiary => "invalid beneficiary",
+            Self::BeneficiaryTransferFailed => "failed to send refund to beneficiary",
+            Self::InternalCallOnly => "EntryPoint internal call only",
+            Self::InvalidPaymasterAndData => "invalid paymasterAndData",
+            Self::GasValuesOverflow => "gas values overflow",
+            Self::OutOfGas => "bundle ran out of gas",
+            Self::InvalidAggregator => "invalid aggregator",
+            Self::Other(_) => "unknown EntryPoint error",
+        }
+    }
+}
+
+impl std::fmt::Display for AaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AA{:02} {}", self.code(), self.description())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_entry_point_revert_reasons() {
+        assert_eq!(AaError::parse("AA13 initCode failed or OOG"), Some(AaError::InitCodeFailed));
+        assert_eq!(AaError::parse("FailedOp(0, \"AA23 reverted: bad signature\")"), Some(AaError::AccountReverted));
+        assert_eq!(AaError::parse("AA33 reverted (or OOG)"), Some(AaError::PaymasterReverted));
+        assert_eq!(AaError::parse("AA21 didn't pay prefund"), Some(AaError::PrefundNotPaid));
+        assert_eq!(AaError::parse("(code: -32500) AA25 invalid account nonce"), Some(AaError::InvalidNonce));
+        assert_eq!(AaError::parse("AA97 something new"), Some(AaError::Other(97)));
+        assert_eq!(AaError::Other(97).code(), 97);
+    }
+
+    #[test]
+    fn test_ignores_text_without_a_code() {
+        assert_eq!(AaError::parse("execution reverted"), None);
+        assert_eq!(AaError::parse("0xAA2345 is not a code"), None);
+        assert_eq!(AaError::parse("AA2"), None);
+        assert_eq!(AaError::parse(""), None);
+    }
+
+    #[test]
+    fn test_exposed_through_user_op_error() {
+        let err = UserOpError::RPC("AA24 signature error".to_string());
+        assert_eq!(err.aa_error(), Some(AaError::AccountSignature));
+        assert_eq!(err.aa_error().unwrap().to_string(), "AA24 account signature error");
+        assert_eq!(UserOpError::RPC("nonce too low".to_string()).aa_error(), None);
+    }
+}
@@ -1,205 +1,2480 @@
+use dashmap::DashMap;
 use ethers::prelude::*;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+use crate::contracts::EntryPointVersion;
 use crate::error::{Result, UserOpError};
 use crate::userop::UserOperation;
-use crate::cache::{GasCache, RpcCache};
-use crate::retry::{RetryConfig, with_retry};
-use crate::metrics::Timer;
+use crate::cache::{EstimateCache, GasCache, RpcCache};
+use crate::config::Config;
+use crate::retry::{RetryConfig, classify_provider_error, with_retry};
+use crate::metrics::{RpcMethod, Timer};
+use crate::provider::RpcClient;
 
-#[derive(Debug, Clone)]
+/// Percentiles requested from `eth_feeHistory` by default.
+pub const DEFAULT_REWARD_PERCENTILES: [f64; 2] = [10.0, 50.0];
+
+/// Reward percentile used as the priority fee by default.
+pub const DEFAULT_PRIORITY_FEE_PERCENTILE: f64 = 50.0;
+
+/// Call gas used for senders that are not deployed yet.
+pub const DEFAULT_UNDEPLOYED_CALL_GAS_LIMIT: u64 = 200_000;
+
+/// Updates buffered per chain before slow subscribers start lagging.
+pub const GAS_UPDATE_CAPACITY: usize = 16;
+
+/// Fees published to `subscribe_gas_updates` subscribers when the
+/// refresher sees them change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GasUpdate {
+    pub chain_id: u64,
+    pub base_fee: U256,
+    pub priority_fee: U256,
+}
+
+/// Serializes with camelCase keys and hex quantities, the shape bundlers use.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct GasParams {
     pub call_gas_limit: U256,
     pub verification_gas_limit: U256,
     pub pre_verification_gas: U256,
     pub max_fee_per_gas: U256,
     pub max_priority_fee_per_gas: U256,
+    /// EntryPoint v0.7 paymaster limits, when the bundler reports them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_verification_gas_limit: Option<U256>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_post_op_gas_limit: Option<U256>,
+}
+
+impl GasParams {
+    /// Pretty-printed JSON for attaching to bug reports.
+    pub fn to_json(&self) -> String {
+        // Only U256 and Option<U256> fields, which always serialize
+        serde_json::to_string_pretty(self).expect("GasParams serializes to JSON")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json)
+            .map_err(|e| UserOpError::Validation(format!("Invalid GasParams JSON: {}", e)))
+    }
+
+    /// Upper bound on the op's cost in wei at these gas limits, saturating
+    /// at `U256::MAX`.
+    pub fn max_gas_cost(&self) -> U256 {
+        self.total_gas().saturating_mul(self.max_fee_per_gas)
+    }
+
+    /// Every gas limit summed, v0.7 paymaster limits included.
+    fn total_gas(&self) -> U256 {
+        [
+            self.call_gas_limit,
+            self.verification_gas_limit,
+            self.pre_verification_gas,
+            self.paymaster_verification_gas_limit.unwrap_or_default(),
+            self.paymaster_post_op_gas_limit.unwrap_or_default(),
+        ]
+        .into_iter()
+        .fold(U256::zero(), U256::saturating_add)
+    }
+
+    /// Most gas EntryPoint `version` may charge an op with these limits.
+    /// v0.7 and later sum every limit, paymaster ones included; v0.6
+    /// triples the verification gas of sponsored ops to cover postOp.
+    /// Saturates at `U256::MAX`.
+    pub fn max_gas(&self, version: EntryPointVersion, has_paymaster: bool) -> U256 {
+        match version {
+            EntryPointVersion::V06 => {
+                let verification_multiplier = if has_paymaster { 3 } else { 1 };
+                self.call_gas_limit
+                    .saturating_add(self.verification_gas_limit.saturating_mul(U256::from(verification_multiplier)))
+                    .saturating_add(self.pre_verification_gas)
+            }
+            EntryPointVersion::V07 | EntryPointVersion::V08 => self.total_gas(),
+        }
+    }
+
+    /// `requiredPreFund` as EntryPoint `version` computes it: `max_gas`
+    /// times `max_fee_per_gas`, saturating at `U256::MAX`.
+    pub fn required_prefund(&self, version: EntryPointVersion, has_paymaster: bool) -> U256 {
+        self.max_gas(version, has_paymaster).saturating_mul(self.max_fee_per_gas)
+    }
+}
+
+pub struct ChainProviders<P = RpcClient> {
+    pub ethereum: Provider<P>,
+    pub polygon: Provider<P>,
+    pub arbitrum: Provider<P>,
+}
+
+/// Where the priority fee for a chain is sourced from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GasOracle {
+    /// Reward percentiles from `eth_feeHistory`, falling back to
+    /// `eth_maxPriorityFeePerGas` when rewards are empty or zero.
+    #[default]
+    FeeHistory,
+    /// `eth_maxPriorityFeePerGas` directly, for chains where the node's
+    /// suggestion tracks inclusion better than recent rewards.
+    MaxPriorityFee,
+}
+
+/// How gas is priced for a chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GasStrategy {
+    Ethereum,
+    /// Ethereum fees with a doubled call gas limit.
+    Polygon,
+    /// `eth_gasPrice` with no priority fee.
+    Arbitrum,
+    /// Any other chain with a configured provider: `eth_feeHistory`, falling
+    /// back to `eth_gasPrice` on chains without EIP-1559.
+    Generic,
+    /// `eth_gasPrice` as both fees, never calling `eth_feeHistory`. Only
+    /// selected through `GasEstimator::set_gas_strategy`.
+    Legacy,
+}
+
+/// `postOp` gas given to paymaster-sponsored ops when neither the bundler
+/// nor the chain's defaults supply one.
+pub const DEFAULT_PAYMASTER_POST_OP_GAS_LIMIT: u64 = 50_000;
+
+/// Fixed verification and pre-verification gas applied per chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GasDefaults {
+    pub verification_gas_limit: U256,
+    pub pre_verification_gas: U256,
+    /// Applied to ops with a paymaster attached.
+    pub paymaster_post_op_gas_limit: U256,
+}
+
+impl GasDefaults {
+    pub fn new(verification_gas_limit: u64, pre_verification_gas: u64) -> Self {
+        Self {
+            verification_gas_limit: U256::from(verification_gas_limit),
+            pre_verification_gas: U256::from(pre_verification_gas),
+            paymaster_post_op_gas_limit: U256::from(DEFAULT_PAYMASTER_POST_OP_GAS_LIMIT),
+        }
+    }
+
+    pub fn with_paymaster_post_op_gas_limit(mut self, gas_limit: u64) -> Self {
+        self.paymaster_post_op_gas_limit = U256::from(gas_limit);
+        self
+    }
+
+    /// Built-in defaults used when a chain has no configured override.
+    pub fn for_chain(chain_id: u64) -> Self {
+        match chain_id {
+            137 => Self::new(200000, 40000),
+            42161 => Self::new(150000, 50000),
+            _ => Self::new(100000, 21000),
+        }
+    }
+}
+
+/// Minimum priority fee a chain needs for ops to be included, if any.
+/// Polygon rejects transactions tipping under ~30 gwei.
+pub fn default_min_priority_fee(chain_id: u64) -> U256 {
+    match chain_id {
+        137 => U256::from(30_000_000_000u64),
+        _ => U256::zero(),
+    }
+}
+
+/// Most blocks a single `eth_feeHistory` call may request.
+pub const MAX_FEE_HISTORY_BLOCKS: u64 = 1024;
+
+/// Blocks of fee history sampled per estimate. Chains with short block
+/// times sample more blocks to smooth out per-block noise.
+pub fn default_fee_history_blocks(chain_id: u64) -> u64 {
+    match chain_id {
+        137 => 10,
+        42161 => 20,
+        _ => 4,
+    }
+}
+
+/// Raises the priority fee to `floor`, bumping the max fee by the same amount.
+pub fn apply_priority_fee_floor(params: &mut GasParams, floor: U256) {
+    if params.max_priority_fee_per_gas < floor {
+        let bump = floor - params.max_priority_fee_per_gas;
+        params.max_priority_fee_per_gas = floor;
+        params.max_fee_per_gas = params.max_fee_per_gas.saturating_add(bump);
+    }
+}
+
+/// Block that fee history and gas estimates are taken against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockTag {
+    #[default]
+    Latest,
+    /// Fresher base fees during congestion, for ops about to be submitted.
+    Pending,
+    Safe,
+}
+
+impl From<BlockTag> for BlockNumber {
+    fn from(tag: BlockTag) -> Self {
+        match tag {
+            BlockTag::Latest => BlockNumber::Latest,
+            BlockTag::Pending => BlockNumber::Pending,
+            BlockTag::Safe => BlockNumber::Safe,
+        }
+    }
+}
+
+impl BlockTag {
+    /// Block argument for `eth_estimateGas`. `Latest` is the node default,
+    /// so it is left off the request.
+    fn estimate_block(self) -> Option<BlockId> {
+        match self {
+            BlockTag::Latest => None,
+            tag => Some(BlockNumber::from(tag).into()),
+        }
+    }
+}
+
+/// What to do when an estimate's `max_fee_per_gas` exceeds the chain's cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CapBehavior {
+    /// Lower the max fee to the cap and record a warning metric.
+    #[default]
+    Clamp,
+    /// Reject the estimate with `UserOpError::GasEstimation`.
+    Fail,
+}
+
+/// What estimating does when an op's `execute`/`executeBatch` calldata
+/// calls a target that has no code. Plain value transfers, with empty inner
+/// calldata, are never checked since sending to an EOA is valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CallTargetCheck {
+    /// Don't look up the targets.
+    #[default]
+    Off,
+    /// Log a warning and estimate anyway.
+    Warn,
+    /// Reject the op with `UserOpError::Validation`.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeCap {
+    pub max_fee_per_gas: U256,
+    pub behavior: CapBehavior,
+}
+
+/// Raises `fee` by `percent`, rounding up so small fees still move and
+/// saturating at `U256::MAX`.
+pub fn bump_fee(fee: U256, percent: u64) -> U256 {
+    let increase = fee.saturating_mul(U256::from(percent)).saturating_add(U256::from(99)) / 100;
+    fee.saturating_add(increase)
+}
+
+/// Enforces `cap` on `params`, clamping or failing per its behavior.
+pub fn apply_fee_cap(chain_id: u64, params: &mut GasParams, cap: &FeeCap) -> Result<()> {
+    if params.max_fee_per_gas <= cap.max_fee_per_gas {
+        return Ok(());
+    }
+
+    match cap.behavior {
+        CapBehavior::Clamp => {
+            warn!(
+                "Clamping max fee {} to cap {} on chain {}",
+                params.max_fee_per_gas, cap.max_fee_per_gas, chain_id
+            );
+            crate::metrics::Metrics::record_fee_cap_clamped(chain_id);
+            params.max_fee_per_gas = cap.max_fee_per_gas;
+            params.max_priority_fee_per_gas = params.max_priority_fee_per_gas.min(cap.max_fee_per_gas);
+            Ok(())
+        }
+        CapBehavior::Fail => Err(UserOpError::GasEstimation(format!(
+            "Max fee {} exceeds cap {} on chain {}",
+            params.max_fee_per_gas, cap.max_fee_per_gas, chain_id
+        ))),
+    }
 }
 
-pub struct ChainProviders {
-    pub ethereum: Provider<Http>,
-    pub polygon: Provider<Http>,
-    pub arbitrum: Provider<Http>,
+/// Picks the chain whose estimate has the lowest max cost once converted with
+/// `native_prices` (price of one native token, keyed by chain id). Chains that
+/// errored or have no price are skipped.
+pub fn cheapest_estimate(
+    estimates: HashMap<u64, Result<GasParams>>,
+    native_prices: &HashMap<u64, f64>,
+) -> Option<(u64, GasParams)> {
+    estimates
+        .into_iter()
+        .filter_map(|(chain_id, estimate)| {
+            let params = estimate.ok()?;
+            let price = native_prices.get(&chain_id)?;
+            let cost = ethers::utils::format_units(params.max_gas_cost(), "ether")
+                .ok()?
+                .parse::<f64>()
+                .ok()?
+                * price;
+            Some((chain_id, params, cost))
+        })
+        .min_by(|(_, _, a), (_, _, b)| a.total_cmp(b))
+        .map(|(chain_id, params, _)| (chain_id, params))
+}
+
+/// Hashes the op fields that affect its gas estimate, so structurally
+/// identical ops can share one estimate. Gas limits, fees, nonce and
+/// signature are left out; the access list and EIP-7702 authorization,
+/// which change what the estimate runs, are kept.
+pub fn estimate_fingerprint(user_op: &UserOperation) -> H256 {
+    let encoded = ethers::abi::encode(&[
+        ethers::abi::Token::Address(user_op.sender),
+        ethers::abi::Token::FixedBytes(ethers::utils::keccak256(&user_op.init_code).to_vec()),
+        ethers::abi::Token::FixedBytes(ethers::utils::keccak256(&user_op.call_data).to_vec()),
+        ethers::abi::Token::FixedBytes(ethers::utils::keccak256(&user_op.paymaster_and_data).to_vec()),
+        ethers::abi::Token::FixedBytes(
+            ethers::utils::keccak256(serde_json::to_vec(&user_op.access_list).unwrap_or_default()).to_vec(),
+        ),
+        ethers::abi::Token::FixedBytes(
+            ethers::utils::keccak256(serde_json::to_vec(&user_op.eip7702_auth).unwrap_or_default()).to_vec(),
+        ),
+    ]);
+    ethers::utils::keccak256(encoded).into()
 }
 
-pub struct GasEstimator {
-    providers: Arc<ChainProviders>,
+pub struct GasEstimator<P = RpcClient> {
+    providers: Arc<ChainProviders<P>>,
     gas_cache: Arc<GasCache>,
     rpc_cache: Arc<RpcCache>,
     retry_config: RetryConfig,
+    reward_percentiles: Vec<f64>,
+    priority_fee_percentile: f64,
+    gas_oracles: HashMap<u64, GasOracle>,
+    gas_defaults: HashMap<u64, GasDefaults>,
+    min_priority_fees: HashMap<u64, U256>,
+    fee_history_blocks: HashMap<u64, u64>,
+    fee_caps: HashMap<u64, FeeCap>,
+    block_tag: BlockTag,
+    estimate_cache: EstimateCache,
+    undeployed_call_gas_limit: U256,
+    check_sender_code: bool,
+    call_target_check: CallTargetCheck,
+    entry_point_version: EntryPointVersion,
+    caching_enabled: bool,
+    generic_providers: HashMap<u64, Provider<P>>,
+    gas_updates: DashMap<u64, broadcast::Sender<GasUpdate>>,
+    strategy_overrides: DashMap<u64, GasStrategy>,
+}
+
+/// Builds a `GasEstimator`. Only `providers` is required; the caches and
+/// retry policy default to fresh instances.
+pub struct GasEstimatorBuilder<P = RpcClient> {
+    providers: Option<Arc<ChainProviders<P>>>,
+    gas_cache: Option<Arc<GasCache>>,
+    rpc_cache: Option<Arc<RpcCache>>,
+    retry_config: Option<RetryConfig>,
+    gas_oracles: HashMap<u64, GasOracle>,
+    gas_defaults: HashMap<u64, GasDefaults>,
+    generic_providers: HashMap<u64, Provider<P>>,
+    config: Option<Config>,
+}
+
+impl<P: JsonRpcClient> Default for GasEstimatorBuilder<P> {
+    fn default() -> Self {
+        Self {
+            providers: None,
+            gas_cache: None,
+            rpc_cache: None,
+            retry_config: None,
+            gas_oracles: HashMap::new(),
+            gas_defaults: HashMap::new(),
+            generic_providers: HashMap::new(),
+            config: None,
+        }
+    }
+}
+
+impl GasEstimatorBuilder<RpcClient> {
+    /// Applies `config` as `GasEstimator::with_config` does, and prices each
+    /// configured chain outside the built-in set with `GasStrategy::Generic`
+    /// over its configured RPC URL.
+    pub fn with_config(mut self, config: &Config) -> Result<Self> {
+        for chain_id in config.chains.keys() {
+            if !matches!(chain_id, 1 | 137 | 42161) {
+                self.generic_providers.insert(*chain_id, config.get_provider(*chain_id)?);
+            }
+        }
+        self.config = Some(config.clone());
+        Ok(self)
+    }
+}
+
+impl<P: JsonRpcClient> GasEstimatorBuilder<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn providers(mut self, providers: Arc<ChainProviders<P>>) -> Self {
+        self.providers = Some(providers);
+        self
+    }
+
+    pub fn gas_cache(mut self, gas_cache: Arc<GasCache>) -> Self {
+        self.gas_cache = Some(gas_cache);
+        self
+    }
+
+    pub fn rpc_cache(mut self, rpc_cache: Arc<RpcCache>) -> Self {
+        self.rpc_cache = Some(rpc_cache);
+        self
+    }
+
+    pub fn retry_config(mut self, retry_config: RetryConfig) -> Self {
+        self.retry_config = Some(retry_config);
+        self
+    }
+
+    /// Sets the priority fee source for a chain.
+    pub fn oracle(mut self, chain_id: u64, oracle: GasOracle) -> Self {
+        self.gas_oracles.insert(chain_id, oracle);
+        self
+    }
+
+    /// Sets the verification and pre-verification gas buffers for a chain.
+    pub fn buffers(mut self, chain_id: u64, defaults: GasDefaults) -> Self {
+        self.gas_defaults.insert(chain_id, defaults);
+        self
+    }
+
+    /// Adds a chain outside the built-in set, priced with `GasStrategy::Generic`.
+    pub fn generic_chain(mut self, chain_id: u64, provider: Provider<P>) -> Self {
+        self.generic_providers.insert(chain_id, provider);
+        self
+    }
+
+    pub fn build(mut self) -> Result<GasEstimator<P>> {
+        let providers = self.providers
+            .take()
+            .ok_or_else(|| UserOpError::Config("GasEstimator requires chain providers".to_string()))?;
+        Ok(self.assemble(providers))
+    }
+
+    fn assemble(mut self, providers: Arc<ChainProviders<P>>) -> GasEstimator<P> {
+        let config = self.config.take();
+        let estimator = GasEstimator {
+            providers,
+            gas_cache: self.gas_cache.unwrap_or_else(|| Arc::new(GasCache::new())),
+            rpc_cache: self.rpc_cache.unwrap_or_else(|| Arc::new(RpcCache::new())),
+            retry_config: self.retry_config.unwrap_or_default(),
+            reward_percentiles: DEFAULT_REWARD_PERCENTILES.to_vec(),
+            priority_fee_percentile: DEFAULT_PRIORITY_FEE_PERCENTILE,
+            gas_oracles: self.gas_oracles,
+            gas_defaults: self.gas_defaults,
+            min_priority_fees: HashMap::new(),
+            fee_history_blocks: HashMap::new(),
+            fee_caps: HashMap::new(),
+            block_tag: BlockTag::default(),
+            estimate_cache: EstimateCache::new(),
+            undeployed_call_gas_limit: U256::from(DEFAULT_UNDEPLOYED_CALL_GAS_LIMIT),
+            check_sender_code: false,
+            call_target_check: CallTargetCheck::Off,
+            entry_point_version: EntryPointVersion::V06,
+            caching_enabled: true,
+            generic_providers: self.generic_providers,
+            gas_updates: DashMap::new(),
+            strategy_overrides: DashMap::new(),
+        };
+        match config {
+            Some(config) => estimator.with_config(&config),
+            None => estimator,
+        }
+    }
+}
+
+/// The targets `call_data` calls with calldata of their own through the
+/// wallet's `execute` or `executeBatch`. Anything else, including plain
+/// value transfers, has none.
+pub fn contract_call_targets(call_data: &[u8]) -> Vec<Address> {
+    use ethers::abi::AbiDecode;
+    use crate::contracts::ISmartWalletCalls;
+
+    match ISmartWalletCalls::decode(call_data) {
+        Ok(ISmartWalletCalls::Execute(call)) if !call.data.is_empty() => vec![call.target],
+        Ok(ISmartWalletCalls::ExecuteBatch(call)) => call
+            .dest
+            .into_iter()
+            .zip(call.func)
+            .filter(|(_, func)| !func.is_empty())
+            .map(|(dest, _)| dest)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the position of `target` in the requested reward percentiles.
+pub fn reward_index(percentiles: &[f64], target: f64) -> Option<usize> {
+    percentiles.iter().position(|p| (p - target).abs() < f64::EPSILON)
 }
 
-impl GasEstimator {
+/// Picks the priority fee for `target` out of the latest block's rewards.
+/// Providers sometimes return fewer rewards than requested, so this falls
+/// back to the last reward available and only returns `None` when the
+/// latest block has no rewards at all.
+pub fn priority_fee_from_history(
+    fee_history: &FeeHistory,
+    percentiles: &[f64],
+    target: f64,
+) -> Option<U256> {
+    let rewards = fee_history.reward.last()?;
+    reward_index(percentiles, target)
+        .and_then(|index| rewards.get(index))
+        .or_else(|| rewards.last())
+        .copied()
+}
+
+impl<P: JsonRpcClient> GasEstimator<P> {
     pub fn new(
-        providers: Arc<ChainProviders>,
+        providers: Arc<ChainProviders<P>>,
         gas_cache: Arc<GasCache>,
         rpc_cache: Arc<RpcCache>,
         retry_config: RetryConfig,
     ) -> Self {
-        Self {
-            providers,
-            gas_cache,
-            rpc_cache,
-            retry_config,
+        GasEstimatorBuilder::new()
+            .gas_cache(gas_cache)
+            .rpc_cache(rpc_cache)
+            .retry_config(retry_config)
+            .assemble(providers)
+    }
+
+    pub fn builder() -> GasEstimatorBuilder<P> {
+        GasEstimatorBuilder::new()
+    }
+
+    pub fn gas_cache(&self) -> &Arc<GasCache> {
+        &self.gas_cache
+    }
+
+    pub fn rpc_cache(&self) -> &Arc<RpcCache> {
+        &self.rpc_cache
+    }
+
+    /// Call gas limit used when the sender has no code yet, since
+    /// `eth_estimateGas` against an empty account says nothing about the call.
+    pub fn with_undeployed_call_gas_limit(mut self, limit: U256) -> Self {
+        self.undeployed_call_gas_limit = limit;
+        self
+    }
+
+    /// Checks the sender's code with `eth_getCode` before estimating, so
+    /// counterfactual wallets without `init_code` set still get the
+    /// undeployed call gas limit. Ops carrying `init_code` always do.
+    pub fn with_sender_code_check(mut self, enabled: bool) -> Self {
+        self.check_sender_code = enabled;
+        self
+    }
+
+    /// Checks with `eth_getCode` that the targets an op's `execute` calldata
+    /// calls are contracts before estimating. Off by default.
+    pub fn with_call_target_check(mut self, check: CallTargetCheck) -> Self {
+        self.call_target_check = check;
+        self
+    }
+
+    /// The EntryPoint release estimates are for. From v0.7 on, sponsored
+    /// ops get a default paymaster postOp gas limit when none is estimated;
+    /// v0.6 has no separate limit for it. Defaults to v0.6.
+    pub fn with_entry_point_version(mut self, version: EntryPointVersion) -> Self {
+        self.entry_point_version = version;
+        self
+    }
+
+    /// With caching disabled every estimate fetches fresh fee data and gas
+    /// limits: neither the gas price cache nor the estimate cache is read
+    /// or written. Lookups that would have gone to a cache are counted as
+    /// bypasses rather than misses.
+    pub fn with_caching(mut self, enabled: bool) -> Self {
+        self.caching_enabled = enabled;
+        self
+    }
+
+    /// Adds a chain outside the built-in set, priced with `GasStrategy::Generic`.
+    pub fn with_generic_chain(mut self, chain_id: u64, provider: Provider<P>) -> Self {
+        self.generic_providers.insert(chain_id, provider);
+        self
+    }
+
+    /// Switches `chain_id` to `strategy` without rebuilding the estimator,
+    /// e.g. to `GasStrategy::Legacy` when a provider stops serving fee
+    /// history. Each estimate reads the strategy once, so in-flight
+    /// estimates finish on the one they started with. The chain-specific
+    /// strategies can only be set on their own chain. The chain's cached
+    /// fees are dropped, since each strategy caches them in its own shape.
+    pub async fn set_gas_strategy(&self, chain_id: u64, strategy: GasStrategy) -> Result<()> {
+        let home_chain = match strategy {
+            GasStrategy::Ethereum => Some(1),
+            GasStrategy::Polygon => Some(137),
+            GasStrategy::Arbitrum => Some(42161),
+            GasStrategy::Generic | GasStrategy::Legacy => None,
+        };
+        if home_chain.is_some_and(|home_chain| home_chain != chain_id) {
+            return Err(UserOpError::Config(format!(
+                "{:?} gas strategy cannot price chain {}",
+                strategy, chain_id
+            )));
         }
+        // Fails for chains without a provider
+        self.gas_strategy(chain_id)?;
+
+        self.strategy_overrides.insert(chain_id, strategy);
+        self.gas_cache.invalidate_fees(chain_id).await;
+        Ok(())
     }
 
-    pub async fn estimate_gas(&self, user_op: &UserOperation, chain_id: u64) -> Result<GasParams> {
-        let timer = Timer::new();
-        
-        let result = match chain_id {
-            1 => self.estimate_ethereum_gas(user_op).await,
-            137 => self.estimate_polygon_gas(user_op).await,
-            42161 => self.estimate_arbitrum_gas(user_op).await,
+    /// Drops a `set_gas_strategy` override, returning `chain_id` to its
+    /// default strategy along with its cached fees.
+    pub async fn clear_gas_strategy(&self, chain_id: u64) {
+        self.strategy_overrides.remove(&chain_id);
+        self.gas_cache.invalidate_fees(chain_id).await;
+    }
+
+    /// Picks the pricing strategy for `chain_id`: its runtime override if
+    /// one is set, else the default. Only chains that are neither built in
+    /// nor configured are unsupported.
+    pub fn gas_strategy(&self, chain_id: u64) -> Result<GasStrategy> {
+        if let Some(strategy) = self.strategy_overrides.get(&chain_id) {
+            return Ok(*strategy);
+        }
+        match chain_id {
+            1 => Ok(GasStrategy::Ethereum),
+            137 => Ok(GasStrategy::Polygon),
+            42161 => Ok(GasStrategy::Arbitrum),
+            _ if self.generic_providers.contains_key(&chain_id) => Ok(GasStrategy::Generic),
             _ => Err(UserOpError::UnsupportedChain(chain_id.to_string())),
+        }
+    }
+
+    /// The provider for `chain_id`, or `provider_override` when one is given.
+    fn provider<'a>(&'a self, chain_id: u64, provider_override: Option<&'a Provider<P>>) -> Result<&'a Provider<P>> {
+        if let Some(provider) = provider_override {
+            return Ok(provider);
+        }
+        match chain_id {
+            1 => Ok(&self.providers.ethereum),
+            137 => Ok(&self.providers.polygon),
+            42161 => Ok(&self.providers.arbitrum),
+            _ => self.generic_providers
+                .get(&chain_id)
+                .ok_or_else(|| UserOpError::UnsupportedChain(chain_id.to_string())),
+        }
+    }
+
+    /// Sets the block fee history and gas estimates are taken against.
+    pub fn with_block_tag(mut self, block_tag: BlockTag) -> Self {
+        self.block_tag = block_tag;
+        self
+    }
+
+    /// Caps `max_fee_per_gas` for a chain to avoid overpaying during spikes.
+    pub fn with_fee_cap(mut self, chain_id: u64, cap: FeeCap) -> Self {
+        self.fee_caps.insert(chain_id, cap);
+        self
+    }
+
+    /// Overrides the verification and pre-verification gas for a chain.
+    pub fn with_gas_defaults(mut self, chain_id: u64, defaults: GasDefaults) -> Self {
+        self.gas_defaults.insert(chain_id, defaults);
+        self
+    }
+
+    /// Sets the minimum priority fee applied to every estimate for a chain.
+    pub fn with_min_priority_fee(mut self, chain_id: u64, floor: U256) -> Self {
+        self.min_priority_fees.insert(chain_id, floor);
+        self
+    }
+
+    /// Sets how many blocks of fee history are sampled for a chain.
+    pub fn with_fee_history_blocks(mut self, chain_id: u64, blocks: u64) -> Self {
+        self.fee_history_blocks.insert(chain_id, blocks);
+        self
+    }
+
+    /// Applies the gas defaults, priority fee floors and fee history windows
+    /// of every chain in `config`.
+    pub fn with_config(mut self, config: &Config) -> Self {
+        for (chain_id, chain_config) in &config.chains {
+            self.gas_defaults.insert(*chain_id, chain_config.gas_defaults());
+            if let Some(floor) = chain_config.min_priority_fee {
+                self.min_priority_fees.insert(*chain_id, U256::from(floor));
+            }
+            if let Some(blocks) = chain_config.fee_history_blocks {
+                self.fee_history_blocks.insert(*chain_id, blocks);
+            }
+            if let Some(cap) = chain_config.max_fee_cap {
+                self.fee_caps.insert(*chain_id, FeeCap {
+                    max_fee_per_gas: U256::from(cap),
+                    behavior: chain_config.cap_behavior,
+                });
+            }
+        }
+        self
+    }
+
+    pub fn min_priority_fee(&self, chain_id: u64) -> U256 {
+        self.min_priority_fees
+            .get(&chain_id)
+            .copied()
+            .unwrap_or_else(|| default_min_priority_fee(chain_id))
+    }
+
+    pub fn fee_history_blocks(&self, chain_id: u64) -> u64 {
+        self.fee_history_blocks
+            .get(&chain_id)
+            .copied()
+            .unwrap_or_else(|| default_fee_history_blocks(chain_id))
+    }
+
+    pub fn gas_defaults(&self, chain_id: u64) -> GasDefaults {
+        self.gas_defaults
+            .get(&chain_id)
+            .copied()
+            .unwrap_or_else(|| GasDefaults::for_chain(chain_id))
+    }
+
+    /// Sets the priority fee source for a chain.
+    pub fn with_gas_oracle(mut self, chain_id: u64, oracle: GasOracle) -> Self {
+        self.gas_oracles.insert(chain_id, oracle);
+        self
+    }
+
+    pub fn gas_oracle(&self, chain_id: u64) -> GasOracle {
+        self.gas_oracles.get(&chain_id).copied().unwrap_or_default()
+    }
+
+    /// Sets the fee history reward percentile used as the priority fee.
+    /// The percentile is added to the requested set if it isn't already there.
+    pub fn with_priority_fee_percentile(mut self, percentile: f64) -> Self {
+        if reward_index(&self.reward_percentiles, percentile).is_none() {
+            self.reward_percentiles.push(percentile);
+            self.reward_percentiles.sort_by(|a, b| a.total_cmp(b));
+        }
+        self.priority_fee_percentile = percentile;
+        self
+    }
+
+    /// Estimates gas for `user_op` on `chain_id`. `retry_config` overrides
+    /// the estimator's retry policy for this call only.
+    pub async fn estimate_gas(
+        &self,
+        user_op: &UserOperation,
+        chain_id: u64,
+        retry_config: Option<&RetryConfig>,
+    ) -> Result<GasParams> {
+        self.estimate_gas_via(user_op, chain_id, None, retry_config).await
+    }
+
+    /// Like `estimate_gas`, but every RPC goes to `provider` instead of the
+    /// configured one. Chains outside the built-in set are priced with
+    /// `GasStrategy::Generic` even if they aren't configured.
+    pub async fn estimate_gas_with_provider(
+        &self,
+        user_op: &UserOperation,
+        chain_id: u64,
+        provider: &Provider<P>,
+        retry_config: Option<&RetryConfig>,
+    ) -> Result<GasParams> {
+        self.estimate_gas_via(user_op, chain_id, Some(provider), retry_config).await
+    }
+
+    async fn estimate_gas_via(
+        &self,
+        user_op: &UserOperation,
+        chain_id: u64,
+        provider_override: Option<&Provider<P>>,
+        retry_config: Option<&RetryConfig>,
+    ) -> Result<GasParams> {
+        let timer = Timer::new();
+        let retry_config = retry_config.unwrap_or(&self.retry_config);
+
+        // A one-off provider may not agree with the configured one, so its
+        // estimates are neither served from nor added to the estimate cache
+        let use_estimate_cache = self.caching_enabled && provider_override.is_none();
+        let fingerprint = estimate_fingerprint(user_op);
+        if use_estimate_cache {
+            if let Some(params) = self.estimate_cache.get(chain_id, fingerprint).await {
+                crate::metrics::Metrics::record_cache_hit("gas_estimate");
+                return Ok(params);
+            }
+        }
+        if provider_override.is_none() {
+            self.record_cache_miss("gas_estimate");
+        }
+
+        let result = self.estimate_uncached(user_op, chain_id, provider_override, retry_config).await;
+        // Labelled by outcome and by whether gas prices came from the cache
+        let cache_hit = matches!(result, Ok((_, true)));
+        crate::metrics::Metrics::record_gas_estimation(chain_id, timer.elapsed(), cache_hit, result.is_ok());
+        let (params, _) = result?;
+
+        if use_estimate_cache {
+            self.estimate_cache.insert(chain_id, fingerprint, params.clone()).await;
+        }
+        Ok(params)
+    }
+
+    /// Prices and limits `user_op` on its chain's strategy, returning whether
+    /// the gas prices came from the cache.
+    async fn estimate_uncached(
+        &self,
+        user_op: &UserOperation,
+        chain_id: u64,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(GasParams, bool)> {
+        let strategy = match self.gas_strategy(chain_id) {
+            Err(UserOpError::UnsupportedChain(_)) if provider_override.is_some() => GasStrategy::Generic,
+            strategy => strategy?,
+        };
+        let (mut params, cache_hit) = match strategy {
+            GasStrategy::Ethereum => self.estimate_ethereum_gas(user_op, provider_override, retry_config).await?,
+            GasStrategy::Polygon => self.estimate_polygon_gas(user_op, provider_override, retry_config).await?,
+            GasStrategy::Arbitrum => self.estimate_arbitrum_gas(user_op, provider_override, retry_config).await?,
+            GasStrategy::Generic => self.estimate_eip1559_gas(chain_id, user_op, provider_override, retry_config).await?,
+            GasStrategy::Legacy => self.estimate_legacy_gas(chain_id, user_op, provider_override, retry_config).await?,
         };
 
-        // Record metrics
-        crate::metrics::Metrics::record_gas_estimation(chain_id, timer.elapsed());
-        
-        result
+        if self.entry_point_version != EntryPointVersion::V06
+            && user_op.paymaster().is_some()
+            && params.paymaster_post_op_gas_limit.is_none()
+        {
+            params.paymaster_post_op_gas_limit = Some(self.gas_defaults(chain_id).paymaster_post_op_gas_limit);
+        }
+
+        apply_priority_fee_floor(&mut params, self.min_priority_fee(chain_id));
+        if let Some(cap) = self.fee_caps.get(&chain_id) {
+            apply_fee_cap(chain_id, &mut params, cap)?;
+        }
+        Ok((params, cache_hit))
     }
 
-    async fn estimate_ethereum_gas(&self, user_op: &UserOperation) -> Result<GasParams> {
-        let chain_id = 1;
-        
-        // Check cache for gas prices
+    /// Estimates `user_op` on every chain in `chain_ids` concurrently. Each
+    /// chain goes through its own rate limiter bucket.
+    pub async fn estimate_gas_multi(
+        &self,
+        user_op: &UserOperation,
+        chain_ids: &[u64],
+        retry_config: Option<&RetryConfig>,
+    ) -> HashMap<u64, Result<GasParams>> {
+        let estimates = join_all(chain_ids.iter().map(|&chain_id| async move {
+            (chain_id, self.estimate_gas(user_op, chain_id, retry_config).await)
+        })).await;
+
+        estimates.into_iter().collect()
+    }
+
+    /// Estimates `user_op` on each candidate chain and returns the one where
+    /// it is cheapest in the currency of `native_prices`.
+    pub async fn select_cheapest_chain(
+        &self,
+        user_op: &UserOperation,
+        chain_ids: &[u64],
+        native_prices: &HashMap<u64, f64>,
+        retry_config: Option<&RetryConfig>,
+    ) -> Result<(u64, GasParams)> {
+        let estimates = self.estimate_gas_multi(user_op, chain_ids, retry_config).await;
+        cheapest_estimate(estimates, native_prices).ok_or_else(|| {
+            UserOpError::GasEstimation(format!("No priced estimate for chains {:?}", chain_ids))
+        })
+    }
+
+    async fn estimate_ethereum_gas(
+        &self,
+        user_op: &UserOperation,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(GasParams, bool)> {
+        self.estimate_eip1559_gas(1, user_op, provider_override, retry_config).await
+    }
+
+    async fn cached_base_fee(&self, chain_id: u64) -> Option<U256> {
+        if !self.caching_enabled {
+            return None;
+        }
+        self.gas_cache.get_base_fee(chain_id).await
+    }
+
+    async fn cached_priority_fee(&self, chain_id: u64) -> Option<U256> {
+        if !self.caching_enabled {
+            return None;
+        }
+        self.gas_cache.get_priority_fee(chain_id).await
+    }
+
+    /// Counts a lookup in `cache_type` that found nothing, or that was never
+    /// made because caching is disabled.
+    fn record_cache_miss(&self, cache_type: &str) {
+        if self.caching_enabled {
+            crate::metrics::Metrics::record_cache_miss(cache_type);
+        } else {
+            crate::metrics::Metrics::record_cache_bypass(cache_type);
+        }
+    }
+
+    async fn estimate_eip1559_gas(
+        &self,
+        chain_id: u64,
+        user_op: &UserOperation,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(GasParams, bool)> {
+        let (base_fee, priority_fee, cache_hit) = self.eip1559_fees(chain_id, provider_override, retry_config).await?;
+
+        let call_gas_limit = self.estimate_call_gas_limit(chain_id, user_op, provider_override, retry_config).await?;
+
+        Ok((GasParams {
+            call_gas_limit,
+            verification_gas_limit: self.gas_defaults(chain_id).verification_gas_limit,
+            pre_verification_gas: self.gas_defaults(chain_id).pre_verification_gas,
+            max_fee_per_gas: base_fee + priority_fee,
+            max_priority_fee_per_gas: priority_fee,
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        }, cache_hit))
+    }
+
+    /// Base and priority fee for `chain_id`, from the cache when both are
+    /// fresh. The flag is whether they came from the cache.
+    async fn eip1559_fees(
+        &self,
+        chain_id: u64,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(U256, U256, bool)> {
         if let (Some(base_fee), Some(priority_fee)) = (
-            self.gas_cache.get_base_fee(chain_id).await,
-            self.gas_cache.get_priority_fee(chain_id).await,
+            self.cached_base_fee(chain_id).await,
+            self.cached_priority_fee(chain_id).await,
         ) {
             crate::metrics::Metrics::record_cache_hit("gas_prices");
-            
-            // Still need to estimate call gas limit
-            let call_gas_limit = self.estimate_call_gas_limit(chain_id, user_op).await?;
-            
-            return Ok(GasParams {
-                call_gas_limit,
-                verification_gas_limit: U256::from(100000),
-                pre_verification_gas: U256::from(21000),
-                max_fee_per_gas: base_fee + priority_fee,
-                max_priority_fee_per_gas: priority_fee,
-            });
+            return Ok((base_fee, priority_fee, true));
         }
 
-        crate::metrics::Metrics::record_cache_miss("gas_prices");
+        self.record_cache_miss("gas_prices");
+
+        // Only Ethereum itself is known to support EIP-1559 here
+        let (base_fee, priority_fee) = match chain_id {
+            1 => self.fetch_eip1559_fees(chain_id, provider_override, retry_config).await?,
+            _ => self.fetch_generic_fees(chain_id, provider_override, retry_config).await?,
+        };
+        Ok((base_fee, priority_fee, false))
+    }
 
+    /// Fetches fresh base and priority fees and caches them for `chain_id`.
+    async fn fetch_eip1559_fees(
+        &self,
+        chain_id: u64,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(U256, U256)> {
         // Get fresh gas prices with retry
-        let provider = &self.providers.ethereum;
+        let provider = self.provider(chain_id, provider_override)?;
+        let percentiles = &self.reward_percentiles;
+        let block: BlockNumber = self.block_tag.into();
+        let block_count = self.fee_history_blocks(chain_id);
         let fee_history = with_retry(
             chain_id,
+            RpcMethod::FeeHistory,
             || async {
                 provider
-                    .fee_history(4, BlockNumber::Latest, &[10.0, 50.0])
+                    .fee_history(block_count, block, percentiles)
                     .await
-                    .map_err(|e| UserOpError::GasEstimation(e.to_string()))
+                    .map_err(|e| classify_provider_error(e, UserOpError::GasEstimation))
             },
-            &self.retry_config,
+            retry_config,
         ).await?;
 
-        let base_fee = fee_history.base_fee_per_gas.last()
+        let base_fee = *fee_history.base_fee_per_gas.last()
             .ok_or_else(|| UserOpError::GasEstimation("No base fee available".into()))?;
 
-        let priority_fee = fee_history.reward
-            .last()
-            .and_then(|r| r.get(1))
-            .ok_or_else(|| UserOpError::GasEstimation("No priority fee available".into()))?;
-
-        // Cache the new values
-        self.gas_cache.set_base_fee(chain_id, *base_fee).await;
-        self.gas_cache.set_priority_fee(chain_id, *priority_fee).await;
+        // Some providers return empty or zeroed reward arrays; fall back to the node's suggestion
+        let priority_fee = match self.gas_oracle(chain_id) {
+            GasOracle::MaxPriorityFee => self.fetch_max_priority_fee(chain_id, provider, retry_config).await?,
+            GasOracle::FeeHistory => {
+                let from_history = priority_fee_from_history(
+                    &fee_history,
+                    percentiles,
+                    self.priority_fee_percentile,
+                ).filter(|fee| !fee.is_zero());
 
-        let call_gas_limit = self.estimate_call_gas_limit(chain_id, user_op).await?;
+                match from_history {
+                    Some(fee) => fee,
+                    None => self.fetch_max_priority_fee(chain_id, provider, retry_config).await?,
+                }
+            }
+        };
 
-        Ok(GasParams {
-            call_gas_limit,
-            verification_gas_limit: U256::from(100000),
-            pre_verification_gas: U256::from(21000),
-            max_fee_per_gas: base_fee + priority_fee,
-            max_priority_fee_per_gas: *priority_fee,
-        })
-    }
+        // Cache the new values
+        if self.caching_enabled {
+            let newest_block = fee_history.oldest_block.low_u64() + fee_history.gas_used_ratio.len().saturating_sub(1) as u64;
+            self.cache_base_fee(chain_id, base_fee, Some(newest_block), provider).await;
+            self.gas_cache.set_priority_fee(chain_id, priority_fee).await;
+        }
 
-    async fn estimate_polygon_gas(&self, user_op: &UserOperation) -> Result<GasParams> {
-        let eth_estimate = self.estimate_ethereum_gas(user_op).await?;
-        
-        Ok(GasParams {
-            call_gas_limit: eth_estimate.call_gas_limit * 2,
-            verification_gas_limit: U256::from(200000),
-            pre_verification_gas: U256::from(40000),
-            max_fee_per_gas: eth_estimate.max_fee_per_gas,
-            max_priority_fee_per_gas: eth_estimate.max_priority_fee_per_gas,
-        })
+        Ok((base_fee, priority_fee))
     }
 
-    async fn estimate_arbitrum_gas(&self, user_op: &UserOperation) -> Result<GasParams> {
-        let chain_id = 42161;
-        
-        // Check cache for gas price
-        if let Some(gas_price) = self.gas_cache.get_base_fee(chain_id).await {
-            crate::metrics::Metrics::record_cache_hit("arbitrum_gas_price");
-            
-            let call_gas_limit = self.estimate_call_gas_limit(chain_id, user_op).await?;
-            
-            return Ok(GasParams {
-                call_gas_limit,
-                verification_gas_limit: U256::from(150000),
-                pre_verification_gas: U256::from(50000),
-                max_fee_per_gas: gas_price,
-                max_priority_fee_per_gas: U256::zero(),
-            });
+    /// Fee history where the chain supports it; otherwise the legacy gas
+    /// price as the priority fee over a zero base fee, so the cached pair
+    /// still adds up to the price actually paid.
+    async fn fetch_generic_fees(
+        &self,
+        chain_id: u64,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(U256, U256)> {
+        match self.fetch_eip1559_fees(chain_id, provider_override, retry_config).await {
+            Ok((base_fee, priority_fee)) if !base_fee.is_zero() => return Ok((base_fee, priority_fee)),
+            Ok(_) => debug!("Chain {} reports no base fee, using eth_gasPrice", chain_id),
+            Err(e) => warn!("Fee history failed for chain {}, using eth_gasPrice: {}", chain_id, e),
         }
 
-        crate::metrics::Metrics::record_cache_miss("arbitrum_gas_price");
+        self.fetch_legacy_fees(chain_id, provider_override, retry_config).await
+    }
 
-        // Get fresh gas price with retry
-        let provider = &self.providers.arbitrum;
+    /// The legacy gas price as the priority fee over a zero base fee.
+    async fn fetch_legacy_fees(
+        &self,
+        chain_id: u64,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(U256, U256)> {
+        let provider = self.provider(chain_id, provider_override)?;
         let gas_price = with_retry(
             chain_id,
+            RpcMethod::GasPrice,
             || async {
                 provider
                     .get_gas_price()
                     .await
-                    .map_err(|e| UserOpError::GasEstimation(e.to_string()))
+                    .map_err(|e| classify_provider_error(e, UserOpError::GasEstimation))
             },
-            &self.retry_config,
+            retry_config,
         ).await?;
 
-        // Cache the new value
-        self.gas_cache.set_base_fee(chain_id, gas_price).await;
+        if self.caching_enabled {
+            self.cache_base_fee(chain_id, U256::zero(), None, provider).await;
+            self.gas_cache.set_priority_fee(chain_id, gas_price).await;
+        }
 
-        let call_gas_limit = self.estimate_call_gas_limit(chain_id, user_op).await?;
+        Ok((U256::zero(), gas_price))
+    }
 
-        Ok(GasParams {
-            call_gas_limit,
-            verification_gas_limit: U256::from(150000),
-            pre_verification_gas: U256::from(50000),
-            max_fee_per_gas: gas_price,
+    async fn fetch_max_priority_fee(
+        &self,
+        chain_id: u64,
+        provider: &Provider<P>,
+        retry_config: &RetryConfig,
+    ) -> Result<U256> {
+        with_retry(
+            chain_id,
+            RpcMethod::MaxPriorityFeePerGas,
+            || async {
+                provider
+                    .request::<_, U256>("eth_maxPriorityFeePerGas", ())
+                    .await
+                    .map_err(|e| classify_provider_error(e, UserOpError::GasEstimation))
+            },
+            retry_config,
+        ).await
+    }
+
+    /// Prices with a fresh `eth_gasPrice`, bypassing the fee cache since it
+    /// may hold fee history values from before a strategy switch.
+    async fn estimate_legacy_gas(
+        &self,
+        chain_id: u64,
+        user_op: &UserOperation,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(GasParams, bool)> {
+        let (base_fee, priority_fee) = self.fetch_legacy_fees(chain_id, provider_override, retry_config).await?;
+        let call_gas_limit = self.estimate_call_gas_limit(chain_id, user_op, provider_override, retry_config).await?;
+
+        Ok((GasParams {
+            call_gas_limit,
+            verification_gas_limit: self.gas_defaults(chain_id).verification_gas_limit,
+            pre_verification_gas: self.gas_defaults(chain_id).pre_verification_gas,
+            max_fee_per_gas: base_fee + priority_fee,
+            max_priority_fee_per_gas: priority_fee,
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        }, false))
+    }
+
+    async fn estimate_polygon_gas(
+        &self,
+        user_op: &UserOperation,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(GasParams, bool)> {
+        let (eth_estimate, cache_hit) = self.estimate_ethereum_gas(user_op, provider_override, retry_config).await?;
+        
+        Ok((GasParams {
+            call_gas_limit: eth_estimate.call_gas_limit * 2,
+            verification_gas_limit: self.gas_defaults(137).verification_gas_limit,
+            pre_verification_gas: self.gas_defaults(137).pre_verification_gas,
+            max_fee_per_gas: eth_estimate.max_fee_per_gas,
+            max_priority_fee_per_gas: eth_estimate.max_priority_fee_per_gas,
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        }, cache_hit))
+    }
+
+    async fn estimate_arbitrum_gas(
+        &self,
+        user_op: &UserOperation,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(GasParams, bool)> {
+        let chain_id = 42161;
+        let (gas_price, cache_hit) = self.arbitrum_gas_price(provider_override, retry_config).await?;
+
+        let call_gas_limit = self.estimate_call_gas_limit(chain_id, user_op, provider_override, retry_config).await?;
+
+        Ok((GasParams {
+            call_gas_limit,
+            verification_gas_limit: self.gas_defaults(chain_id).verification_gas_limit,
+            pre_verification_gas: self.gas_defaults(chain_id).pre_verification_gas,
+            max_fee_per_gas: gas_price,
             max_priority_fee_per_gas: U256::zero(),
-        })
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        }, cache_hit))
+    }
+
+    /// Arbitrum's gas price, from the cache when fresh. The flag is whether
+    /// it came from the cache.
+    async fn arbitrum_gas_price(
+        &self,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<(U256, bool)> {
+        if let Some(gas_price) = self.cached_base_fee(42161).await {
+            crate::metrics::Metrics::record_cache_hit("arbitrum_gas_price");
+            return Ok((gas_price, true));
+        }
+
+        self.record_cache_miss("arbitrum_gas_price");
+        let gas_price = self.fetch_arbitrum_gas_price(provider_override, retry_config).await?;
+        Ok((gas_price, false))
+    }
+
+    /// Fetches a fresh Arbitrum gas price and caches it as the base fee.
+    async fn fetch_arbitrum_gas_price(
+        &self,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<U256> {
+        let chain_id = 42161;
+
+        // Get fresh gas price with retry
+        let provider = self.provider(chain_id, provider_override)?;
+        let gas_price = with_retry(
+            chain_id,
+            RpcMethod::GasPrice,
+            || async {
+                provider
+                    .get_gas_price()
+                    .await
+                    .map_err(|e| classify_provider_error(e, UserOpError::GasEstimation))
+            },
+            retry_config,
+        ).await?;
+
+        // Cache the new value
+        if self.caching_enabled {
+            self.cache_base_fee(chain_id, gas_price, None, provider).await;
+        }
+
+        Ok(gas_price)
     }
 
-    async fn estimate_call_gas_limit(&self, chain_id: u64, user_op: &UserOperation) -> Result<U256> {
-        let provider = match chain_id {
-            1 => &self.providers.ethereum,
-            137 => &self.providers.polygon,
-            42161 => &self.providers.arbitrum,
-            _ => return Err(UserOpError::UnsupportedChain(chain_id.to_string())),
+    /// Caches `base_fee` with the block it was read at, so the cache's
+    /// staleness check can age it. Fee sources that don't report a block
+    /// only pay for a head lookup while that check is on.
+    async fn cache_base_fee(&self, chain_id: u64, base_fee: U256, block_number: Option<u64>, provider: &Provider<P>) {
+        let block_number = match block_number {
+            Some(block_number) => Some(block_number),
+            None if self.gas_cache.staleness_check() => match provider.get_block_number().await {
+                Ok(block_number) => Some(block_number.as_u64()),
+                Err(e) => {
+                    warn!("Failed to read chain {}'s head for its base fee: {}", chain_id, e);
+                    None
+                }
+            },
+            None => None,
         };
+        match block_number {
+            Some(block_number) => self.gas_cache.set_base_fee_at(chain_id, base_fee, block_number).await,
+            None => self.gas_cache.set_base_fee(chain_id, base_fee).await,
+        }
+    }
+
+    /// Fetches and caches gas prices for `chain_ids` ahead of the first op.
+    /// Failures are logged and skipped; returns the chains that were warmed.
+    pub async fn warmup(&self, chain_ids: &[u64]) -> Vec<u64> {
+        let mut warmed = Vec::new();
+
+        for &chain_id in chain_ids {
+            match self.refresh_fees(chain_id).await {
+                Ok(()) => warmed.push(chain_id),
+                Err(e) => warn!("Gas cache warmup failed for chain {}: {}", chain_id, e),
+            }
+        }
+
+        warmed
+    }
+
+    /// Current `(max_fee_per_gas, max_priority_fee_per_gas)` on `chain_id`,
+    /// priced as `estimate_gas` would price an op, cache, priority fee floor
+    /// and fee cap included, but without estimating any call gas.
+    pub async fn estimate_fees_only(&self, chain_id: u64) -> Result<(U256, U256)> {
+        let retry_config = &self.retry_config;
+        let (max_fee_per_gas, max_priority_fee_per_gas) = match self.gas_strategy(chain_id)? {
+            // Polygon estimates are priced off the Ethereum fee cache
+            GasStrategy::Ethereum | GasStrategy::Polygon => {
+                let (base_fee, priority_fee, _) = self.eip1559_fees(1, None, retry_config).await?;
+                (base_fee + priority_fee, priority_fee)
+            }
+            GasStrategy::Generic => {
+                let (base_fee, priority_fee, _) = self.eip1559_fees(chain_id, None, retry_config).await?;
+                (base_fee + priority_fee, priority_fee)
+            }
+            GasStrategy::Arbitrum => (self.arbitrum_gas_price(None, retry_config).await?.0, U256::zero()),
+            GasStrategy::Legacy => {
+                let (base_fee, priority_fee) = self.fetch_legacy_fees(chain_id, None, retry_config).await?;
+                (base_fee + priority_fee, priority_fee)
+            }
+        };
+
+        let mut params = GasParams {
+            call_gas_limit: U256::zero(),
+            verification_gas_limit: U256::zero(),
+            pre_verification_gas: U256::zero(),
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        };
+        apply_priority_fee_floor(&mut params, self.min_priority_fee(chain_id));
+        if let Some(cap) = self.fee_caps.get(&chain_id) {
+            apply_fee_cap(chain_id, &mut params, cap)?;
+        }
+        Ok((params.max_fee_per_gas, params.max_priority_fee_per_gas))
+    }
+
+    /// Fetches fresh gas prices for `chain_id` into the cache.
+    pub async fn refresh_fees(&self, chain_id: u64) -> Result<()> {
+        self.fetch_fees(chain_id).await.map(|_| ())
+    }
+
+    /// Fetches and caches `chain_id`'s base and priority fee.
+    async fn fetch_fees(&self, chain_id: u64) -> Result<(U256, U256)> {
+        match self.gas_strategy(chain_id)? {
+            // Polygon estimates are priced off the Ethereum fee cache
+            GasStrategy::Ethereum | GasStrategy::Polygon => self.fetch_eip1559_fees(1, None, &self.retry_config).await,
+            GasStrategy::Arbitrum => self
+                .fetch_arbitrum_gas_price(None, &self.retry_config)
+                .await
+                .map(|gas_price| (gas_price, U256::zero())),
+            GasStrategy::Generic => self.fetch_generic_fees(chain_id, None, &self.retry_config).await,
+            GasStrategy::Legacy => self.fetch_legacy_fees(chain_id, None, &self.retry_config).await,
+        }
+    }
+
+    /// Records the head of the chain `chain_id` is priced from, so the gas
+    /// cache's staleness check can age the fees cached before it. Only
+    /// read while that check is on.
+    async fn record_head(&self, chain_id: u64) {
+        if !self.gas_cache.staleness_check() {
+            return;
+        }
+        let fee_chain = match self.gas_strategy(chain_id) {
+            // Polygon estimates are priced off the Ethereum fee cache
+            Ok(GasStrategy::Ethereum | GasStrategy::Polygon) => 1,
+            Ok(_) => chain_id,
+            Err(_) => return,
+        };
+        let Ok(provider) = self.provider(fee_chain, None) else {
+            return;
+        };
+        match provider.get_block_number().await {
+            Ok(head) => self.gas_cache.set_latest_block(fee_chain, head.as_u64()),
+            Err(e) => warn!("Failed to read chain {}'s head: {}", fee_chain, e),
+        }
+    }
+
+    /// Live fee updates for `chain_id`, published by `spawn_refresher`
+    /// whenever the fees it fetches change. A receiver that falls more than
+    /// `GAS_UPDATE_CAPACITY` updates behind gets `RecvError::Lagged` and
+    /// then resumes from the oldest update still buffered.
+    pub fn subscribe_gas_updates(&self, chain_id: u64) -> broadcast::Receiver<GasUpdate> {
+        self.gas_updates
+            .entry(chain_id)
+            .or_insert_with(|| broadcast::channel(GAS_UPDATE_CAPACITY).0)
+            .subscribe()
+    }
+
+    fn publish_gas_update(&self, update: GasUpdate) {
+        if let Some(sender) = self.gas_updates.get(&update.chain_id) {
+            // Only fails when nobody is subscribed
+            let _ = sender.send(update);
+        }
+    }
+
+    async fn has_code(
+        &self,
+        chain_id: u64,
+        provider: &Provider<P>,
+        address: Address,
+        retry_config: &RetryConfig,
+    ) -> Result<bool> {
+        let code = with_retry(
+            chain_id,
+            RpcMethod::GetCode,
+            || async {
+                provider
+                    .get_code(address, None)
+                    .await
+                    .map_err(|e| classify_provider_error(e, UserOpError::GasEstimation))
+            },
+            retry_config,
+        ).await?;
+
+        Ok(!code.is_empty())
+    }
+
+    /// Applies the `CallTargetCheck` to the targets `user_op` calls.
+    async fn check_call_targets(
+        &self,
+        chain_id: u64,
+        provider: &Provider<P>,
+        user_op: &UserOperation,
+        retry_config: &RetryConfig,
+    ) -> Result<()> {
+        if self.call_target_check == CallTargetCheck::Off {
+            return Ok(());
+        }
+
+        for target in contract_call_targets(&user_op.call_data) {
+            if self.has_code(chain_id, provider, target, retry_config).await? {
+                continue;
+            }
+            let message = format!("Op from {:?} calls {:?}, which has no code", user_op.sender, target);
+            match self.call_target_check {
+                CallTargetCheck::Reject => return Err(UserOpError::Validation(message)),
+                _ => warn!("{}", message),
+            }
+        }
+        Ok(())
+    }
+
+    async fn estimate_call_gas_limit(
+        &self,
+        chain_id: u64,
+        user_op: &UserOperation,
+        provider_override: Option<&Provider<P>>,
+        retry_config: &RetryConfig,
+    ) -> Result<U256> {
+        let provider = self.provider(chain_id, provider_override)?;
+        self.check_call_targets(chain_id, provider, user_op, retry_config).await?;
+
+        let undeployed = !user_op.init_code.is_empty()
+            || (self.check_sender_code && !self.has_code(chain_id, provider, user_op.sender, retry_config).await?);
+        if undeployed {
+            return Ok(self.undeployed_call_gas_limit);
+        }
 
         with_retry(
             chain_id,
+            RpcMethod::EstimateGas,
             || async {
-                let tx = TransactionRequest::new()
+                let request = TransactionRequest::new()
                     .to(user_op.sender)
-                    .data(user_op.call_data.clone())
-                    .into();
-                    
+                    .data(user_op.call_data.clone());
+                let tx: TypedTransaction = match &user_op.access_list {
+                    Some(access_list) => Eip2930TransactionRequest::new(request, access_list.clone()).into(),
+                    None => request.into(),
+                };
+
                 provider
-                    .estimate_gas(&tx, None)
+                    .estimate_gas(&tx, self.block_tag.estimate_block())
                     .await
-                    .map_err(|e| UserOpError::GasEstimation(e.to_string()))
+                    .map_err(|e| classify_provider_error(e, UserOpError::GasEstimation))
             },
-            &self.retry_config,
+            retry_config,
         ).await
     }
 }
+
+impl<P: JsonRpcClient + 'static> GasEstimator<P> {
+    /// Spawns a task that refreshes `chain_id`'s gas prices every `interval`
+    /// (typically the block time) so estimates are served from a warm cache.
+    /// Refreshes go through the retry config's rate limiter; the task exits
+    /// when `cancel` is triggered. Fee changes are published to
+    /// `subscribe_gas_updates` subscribers.
+    pub fn spawn_refresher(
+        self: &Arc<Self>,
+        chain_id: u64,
+        interval: Duration,
+        cancel: CancellationToken,
+    ) -> JoinHandle<()> {
+        let estimator = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            let mut last_fees = None;
+
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = async {
+                        ticker.tick().await;
+                        estimator.record_head(chain_id).await;
+                        match estimator.fetch_fees(chain_id).await {
+                            Ok(fees) if last_fees != Some(fees) => {
+                                last_fees = Some(fees);
+                                estimator.publish_gas_update(GasUpdate {
+                                    chain_id,
+                                    base_fee: fees.0,
+                                    priority_fee: fees.1,
+                                });
+                            }
+                            Ok(_) => {}
+                            Err(e) => warn!("Gas price refresh failed for chain {}: {}", chain_id, e),
+                        }
+                    } => {}
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::transaction::eip2718::TypedTransaction;
+    use ethers::types::transaction::eip2930::{AccessList, AccessListItem};
+    use std::str::FromStr;
+
+    fn estimator_with<P: JsonRpcClient + Clone>(provider: Provider<P>) -> GasEstimator<P> {
+        let providers = Arc::new(ChainProviders {
+            ethereum: provider.clone(),
+            polygon: provider.clone(),
+            arbitrum: provider,
+        });
+
+        GasEstimator::new(
+            providers,
+            Arc::new(GasCache::new()),
+            Arc::new(RpcCache::new()),
+            RetryConfig::default(),
+        )
+    }
+
+    fn mocked_estimator() -> (GasEstimator<MockProvider>, MockProvider) {
+        let (provider, mock) = Provider::mocked();
+        (estimator_with(provider), mock)
+    }
+
+    /// A mock transport that takes `delay` to answer every request.
+    #[derive(Debug, Clone)]
+    struct DelayedMock {
+        inner: MockProvider,
+        delay: std::time::Duration,
+    }
+
+    #[async_trait::async_trait]
+    impl JsonRpcClient for DelayedMock {
+        type Error = MockError;
+
+        async fn request<T, R>(&self, method: &str, params: T) -> std::result::Result<R, MockError>
+        where
+            T: std::fmt::Debug + serde::Serialize + Send + Sync,
+            R: serde::de::DeserializeOwned + Send,
+        {
+            tokio::time::sleep(self.delay).await;
+            JsonRpcClient::request(&self.inner, method, params).await
+        }
+    }
+
+    /// A mock transport that fails every request, counting attempts.
+    #[derive(Debug, Clone, Default)]
+    struct FailingMock {
+        calls: Arc<std::sync::atomic::AtomicU32>,
+    }
+
+    #[async_trait::async_trait]
+    impl JsonRpcClient for FailingMock {
+        type Error = MockError;
+
+        async fn request<T, R>(&self, _method: &str, _params: T) -> std::result::Result<R, MockError>
+        where
+            T: std::fmt::Debug + serde::Serialize + Send + Sync,
+            R: serde::de::DeserializeOwned + Send,
+        {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(MockError::EmptyResponses)
+        }
+    }
+
+    fn fee_history(reward: Vec<Vec<U256>>) -> FeeHistory {
+        FeeHistory {
+            base_fee_per_gas: vec![U256::from(30_000_000_000u64)],
+            gas_used_ratio: vec![0.5],
+            oldest_block: U256::from(100),
+            reward,
+        }
+    }
+
+    fn fee_history_with_base_fee(base_fee: u64) -> FeeHistory {
+        FeeHistory {
+            base_fee_per_gas: vec![U256::from(base_fee)],
+            ..fee_history(vec![vec![U256::from(1), U256::from(2)]])
+        }
+    }
+
+    fn test_user_op() -> UserOperation {
+        UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+    }
+
+    #[test]
+    fn test_required_prefund_v06() {
+        let params = GasParams {
+            call_gas_limit: U256::from(100_000),
+            verification_gas_limit: U256::from(50_000),
+            pre_verification_gas: U256::from(21_000),
+            max_fee_per_gas: U256::from(10_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(1_000_000_000u64),
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        };
+
+        // (100k + 50k + 21k) * 10 gwei
+        assert_eq!(params.required_prefund(EntryPointVersion::V06, false), U256::from(1_710_000_000_000_000u64));
+        // (100k + 3 * 50k + 21k) * 10 gwei
+        assert_eq!(params.required_prefund(EntryPointVersion::V06, true), U256::from(2_710_000_000_000_000u64));
+    }
+
+    #[test]
+    fn test_required_prefund_v07_sums_paymaster_limits() {
+        let params = GasParams {
+            call_gas_limit: U256::from(100_000),
+            verification_gas_limit: U256::from(50_000),
+            pre_verification_gas: U256::from(21_000),
+            max_fee_per_gas: U256::from(10_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(1_000_000_000u64),
+            paymaster_verification_gas_limit: Some(U256::from(30_000)),
+            paymaster_post_op_gas_limit: Some(U256::from(20_000)),
+        };
+
+        // (100k + 50k + 21k + 30k + 20k) * 10 gwei, no multiplier
+        assert_eq!(params.required_prefund(EntryPointVersion::V07, true), U256::from(2_210_000_000_000_000u64));
+        assert_eq!(params.required_prefund(EntryPointVersion::V08, true), U256::from(2_210_000_000_000_000u64));
+
+        // v0.7 doesn't triple verification gas even without paymaster limits
+        let unsponsored = GasParams {
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+            ..params
+        };
+        assert_eq!(unsponsored.required_prefund(EntryPointVersion::V07, true), U256::from(1_710_000_000_000_000u64));
+    }
+
+    #[test]
+    fn test_max_gas_cost_saturates() {
+        let params = GasParams {
+            call_gas_limit: U256::MAX,
+            verification_gas_limit: U256::from(50_000),
+            pre_verification_gas: U256::from(21_000),
+            max_fee_per_gas: U256::from(10_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(1_000_000_000u64),
+            paymaster_verification_gas_limit: Some(U256::from(30_000)),
+            paymaster_post_op_gas_limit: None,
+        };
+
+        assert_eq!(params.max_gas_cost(), U256::MAX);
+    }
+
+    #[test]
+    fn test_gas_params_json_round_trip() {
+        let params = GasParams {
+            call_gas_limit: U256::from(120_000),
+            verification_gas_limit: U256::from(100_000),
+            pre_verification_gas: U256::from(21_000),
+            max_fee_per_gas: U256::from(32_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(2_000_000_000u64),
+            paymaster_verification_gas_limit: Some(U256::from(60_000)),
+            paymaster_post_op_gas_limit: None,
+        };
+
+        let json = params.to_json();
+        assert!(json.contains("\"callGasLimit\": \"0x1d4c0\""));
+        assert!(!json.contains("paymasterPostOpGasLimit"));
+        assert_eq!(GasParams::from_json(&json).unwrap(), params);
+
+        // The same JSON reads as a bundler gas estimate
+        let estimate: crate::bundler::UserOperationGasEstimate = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            estimate.into_gas_params(params.max_fee_per_gas, params.max_priority_fee_per_gas),
+            params
+        );
+
+        assert!(matches!(GasParams::from_json("{}"), Err(UserOpError::Validation(_))));
+    }
+
+    #[test]
+    fn test_reward_index_selects_by_percentile() {
+        assert_eq!(reward_index(&[10.0, 50.0], 50.0), Some(1));
+        assert_eq!(reward_index(&[25.0, 50.0, 75.0], 75.0), Some(2));
+        assert_eq!(reward_index(&[10.0, 50.0], 90.0), None);
+
+        let history = fee_history(vec![vec![U256::from(1), U256::from(2), U256::from(3)]]);
+        assert_eq!(priority_fee_from_history(&history, &[25.0, 50.0, 75.0], 50.0), Some(U256::from(2)));
+    }
+
+    #[test]
+    fn test_priority_fee_from_short_reward_arrays() {
+        let percentiles = [10.0, 50.0];
+
+        // Empty: nothing to extract, the caller falls back to eth_maxPriorityFeePerGas
+        assert_eq!(priority_fee_from_history(&fee_history(vec![]), &percentiles, 50.0), None);
+        assert_eq!(priority_fee_from_history(&fee_history(vec![vec![]]), &percentiles, 50.0), None);
+
+        // Single element: the requested index is missing, use the last available
+        let single = fee_history(vec![vec![U256::from(7)]]);
+        assert_eq!(priority_fee_from_history(&single, &percentiles, 50.0), Some(U256::from(7)));
+
+        // Full: the requested index is used
+        let full = fee_history(vec![vec![U256::from(1), U256::from(2)]]);
+        assert_eq!(priority_fee_from_history(&full, &percentiles, 50.0), Some(U256::from(2)));
+        assert_eq!(priority_fee_from_history(&full, &percentiles, 10.0), Some(U256::from(1)));
+    }
+
+    #[test]
+    fn test_priority_fee_percentile_is_requested() {
+        let (estimator, _mock) = mocked_estimator();
+        let estimator = estimator.with_priority_fee_percentile(75.0);
+
+        assert_eq!(estimator.reward_percentiles, vec![10.0, 50.0, 75.0]);
+        assert_eq!(reward_index(&estimator.reward_percentiles, estimator.priority_fee_percentile), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_empty_rewards_fall_back_to_max_priority_fee() {
+        let (estimator, mock) = mocked_estimator();
+
+        // MockProvider pops responses LIFO, so push in reverse call order
+        mock.push(U256::from(50_000)).unwrap(); // eth_estimateGas
+        mock.push(U256::from(2_000_000_000u64)).unwrap(); // eth_maxPriorityFeePerGas
+        mock.push(fee_history(vec![])).unwrap(); // eth_feeHistory
+
+        let params = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+
+        assert_eq!(params.max_priority_fee_per_gas, U256::from(2_000_000_000u64));
+        assert_eq!(params.max_fee_per_gas, U256::from(32_000_000_000u64));
+        assert_eq!(params.call_gas_limit, U256::from(50_000));
+    }
+
+    #[tokio::test]
+    async fn test_zero_rewards_fall_back_to_max_priority_fee() {
+        let (estimator, mock) = mocked_estimator();
+
+        mock.push(U256::from(50_000)).unwrap();
+        mock.push(U256::from(1_500_000_000u64)).unwrap();
+        mock.push(fee_history(vec![vec![U256::zero(), U256::zero()]])).unwrap();
+
+        let params = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+
+        assert_eq!(params.max_priority_fee_per_gas, U256::from(1_500_000_000u64));
+        assert_eq!(estimator.gas_cache.get_priority_fee(1).await, Some(U256::from(1_500_000_000u64)));
+    }
+
+    #[tokio::test]
+    async fn test_preferred_max_priority_fee_oracle() {
+        let (estimator, mock) = mocked_estimator();
+        let estimator = estimator.with_gas_oracle(1, GasOracle::MaxPriorityFee);
+
+        mock.push(U256::from(50_000)).unwrap();
+        mock.push(U256::from(3_000_000_000u64)).unwrap();
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2)]])).unwrap();
+
+        let params = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+
+        assert_eq!(params.max_priority_fee_per_gas, U256::from(3_000_000_000u64));
+        assert_eq!(estimator.gas_oracle(42161), GasOracle::FeeHistory);
+    }
+
+    #[tokio::test]
+    async fn test_fetched_base_fees_record_their_block() {
+        let (provider, mock) = Provider::mocked();
+        let gas_cache = Arc::new(GasCache::new().with_staleness_check(true));
+        let estimator = GasEstimator::new(
+            Arc::new(ChainProviders {
+                ethereum: provider.clone(),
+                polygon: provider.clone(),
+                arbitrum: provider,
+            }),
+            gas_cache.clone(),
+            Arc::new(RpcCache::new()),
+            RetryConfig::default(),
+        );
+
+        // Fee history ending at block 100
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2)]])).unwrap();
+        estimator.refresh_fees(1).await.unwrap();
+        gas_cache.set_latest_block(1, 101);
+        assert!(!gas_cache.is_stale(1));
+        gas_cache.set_latest_block(1, 102);
+        assert!(gas_cache.is_stale(1));
+
+        // eth_gasPrice reports no block, so the head is read after it
+        mock.push(U64::from(500)).unwrap();
+        mock.push(U256::from(100_000_000u64)).unwrap();
+        estimator.refresh_fees(42161).await.unwrap();
+        gas_cache.set_latest_block(42161, 502);
+        assert!(gas_cache.is_stale(42161));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresher_flags_fees_it_failed_to_replace() {
+        let (provider, mock) = Provider::mocked();
+        let gas_cache = Arc::new(GasCache::new().with_staleness_check(true));
+        let estimator = Arc::new(GasEstimator::new(
+            Arc::new(ChainProviders {
+                ethereum: provider.clone(),
+                polygon: provider.clone(),
+                arbitrum: provider,
+            }),
+            gas_cache.clone(),
+            Arc::new(RpcCache::new()),
+            RetryConfig::default(),
+        ));
+
+        // LIFO: the first tick reads head 100 and fees ending at block 100,
+        // the second reads head 103 and then fails to fetch fees
+        mock.push(U64::from(103)).unwrap();
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2)]])).unwrap();
+        mock.push(U64::from(100)).unwrap();
+
+        let cancel = CancellationToken::new();
+        let handle = estimator.spawn_refresher(1, Duration::from_secs(12), cancel.clone());
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!gas_cache.is_stale(1));
+
+        tokio::time::sleep(Duration::from_secs(12)).await;
+        assert!(gas_cache.is_stale(1));
+
+        cancel.cancel();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_configured_unknown_chain_uses_generic_strategy() {
+        let (estimator, _mock) = mocked_estimator();
+        let (provider, mock) = Provider::mocked();
+        let estimator = estimator.with_generic_chain(8453, provider);
+
+        mock.push(U256::from(60_000)).unwrap(); // eth_estimateGas
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2_000_000_000u64)]])).unwrap();
+
+        assert_eq!(estimator.gas_strategy(8453).unwrap(), GasStrategy::Generic);
+        let params = estimator.estimate_gas(&test_user_op(), 8453, None).await.unwrap();
+
+        assert_eq!(params.call_gas_limit, U256::from(60_000));
+        assert_eq!(params.max_priority_fee_per_gas, U256::from(2_000_000_000u64));
+        assert_eq!(params.max_fee_per_gas, U256::from(32_000_000_000u64));
+        assert_eq!(params.verification_gas_limit, GasDefaults::for_chain(8453).verification_gas_limit);
+    }
+
+    #[tokio::test]
+    async fn test_builder_config_prices_config_only_chain() {
+        let results = HashMap::from([
+            ("eth_feeHistory", serde_json::to_value(fee_history(vec![vec![U256::from(1), U256::from(2_000_000_000u64)]])).unwrap()),
+            ("eth_estimateGas", serde_json::to_value(U256::from(60_000)).unwrap()),
+        ]);
+        let url = crate::provider::tests::serve_rpc(move |method| results[method].clone()).await;
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "chains": {
+                "8453": {
+                    "chain_id": 8453,
+                    "rpc_url": url,
+                    "entry_point_address": "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789",
+                    "wallet_factory_address": "0x1234567890123456789012345678901234567890",
+                    "paymaster_address": "0x1234567890123456789012345678901234567890",
+                    "default_pre_verification_gas": 70_000
+                }
+            }
+        }))
+        .unwrap();
+        let unused = crate::provider::rpc_provider("http://127.0.0.1:1", false, false, &Default::default()).unwrap();
+        let estimator = GasEstimator::builder()
+            .providers(Arc::new(ChainProviders {
+                ethereum: unused.clone(),
+                polygon: unused.clone(),
+                arbitrum: unused,
+            }))
+            .with_config(&config)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(estimator.gas_strategy(8453).unwrap(), GasStrategy::Generic);
+        let params = estimator.estimate_gas(&test_user_op(), 8453, None).await.unwrap();
+        assert_eq!(params.call_gas_limit, U256::from(60_000));
+        assert_eq!(params.max_fee_per_gas, U256::from(32_000_000_000u64));
+        assert_eq!(params.pre_verification_gas, U256::from(70_000));
+        assert!(matches!(estimator.gas_strategy(10), Err(UserOpError::UnsupportedChain(_))));
+    }
+
+    #[tokio::test]
+    async fn test_runtime_switch_to_legacy_strategy() {
+        let (estimator, _mock) = mocked_estimator();
+        let (provider, mock) = Provider::mocked();
+        let estimator = estimator.with_generic_chain(8453, provider);
+
+        mock.push(U256::from(60_000)).unwrap(); // eth_estimateGas
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2_000_000_000u64)]])).unwrap();
+        let params = estimator.estimate_gas(&test_user_op(), 8453, None).await.unwrap();
+        assert_eq!(params.max_fee_per_gas, U256::from(32_000_000_000u64));
+
+        estimator.set_gas_strategy(8453, GasStrategy::Legacy).await.unwrap();
+        assert_eq!(estimator.gas_strategy(8453).unwrap(), GasStrategy::Legacy);
+
+        // LIFO: eth_gasPrice, then eth_estimateGas; no eth_feeHistory
+        mock.push(U256::from(70_000)).unwrap();
+        mock.push(U256::from(5_000_000_000u64)).unwrap();
+        let user_op = test_user_op().with_call_data(Bytes::from(vec![0x01]));
+        let params = estimator.estimate_gas(&user_op, 8453, None).await.unwrap();
+        assert_eq!(params.call_gas_limit, U256::from(70_000));
+        assert_eq!(params.max_fee_per_gas, U256::from(5_000_000_000u64));
+        assert_eq!(params.max_priority_fee_per_gas, U256::from(5_000_000_000u64));
+
+        estimator.clear_gas_strategy(8453).await;
+        assert_eq!(estimator.gas_strategy(8453).unwrap(), GasStrategy::Generic);
+    }
+
+    #[tokio::test]
+    async fn test_chain_specific_strategy_rejected_elsewhere() {
+        let (estimator, _mock) = mocked_estimator();
+
+        assert!(matches!(estimator.set_gas_strategy(1, GasStrategy::Arbitrum).await, Err(UserOpError::Config(_))));
+        assert!(matches!(estimator.set_gas_strategy(8453, GasStrategy::Legacy).await, Err(UserOpError::UnsupportedChain(_))));
+        assert!(estimator.set_gas_strategy(42161, GasStrategy::Legacy).await.is_ok());
+        assert_eq!(estimator.gas_strategy(1).unwrap(), GasStrategy::Ethereum);
+    }
+
+    #[tokio::test]
+    async fn test_arbitrum_price_survives_a_legacy_detour() {
+        let (estimator, mock) = mocked_estimator();
+        estimator.set_gas_strategy(42161, GasStrategy::Legacy).await.unwrap();
+
+        // Legacy caches the gas price as the priority fee over a zero base fee
+        mock.push(U256::from(100_000_000u64)).unwrap(); // eth_gasPrice
+        estimator.refresh_fees(42161).await.unwrap();
+        assert_eq!(estimator.gas_cache.get_base_fee(42161).await, Some(U256::zero()));
+
+        estimator.clear_gas_strategy(42161).await;
+        assert_eq!(estimator.gas_cache.get_base_fee(42161).await, None);
+
+        // LIFO: eth_gasPrice, then eth_estimateGas
+        mock.push(U256::from(50_000)).unwrap();
+        mock.push(U256::from(100_000_000u64)).unwrap();
+        let params = estimator.estimate_gas(&test_user_op(), 42161, None).await.unwrap();
+        assert_eq!(params.max_fee_per_gas, U256::from(100_000_000u64));
+    }
+
+    #[tokio::test]
+    async fn test_sponsored_op_carries_post_op_gas() {
+        let (estimator, _mock) = mocked_estimator();
+        let (provider, mock) = Provider::mocked();
+        let estimator = estimator
+            .with_generic_chain(8453, provider)
+            .with_entry_point_version(EntryPointVersion::V07);
+        let paymaster = Address::from_str("0x2234567890123456789012345678901234567890").unwrap();
+        let sponsored = test_user_op().with_paymaster(paymaster, Bytes::default());
+
+        mock.push(U256::from(60_000)).unwrap(); // eth_estimateGas
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2_000_000_000u64)]])).unwrap();
+        let params = estimator.estimate_gas(&sponsored, 8453, None).await.unwrap();
+        assert_eq!(params.paymaster_post_op_gas_limit, Some(U256::from(DEFAULT_PAYMASTER_POST_OP_GAS_LIMIT)));
+
+        // Unsponsored ops don't get one; fees are cached by now
+        mock.push(U256::from(60_000)).unwrap(); // eth_estimateGas
+        let params = estimator.estimate_gas(&test_user_op(), 8453, None).await.unwrap();
+        assert_eq!(params.paymaster_post_op_gas_limit, None);
+
+        // v0.6 has no separate postOp limit to fill in
+        let estimator = estimator.with_entry_point_version(EntryPointVersion::V06).with_caching(false);
+        mock.push(U256::from(60_000)).unwrap(); // eth_estimateGas
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2_000_000_000u64)]])).unwrap();
+        let params = estimator.estimate_gas(&sponsored, 8453, None).await.unwrap();
+        assert_eq!(params.paymaster_post_op_gas_limit, None);
+    }
+
+    #[tokio::test]
+    async fn test_generic_chain_without_base_fee_uses_gas_price() {
+        let (estimator, _mock) = mocked_estimator();
+        let (provider, mock) = Provider::mocked();
+        let estimator = estimator.with_generic_chain(56, provider);
+
+        let legacy_history = FeeHistory {
+            base_fee_per_gas: vec![U256::zero()],
+            ..fee_history(vec![vec![U256::zero(), U256::zero()]])
+        };
+        mock.push(U256::from(60_000)).unwrap(); // eth_estimateGas
+        mock.push(U256::from(3_000_000_000u64)).unwrap(); // eth_gasPrice
+        mock.push(U256::from(1_000_000_000u64)).unwrap(); // eth_maxPriorityFeePerGas
+        mock.push(legacy_history).unwrap(); // eth_feeHistory
+
+        let params = estimator.estimate_gas(&test_user_op(), 56, None).await.unwrap();
+
+        assert_eq!(params.max_fee_per_gas, U256::from(3_000_000_000u64));
+        assert_eq!(params.max_priority_fee_per_gas, U256::from(3_000_000_000u64));
+    }
+
+    #[tokio::test]
+    async fn test_estimate_with_explicit_provider() {
+        // The configured providers have no responses queued
+        let (estimator, configured_mock) = mocked_estimator();
+        let (provider, mock) = Provider::mocked();
+
+        mock.push(U256::from(70_000)).unwrap(); // eth_estimateGas
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2_000_000_000u64)]])).unwrap();
+
+        let params = estimator.estimate_gas_with_provider(&test_user_op(), 1, &provider, None).await.unwrap();
+
+        assert_eq!(params.call_gas_limit, U256::from(70_000));
+        assert_eq!(params.max_fee_per_gas, U256::from(32_000_000_000u64));
+        // Fees are cached per chain as with the configured provider
+        assert_eq!(estimator.gas_cache.get_priority_fee(1).await, Some(U256::from(2_000_000_000u64)));
+
+        // The override's estimate isn't reused for the configured provider
+        configured_mock.push(U256::from(50_000)).unwrap();
+        let params = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+        assert_eq!(params.call_gas_limit, U256::from(50_000));
+
+        // Nor is the configured provider's estimate reused for an override
+        mock.push(U256::from(80_000)).unwrap();
+        let params = estimator.estimate_gas_with_provider(&test_user_op(), 1, &provider, None).await.unwrap();
+        assert_eq!(params.call_gas_limit, U256::from(80_000));
+    }
+
+    #[tokio::test]
+    async fn test_explicit_provider_covers_unconfigured_chain() {
+        let (estimator, _mock) = mocked_estimator();
+        let (provider, mock) = Provider::mocked();
+
+        mock.push(U256::from(70_000)).unwrap();
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2_000_000_000u64)]])).unwrap();
+
+        let params = estimator.estimate_gas_with_provider(&test_user_op(), 8453, &provider, None).await.unwrap();
+        assert_eq!(params.call_gas_limit, U256::from(70_000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_config_override_applies_per_call() {
+        let transport = FailingMock::default();
+        let estimator = estimator_with(Provider::new(transport.clone()));
+        let calls = || transport.calls.load(std::sync::atomic::Ordering::SeqCst);
+
+        let single_attempt = RetryConfig {
+            max_attempts: 1,
+            ..RetryConfig::default()
+        };
+        assert!(estimator.estimate_gas(&test_user_op(), 1, Some(&single_attempt)).await.is_err());
+        // ethers resends a failed eth_feeHistory once with a plain block count
+        let per_attempt = calls();
+        assert_eq!(per_attempt, 2);
+
+        // Without an override the estimator's own config retries
+        assert!(estimator.estimate_gas(&test_user_op(), 1, None).await.is_err());
+        assert_eq!(calls(), per_attempt * (1 + RetryConfig::default().max_attempts));
+    }
+
+    #[tokio::test]
+    async fn test_unconfigured_chain_is_unsupported() {
+        let handle = crate::metrics::tests::test_handle();
+        let (estimator, _mock) = mocked_estimator();
+
+        let result = estimator.estimate_gas(&test_user_op(), 8453, None).await;
+        assert!(matches!(result, Err(UserOpError::UnsupportedChain(id)) if id == "8453"));
+        // Failed estimates still record their duration
+        assert!(handle
+            .render()
+            .contains(r#"gas_estimation_duration_seconds_count{chain="8453",cache_hit="false",success="false"}"#));
+    }
+
+    #[test]
+    fn test_builder_defaults() {
+        let (provider, _mock) = Provider::mocked();
+        let estimator = GasEstimator::builder()
+            .providers(Arc::new(ChainProviders {
+                ethereum: provider.clone(),
+                polygon: provider.clone(),
+                arbitrum: provider,
+            }))
+            .oracle(137, GasOracle::MaxPriorityFee)
+            .buffers(1, GasDefaults::new(120_000, 30_000))
+            .build()
+            .unwrap();
+
+        assert_eq!(estimator.gas_oracle(1), GasOracle::FeeHistory);
+        assert_eq!(estimator.gas_oracle(137), GasOracle::MaxPriorityFee);
+        assert_eq!(estimator.gas_defaults(1), GasDefaults::new(120_000, 30_000));
+        assert_eq!(estimator.gas_defaults(42161), GasDefaults::for_chain(42161));
+        assert_eq!(estimator.priority_fee_percentile, DEFAULT_PRIORITY_FEE_PERCENTILE);
+        assert_eq!(estimator.block_tag, BlockTag::Latest);
+    }
+
+    #[test]
+    fn test_builder_requires_providers() {
+        let result = GasEstimatorBuilder::<MockProvider>::new().build();
+        assert!(matches!(result, Err(UserOpError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_undeployed_sender_uses_default_call_gas() {
+        let (estimator, mock) = mocked_estimator();
+        let estimator = estimator
+            .with_sender_code_check(true)
+            .with_undeployed_call_gas_limit(U256::from(333_000));
+        estimator.gas_cache.set_base_fee(1, U256::from(10)).await;
+        estimator.gas_cache.set_priority_fee(1, U256::from(2)).await;
+        mock.push::<Bytes, _>(Bytes::default()).unwrap();
+
+        let params = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+
+        assert_eq!(params.call_gas_limit, U256::from(333_000));
+        mock.assert_request("eth_getCode", (test_user_op().sender, "latest")).unwrap();
+        assert!(mock.assert_request("eth_estimateGas", ()).is_err());
+    }
+
+    fn execute_call_data(target: Address, data: Vec<u8>) -> Bytes {
+        let call = crate::contracts::ExecuteCall { target, value: U256::zero(), data: data.into() };
+        ethers::abi::AbiEncode::encode(call).into()
+    }
+
+    #[tokio::test]
+    async fn test_call_target_check_tells_contracts_from_eoas() {
+        let (estimator, mock) = mocked_estimator();
+        let estimator = estimator.with_call_target_check(CallTargetCheck::Reject);
+        estimator.gas_cache.set_base_fee(1, U256::from(10)).await;
+        estimator.gas_cache.set_priority_fee(1, U256::from(2)).await;
+        let target = Address::from_low_u64_be(0xc0de);
+
+        // A call into an EOA is rejected before estimating
+        mock.push::<Bytes, _>(Bytes::default()).unwrap();
+        let user_op = test_user_op().with_call_data(execute_call_data(target, vec![0xa9, 0x05, 0x9c, 0xbb]));
+        let result = estimator.estimate_gas(&user_op, 1, None).await;
+        assert!(matches!(result, Err(UserOpError::Validation(_))), "{:?}", result);
+        mock.assert_request("eth_getCode", (target, "latest")).unwrap();
+
+        // LIFO: the target's code, then eth_estimateGas
+        mock.push(U256::from(80_000)).unwrap();
+        mock.push::<Bytes, _>(Bytes::from(vec![0x60, 0x80])).unwrap();
+        let params = estimator.estimate_gas(&user_op, 1, None).await.unwrap();
+        assert_eq!(params.call_gas_limit, U256::from(80_000));
+
+        // Sending plain value to an EOA is fine and isn't looked up
+        assert!(contract_call_targets(&execute_call_data(target, vec![])).is_empty());
+        assert!(contract_call_targets(&[0xde, 0xad, 0xbe, 0xef]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_init_code_skips_call_estimation() {
+        let (estimator, _mock) = mocked_estimator();
+        estimator.gas_cache.set_base_fee(1, U256::from(10)).await;
+        estimator.gas_cache.set_priority_fee(1, U256::from(2)).await;
+
+        let mut user_op = test_user_op();
+        user_op.init_code = Bytes::from(vec![0xab; 24]);
+        let params = estimator.estimate_gas(&user_op, 1, None).await.unwrap();
+
+        assert_eq!(params.call_gas_limit, U256::from(DEFAULT_UNDEPLOYED_CALL_GAS_LIMIT));
+    }
+
+    #[tokio::test]
+    async fn test_overridden_gas_defaults_are_used() {
+        let (estimator, mock) = mocked_estimator();
+        let estimator = estimator.with_gas_defaults(1, GasDefaults::new(123_456, 54_321));
+
+        estimator.gas_cache.set_base_fee(1, U256::from(10)).await;
+        estimator.gas_cache.set_priority_fee(1, U256::from(2)).await;
+        mock.push(U256::from(50_000)).unwrap();
+
+        let params = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+
+        assert_eq!(params.verification_gas_limit, U256::from(123_456));
+        assert_eq!(params.pre_verification_gas, U256::from(54_321));
+        assert_eq!(estimator.gas_defaults(42161), GasDefaults::new(150000, 50000));
+    }
+
+    #[tokio::test]
+    async fn test_identical_op_estimate_is_cached() {
+        let handle = crate::metrics::tests::test_handle();
+        let (estimator, mock) = mocked_estimator();
+        estimator.gas_cache.set_base_fee(1, U256::from(10)).await;
+        estimator.gas_cache.set_priority_fee(1, U256::from(2)).await;
+        mock.push(U256::from(50_000)).unwrap();
+
+        let first = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+        // Nothing left in the mock, so this must come from the cache
+        let second = estimator.estimate_gas(&test_user_op().with_nonce(U256::one()), 1, None).await.unwrap();
+        assert_eq!(second.call_gas_limit, first.call_gas_limit);
+        assert!(handle.render().contains(r#"cache_hits_total{type="gas_estimate"}"#));
+
+        let different = test_user_op().with_call_data(Bytes::from(vec![0x01]));
+        assert!(estimator.estimate_gas(&different, 1, None).await.is_err());
+    }
+
+    #[test]
+    fn test_fingerprint_covers_access_list_and_authorization() {
+        let plain = estimate_fingerprint(&test_user_op());
+
+        let mut with_access_list = test_user_op();
+        with_access_list.access_list = Some(AccessList(vec![AccessListItem {
+            address: Address::from_low_u64_be(1),
+            storage_keys: vec![H256::zero()],
+        }]));
+        let delegated = test_user_op().with_eip7702_auth(crate::userop::Authorization {
+            chain_id: U256::one(),
+            address: Address::from_low_u64_be(2),
+            nonce: U256::zero(),
+            y_parity: U64::zero(),
+            r: U256::from(1),
+            s: U256::from(2),
+        });
+
+        assert_ne!(estimate_fingerprint(&with_access_list), plain);
+        assert_ne!(estimate_fingerprint(&delegated), plain);
+        assert_ne!(estimate_fingerprint(&delegated), estimate_fingerprint(&with_access_list));
+    }
+
+    #[tokio::test]
+    async fn test_priority_fee_floor_applies_on_polygon_only() {
+        let (estimator, mock) = mocked_estimator();
+        let gwei = U256::from(1_000_000_000u64);
+
+        estimator.gas_cache.set_base_fee(1, gwei * 10).await;
+        estimator.gas_cache.set_priority_fee(1, gwei).await;
+        mock.push(U256::from(50_000)).unwrap();
+        mock.push(U256::from(50_000)).unwrap();
+
+        let polygon = estimator.estimate_gas(&test_user_op(), 137, None).await.unwrap();
+        assert_eq!(polygon.max_priority_fee_per_gas, gwei * 30);
+        assert_eq!(polygon.max_fee_per_gas, gwei * 40);
+
+        let ethereum = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+        assert_eq!(ethereum.max_priority_fee_per_gas, gwei);
+        assert_eq!(ethereum.max_fee_per_gas, gwei * 11);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_populates_cache() {
+        let (estimator, mock) = mocked_estimator();
+
+        mock.push(U256::from(100_000_000u64)).unwrap(); // eth_gasPrice for Arbitrum
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2_000_000_000u64)]])).unwrap();
+
+        // Chain 10 isn't supported; it should be skipped without aborting warmup
+        let warmed = estimator.warmup(&[1, 10, 42161]).await;
+
+        assert_eq!(warmed, vec![1, 42161]);
+        assert_eq!(estimator.gas_cache.get_base_fee(1).await, Some(U256::from(30_000_000_000u64)));
+        assert_eq!(estimator.gas_cache.get_priority_fee(1).await, Some(U256::from(2_000_000_000u64)));
+        assert_eq!(estimator.gas_cache.get_base_fee(42161).await, Some(U256::from(100_000_000u64)));
+    }
+
+    fn spiked_params() -> GasParams {
+        GasParams {
+            call_gas_limit: U256::from(50_000),
+            verification_gas_limit: U256::from(100_000),
+            pre_verification_gas: U256::from(21_000),
+            max_fee_per_gas: U256::from(500_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(2_000_000_000u64),
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        }
+    }
+
+    #[test]
+    fn test_bump_fee_saturates() {
+        assert_eq!(bump_fee(U256::MAX, 10), U256::MAX);
+        assert_eq!(bump_fee(U256::MAX - 1, 10), U256::MAX);
+        assert_eq!(bump_fee(U256::from(100), 10), U256::from(110));
+        assert_eq!(bump_fee(U256::from(1), 10), U256::from(2));
+    }
+
+    #[test]
+    fn test_fee_cap_clamps() {
+        let mut params = spiked_params();
+        let cap = FeeCap {
+            max_fee_per_gas: U256::from(100_000_000_000u64),
+            behavior: CapBehavior::Clamp,
+        };
+
+        apply_fee_cap(1, &mut params, &cap).unwrap();
+
+        assert_eq!(params.max_fee_per_gas, U256::from(100_000_000_000u64));
+        assert_eq!(params.max_priority_fee_per_gas, U256::from(2_000_000_000u64));
+    }
+
+    #[test]
+    fn test_fee_cap_fails() {
+        let mut params = spiked_params();
+        let cap = FeeCap {
+            max_fee_per_gas: U256::from(100_000_000_000u64),
+            behavior: CapBehavior::Fail,
+        };
+
+        let result = apply_fee_cap(1, &mut params, &cap);
+
+        assert!(matches!(result, Err(UserOpError::GasEstimation(_))));
+        assert_eq!(params.max_fee_per_gas, U256::from(500_000_000_000u64));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_estimate_gas_multi_runs_concurrently() {
+        let delay = std::time::Duration::from_millis(100);
+        let mock = MockProvider::new();
+        let estimator = estimator_with(Provider::new(DelayedMock { inner: mock.clone(), delay }));
+
+        // Cached prices leave one eth_estimateGas call per chain
+        estimator.gas_cache.set_base_fee(1, U256::from(10)).await;
+        estimator.gas_cache.set_priority_fee(1, U256::from(2)).await;
+        estimator.gas_cache.set_base_fee(42161, U256::from(10)).await;
+        for _ in 0..3 {
+            mock.push(U256::from(50_000)).unwrap();
+        }
+
+        let start = tokio::time::Instant::now();
+        let estimates = estimator.estimate_gas_multi(&test_user_op(), &[1, 137, 42161], None).await;
+        let elapsed = start.elapsed();
+
+        assert_eq!(estimates.len(), 3);
+        assert!(estimates.values().all(|estimate| estimate.is_ok()));
+        // Time is paused, so three sequential calls would take exactly 3 * delay
+        assert!(elapsed < delay * 2, "Expected concurrent estimation, took {:?}", elapsed);
+    }
+
+    fn canned_params(gas: u64, max_fee_per_gas: u64) -> GasParams {
+        GasParams {
+            call_gas_limit: U256::from(gas),
+            verification_gas_limit: U256::zero(),
+            pre_verification_gas: U256::zero(),
+            max_fee_per_gas: U256::from(max_fee_per_gas),
+            max_priority_fee_per_gas: U256::zero(),
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        }
+    }
+
+    #[test]
+    fn test_cheapest_estimate_converts_with_native_prices() {
+        let mut estimates = HashMap::new();
+        // 0.003 ETH at $3000 = $9
+        estimates.insert(1, Ok(canned_params(100_000, 30_000_000_000)));
+        // 0.02 MATIC at $0.5 = $0.01
+        estimates.insert(137, Ok(canned_params(200_000, 100_000_000_000)));
+        // 0.00001 ETH at $3000 = $0.03
+        estimates.insert(42161, Ok(canned_params(100_000, 100_000_000)));
+        // Errored chains are skipped
+        estimates.insert(10, Err(UserOpError::UnsupportedChain("10".to_string())));
+
+        let prices = HashMap::from([(1, 3000.0), (137, 0.5), (42161, 3000.0), (10, 3000.0)]);
+
+        let (chain_id, params) = cheapest_estimate(estimates, &prices).unwrap();
+        assert_eq!(chain_id, 137);
+        assert_eq!(params.call_gas_limit, U256::from(200_000));
+    }
+
+    #[test]
+    fn test_cheapest_estimate_requires_prices() {
+        let estimates = HashMap::from([(1, Ok(canned_params(100_000, 1)))]);
+        assert!(cheapest_estimate(estimates, &HashMap::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_block_tag_is_passed_to_rpc() {
+        let (estimator, mock) = mocked_estimator();
+        let estimator = estimator.with_block_tag(BlockTag::Pending);
+        let user_op = test_user_op();
+
+        mock.push(U256::from(50_000)).unwrap();
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2)]])).unwrap();
+
+        estimator.estimate_gas(&user_op, 1, None).await.unwrap();
+
+        mock.assert_request("eth_feeHistory", (U256::from(default_fee_history_blocks(1)), "pending", vec![10.0, 50.0])).unwrap();
+
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(user_op.sender)
+            .data(user_op.call_data.clone())
+            .into();
+        mock.assert_request("eth_estimateGas", (tx, "pending")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_access_list_is_sent_to_estimate_gas() {
+        let (estimator, mock) = mocked_estimator();
+        let access_list = AccessList(vec![AccessListItem {
+            address: Address::from_low_u64_be(0xaa),
+            storage_keys: vec![H256::from_low_u64_be(1)],
+        }]);
+        let user_op = test_user_op().with_access_list(access_list.clone());
+
+        mock.push(U256::from(40_000)).unwrap();
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2)]])).unwrap();
+
+        let params = estimator.estimate_gas(&user_op, 1, None).await.unwrap();
+        assert_eq!(params.call_gas_limit, U256::from(40_000));
+
+        mock.assert_request("eth_feeHistory", (U256::from(default_fee_history_blocks(1)), "latest", vec![10.0, 50.0])).unwrap();
+        let tx: TypedTransaction = Eip2930TransactionRequest::new(
+            TransactionRequest::new().to(user_op.sender).data(user_op.call_data.clone()),
+            access_list,
+        ).into();
+        mock.assert_request("eth_estimateGas", [tx]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fee_history_window_is_per_chain() {
+        let (estimator, mock) = mocked_estimator();
+        let estimator = estimator.with_fee_history_blocks(1, 6);
+        // Price Polygon from its own fee history rather than Ethereum's
+        estimator.set_gas_strategy(137, GasStrategy::Generic).await.unwrap();
+
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2)]])).unwrap();
+        estimator.refresh_fees(1).await.unwrap();
+        mock.assert_request("eth_feeHistory", (U256::from(6), "latest", vec![10.0, 50.0])).unwrap();
+
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2)]])).unwrap();
+        estimator.refresh_fees(137).await.unwrap();
+        mock.assert_request(
+            "eth_feeHistory",
+            (U256::from(default_fee_history_blocks(137)), "latest", vec![10.0, 50.0]),
+        ).unwrap();
+        assert_ne!(estimator.fee_history_blocks(1), estimator.fee_history_blocks(137));
+    }
+
+    #[tokio::test]
+    async fn test_disabled_caching_fetches_fresh_fees() {
+        crate::metrics::tests::test_handle();
+        let (estimator, mock) = mocked_estimator();
+        let estimator = estimator.with_caching(false);
+        let bypassed = || crate::metrics::Metrics::snapshot().counter("cache_bypasses_total", &[("type", "gas_estimate")]);
+        let before = bypassed();
+
+        // LIFO: the second estimate's responses go in first
+        mock.push(U256::from(60_000)).unwrap(); // eth_estimateGas
+        mock.push(fee_history_with_base_fee(20)).unwrap(); // eth_feeHistory
+        mock.push(U256::from(50_000)).unwrap(); // eth_estimateGas
+        mock.push(fee_history_with_base_fee(10)).unwrap(); // eth_feeHistory
+
+        let first = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+        let second = estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+
+        assert_eq!(first.max_fee_per_gas, U256::from(12));
+        assert_eq!(second.max_fee_per_gas, U256::from(22));
+        assert_eq!(second.call_gas_limit, U256::from(60_000));
+        assert_eq!(estimator.gas_cache.get_base_fee(1).await, None);
+        assert!(bypassed() >= before + 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_fees_only_skips_call_gas_estimate() {
+        let (estimator, mock) = mocked_estimator();
+
+        // Only fee history is queued; an eth_estimateGas would fail
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2_000_000_000u64)]])).unwrap();
+        let (max_fee, priority_fee) = estimator.estimate_fees_only(1).await.unwrap();
+
+        assert_eq!(max_fee, U256::from(32_000_000_000u64));
+        assert_eq!(priority_fee, U256::from(2_000_000_000u64));
+        mock.assert_request("eth_feeHistory", (U256::from(default_fee_history_blocks(1)), "latest", vec![10.0, 50.0])).unwrap();
+        assert!(mock.assert_request("eth_estimateGas", ()).is_err());
+
+        // Polygon reuses the now cached Ethereum fees, raised to its floor
+        let (max_fee, priority_fee) = estimator.estimate_fees_only(137).await.unwrap();
+        assert_eq!(priority_fee, default_min_priority_fee(137));
+        assert_eq!(max_fee, U256::from(60_000_000_000u64));
+    }
+
+    #[tokio::test]
+    async fn test_rpc_method_labels() {
+        let handle = crate::metrics::tests::test_handle();
+        let (estimator, mock) = mocked_estimator();
+
+        mock.push(U256::from(50_000)).unwrap();
+        mock.push(fee_history(vec![vec![U256::from(1), U256::from(2)]])).unwrap();
+        estimator.estimate_gas(&test_user_op(), 1, None).await.unwrap();
+
+        let rendered = handle.render();
+        assert!(rendered.contains(r#"rpc_calls_total{chain="1",method="eth_feeHistory"}"#));
+        assert!(rendered.contains(r#"rpc_calls_total{chain="1",method="eth_estimateGas"}"#));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresher_updates_cache_on_schedule() {
+        let (estimator, mock) = mocked_estimator();
+        let estimator = Arc::new(estimator);
+        // LIFO: the first tick sees 10, the second 20
+        mock.push(fee_history_with_base_fee(20)).unwrap();
+        mock.push(fee_history_with_base_fee(10)).unwrap();
+
+        let cancel = CancellationToken::new();
+        let handle = estimator.spawn_refresher(1, Duration::from_millis(50), cancel.clone());
+
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(estimator.gas_cache.get_base_fee(1).await, Some(U256::from(10)));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(estimator.gas_cache.get_base_fee(1).await, Some(U256::from(20)));
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscriber_receives_update_after_block() {
+        let (estimator, mock) = mocked_estimator();
+        let estimator = Arc::new(estimator);
+        // LIFO: the first block sees 10, the next 10 again, then 20
+        mock.push(fee_history_with_base_fee(20)).unwrap();
+        mock.push(fee_history_with_base_fee(10)).unwrap();
+        mock.push(fee_history_with_base_fee(10)).unwrap();
+
+        let mut updates = estimator.subscribe_gas_updates(1);
+        let cancel = CancellationToken::new();
+        let handle = estimator.spawn_refresher(1, Duration::from_secs(12), cancel.clone());
+
+        let first = updates.recv().await.unwrap();
+        assert_eq!(first, GasUpdate { chain_id: 1, base_fee: U256::from(10), priority_fee: U256::from(2) });
+
+        // The unchanged second block is not republished
+        let next = updates.recv().await.unwrap();
+        assert_eq!(next.base_fee, U256::from(20));
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,28 @@
+/// Keccak-256 backend used to hash UserOperations.
+pub trait Hasher: Send + Sync {
+    fn keccak256(&self, data: &[u8]) -> [u8; 32];
+}
+
+/// The default backend, `ethers::utils::keccak256`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EthersKeccak;
+
+impl Hasher for EthersKeccak {
+    fn keccak256(&self, data: &[u8]) -> [u8; 32] {
+        ethers::utils::keccak256(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ethers_keccak_empty_input() {
+        // keccak256("")
+        assert_eq!(
+            ethers::utils::hex::encode(EthersKeccak.keccak256(&[])),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use crate::clock::{Clock, SystemClock};
+use crate::metrics::Metrics;
+use crate::retry::ConcurrencyLimiter;
+
+/// Consecutive failed operations after which a chain's circuit reports open.
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+
+/// Per-chain outcome of RPC operations, fed by `with_retry`.
+pub struct ProviderHealth {
+    last_success: DashMap<u64, Instant>,
+    consecutive_failures: DashMap<u64, u32>,
+    failure_threshold: u32,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for ProviderHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProviderHealth {
+    pub fn new() -> Self {
+        Self {
+            last_success: DashMap::new(),
+            consecutive_failures: DashMap::new(),
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_failure_threshold(mut self, threshold: u32) -> Self {
+        self.failure_threshold = threshold;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn record_success(&self, chain_id: u64) {
+        self.last_success.insert(chain_id, self.clock.now());
+        self.consecutive_failures.insert(chain_id, 0);
+    }
+
+    pub fn record_failure(&self, chain_id: u64) {
+        *self.consecutive_failures.entry(chain_id).or_insert(0) += 1;
+    }
+
+    /// Time since the last successful operation, `None` if there was none.
+    pub fn last_success_age(&self, chain_id: u64) -> Option<Duration> {
+        self.last_success
+            .get(&chain_id)
+            .map(|at| self.clock.now().duration_since(*at))
+    }
+
+    /// Whether the chain has failed `failure_threshold` operations in a row.
+    pub fn circuit_open(&self, chain_id: u64) -> bool {
+        self.consecutive_failures
+            .get(&chain_id)
+            .is_some_and(|failures| *failures >= self.failure_threshold)
+    }
+}
+
+/// Periodically publishes each chain's provider health as gauges.
+pub struct HealthCollector {
+    chain_ids: Vec<u64>,
+    health: Arc<ProviderHealth>,
+    concurrency_limiter: Arc<ConcurrencyLimiter>,
+    interval: Duration,
+}
+
+impl HealthCollector {
+    pub fn new(
+        chain_ids: Vec<u64>,
+        health: Arc<ProviderHealth>,
+        concurrency_limiter: Arc<ConcurrencyLimiter>,
+    ) -> Self {
+        Self {
+            chain_ids,
+            health,
+            concurrency_limiter,
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Records the current gauges for every chain once.
+    pub fn collect(&self) {
+        for &chain_id in &self.chain_ids {
+            if let Some(age) = self.health.last_success_age(chain_id) {
+                Metrics::record_rpc_last_success_age(chain_id, age.as_secs_f64());
+            }
+            Metrics::record_rpc_in_flight(chain_id, self.concurrency_limiter.in_flight(chain_id));
+            Metrics::record_rpc_circuit_open(chain_id, self.health.circuit_open(chain_id));
+        }
+    }
+
+    /// Collects every `interval` until `cancel` is triggered.
+    pub fn spawn(self, cancel: CancellationToken) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+            loop {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = ticker.tick() => self.collect(),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::metrics::tests::test_handle;
+
+    #[tokio::test]
+    async fn test_collector_emits_health_gauges() {
+        let handle = test_handle();
+        let clock = Arc::new(MockClock::new());
+        let health = Arc::new(ProviderHealth::new().with_failure_threshold(2).with_clock(clock.clone()));
+        let concurrency_limiter = Arc::new(ConcurrencyLimiter::new(4));
+        let collector = HealthCollector::new(vec![7001, 7002], health.clone(), concurrency_limiter.clone());
+
+        health.record_success(7001);
+        clock.advance(Duration::from_secs(5));
+        health.record_failure(7002);
+        health.record_failure(7002);
+        let _permit = concurrency_limiter.acquire(7001).await;
+
+        collector.collect();
+
+        let rendered = handle.render();
+        assert!(rendered.contains(r#"rpc_last_success_age_seconds{chain="7001"} 5"#), "{}", rendered);
+        assert!(!rendered.contains(r#"rpc_last_success_age_seconds{chain="7002"}"#));
+        assert!(rendered.contains(r#"rpc_in_flight_requests{chain="7001"} 1"#));
+        assert!(rendered.contains(r#"rpc_in_flight_requests{chain="7002"} 0"#));
+        assert!(rendered.contains(r#"rpc_circuit_open{chain="7001"} 0"#));
+        assert!(rendered.contains(r#"rpc_circuit_open{chain="7002"} 1"#));
+
+        // A success closes the circuit again
+        health.record_success(7002);
+        assert!(!health.circuit_open(7002));
+    }
+}
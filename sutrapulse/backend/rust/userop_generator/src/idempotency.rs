@@ -0,0 +1,247 @@
+use async_trait::async_trait;
+use ethers::prelude::*;
+use moka::future::Cache;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::debug;
+use crate::error::Result;
+use crate::service::Submitter;
+use crate::userop::UserOperation;
+
+/// How long a submission is remembered by `IdempotentSubmitter` by default.
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(600);
+
+/// Wraps a `Submitter` so that submitting the same op again within the TTL,
+/// e.g. retrying after a timeout that may still have reached the node,
+/// returns the first submission's transaction hash instead of resending it
+/// and spending the nonce twice. Ops are keyed by their userOpHash unless
+/// the caller supplies a key. Concurrent submits of the same key share one
+/// send. Failed submissions aren't remembered.
+pub struct IdempotentSubmitter<S> {
+    inner: S,
+    entry_point: Address,
+    chain_id: u64,
+    submitted: Cache<H256, Arc<OnceCell<H256>>>,
+}
+
+impl<S: Submitter> IdempotentSubmitter<S> {
+    pub fn new(inner: S, entry_point: Address, chain_id: u64) -> Self {
+        Self {
+            inner,
+            entry_point,
+            chain_id,
+            submitted: Self::record(DEFAULT_IDEMPOTENCY_TTL),
+        }
+    }
+
+    /// Sets how long a submission is remembered. Forgets earlier ones.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.submitted = Self::record(ttl);
+        self
+    }
+
+    fn record(ttl: Duration) -> Cache<H256, Arc<OnceCell<H256>>> {
+        Cache::builder()
+            .max_capacity(10_000)
+            .time_to_live(ttl)
+            .build()
+    }
+
+    /// The key `submit` uses for `user_op`: its userOpHash.
+    pub fn idempotency_key(&self, user_op: &UserOperation) -> H256 {
+        user_op.hash(self.entry_point, self.chain_id)
+    }
+
+    /// Submits `user_op` unless `idempotency_key` was submitted within the
+    /// TTL, in which case the earlier transaction hash is returned. A submit
+    /// of the same key that is still in flight is waited on, not repeated.
+    pub async fn submit_with_key(
+        &self,
+        idempotency_key: H256,
+        user_op: UserOperation,
+        beneficiary: Address,
+    ) -> Result<H256> {
+        let submission = self.submitted
+            .entry(idempotency_key)
+            .or_insert_with(async { Arc::new(OnceCell::new()) })
+            .await
+            .into_value();
+        if let Some(tx_hash) = submission.get() {
+            debug!("Op {:?} was already submitted as {:?}, not resending", idempotency_key, tx_hash);
+            return Ok(*tx_hash);
+        }
+
+        // A failed send leaves the cell empty, so the next caller retries
+        submission
+            .get_or_try_init(|| self.inner.submit(user_op, beneficiary))
+            .await
+            .copied()
+    }
+}
+
+#[async_trait]
+impl<S: Submitter> Submitter for IdempotentSubmitter<S> {
+    async fn submit(&self, user_op: UserOperation, beneficiary: Address) -> Result<H256> {
+        let idempotency_key = self.idempotency_key(&user_op);
+        self.submit_with_key(idempotency_key, user_op, beneficiary).await
+    }
+
+    async fn wait_for_confirmation(&self, tx_hash: H256) -> Result<()> {
+        self.inner.wait_for_confirmation(tx_hash).await
+    }
+
+    async fn fetch_nonce(&self, sender: Address, key: U256) -> Result<U256> {
+        self.inner.fetch_nonce(sender, key).await
+    }
+
+    async fn receipt_block_hash(&self, tx_hash: H256) -> Result<Option<H256>> {
+        self.inner.receipt_block_hash(tx_hash).await
+    }
+
+    async fn user_op_inclusion(&self, user_op_hash: H256) -> Result<Option<(H256, H256)>> {
+        self.inner.user_op_inclusion(user_op_hash).await
+    }
+
+    /// Replacements aren't deduplicated: each carries freshly bumped fees.
+    async fn resubmit(
+        &self,
+        user_op: UserOperation,
+        beneficiary: Address,
+        replaced: H256,
+        bump_percent: u64,
+    ) -> Result<H256> {
+        self.inner.resubmit(user_op, beneficiary, replaced, bump_percent).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundler::BundlerClient;
+    use crate::error::UserOpError;
+    use std::str::FromStr;
+
+    /// Submits through `eth_sendUserOperation` on a mocked bundler.
+    struct BundlerSubmitter {
+        bundler: BundlerClient<MockProvider>,
+        entry_point: Address,
+    }
+
+    #[async_trait]
+    impl Submitter for BundlerSubmitter {
+        async fn submit(&self, user_op: UserOperation, _beneficiary: Address) -> Result<H256> {
+            self.bundler.send_user_operation(&user_op, self.entry_point).await
+        }
+
+        async fn wait_for_confirmation(&self, _tx_hash: H256) -> Result<()> {
+            Ok(())
+        }
+
+        async fn fetch_nonce(&self, _sender: Address, _key: U256) -> Result<U256> {
+            Ok(U256::zero())
+        }
+    }
+
+    fn idempotent_submitter() -> (IdempotentSubmitter<BundlerSubmitter>, MockProvider) {
+        let (provider, mock) = Provider::mocked();
+        let entry_point = Address::from_str("0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789").unwrap();
+        let inner = BundlerSubmitter {
+            bundler: BundlerClient::with_provider(provider).with_entry_point_check(false),
+            entry_point,
+        };
+        (IdempotentSubmitter::new(inner, entry_point, 1), mock)
+    }
+
+    fn test_user_op(nonce: u64) -> UserOperation {
+        UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+            .with_nonce(U256::from(nonce))
+    }
+
+    #[tokio::test]
+    async fn test_repeated_submit_is_not_resent() {
+        let (submitter, mock) = idempotent_submitter();
+        let beneficiary = Address::from_low_u64_be(1);
+        mock.push(H256::from_low_u64_be(0xaa)).unwrap();
+
+        let first = submitter.submit(test_user_op(0), beneficiary).await.unwrap();
+        // A second RPC call would fail on the empty mock
+        let retried = submitter.submit(test_user_op(0), beneficiary).await.unwrap();
+        assert_eq!(first, H256::from_low_u64_be(0xaa));
+        assert_eq!(retried, first);
+
+        // An explicit key is deduplicated the same way
+        let key = H256::repeat_byte(0x01);
+        mock.push(H256::from_low_u64_be(0xbb)).unwrap();
+        assert_eq!(submitter.submit_with_key(key, test_user_op(1), beneficiary).await.unwrap(), H256::from_low_u64_be(0xbb));
+        assert_eq!(submitter.submit_with_key(key, test_user_op(1), beneficiary).await.unwrap(), H256::from_low_u64_be(0xbb));
+
+        // Different ops are still sent
+        mock.push(H256::from_low_u64_be(0xcc)).unwrap();
+        assert_eq!(submitter.submit(test_user_op(2), beneficiary).await.unwrap(), H256::from_low_u64_be(0xcc));
+    }
+
+    /// Counts submits, each taking a second to return.
+    #[derive(Default)]
+    struct SlowSubmitter {
+        sent: std::sync::atomic::AtomicU64,
+    }
+
+    #[async_trait]
+    impl Submitter for SlowSubmitter {
+        async fn submit(&self, _user_op: UserOperation, _beneficiary: Address) -> Result<H256> {
+            let sent = self.sent.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(H256::from_low_u64_be(sent))
+        }
+
+        async fn wait_for_confirmation(&self, _tx_hash: H256) -> Result<()> {
+            Ok(())
+        }
+
+        async fn fetch_nonce(&self, _sender: Address, _key: U256) -> Result<U256> {
+            Ok(U256::zero())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_submits_share_one_send() {
+        let submitter = IdempotentSubmitter::new(SlowSubmitter::default(), Address::zero(), 1);
+        let beneficiary = Address::from_low_u64_be(1);
+
+        let (first, second) = tokio::join!(
+            submitter.submit(test_user_op(0), beneficiary),
+            submitter.submit(test_user_op(0), beneficiary),
+        );
+
+        assert_eq!(first.unwrap(), H256::from_low_u64_be(1));
+        assert_eq!(second.unwrap(), H256::from_low_u64_be(1));
+        assert_eq!(submitter.inner.sent.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_submit_is_retried() {
+        let (submitter, mock) = idempotent_submitter();
+        let beneficiary = Address::from_low_u64_be(1);
+
+        let result = submitter.submit(test_user_op(0), beneficiary).await;
+        assert!(matches!(result, Err(UserOpError::RPC(_))), "{:?}", result);
+
+        mock.push(H256::from_low_u64_be(0xaa)).unwrap();
+        assert_eq!(submitter.submit(test_user_op(0), beneficiary).await.unwrap(), H256::from_low_u64_be(0xaa));
+    }
+
+    #[tokio::test]
+    async fn test_submission_is_forgotten_after_ttl() {
+        let (submitter, mock) = idempotent_submitter();
+        let submitter = submitter.with_ttl(Duration::from_millis(20));
+        let beneficiary = Address::from_low_u64_be(1);
+
+        mock.push(H256::from_low_u64_be(0xbb)).unwrap(); // after the TTL
+        mock.push(H256::from_low_u64_be(0xaa)).unwrap(); // first submit
+        assert_eq!(submitter.submit(test_user_op(0), beneficiary).await.unwrap(), H256::from_low_u64_be(0xaa));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(submitter.submit(test_user_op(0), beneficiary).await.unwrap(), H256::from_low_u64_be(0xbb));
+    }
+}
@@ -7,13 +7,47 @@ pub mod metrics;
 pub mod retry;
 pub mod contracts;
 pub mod config;
+pub mod relay;
+pub mod provider;
+pub mod paymaster;
+pub mod bundler;
+pub mod service;
+pub mod nonce;
+pub mod shutdown;
+pub mod hasher;
+pub mod clock;
+pub mod health;
+pub mod policy;
+pub mod idempotency;
+pub mod units;
+pub mod ccip;
+#[cfg(feature = "mock-bundler")]
+pub mod mock_bundler;
 
-pub use error::{Result, UserOpError};
-pub use gas::{GasEstimator, GasParams, ChainProviders};
-pub use userop::{UserOperation, UserOpGenerator};
+pub use error::{AaError, Result, UserOpError};
+pub use gas::{bump_fee, GasEstimator, GasEstimatorBuilder, GasParams, ChainProviders, GasOracle, GasStrategy, GasDefaults, DEFAULT_PAYMASTER_POST_OP_GAS_LIMIT, default_fee_history_blocks, MAX_FEE_HISTORY_BLOCKS, FeeCap, CapBehavior, BlockTag, GasUpdate, CallTargetCheck};
+pub use userop::{normalize_signature, Authorization, FieldChange, HashSigner, HashSigningMode, UserOperation, UserOpGenerator, SizeLimits};
 pub use chain::{Chain, ChainConfig as ChainSettings, ChainProvider};
-pub use cache::{GasCache, RpcCache};
-pub use metrics::Metrics;
-pub use retry::{RetryConfig, RateLimiter};
-pub use contracts::Contracts;
-pub use config::{Config, ChainConfig, ContractAddresses}; 
\ No newline at end of file
+pub use cache::{EstimateCache, GasCache, RpcCache, DEFAULT_MAX_PROVIDERS};
+pub use metrics::{HistogramSnapshot, Metrics, MetricsSnapshot, RpcMethod, METRICS_PORT};
+pub use retry::{ConcurrencyLimiter, RetryConfig, RateLimiter};
+pub use contracts::{order_user_ops, split_batch, BatchLimits, BundleTransaction, Contracts, EntryPointVersion, StateOverride, UserOpReceipt, WalletState, DEFAULT_MAX_BATCH_GAS, DEFAULT_MAX_BATCH_OPS, DEFAULT_WATCH_TIMEOUT, EIP7702_PER_AUTH_GAS, EIP7702_TX_TYPE, ENTRY_POINT_V06_ADDRESS, ENTRY_POINT_V07_ADDRESS, ENTRY_POINT_V08_ADDRESS, USER_OP_LOOKUP_BLOCKS};
+#[cfg(feature = "debug-trace")]
+pub use contracts::TraceFrame;
+pub use config::{Config, ChainConfig, ContractAddresses};
+pub use relay::{RelayClient, SubmissionRoute};
+pub use provider::{rpc_provider, ConnectionRetryClient, LoggingClient, MeteredClient, ReconnectingProvider, RpcClient, DEFAULT_CONNECTION_RETRIES, DEFAULT_CONNECTION_RETRY_DELAY};
+pub use paymaster::{PaymasterAndData, TokenPaymaster, VerifyingPaymaster};
+pub use bundler::{BundlerClient, UserOperationGasEstimate, UserOperationReceipt};
+pub use service::{bump_fees, is_nonce_too_low, resolve_beneficiary, CorrelationId, EventHook, NoopHook, ReorgStatus, ReorgWatch, ResubmitPolicy, Submitter, UserOpService};
+pub use nonce::{FileNonceStore, NonceKeyStrategy, NonceManager, NonceStore};
+pub use shutdown::{OpGuard, OpTracker};
+pub use hasher::{EthersKeccak, Hasher};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use health::{HealthCollector, ProviderHealth};
+pub use policy::{SenderPolicy, SharedSenderPolicy};
+pub use idempotency::{IdempotentSubmitter, DEFAULT_IDEMPOTENCY_TTL};
+pub use units::{gwei_to_wei, parse_gwei, wei_to_gwei, GweiU256, WEI_PER_GWEI};
+pub use ccip::{CcipReader, OffchainLookup, DEFAULT_CCIP_CONNECT_TIMEOUT, DEFAULT_CCIP_TIMEOUT, MAX_CCIP_REDIRECTS, OFFCHAIN_LOOKUP_SELECTOR};
+#[cfg(feature = "mock-bundler")]
+pub use mock_bundler::MockBundler;
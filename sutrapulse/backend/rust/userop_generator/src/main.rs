@@ -1,22 +1,16 @@
-mod error;
-mod gas;
-mod userop;
-mod chain;
-mod cache;
-mod metrics;
-mod retry;
-
+use std::collections::HashMap;
 use std::sync::Arc;
 use dotenv::dotenv;
 use std::env;
-use ethers::prelude::*;
-use crate::chain::{ethereum, polygon, arbitrum};
-use crate::gas::{GasEstimator, ChainProviders};
-use crate::cache::{GasCache, RpcCache};
-use crate::metrics::Metrics;
-use crate::retry::{RetryConfig, RateLimiter};
+use userop_generator::chain::{ethereum, polygon, arbitrum};
+use userop_generator::{
+    ChainProviders, Clock, ConcurrencyLimiter, Config, GasCache, GasEstimator, HealthCollector, Metrics, OpTracker,
+    ProviderHealth, RateLimiter, RetryConfig, RpcCache, SystemClock, UserOpGenerator, UserOpService,
+    DEFAULT_MAX_PROVIDERS, METRICS_PORT,
+};
 use std::time::Duration;
-use tracing::info;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -28,35 +22,64 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    // Initialize metrics
-    Metrics::init();
-    info!("Metrics server started on port 9000");
-
-    // Get provider URLs from environment
-    let eth_url = env::var("ETH_PROVIDER_URL").expect("ETH_PROVIDER_URL must be set");
-    let polygon_url = env::var("POLYGON_PROVIDER_URL").expect("POLYGON_PROVIDER_URL must be set");
-    let arbitrum_url = env::var("ARBITRUM_PROVIDER_URL").expect("ARBITRUM_PROVIDER_URL must be set");
+    // Initialize metrics, namespaced when several services share a Prometheus
+    let metrics_started = match env::var("METRICS_NAMESPACE") {
+        Ok(namespace) => Metrics::init_with_namespace(&namespace),
+        Err(_) => Metrics::init(),
+    };
+    let serving_metrics = match metrics_started {
+        Ok(()) => {
+            info!("Metrics server started on port {}", METRICS_PORT);
+            true
+        }
+        // Metrics are still recorded, just not served
+        Err(e) => {
+            warn!("Continuing without the metrics endpoint: {}", e);
+            false
+        }
+    };
 
-    // Get EntryPoint address
-    let entry_point = env::var("ENTRY_POINT_ADDRESS").expect("ENTRY_POINT_ADDRESS must be set");
-    let entry_point = entry_point.parse::<Address>()?;
+    // Chains, contracts and RPC settings come from the env.SECTION§KEY config
+    let config = Config::from_env()?;
+    let rpc_url = |chain_id: u64| config.get_chain_config(chain_id).map(|chain| chain.rpc_url.clone());
+    let eth_url = rpc_url(1)?;
+    let polygon_url = rpc_url(137)?;
+    let arbitrum_url = rpc_url(42161)?;
+    let entry_point = config.get_contract_addresses(1)?.entry_point;
 
     // Initialize caches
     let gas_cache = Arc::new(GasCache::new());
-    let rpc_cache = Arc::new(RpcCache::new());
+    let max_providers = env::var("RPC_CACHE_MAX_PROVIDERS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_MAX_PROVIDERS);
+    let rpc_cache = Arc::new(
+        RpcCache::with_max_providers(max_providers)
+            .with_compression(config.rpc_compression)
+            .with_request_logging(config.rpc_log_requests),
+    );
 
     // Initialize rate limiter with chain-specific limits
     let eth_rate_limiter = Arc::new(RateLimiter::new(1, 100));     // 100 requests per second
     let polygon_rate_limiter = Arc::new(RateLimiter::new(1, 200)); // 200 requests per second
     let arbitrum_rate_limiter = Arc::new(RateLimiter::new(1, 150)); // 150 requests per second
 
+    // Bound in-flight requests per chain
+    let concurrency_limiter = Arc::new(ConcurrencyLimiter::new(32));
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let health = Arc::new(ProviderHealth::new());
+
     // Create retry configs for each chain
     let eth_retry_config = RetryConfig {
         max_attempts: 3,
         initial_interval: Duration::from_millis(100),
         max_interval: Duration::from_secs(5),
         multiplier: 2.0,
+        max_elapsed_time: None,
         rate_limiter: eth_rate_limiter,
+        concurrency_limiter: concurrency_limiter.clone(),
+        clock: clock.clone(),
+        health: health.clone(),
     };
 
     let polygon_retry_config = RetryConfig {
@@ -64,7 +87,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         initial_interval: Duration::from_millis(50),
         max_interval: Duration::from_secs(3),
         multiplier: 1.5,
+        max_elapsed_time: None,
         rate_limiter: polygon_rate_limiter,
+        concurrency_limiter: concurrency_limiter.clone(),
+        clock: clock.clone(),
+        health: health.clone(),
     };
 
     let arbitrum_retry_config = RetryConfig {
@@ -72,7 +99,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         initial_interval: Duration::from_millis(200),
         max_interval: Duration::from_secs(8),
         multiplier: 2.0,
+        max_elapsed_time: None,
         rate_limiter: arbitrum_rate_limiter,
+        concurrency_limiter: concurrency_limiter.clone(),
+        clock,
+        health: health.clone(),
     };
 
     // Initialize chain providers with caching
@@ -92,12 +123,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _arbitrum = arbitrum::create_arbitrum_chain(entry_point, arbitrum_url.clone())?;
 
     // Initialize gas estimator with caching and retry logic
-    let _gas_estimator = GasEstimator::new(
-        chain_providers.clone(),
-        gas_cache.clone(),
-        rpc_cache.clone(),
-        eth_retry_config.clone(), // Use Ethereum's retry config as default
-    );
+    let new_estimator = || {
+        GasEstimator::new(
+            chain_providers.clone(),
+            gas_cache.clone(),
+            rpc_cache.clone(),
+            eth_retry_config.clone(), // Use Ethereum's retry config as default
+        )
+        .with_config(&config)
+    };
+    let gas_estimator = new_estimator();
+
+    // Warm the gas price cache so the first op per chain skips fee history latency
+    let warmed = gas_estimator.warmup(&[1, 137, 42161]).await;
+    info!("Gas cache warmed for chains: {:?}", warmed);
 
     info!("UserOp Generator initialized with optimizations:");
     info!("- Caching enabled for gas prices and RPC providers");
@@ -106,28 +145,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         polygon_retry_config.rate_limiter.max_requests,
         arbitrum_retry_config.rate_limiter.max_requests
     );
-    info!("- Metrics exposed on :9000/metrics");
+    if serving_metrics {
+        info!("- Metrics exposed on :{}/metrics", METRICS_PORT);
+    }
     info!("- Chain-specific retry policies configured");
 
-    // Keep the application running
-    loop {
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        
-        // Record metrics periodically
-        for (chain_id, _) in [
-            (1, &chain_providers.ethereum),
-            (137, &chain_providers.polygon),
-            (42161, &chain_providers.arbitrum),
-        ] {
-            // Record basic chain metrics
-            Metrics::record_active_connections(chain_id, 1); // Just record that the provider is active
-        }
+    // Ops register here so shutdown can let them finish
+    let op_tracker = Arc::new(OpTracker::new());
+
+    // One service per configured chain, all sharing the gas caches
+    let mut services = HashMap::new();
+    for chain_id in config.chains.keys().copied() {
+        let service = UserOpService::from_config(UserOpGenerator::new(new_estimator()), &config, chain_id)?
+            .with_tracker(op_tracker.clone());
+        services.insert(chain_id, service);
     }
+    info!("- Op services ready for chains: {:?}", services.keys().collect::<Vec<_>>());
+
+    // Publish per-chain provider health on a configurable interval
+    let health_interval = env::var("HEALTH_METRICS_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_secs(1));
+    let health_cancel = CancellationToken::new();
+    let health_task = HealthCollector::new(vec![1, 137, 42161], health, concurrency_limiter)
+        .with_interval(health_interval)
+        .spawn(health_cancel.clone());
+
+    // Keep the application running until Ctrl-C
+    tokio::signal::ctrl_c().await?;
+    info!("Shutdown requested, draining in-flight operations");
+    if !op_tracker.drain(Duration::from_secs(30)).await {
+        info!("Exiting with {} operations still in flight", op_tracker.in_flight());
+    }
+    health_cancel.cancel();
+    let _ = health_task.await;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ethers::prelude::*;
+    use userop_generator::MockClock;
     use std::str::FromStr;
 
     #[tokio::test]
@@ -158,11 +219,15 @@ mod tests {
 
     #[tokio::test]
     async fn test_rate_limiting() {
-        let rate_limiter = RateLimiter::new(1, 2); // 2 requests per second
+        let clock = Arc::new(MockClock::new());
+        let rate_limiter = RateLimiter::new(1, 2).with_clock(clock.clone()); // 2 requests per second
         let chain_id = 1;
 
         assert!(rate_limiter.check_and_record(chain_id).await); // First request
         assert!(rate_limiter.check_and_record(chain_id).await); // Second request
         assert!(!rate_limiter.check_and_record(chain_id).await); // Third request should be limited
+
+        clock.advance(Duration::from_millis(1001));
+        assert!(rate_limiter.check_and_record(chain_id).await); // Window has passed
     }
 }
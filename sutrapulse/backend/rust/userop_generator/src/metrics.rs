@@ -1,51 +1,316 @@
 use metrics::{counter, gauge, histogram};
-use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
 use std::time::Instant;
+use tracing::warn;
+use crate::error::{Result, UserOpError};
+
+/// Port `Metrics::init` serves `/metrics` on.
+pub const METRICS_PORT: u16 = 9000;
+
+static NAMESPACE: OnceLock<String> = OnceLock::new();
+/// Handle to the installed recorder, read by `Metrics::snapshot`.
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Sets the prefix for every metric name. Only the first call takes effect.
+fn set_namespace(prefix: &str) {
+    if NAMESPACE.set(prefix.to_string()).is_err() && NAMESPACE.get().map(String::as_str) != Some(prefix) {
+        warn!("Metrics namespace already set to {:?}, ignoring {:?}", NAMESPACE.get(), prefix);
+    }
+}
+
+/// `name` with the configured namespace prepended, e.g.
+/// `sutrapulse_rpc_calls_total`. Unchanged when no namespace is set.
+pub fn metric_name(name: &str) -> String {
+    match NAMESPACE.get() {
+        Some(namespace) if !namespace.is_empty() => format!("{}_{}", namespace, name),
+        _ => name.to_string(),
+    }
+}
+
+/// JSON-RPC methods, named consistently for the `method` metric label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RpcMethod {
+    FeeHistory,
+    MaxPriorityFeePerGas,
+    GasPrice,
+    EstimateGas,
+    Call,
+    GetCode,
+    GetBalance,
+    SendRawTransaction,
+}
+
+impl RpcMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RpcMethod::FeeHistory => "eth_feeHistory",
+            RpcMethod::MaxPriorityFeePerGas => "eth_maxPriorityFeePerGas",
+            RpcMethod::GasPrice => "eth_gasPrice",
+            RpcMethod::EstimateGas => "eth_estimateGas",
+            RpcMethod::Call => "eth_call",
+            RpcMethod::GetCode => "eth_getCode",
+            RpcMethod::GetBalance => "eth_getBalance",
+            RpcMethod::SendRawTransaction => "eth_sendRawTransaction",
+        }
+    }
+}
 
 pub struct Metrics;
 
 impl Metrics {
-    pub fn init() {
-        PrometheusBuilder::new()
-            .with_http_listener(([0, 0, 0, 0], 9000))
-            .install()
-            .expect("Failed to install Prometheus metrics exporter");
+    /// Installs the recorder and serves it on `METRICS_PORT` at `/metrics`.
+    /// Must be called from within a Tokio runtime. See `init_with_address`
+    /// for what happens when the port is taken.
+    pub fn init() -> Result<()> {
+        Self::init_with_address(([0, 0, 0, 0], METRICS_PORT).into())
+    }
+
+    /// Installs the recorder and serves it on `address`. If the exporter
+    /// can't listen there, the recorder is installed anyway, so metrics
+    /// keep recording and `snapshot` keeps working, and the bind failure is
+    /// returned as `UserOpError::Config` for the caller to report.
+    pub fn init_with_address(address: SocketAddr) -> Result<()> {
+        let (recorder, exporter) = match PrometheusBuilder::new().with_http_listener(address).build() {
+            Ok(built) => built,
+            Err(e) => {
+                Self::init_recorder();
+                return Err(UserOpError::Config(format!("Metrics exporter can't listen on {}: {}", address, e)));
+            }
+        };
+        let _ = HANDLE.set(recorder.handle());
+        metrics::set_boxed_recorder(Box::new(recorder))
+            .map_err(|e| UserOpError::Config(format!("Failed to install metrics recorder: {}", e)))?;
+        tokio::spawn(exporter);
+        Ok(())
+    }
+
+    /// Installs a recorder without the HTTP exporter, for tests and
+    /// in-process introspection through `snapshot`. Later calls return the
+    /// same handle.
+    pub fn init_recorder() -> &'static PrometheusHandle {
+        HANDLE.get_or_init(|| {
+            PrometheusBuilder::new()
+                .install_recorder()
+                .expect("Failed to install metrics recorder")
+        })
+    }
+
+    /// Current value of every metric. Empty until a recorder is installed
+    /// with `init` or `init_recorder`.
+    pub fn snapshot() -> MetricsSnapshot {
+        HANDLE
+            .get()
+            .map(|handle| MetricsSnapshot::parse(&handle.render()))
+            .unwrap_or_default()
+    }
+
+    /// Like `init`, prefixing every metric name with `prefix_` so several
+    /// services can share a Prometheus without colliding.
+    pub fn init_with_namespace(prefix: &str) -> Result<()> {
+        set_namespace(prefix);
+        Self::init()
     }
 
     pub fn record_userop_generation(chain_id: u64, success: bool) {
         let chain = chain_id.to_string();
-        counter!("userop_generation_total", 1, "chain" => chain.clone());
+        counter!(metric_name("userop_generation_total"), 1, "chain" => chain.clone());
         if success {
-            counter!("userop_generation_success", 1, "chain" => chain);
+            counter!(metric_name("userop_generation_success"), 1, "chain" => chain);
         } else {
-            counter!("userop_generation_failure", 1, "chain" => chain);
+            counter!(metric_name("userop_generation_failure"), 1, "chain" => chain);
         }
     }
 
-    pub fn record_gas_estimation(chain_id: u64, duration: f64) {
-        histogram!("gas_estimation_duration_seconds", duration, "chain" => chain_id.to_string());
+    /// Failed estimates are recorded with `cache_hit` false.
+    pub fn record_gas_estimation(chain_id: u64, duration: f64, cache_hit: bool, success: bool) {
+        histogram!(
+            metric_name("gas_estimation_duration_seconds"),
+            duration,
+            "chain" => chain_id.to_string(),
+            "cache_hit" => cache_hit.to_string(),
+            "success" => success.to_string()
+        );
     }
 
     pub fn record_rpc_call(chain_id: u64, method: &str, success: bool, duration: f64) {
         let chain = chain_id.to_string();
-        counter!("rpc_calls_total", 1, "chain" => chain.clone(), "method" => method.to_string());
-        histogram!("rpc_call_duration_seconds", duration, "chain" => chain.clone(), "method" => method.to_string());
+        counter!(metric_name("rpc_calls_total"), 1, "chain" => chain.clone(), "method" => method.to_string());
+        histogram!(metric_name("rpc_call_duration_seconds"), duration, "chain" => chain.clone(), "method" => method.to_string());
         
         if !success {
-            counter!("rpc_calls_failed", 1, "chain" => chain, "method" => method.to_string());
+            counter!(metric_name("rpc_calls_failed"), 1, "chain" => chain, "method" => method.to_string());
         }
     }
 
+    /// Counts one attempt of a retried RPC operation; `attempt` is 1-based.
+    pub fn record_retry_attempt(chain_id: u64, method: &str, attempt: u32) {
+        let chain = chain_id.to_string();
+        counter!(metric_name("retry_attempts_total"), 1, "chain" => chain.clone(), "method" => method.to_string());
+        histogram!(metric_name("retry_attempt_number"), attempt as f64, "chain" => chain, "method" => method.to_string());
+    }
+
+    /// Total time one operation spent backing off between attempts.
+    pub fn record_retry_backoff(chain_id: u64, method: &str, seconds: f64) {
+        histogram!(metric_name("retry_backoff_seconds"), seconds, "chain" => chain_id.to_string(), "method" => method.to_string());
+    }
+
+    pub fn record_fee_cap_clamped(chain_id: u64) {
+        counter!(metric_name("fee_cap_clamped_total"), 1, "chain" => chain_id.to_string());
+    }
+
+    /// A cached gas price was read more than one block after it was fetched.
+    pub fn record_gas_cache_stale(chain_id: u64) {
+        counter!(metric_name("gas_cache_stale"), 1, "chain" => chain_id.to_string());
+    }
+
+    /// A confirmed transaction was moved out of its block by a reorg.
+    pub fn record_reorg_detected(chain_id: u64) {
+        counter!(metric_name("reorg_detected"), 1, "chain" => chain_id.to_string());
+    }
+
+    /// The local userOpHash disagreed with `EntryPoint.getUserOpHash`.
+    pub fn record_user_op_hash_mismatch(chain_id: u64) {
+        counter!(metric_name("user_op_hash_mismatch"), 1, "chain" => chain_id.to_string());
+    }
+
     pub fn record_cache_hit(cache_type: &str) {
-        counter!("cache_hits_total", 1, "type" => cache_type.to_string());
+        counter!(metric_name("cache_hits_total"), 1, "type" => cache_type.to_string());
     }
 
     pub fn record_cache_miss(cache_type: &str) {
-        counter!("cache_misses_total", 1, "type" => cache_type.to_string());
+        counter!(metric_name("cache_misses_total"), 1, "type" => cache_type.to_string());
     }
 
-    pub fn record_active_connections(chain_id: u64, count: i64) {
-        gauge!("active_connections", count as f64, "chain" => chain_id.to_string());
+    /// A cache lookup skipped because caching is disabled.
+    pub fn record_cache_bypass(cache_type: &str) {
+        counter!(metric_name("cache_bypasses_total"), 1, "type" => cache_type.to_string());
+    }
+
+    pub fn record_provider_created() {
+        counter!(metric_name("rpc_provider_creations_total"), 1);
+    }
+
+    pub fn record_provider_reused() {
+        counter!(metric_name("rpc_provider_reuses_total"), 1);
+    }
+
+    /// Counts providers dropped because their pool was at capacity.
+    pub fn record_provider_evicted() {
+        counter!(metric_name("rpc_provider_evictions_total"), 1);
+    }
+
+    pub fn record_provider_pool_size(size: u64) {
+        gauge!(metric_name("rpc_provider_pool_size"), size as f64);
+    }
+
+    /// Records uncompressed JSON-RPC payload sizes in bytes.
+    pub fn record_rpc_payload_size(method: &str, request_bytes: usize, response_bytes: usize) {
+        histogram!(metric_name("rpc_request_bytes"), request_bytes as f64, "method" => method.to_string());
+        histogram!(metric_name("rpc_response_bytes"), response_bytes as f64, "method" => method.to_string());
+    }
+
+    pub fn record_rpc_permits_available(chain_id: u64, available: usize) {
+        gauge!(metric_name("rpc_concurrency_permits_available"), available as f64, "chain" => chain_id.to_string());
+    }
+
+    pub fn record_rpc_last_success_age(chain_id: u64, seconds: f64) {
+        gauge!(metric_name("rpc_last_success_age_seconds"), seconds, "chain" => chain_id.to_string());
+    }
+
+    pub fn record_rpc_in_flight(chain_id: u64, in_flight: usize) {
+        gauge!(metric_name("rpc_in_flight_requests"), in_flight as f64, "chain" => chain_id.to_string());
+    }
+
+    pub fn record_rpc_circuit_open(chain_id: u64, open: bool) {
+        gauge!(metric_name("rpc_circuit_open"), if open { 1.0 } else { 0.0 }, "chain" => chain_id.to_string());
+    }
+}
+
+/// Count and sum of a histogram's observations.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum: f64,
+}
+
+/// Metric values at one point in time, keyed by series as Prometheus
+/// renders them, e.g. `userop_generation_total{chain="1"}`, namespace
+/// included.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub counters: BTreeMap<String, f64>,
+    pub gauges: BTreeMap<String, f64>,
+    pub histograms: BTreeMap<String, HistogramSnapshot>,
+}
+
+impl MetricsSnapshot {
+    /// Reads Prometheus text exposition output. Histograms are rendered as
+    /// summaries by default; either way only `_count` and `_sum` are kept.
+    fn parse(rendered: &str) -> Self {
+        let mut snapshot = Self::default();
+        let mut types = BTreeMap::new();
+
+        for line in rendered.lines() {
+            if let Some(declaration) = line.strip_prefix("# TYPE ") {
+                if let Some((name, kind)) = declaration.split_once(' ') {
+                    types.insert(name.to_string(), kind.to_string());
+                }
+                continue;
+            }
+            let Some((series, value)) = line.rsplit_once(' ') else { continue };
+            let Ok(value) = value.parse::<f64>() else { continue };
+            let (name, labels) = match series.find('{') {
+                Some(brace) => series.split_at(brace),
+                None => (series, ""),
+            };
+
+            match types.get(name).map(String::as_str) {
+                Some("counter") => { snapshot.counters.insert(series.to_string(), value); }
+                Some("gauge") => { snapshot.gauges.insert(series.to_string(), value); }
+                _ => {
+                    let (base, is_count) = match (name.strip_suffix("_count"), name.strip_suffix("_sum")) {
+                        (Some(base), _) => (base, true),
+                        (_, Some(base)) => (base, false),
+                        _ => continue,
+                    };
+                    let histogram = snapshot.histograms.entry(format!("{}{}", base, labels)).or_default();
+                    if is_count {
+                        histogram.count = value as u64;
+                    } else {
+                        histogram.sum = value;
+                    }
+                }
+            }
+        }
+
+        snapshot
+    }
+
+    /// The series key for `name`, given without the namespace, with
+    /// `labels` in the order they were recorded.
+    fn series(name: &str, labels: &[(&str, &str)]) -> String {
+        let name = metric_name(name);
+        if labels.is_empty() {
+            return name;
+        }
+        let labels: Vec<String> = labels.iter().map(|(key, value)| format!("{}=\"{}\"", key, value)).collect();
+        format!("{}{{{}}}", name, labels.join(","))
+    }
+
+    /// A counter's value, 0 if it hasn't been recorded.
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> f64 {
+        self.counters.get(&Self::series(name, labels)).copied().unwrap_or(0.0)
+    }
+
+    pub fn gauge(&self, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        self.gauges.get(&Self::series(name, labels)).copied()
+    }
+
+    pub fn histogram(&self, name: &str, labels: &[(&str, &str)]) -> Option<HistogramSnapshot> {
+        self.histograms.get(&Self::series(name, labels)).copied()
     }
 }
 
@@ -53,6 +318,12 @@ pub struct Timer {
     start: Instant,
 }
 
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Timer {
     pub fn new() -> Self {
         Self {
@@ -63,4 +334,84 @@ impl Timer {
     pub fn elapsed(&self) -> f64 {
         self.start.elapsed().as_secs_f64()
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Installs a process-wide recorder once, under the `test` namespace,
+    /// and hands out its render handle.
+    pub(crate) fn test_handle() -> &'static PrometheusHandle {
+        set_namespace("test");
+        Metrics::init_recorder()
+    }
+
+    /// Reads an unlabelled counter or gauge from rendered output, 0 if absent.
+    /// `name` is given without the namespace.
+    pub(crate) fn counter_value(rendered: &str, name: &str) -> f64 {
+        let name = metric_name(name);
+        rendered
+            .lines()
+            .find_map(|line| line.strip_prefix(name.as_str())?.strip_prefix(' ')?.parse().ok())
+            .unwrap_or(0.0)
+    }
+
+    #[tokio::test]
+    async fn test_exporter_bind_failure_is_an_error() {
+        test_handle();
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let result = Metrics::init_with_address(taken.local_addr().unwrap());
+        assert!(matches!(result, Err(UserOpError::Config(_))), "{:?}", result);
+
+        // The recorder is still in place
+        Metrics::record_cache_hit("bind_failure_test");
+        assert!(Metrics::snapshot().counter("cache_hits_total", &[("type", "bind_failure_test")]) >= 1.0);
+    }
+
+    #[test]
+    fn test_gas_estimation_cache_hit_label() {
+        let handle = test_handle();
+
+        Metrics::record_gas_estimation(1, 0.01, true, true);
+        Metrics::record_gas_estimation(1, 0.25, false, true);
+        Metrics::record_gas_estimation(1, 0.5, false, false);
+
+        let rendered = handle.render();
+        assert!(rendered.contains(r#"gas_estimation_duration_seconds_count{chain="1",cache_hit="true",success="true"}"#));
+        assert!(rendered.contains(r#"gas_estimation_duration_seconds_count{chain="1",cache_hit="false",success="true"}"#));
+        assert!(rendered.contains(r#"gas_estimation_duration_seconds_count{chain="1",cache_hit="false",success="false"}"#));
+    }
+
+    #[test]
+    fn test_metric_names_carry_namespace() {
+        let handle = test_handle();
+
+        Metrics::record_rpc_call(10, "eth_chainId", true, 0.01);
+        Metrics::record_provider_pool_size(3);
+
+        let rendered = handle.render();
+        assert!(rendered.contains(r#"test_rpc_calls_total{chain="10",method="eth_chainId"}"#));
+        assert!(rendered.contains(r#"test_rpc_call_duration_seconds_count{chain="10",method="eth_chainId"}"#));
+        assert!(rendered.lines().any(|line| line.starts_with("test_rpc_provider_pool_size ")));
+    }
+
+    #[test]
+    fn test_snapshot_reads_every_kind() {
+        test_handle();
+
+        Metrics::record_fee_cap_clamped(424242);
+        Metrics::record_rpc_permits_available(424242, 7);
+        Metrics::record_gas_estimation(424242, 0.5, true, true);
+
+        let snapshot = Metrics::snapshot();
+        assert!(snapshot.counter("fee_cap_clamped_total", &[("chain", "424242")]) >= 1.0);
+        assert_eq!(snapshot.gauge("rpc_concurrency_permits_available", &[("chain", "424242")]), Some(7.0));
+        let histogram = snapshot
+            .histogram("gas_estimation_duration_seconds", &[("chain", "424242"), ("cache_hit", "true"), ("success", "true")])
+            .unwrap();
+        assert!(histogram.count >= 1);
+        assert!(histogram.sum >= 0.5);
+    }
+}
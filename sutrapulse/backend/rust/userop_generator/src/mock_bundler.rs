@@ -0,0 +1,282 @@
+use ethers::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::bundler::{BundlerClient, UserOperationGasEstimate, UserOperationReceipt};
+use crate::userop::UserOperation;
+
+/// JSON-RPC error code bundlers use for ops the EntryPoint would reject.
+pub const REJECTED_BY_ENTRY_POINT: i64 = -32500;
+/// JSON-RPC error code for malformed or unsupported parameters.
+pub const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Debug, Default)]
+struct State {
+    /// Next nonce the EntryPoint expects from each sender.
+    nonces: HashMap<Address, U256>,
+    receipts: HashMap<H256, UserOperationReceipt>,
+    block_number: u64,
+}
+
+/// In-process bundler transport for tests. Ops are validated the way the
+/// EntryPoint would, then "included" immediately, one bundle per op, so a
+/// `send_user_operation` is followed by a receipt straight away. Hashes,
+/// block numbers and gas figures are deterministic.
+///
+/// ```ignore
+/// let bundler = MockBundler::new(entry_point, 1).into_client();
+/// let hash = bundler.send_user_operation(&user_op, entry_point).await?;
+/// let receipt = bundler.get_user_operation_receipt(hash).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockBundler {
+    entry_point: Address,
+    chain_id: u64,
+    beneficiary: Address,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockBundler {
+    pub fn new(entry_point: Address, chain_id: u64) -> Self {
+        Self {
+            entry_point,
+            chain_id,
+            beneficiary: Address::zero(),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Sets the `from` address of the simulated bundle transactions.
+    pub fn with_beneficiary(mut self, beneficiary: Address) -> Self {
+        self.beneficiary = beneficiary;
+        self
+    }
+
+    /// Starts `sender`'s nonce at `nonce` instead of zero.
+    pub fn with_nonce(self, sender: Address, nonce: U256) -> Self {
+        self.lock().nonces.insert(sender, nonce);
+        self
+    }
+
+    /// A `BundlerClient` talking to this mock. Clones share state.
+    pub fn into_client(self) -> BundlerClient<MockBundler> {
+        BundlerClient::with_provider(Provider::new(self))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("mock bundler state poisoned")
+    }
+
+    fn estimate(&self, user_op: &UserOperation) -> UserOperationGasEstimate {
+        let calldata_gas = 16 * (user_op.call_data.len() + user_op.init_code.len()) as u64;
+        UserOperationGasEstimate {
+            pre_verification_gas: U256::from(21_000 + calldata_gas),
+            verification_gas_limit: U256::from(if user_op.init_code.is_empty() { 100_000 } else { 400_000 }),
+            call_gas_limit: U256::from(100_000),
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        }
+    }
+
+    /// Checks `user_op` as `EntryPoint.handleOps` would and, if it passes,
+    /// records its inclusion in a new block.
+    fn include(&self, user_op: UserOperation, entry_point: Address) -> std::result::Result<H256, MockError> {
+        self.check_entry_point(entry_point)?;
+
+        let mut state = self.lock();
+        let expected_nonce = state.nonces.get(&user_op.sender).copied().unwrap_or_default();
+        if user_op.nonce != expected_nonce {
+            return Err(rejected(format!(
+                "AA25 invalid account nonce: expected {}, got {}",
+                expected_nonce, user_op.nonce
+            )));
+        }
+        if user_op.signature.is_empty() {
+            return Err(rejected("AA24 signature error".to_string()));
+        }
+        if user_op.verification_gas_limit.is_zero() {
+            return Err(rejected("verificationGasLimit must be non-zero".to_string()));
+        }
+        if user_op.max_priority_fee_per_gas > user_op.max_fee_per_gas {
+            return Err(rejected("maxPriorityFeePerGas exceeds maxFeePerGas".to_string()));
+        }
+
+        let user_op_hash = user_op.hash(self.entry_point, self.chain_id);
+        state.block_number += 1;
+        state.nonces.insert(user_op.sender, user_op.nonce + 1);
+
+        let actual_gas_used = user_op.call_gas_limit
+            + user_op.verification_gas_limit
+            + user_op.pre_verification_gas;
+        let actual_gas_cost = actual_gas_used.saturating_mul(user_op.max_fee_per_gas);
+        let block_number = state.block_number;
+        let receipt = TransactionReceipt {
+            transaction_hash: H256::from(ethers::utils::keccak256(
+                [user_op_hash.as_bytes(), &block_number.to_be_bytes()].concat(),
+            )),
+            block_number: Some(U64::from(block_number)),
+            block_hash: Some(H256::from_low_u64_be(block_number)),
+            from: self.beneficiary,
+            to: Some(self.entry_point),
+            gas_used: Some(actual_gas_used),
+            effective_gas_price: Some(user_op.max_fee_per_gas),
+            status: Some(U64::one()),
+            ..Default::default()
+        };
+
+        state.receipts.insert(user_op_hash, UserOperationReceipt {
+            user_op_hash,
+            entry_point: self.entry_point,
+            sender: user_op.sender,
+            nonce: user_op.nonce,
+            paymaster: user_op.paymaster(),
+            actual_gas_cost,
+            actual_gas_used,
+            success: true,
+            receipt,
+        });
+        Ok(user_op_hash)
+    }
+
+    fn check_entry_point(&self, entry_point: Address) -> std::result::Result<(), MockError> {
+        if entry_point != self.entry_point {
+            return Err(json_rpc_error(INVALID_PARAMS, format!("EntryPoint {:?} is not supported", entry_point)));
+        }
+        Ok(())
+    }
+
+    fn dispatch(&self, method: &str, params: Value) -> std::result::Result<Value, MockError> {
+        match method {
+            "eth_supportedEntryPoints" => Ok(serde_json::to_value([self.entry_point])?),
+            "eth_chainId" => Ok(serde_json::to_value(U64::from(self.chain_id))?),
+            "eth_estimateUserOperationGas" => {
+                let (user_op, entry_point): (UserOperation, Address) = serde_json::from_value(params)?;
+                self.check_entry_point(entry_point)?;
+                Ok(serde_json::to_value(self.estimate(&user_op))?)
+            }
+            "eth_sendUserOperation" => {
+                let (user_op, entry_point): (UserOperation, Address) = serde_json::from_value(params)?;
+                Ok(serde_json::to_value(self.include(user_op, entry_point)?)?)
+            }
+            "eth_getUserOperationReceipt" => {
+                let [user_op_hash]: [H256; 1] = serde_json::from_value(params)?;
+                Ok(serde_json::to_value(self.lock().receipts.get(&user_op_hash))?)
+            }
+            _ => Err(json_rpc_error(-32601, format!("Method {} is not supported by the mock bundler", method))),
+        }
+    }
+}
+
+fn json_rpc_error(code: i64, message: String) -> MockError {
+    MockError::JsonRpcError(JsonRpcError { code, message, data: None })
+}
+
+fn rejected(reason: String) -> MockError {
+    json_rpc_error(REJECTED_BY_ENTRY_POINT, reason)
+}
+
+#[async_trait::async_trait]
+impl JsonRpcClient for MockBundler {
+    type Error = MockError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> std::result::Result<R, MockError>
+    where
+        T: std::fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let result = self.dispatch(method, serde_json::to_value(params)?)?;
+        Ok(serde_json::from_value(result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::UserOpError;
+    use std::str::FromStr;
+
+    const ENTRY_POINT: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+
+    fn entry_point() -> Address {
+        Address::from_str(ENTRY_POINT).unwrap()
+    }
+
+    fn signed_user_op(nonce: u64) -> UserOperation {
+        let mut user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+            .with_nonce(U256::from(nonce))
+            .with_signature(Bytes::from(vec![0x11; 65]));
+        user_op.call_gas_limit = U256::from(100_000);
+        user_op.verification_gas_limit = U256::from(100_000);
+        user_op.pre_verification_gas = U256::from(21_000);
+        user_op.max_fee_per_gas = U256::from(10);
+        user_op.max_priority_fee_per_gas = U256::from(2);
+        user_op
+    }
+
+    #[tokio::test]
+    async fn test_send_then_receipt() {
+        let bundler = MockBundler::new(entry_point(), 1).into_client();
+        let user_op = signed_user_op(0);
+
+        let user_op_hash = bundler.send_user_operation(&user_op, entry_point()).await.unwrap();
+        assert_eq!(user_op_hash, user_op.hash(entry_point(), 1));
+
+        let receipt = bundler.get_user_operation_receipt(user_op_hash).await.unwrap().unwrap();
+        assert!(receipt.success);
+        assert_eq!(receipt.sender, user_op.sender);
+        assert_eq!(receipt.actual_gas_used, U256::from(221_000));
+        assert_eq!(receipt.actual_gas_cost, U256::from(2_210_000));
+        assert_eq!(receipt.receipt.block_number, Some(U64::one()));
+        assert_eq!(receipt.receipt.to, Some(entry_point()));
+
+        // The next nonce lands in the next block
+        let next_hash = bundler.send_user_operation(&signed_user_op(1), entry_point()).await.unwrap();
+        let next = bundler.get_user_operation_receipt(next_hash).await.unwrap().unwrap();
+        assert_eq!(next.receipt.block_number, Some(U64::from(2)));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_hash_has_no_receipt() {
+        let bundler = MockBundler::new(entry_point(), 1).into_client();
+        assert_eq!(bundler.get_user_operation_receipt(H256::repeat_byte(0xab)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_replayed_nonce_and_unsigned_ops() {
+        let bundler = MockBundler::new(entry_point(), 1).into_client();
+        bundler.send_user_operation(&signed_user_op(0), entry_point()).await.unwrap();
+
+        match bundler.send_user_operation(&signed_user_op(0), entry_point()).await {
+            Err(UserOpError::RPC(msg)) => assert!(msg.contains("AA25"), "{}", msg),
+            other => panic!("Expected a nonce rejection, got {:?}", other),
+        }
+
+        let unsigned = signed_user_op(1).with_signature(Bytes::default());
+        match bundler.send_user_operation(&unsigned, entry_point()).await {
+            Err(UserOpError::RPC(msg)) => assert!(msg.contains("AA24"), "{}", msg),
+            other => panic!("Expected a signature rejection, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_estimate_feeds_gas_params() {
+        let bundler = MockBundler::new(entry_point(), 1).into_client();
+        let user_op = signed_user_op(0).with_call_data(Bytes::from(vec![0u8; 10]));
+
+        let estimate = bundler.estimate_user_operation_gas(&user_op, entry_point(), 1).await.unwrap();
+
+        assert_eq!(estimate.pre_verification_gas, U256::from(21_160));
+        assert_eq!(bundler.min_pre_verification_gas(1), U256::from(21_160));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_entry_point_fails_early() {
+        let other = Address::from_str("0x0000000071727De22E5E9d8BAf0edAc6f37da032").unwrap();
+        let bundler = MockBundler::new(entry_point(), 1).into_client();
+
+        let result = bundler.send_user_operation(&signed_user_op(0), other).await;
+        assert!(matches!(result, Err(UserOpError::Config(_))));
+    }
+}
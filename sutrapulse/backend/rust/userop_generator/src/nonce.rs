@@ -0,0 +1,354 @@
+use dashmap::DashMap;
+use ethers::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::cache::GasCache;
+use crate::error::{Result, UserOpError};
+
+/// Largest value that fits the 192-bit nonce key.
+fn max_nonce_key() -> U256 {
+    (U256::one() << 192) - 1
+}
+
+/// Composes an ERC-4337 2D nonce: `(key << 64) | sequence`.
+pub fn compose_nonce(key: U256, sequence: u64) -> U256 {
+    (key << 64) | U256::from(sequence)
+}
+
+/// Splits a 2D nonce into its key and sequence.
+pub fn split_nonce(nonce: U256) -> (U256, u64) {
+    (nonce >> 64, nonce.low_u64())
+}
+
+/// How the 192-bit key half of a 2D nonce is chosen. Ops with different
+/// keys have independent sequences and can be included in parallel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NonceKeyStrategy {
+    /// Always use the given key.
+    Fixed(U256),
+    /// Pick a fresh random key for every op.
+    Random,
+    /// Derive a stable key from a caller id, giving each caller its own channel.
+    PerCaller(String),
+}
+
+impl Default for NonceKeyStrategy {
+    fn default() -> Self {
+        Self::Fixed(U256::zero())
+    }
+}
+
+impl NonceKeyStrategy {
+    pub fn resolve_key(&self) -> Result<U256> {
+        match self {
+            Self::Fixed(key) => {
+                if *key > max_nonce_key() {
+                    return Err(UserOpError::Validation(format!("Nonce key {} exceeds 192 bits", key)));
+                }
+                Ok(*key)
+            }
+            Self::Random => {
+                let bytes: [u8; 24] = ethers::core::rand::random();
+                Ok(U256::from_big_endian(&bytes))
+            }
+            Self::PerCaller(id) => {
+                let hash = ethers::utils::keccak256(id.as_bytes());
+                Ok(U256::from_big_endian(&hash[..24]))
+            }
+        }
+    }
+}
+
+/// The next sequence for one chain, sender and nonce key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NonceRecord {
+    pub chain_id: u64,
+    pub sender: Address,
+    pub key: U256,
+    pub sequence: u64,
+}
+
+/// Persists locally issued nonce sequences so in-flight ops survive restarts.
+/// Saves can block; `NonceManager` runs them off the async runtime.
+pub trait NonceStore: Send + Sync {
+    fn load(&self) -> Result<Vec<NonceRecord>>;
+    fn save(&self, records: &[NonceRecord]) -> Result<()>;
+}
+
+/// Stores nonce records as JSON in a single file.
+pub struct FileNonceStore {
+    path: PathBuf,
+}
+
+impl FileNonceStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl NonceStore for FileNonceStore {
+    fn load(&self) -> Result<Vec<NonceRecord>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let contents = std::fs::read_to_string(&self.path)
+            .map_err(|e| UserOpError::Cache(format!("Failed to read nonce store: {}", e)))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| UserOpError::Cache(format!("Invalid nonce store: {}", e)))
+    }
+
+    fn save(&self, records: &[NonceRecord]) -> Result<()> {
+        let contents = serde_json::to_vec_pretty(records)
+            .map_err(|e| UserOpError::Cache(e.to_string()))?;
+        let write_error = |e: std::io::Error| UserOpError::Cache(format!("Failed to write nonce store: {}", e));
+
+        // Write to a uniquely named sibling and rename it over the store, so
+        // neither a crash nor a concurrent save leaves a torn file
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir).map_err(write_error)?;
+        file.write_all(&contents)
+            .and_then(|_| file.as_file().sync_all())
+            .map_err(write_error)?;
+        file.persist(&self.path).map_err(|e| write_error(e.error))?;
+        Ok(())
+    }
+}
+
+/// Hands out locally incremented 2D nonces per chain, sender and key.
+#[derive(Default)]
+pub struct NonceManager {
+    sequences: DashMap<(u64, Address, U256), u64>,
+    /// The last on-chain sequence seen by `reconcile`. Keys whose local
+    /// sequence it has caught up with have nothing in flight and aren't
+    /// persisted.
+    confirmed: DashMap<(u64, Address, U256), u64>,
+    store: Option<Arc<dyn NonceStore>>,
+    /// Held across snapshotting and saving, so saves land in order.
+    persisting: tokio::sync::Mutex<()>,
+    gas_cache: Option<Arc<GasCache>>,
+}
+
+impl NonceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a manager backed by `store`, reloading its persisted sequences.
+    pub fn with_store(store: Arc<dyn NonceStore>) -> Result<Self> {
+        let sequences = DashMap::new();
+        for record in store.load()? {
+            sequences.insert((record.chain_id, record.sender, record.key), record.sequence);
+        }
+
+        Ok(Self {
+            sequences,
+            store: Some(store),
+            ..Self::default()
+        })
+    }
+
+    /// Shares `gas_cache`'s EntryPoint nonces so `claim_cached_nonce` can
+    /// hand them out.
+    pub fn with_gas_cache(mut self, gas_cache: Arc<GasCache>) -> Self {
+        self.gas_cache = Some(gas_cache);
+        self
+    }
+
+    /// Takes the cached EntryPoint nonce for `sender` and bumps the cache, so
+    /// concurrent callers never get the same value. Returns `None` when
+    /// nothing is cached and the nonce has to be read from chain.
+    pub async fn claim_cached_nonce(&self, chain_id: u64, sender: Address) -> Option<U256> {
+        let gas_cache = self.gas_cache.as_ref()?;
+        loop {
+            let current = gas_cache.get_nonce(chain_id, sender).await?;
+            if gas_cache.compare_and_increment_nonce(chain_id, sender, current).await.is_some() {
+                return Some(current);
+            }
+        }
+    }
+
+    /// Sequences with ops still in flight.
+    fn records(&self) -> Vec<NonceRecord> {
+        self.sequences
+            .iter()
+            .filter(|entry| {
+                self.confirmed
+                    .get(entry.key())
+                    .is_none_or(|confirmed| *confirmed < *entry.value())
+            })
+            .map(|entry| {
+                let (chain_id, sender, key) = *entry.key();
+                NonceRecord { chain_id, sender, key, sequence: *entry.value() }
+            })
+            .collect()
+    }
+
+    async fn persist(&self) -> Result<()> {
+        let Some(store) = self.store.clone() else {
+            return Ok(());
+        };
+        let _persisting = self.persisting.lock().await;
+        let records = self.records();
+        tokio::task::spawn_blocking(move || store.save(&records))
+            .await
+            .map_err(|e| UserOpError::Cache(format!("Nonce store save panicked: {}", e)))?
+    }
+
+    /// Reconciles a key's sequence with the EntryPoint's `getNonce(sender, key)`.
+    /// The on-chain value wins when it has advanced past the local one, e.g.
+    /// because ops were submitted elsewhere; otherwise the local sequence keeps
+    /// covering ops that are still in flight. Once the chain has caught up,
+    /// the key is dropped from the store.
+    pub async fn reconcile(&self, chain_id: u64, sender: Address, on_chain_nonce: U256) -> Result<u64> {
+        let (key, on_chain_sequence) = split_nonce(on_chain_nonce);
+        let sequence = {
+            let mut sequence = self.sequences.entry((chain_id, sender, key)).or_insert(0);
+            *sequence = (*sequence).max(on_chain_sequence);
+            *sequence
+        };
+        self.confirmed.insert((chain_id, sender, key), on_chain_sequence);
+        self.persist().await?;
+        Ok(sequence)
+    }
+
+    /// Returns the next nonce for `sender` under the key picked by `strategy`.
+    pub async fn next_nonce(&self, chain_id: u64, sender: Address, strategy: &NonceKeyStrategy) -> Result<U256> {
+        let key = strategy.resolve_key()?;
+        let nonce = {
+            let mut sequence = self.sequences.entry((chain_id, sender, key)).or_insert(0);
+            let nonce = compose_nonce(key, *sequence);
+            *sequence += 1;
+            nonce
+        };
+        self.persist().await?;
+        Ok(nonce)
+    }
+
+    /// The next sequence that will be handed out for a key.
+    pub fn sequence(&self, chain_id: u64, sender: Address, key: U256) -> u64 {
+        self.sequences
+            .get(&(chain_id, sender, key))
+            .map(|sequence| *sequence)
+            .unwrap_or(0)
+    }
+
+    /// Sets the next sequence for a key, e.g. after reading it from chain.
+    pub async fn set_sequence(&self, chain_id: u64, sender: Address, key: U256, sequence: u64) -> Result<()> {
+        self.sequences.insert((chain_id, sender, key), sequence);
+        self.persist().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compose_nonce_per_strategy() {
+        let fixed = NonceKeyStrategy::Fixed(U256::from(7)).resolve_key().unwrap();
+        assert_eq!(compose_nonce(fixed, 3), (U256::from(7) << 64) + 3);
+        assert_eq!(split_nonce(compose_nonce(fixed, 3)), (U256::from(7), 3));
+
+        let caller = NonceKeyStrategy::PerCaller("checkout".to_string());
+        assert_eq!(caller.resolve_key().unwrap(), caller.resolve_key().unwrap());
+        let (key, sequence) = split_nonce(compose_nonce(caller.resolve_key().unwrap(), 9));
+        assert_eq!(key, caller.resolve_key().unwrap());
+        assert_eq!(sequence, 9);
+
+        let random = NonceKeyStrategy::Random.resolve_key().unwrap();
+        assert!(random <= max_nonce_key());
+        assert_eq!(split_nonce(compose_nonce(random, 1)).0, random);
+
+        assert!(NonceKeyStrategy::Fixed(U256::one() << 192).resolve_key().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_keys_have_independent_sequences() {
+        let manager = NonceManager::new();
+        let sender = Address::from_low_u64_be(1);
+        let a = NonceKeyStrategy::Fixed(U256::from(1));
+        let b = NonceKeyStrategy::Fixed(U256::from(2));
+
+        assert_eq!(manager.next_nonce(1, sender, &a).await.unwrap(), compose_nonce(U256::from(1), 0));
+        assert_eq!(manager.next_nonce(1, sender, &a).await.unwrap(), compose_nonce(U256::from(1), 1));
+        assert_eq!(manager.next_nonce(1, sender, &b).await.unwrap(), compose_nonce(U256::from(2), 0));
+        assert_eq!(manager.next_nonce(137, sender, &a).await.unwrap(), compose_nonce(U256::from(1), 0));
+
+        assert_eq!(manager.sequence(1, sender, U256::from(1)), 2);
+        assert_eq!(manager.sequence(1, sender, U256::from(2)), 1);
+    }
+
+    #[tokio::test]
+    async fn test_nonce_store_save_reload_reconcile() {
+        let path = std::env::temp_dir().join(format!("userop-nonces-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sender = Address::from_low_u64_be(1);
+        let strategy = NonceKeyStrategy::Fixed(U256::from(3));
+
+        let manager = NonceManager::with_store(Arc::new(FileNonceStore::new(&path))).unwrap();
+        manager.next_nonce(1, sender, &strategy).await.unwrap();
+        manager.next_nonce(1, sender, &strategy).await.unwrap();
+        drop(manager);
+
+        // A restarted manager picks up where the previous one stopped
+        let manager = NonceManager::with_store(Arc::new(FileNonceStore::new(&path))).unwrap();
+        assert_eq!(manager.sequence(1, sender, U256::from(3)), 2);
+
+        // On-chain behind the local sequence: keep the local value for in-flight ops
+        assert_eq!(manager.reconcile(1, sender, compose_nonce(U256::from(3), 1)).await.unwrap(), 2);
+
+        // On-chain advanced past the persisted value: jump ahead
+        assert_eq!(manager.reconcile(1, sender, compose_nonce(U256::from(3), 5)).await.unwrap(), 5);
+        assert_eq!(manager.next_nonce(1, sender, &strategy).await.unwrap(), compose_nonce(U256::from(3), 5));
+
+        let reloaded = NonceManager::with_store(Arc::new(FileNonceStore::new(&path))).unwrap();
+        assert_eq!(reloaded.sequence(1, sender, U256::from(3)), 6);
+
+        // Once everything issued is confirmed on chain, the key is pruned
+        assert_eq!(manager.reconcile(1, sender, compose_nonce(U256::from(3), 6)).await.unwrap(), 6);
+        assert_eq!(manager.sequence(1, sender, U256::from(3)), 6);
+        let reloaded = NonceManager::with_store(Arc::new(FileNonceStore::new(&path))).unwrap();
+        assert_eq!(reloaded.sequence(1, sender, U256::from(3)), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_claims_get_sequential_nonces() {
+        let gas_cache = Arc::new(GasCache::new());
+        let manager = Arc::new(NonceManager::new().with_gas_cache(gas_cache.clone()));
+        let sender = Address::from_low_u64_be(1);
+        gas_cache.set_nonce(1, sender, U256::from(10)).await;
+
+        let tasks: Vec<_> = (0..64)
+            .map(|_| {
+                let manager = manager.clone();
+                tokio::spawn(async move { manager.claim_cached_nonce(1, sender).await.unwrap() })
+            })
+            .collect();
+        let mut nonces = Vec::new();
+        for task in tasks {
+            nonces.push(task.await.unwrap());
+        }
+        nonces.sort();
+
+        let expected: Vec<U256> = (10..74).map(U256::from).collect();
+        assert_eq!(nonces, expected);
+        assert_eq!(gas_cache.get_nonce(1, sender).await, Some(U256::from(74)));
+    }
+
+    #[tokio::test]
+    async fn test_claim_without_cached_nonce() {
+        let sender = Address::from_low_u64_be(1);
+        assert_eq!(NonceManager::new().claim_cached_nonce(1, sender).await, None);
+
+        let manager = NonceManager::new().with_gas_cache(Arc::new(GasCache::new()));
+        assert_eq!(manager.claim_cached_nonce(1, sender).await, None);
+    }
+}
@@ -0,0 +1,352 @@
+use ethers::abi::Token;
+use ethers::prelude::*;
+use ethers::utils::keccak256;
+use std::sync::Arc;
+use crate::contracts::EntryPointVersion;
+use crate::error::{Result, UserOpError};
+use crate::gas::GasEstimator;
+use crate::userop::UserOperation;
+
+abigen!(
+    ITokenPaymaster,
+    r#"[
+        function getTokenAmount(address token, uint256 ethCost) external view returns (uint256)
+    ]"#
+);
+
+/// Sponsors ops through an ERC-20 paymaster: the wallet pays the paymaster in
+/// `token`, capped at a quoted maximum.
+///
+/// `paymasterAndData` layout: `paymaster (20) | token (20) | maxTokenCost (32)`.
+pub struct TokenPaymaster<M = Provider<Http>> {
+    contract: ITokenPaymaster<M>,
+}
+
+impl<M: Middleware> TokenPaymaster<M> {
+    pub fn new(address: Address, client: Arc<M>) -> Self {
+        Self {
+            contract: ITokenPaymaster::new(address, client),
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.contract.address()
+    }
+
+    /// Asks the paymaster's price oracle how much `token` covers `gas_cost` wei.
+    pub async fn quote(&self, token: Address, gas_cost: U256) -> Result<U256> {
+        self.contract
+            .get_token_amount(token, gas_cost)
+            .call()
+            .await
+            .map_err(|e| UserOpError::Contract(e.to_string()))
+    }
+
+    pub fn paymaster_and_data(&self, token: Address, max_token_cost: U256) -> Bytes {
+        encode_token_paymaster_data(self.address(), token, max_token_cost)
+    }
+
+    /// Quotes the op's current max gas cost and attaches the resulting
+    /// `paymasterAndData`.
+    pub async fn attach(&self, user_op: UserOperation, token: Address) -> Result<UserOperation> {
+        let max_token_cost = self.quote(token, user_op.gas_params().max_gas_cost()).await?;
+        let mut user_op = user_op;
+        user_op.paymaster_and_data = self.paymaster_and_data(token, max_token_cost);
+        Ok(user_op)
+    }
+
+    /// Attaches the paymaster, re-estimates gas with it in place, then
+    /// re-quotes so the token cap covers the final gas limits.
+    pub async fn sponsor<P: JsonRpcClient>(
+        &self,
+        user_op: UserOperation,
+        token: Address,
+        gas_estimator: &GasEstimator<P>,
+        chain_id: u64,
+    ) -> Result<UserOperation> {
+        let user_op = self.attach(user_op, token).await?;
+        let gas_params = gas_estimator.estimate_gas(&user_op, chain_id, None).await?;
+        self.attach(user_op.with_gas_params(&gas_params), token).await
+    }
+}
+
+pub fn encode_token_paymaster_data(paymaster: Address, token: Address, max_token_cost: U256) -> Bytes {
+    let mut cost = [0u8; 32];
+    max_token_cost.to_big_endian(&mut cost);
+    Bytes::from([paymaster.as_bytes(), token.as_bytes(), &cost[..]].concat())
+}
+
+/// A self-hosted verifying paymaster that sponsors ops carrying its
+/// sponsor's signature, hashed as the reference v0.6 `VerifyingPaymaster`
+/// does.
+///
+/// `paymasterAndData` layout:
+/// `paymaster (20) | abi.encode(validUntil, validAfter) (64) | signature (65)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyingPaymaster {
+    address: Address,
+    chain_id: u64,
+}
+
+impl VerifyingPaymaster {
+    pub fn new(address: Address, chain_id: u64) -> Self {
+        Self { address, chain_id }
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// `VerifyingPaymaster.getHash`: the op's fields other than
+    /// `paymasterAndData` and the signature, the chain, the paymaster, the
+    /// contract's `senderNonce(sender)` and the validity window.
+    pub fn hash(&self, user_op: &UserOperation, sender_nonce: U256, valid_until: u64, valid_after: u64) -> H256 {
+        let encoded = ethers::abi::encode(&[
+            Token::Address(user_op.sender),
+            Token::Uint(user_op.nonce),
+            Token::FixedBytes(keccak256(&user_op.init_code).to_vec()),
+            Token::FixedBytes(keccak256(&user_op.call_data).to_vec()),
+            Token::Uint(user_op.call_gas_limit),
+            Token::Uint(user_op.verification_gas_limit),
+            Token::Uint(user_op.pre_verification_gas),
+            Token::Uint(user_op.max_fee_per_gas),
+            Token::Uint(user_op.max_priority_fee_per_gas),
+            Token::Uint(U256::from(self.chain_id)),
+            Token::Address(self.address),
+            Token::Uint(sender_nonce),
+            Token::Uint(U256::from(valid_until)),
+            Token::Uint(U256::from(valid_after)),
+        ]);
+        keccak256(encoded).into()
+    }
+
+    /// Signs the op's paymaster hash with `sponsor` (EIP-191, as the
+    /// contract recovers it) and returns the complete `paymasterAndData`.
+    /// The op's gas and fees must be final, and the wallet signs after
+    /// this since `paymasterAndData` is part of the userOpHash. The window
+    /// is checked and laid out by `UserOperation::with_verifying_paymaster`;
+    /// a `valid_until` of 0 means no expiry.
+    pub async fn sign_paymaster_data<S: Signer>(
+        &self,
+        user_op: &UserOperation,
+        sender_nonce: U256,
+        valid_until: u64,
+        valid_after: u64,
+        sponsor: &S,
+    ) -> Result<Bytes> {
+        let hash = self.hash(user_op, sender_nonce, valid_until, valid_after);
+        let signature = sponsor
+            .sign_message(hash)
+            .await
+            .map_err(|e| UserOpError::Signature(e.to_string()))?;
+
+        let sponsored = user_op
+            .clone()
+            .with_verifying_paymaster(self.address, valid_until, valid_after, signature.to_vec().into())?;
+        Ok(sponsored.paymaster_and_data)
+    }
+}
+
+/// `paymasterAndData` split into its fields. Layouts by EntryPoint version:
+///
+/// - v0.6: `paymaster (20) | data`
+/// - v0.7: `paymaster (20) | verificationGasLimit (16) | postOpGasLimit (16) | data`
+///
+/// Empty bytes mean the op has no paymaster.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PaymasterAndData {
+    pub paymaster: Option<Address>,
+    /// v0.7 only.
+    pub paymaster_verification_gas_limit: Option<U256>,
+    /// v0.7 only.
+    pub paymaster_post_op_gas_limit: Option<U256>,
+    pub data: Bytes,
+}
+
+impl PaymasterAndData {
+    pub fn parse(bytes: &[u8], version: EntryPointVersion) -> Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let header_len = match version {
+            EntryPointVersion::V06 => 20,
+            EntryPointVersion::V07 | EntryPointVersion::V08 => 52,
+        };
+        if bytes.len() < header_len {
+            return Err(UserOpError::Validation(format!(
+                "paymasterAndData is {} bytes, {:?} needs at least {}",
+                bytes.len(), version, header_len
+            )));
+        }
+
+        let (gas_limit, post_op_gas_limit) = match version {
+            EntryPointVersion::V06 => (None, None),
+            EntryPointVersion::V07 | EntryPointVersion::V08 => (
+                Some(U256::from_big_endian(&bytes[20..36])),
+                Some(U256::from_big_endian(&bytes[36..52])),
+            ),
+        };
+
+        Ok(Self {
+            paymaster: Some(Address::from_slice(&bytes[..20])),
+            paymaster_verification_gas_limit: gas_limit,
+            paymaster_post_op_gas_limit: post_op_gas_limit,
+            data: Bytes::from(bytes[header_len..].to_vec()),
+        })
+    }
+
+    /// Re-encodes in the v0.7 layout when either gas limit is set, else the
+    /// v0.6 one. Gas limits are truncated to their 128-bit field.
+    pub fn to_bytes(&self) -> Bytes {
+        let paymaster = match self.paymaster {
+            Some(paymaster) => paymaster,
+            None => return Bytes::default(),
+        };
+
+        let mut bytes = paymaster.as_bytes().to_vec();
+        if self.paymaster_verification_gas_limit.is_some() || self.paymaster_post_op_gas_limit.is_some() {
+            for gas_limit in [self.paymaster_verification_gas_limit, self.paymaster_post_op_gas_limit] {
+                bytes.extend_from_slice(&gas_limit.unwrap_or_default().low_u128().to_be_bytes());
+            }
+        }
+        bytes.extend_from_slice(&self.data);
+        Bytes::from(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const PAYMASTER: &str = "0x2234567890123456789012345678901234567890";
+    const TOKEN: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+
+    #[tokio::test]
+    async fn test_token_paymaster_data_layout() {
+        let (provider, mock) = Provider::mocked();
+        let quote = U256::from(1_250_000u64); // 1.25 USDC
+        mock.push::<Bytes, _>(Bytes::from(ethers::abi::encode(&[Token::Uint(quote)]))).unwrap();
+
+        let paymaster = TokenPaymaster::new(Address::from_str(PAYMASTER).unwrap(), Arc::new(provider));
+        let token = Address::from_str(TOKEN).unwrap();
+        let user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap());
+
+        let user_op = paymaster.attach(user_op, token).await.unwrap();
+        let data = user_op.paymaster_and_data;
+
+        assert_eq!(data.len(), 72);
+        assert_eq!(&data[..20], Address::from_str(PAYMASTER).unwrap().as_bytes());
+        assert_eq!(&data[20..40], token.as_bytes());
+        assert_eq!(U256::from_big_endian(&data[40..]), quote);
+    }
+
+    #[test]
+    fn test_empty_paymaster_and_data_round_trip() {
+        for version in [EntryPointVersion::V06, EntryPointVersion::V07] {
+            let parsed = PaymasterAndData::parse(&[], version).unwrap();
+            assert_eq!(parsed, PaymasterAndData::default());
+            assert!(parsed.to_bytes().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_v06_paymaster_and_data_round_trip() {
+        let paymaster = Address::from_str(PAYMASTER).unwrap();
+        let bytes = encode_token_paymaster_data(paymaster, Address::from_str(TOKEN).unwrap(), U256::from(1_250_000u64));
+
+        let parsed = PaymasterAndData::parse(&bytes, EntryPointVersion::V06).unwrap();
+
+        assert_eq!(parsed.paymaster, Some(paymaster));
+        assert_eq!(parsed.paymaster_verification_gas_limit, None);
+        assert_eq!(&parsed.data[..], &bytes[20..]);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_v07_paymaster_and_data_round_trip() {
+        let paymaster = Address::from_str(PAYMASTER).unwrap();
+        let mut bytes = paymaster.as_bytes().to_vec();
+        bytes.extend_from_slice(&60_000u128.to_be_bytes());
+        bytes.extend_from_slice(&30_000u128.to_be_bytes());
+        bytes.extend_from_slice(&[0xab, 0xcd]);
+
+        let parsed = PaymasterAndData::parse(&bytes, EntryPointVersion::V07).unwrap();
+
+        assert_eq!(parsed.paymaster, Some(paymaster));
+        assert_eq!(parsed.paymaster_verification_gas_limit, Some(U256::from(60_000)));
+        assert_eq!(parsed.paymaster_post_op_gas_limit, Some(U256::from(30_000)));
+        assert_eq!(parsed.data, Bytes::from(vec![0xab, 0xcd]));
+        assert_eq!(parsed.to_bytes(), Bytes::from(bytes.clone()));
+
+        // Too short for the v0.7 gas fields
+        assert!(matches!(
+            PaymasterAndData::parse(&bytes[..40], EntryPointVersion::V07),
+            Err(UserOpError::Validation(_))
+        ));
+    }
+
+    fn sponsored_user_op() -> UserOperation {
+        let mut user_op = UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+            .with_nonce(U256::from(7))
+            .with_call_data(Bytes::from(vec![0xde, 0xad, 0xbe, 0xef]));
+        user_op.call_gas_limit = U256::from(100_000);
+        user_op.verification_gas_limit = U256::from(150_000);
+        user_op.pre_verification_gas = U256::from(21_000);
+        user_op.max_fee_per_gas = U256::from(30_000_000_000u64);
+        user_op.max_priority_fee_per_gas = U256::from(1_000_000_000u64);
+        user_op
+    }
+
+    #[test]
+    fn test_verifying_paymaster_hash_matches_reference() {
+        let paymaster = VerifyingPaymaster::new(Address::from_str(PAYMASTER).unwrap(), 1);
+
+        // keccak256(abi.encode(...)) as VerifyingPaymaster.getHash computes it
+        let hash = paymaster.hash(&sponsored_user_op(), U256::from(2), 1_700_003_600, 1_700_000_000);
+        assert_eq!(
+            hash,
+            H256::from_str("0x983fee7a9369c2bfe68be3db089d8ad254751058199c13696a79183eacdb0b2b").unwrap()
+        );
+
+        // The signature and paymasterAndData don't take part
+        let signed = sponsored_user_op().with_signature(Bytes::from(vec![0x11; 65]));
+        assert_eq!(paymaster.hash(&signed, U256::from(2), 1_700_003_600, 1_700_000_000), hash);
+    }
+
+    #[tokio::test]
+    async fn test_sign_paymaster_data_layout() {
+        let paymaster = VerifyingPaymaster::new(Address::from_str(PAYMASTER).unwrap(), 1);
+        let sponsor = LocalWallet::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+        let user_op = sponsored_user_op();
+
+        let data = paymaster
+            .sign_paymaster_data(&user_op, U256::from(2), 1_700_003_600, 1_700_000_000, &sponsor)
+            .await
+            .unwrap();
+
+        assert_eq!(data.len(), 149);
+        assert_eq!(&data[..20], paymaster.address().as_bytes());
+        assert_eq!(U256::from_big_endian(&data[20..52]), U256::from(1_700_003_600u64));
+        assert_eq!(U256::from_big_endian(&data[52..84]), U256::from(1_700_000_000u64));
+
+        let signature = Signature::try_from(&data[84..]).unwrap();
+        let hash = paymaster.hash(&user_op, U256::from(2), 1_700_003_600, 1_700_000_000);
+        // Recovered as the contract does, over the EIP-191 message hash
+        assert_eq!(signature.recover(hash.as_bytes()).unwrap(), sponsor.address());
+    }
+
+    #[tokio::test]
+    async fn test_sign_paymaster_data_rejects_bad_window() {
+        let paymaster = VerifyingPaymaster::new(Address::from_str(PAYMASTER).unwrap(), 1);
+        let sponsor = LocalWallet::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+
+        for (valid_until, valid_after) in [(100, 100), (1 << 48, 0)] {
+            let result = paymaster
+                .sign_paymaster_data(&sponsored_user_op(), U256::zero(), valid_until, valid_after, &sponsor)
+                .await;
+            assert!(matches!(result, Err(UserOpError::Validation(_))), "{:?}", result);
+        }
+    }
+}
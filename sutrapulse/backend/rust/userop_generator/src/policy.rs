@@ -0,0 +1,99 @@
+use ethers::types::Address;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use crate::error::{Result, UserOpError};
+
+/// Which senders ops are processed for. Denied senders are always rejected;
+/// a non-empty allowlist additionally rejects everyone not on it. The
+/// default, empty policy allows every sender.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SenderPolicy {
+    allowlist: HashSet<Address>,
+    denylist: HashSet<Address>,
+}
+
+/// A `SenderPolicy` that can be swapped while ops are being processed.
+pub type SharedSenderPolicy = Arc<RwLock<SenderPolicy>>;
+
+impl SenderPolicy {
+    pub fn new(allowlist: impl IntoIterator<Item = Address>, denylist: impl IntoIterator<Item = Address>) -> Self {
+        Self {
+            allowlist: allowlist.into_iter().collect(),
+            denylist: denylist.into_iter().collect(),
+        }
+    }
+
+    pub fn allow(mut self, sender: Address) -> Self {
+        self.allowlist.insert(sender);
+        self
+    }
+
+    pub fn deny(mut self, sender: Address) -> Self {
+        self.denylist.insert(sender);
+        self
+    }
+
+    pub fn into_shared(self) -> SharedSenderPolicy {
+        Arc::new(RwLock::new(self))
+    }
+
+    pub fn is_allowed(&self, sender: Address) -> bool {
+        !self.denylist.contains(&sender) && (self.allowlist.is_empty() || self.allowlist.contains(&sender))
+    }
+
+    /// Fails with `UserOpError::Validation` if `sender` may not be processed.
+    pub fn check(&self, sender: Address) -> Result<()> {
+        if self.denylist.contains(&sender) {
+            return Err(UserOpError::Validation(format!("Sender {:?} is denied", sender)));
+        }
+        if !self.is_allowed(sender) {
+            return Err(UserOpError::Validation(format!("Sender {:?} is not on the allowlist", sender)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_policy_allows_everyone() {
+        let policy = SenderPolicy::default();
+        assert!(policy.check(Address::from_low_u64_be(1)).is_ok());
+        assert!(policy.check(Address::zero()).is_ok());
+    }
+
+    #[test]
+    fn test_allowlist_rejects_unlisted_senders() {
+        let policy = SenderPolicy::default().allow(Address::from_low_u64_be(1));
+
+        assert!(policy.check(Address::from_low_u64_be(1)).is_ok());
+        match policy.check(Address::from_low_u64_be(2)) {
+            Err(UserOpError::Validation(msg)) => assert!(msg.contains("allowlist"), "{}", msg),
+            other => panic!("Expected validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_denylist_wins_over_allowlist() {
+        let sender = Address::from_low_u64_be(1);
+        let policy = SenderPolicy::new([sender], [sender]);
+
+        assert!(matches!(policy.check(sender), Err(UserOpError::Validation(_))));
+        // Without an allowlist everyone else still passes
+        let policy = SenderPolicy::default().deny(sender);
+        assert!(matches!(policy.check(sender), Err(UserOpError::Validation(_))));
+        assert!(policy.check(Address::from_low_u64_be(2)).is_ok());
+    }
+
+    #[test]
+    fn test_shared_policy_can_be_swapped() {
+        let sender = Address::from_low_u64_be(1);
+        let shared = SenderPolicy::default().into_shared();
+        assert!(shared.read().unwrap().check(sender).is_ok());
+
+        *shared.write().unwrap() = SenderPolicy::default().deny(sender);
+        assert!(shared.read().unwrap().check(sender).is_err());
+    }
+}
@@ -0,0 +1,819 @@
+use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
+use ethers::prelude::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+use crate::error::{Result, UserOpError};
+use crate::metrics::Metrics;
+
+/// Builds a fresh provider for a URL.
+pub type Connector<P> = Arc<dyn Fn(&str) -> Result<Provider<P>> + Send + Sync>;
+
+/// Returns true for transport failures (DNS, refused or dropped connections)
+/// as opposed to JSON-RPC error responses such as reverts, which must not be retried.
+pub fn is_connection_error(err: &ProviderError) -> bool {
+    match err {
+        ProviderError::JsonRpcClientError(e) => is_transport_error(e.as_ref()),
+        ProviderError::HTTPError(_) => true,
+        _ => false,
+    }
+}
+
+/// A client error that is neither a JSON-RPC error response nor a malformed
+/// payload, i.e. the request never got a proper answer.
+fn is_transport_error<E: RpcError + ?Sized>(err: &E) -> bool {
+    err.as_error_response().is_none() && err.as_serde_error().is_none()
+}
+
+/// Builds the reqwest client used for HTTP providers. With `compression` on,
+/// reqwest advertises `Accept-Encoding: gzip, deflate` and transparently
+/// decodes compressed responses.
+pub fn http_client(compression: bool) -> Result<reqwest::Client> {
+    http_client_with_headers(compression, &HashMap::new())
+}
+
+/// Like `http_client`, sending `headers` with every request. Header values
+/// are marked sensitive and never appear in errors.
+pub fn http_client_with_headers(compression: bool, headers: &HashMap<String, String>) -> Result<reqwest::Client> {
+    let mut default_headers = HeaderMap::new();
+    for (name, value) in headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| UserOpError::RPC(format!("Invalid header name {:?}: {}", name, e)))?;
+        let mut header_value = HeaderValue::from_str(value)
+            .map_err(|_| UserOpError::RPC(format!("Invalid value for header {:?}", name)))?;
+        header_value.set_sensitive(true);
+        default_headers.insert(header_name, header_value);
+    }
+
+    reqwest::Client::builder()
+        .gzip(compression)
+        .deflate(compression)
+        .default_headers(default_headers)
+        .build()
+        .map_err(|e| UserOpError::RPC(e.to_string()))
+}
+
+/// Builds an HTTP provider for `url`, optionally negotiating compression.
+pub fn http_provider(url: &str, compression: bool) -> Result<Provider<Http>> {
+    http_provider_with_headers(url, compression, &HashMap::new())
+}
+
+/// Like `http_provider`, for RPCs that authenticate with headers.
+pub fn http_provider_with_headers(
+    url: &str,
+    compression: bool,
+    headers: &HashMap<String, String>,
+) -> Result<Provider<Http>> {
+    Ok(Provider::new(http_transport(url, compression, headers)?))
+}
+
+fn http_transport(url: &str, compression: bool, headers: &HashMap<String, String>) -> Result<Http> {
+    let url = url.parse::<reqwest::Url>().map_err(|e| UserOpError::RPC(e.to_string()))?;
+    Ok(Http::new_with_client(url, http_client_with_headers(compression, headers)?))
+}
+
+/// The transport the crate's own providers use: HTTP, retried on connection
+/// failures, with each request's payload sizes recorded and, optionally,
+/// the request logged.
+pub type RpcClient = LoggingClient<MeteredClient<ConnectionRetryClient<Http>>>;
+
+/// Times `RpcClient` retries a request that failed at the transport level.
+pub const DEFAULT_CONNECTION_RETRIES: u32 = 2;
+
+/// Delay between `RpcClient`'s transport-level retries.
+pub const DEFAULT_CONNECTION_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Builds a provider over `RpcClient` for `url`, logging requests when
+/// `log_requests` is set. This is what `Config`, `RpcCache` and
+/// `ReconnectingProvider` hand out.
+pub fn rpc_provider(
+    url: &str,
+    compression: bool,
+    log_requests: bool,
+    headers: &HashMap<String, String>,
+) -> Result<Provider<RpcClient>> {
+    let transport = ConnectionRetryClient::new(
+        http_transport(url, compression, headers)?,
+        DEFAULT_CONNECTION_RETRIES,
+        DEFAULT_CONNECTION_RETRY_DELAY,
+    );
+    let mut provider = Provider::new(LoggingClient::new(MeteredClient::new(transport), log_requests));
+    // Local nodes mine fast; poll them as often as `ProviderExt::try_connect` does
+    if is_local_endpoint(url) {
+        provider.set_interval(DEFAULT_LOCAL_POLL_INTERVAL);
+    }
+    Ok(provider)
+}
+
+/// Errors from the wrapping clients here that decode responses themselves.
+#[derive(Debug, thiserror::Error)]
+pub enum MeteredClientError<E> {
+    #[error(transparent)]
+    Client(E),
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+}
+
+impl<E: RpcError> RpcError for MeteredClientError<E> {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        match self {
+            MeteredClientError::Client(e) => e.as_error_response(),
+            MeteredClientError::Serde(_) => None,
+        }
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        match self {
+            MeteredClientError::Client(e) => e.as_serde_error(),
+            MeteredClientError::Serde(e) => Some(e),
+        }
+    }
+}
+
+impl<E: RpcError + 'static> From<MeteredClientError<E>> for ProviderError {
+    fn from(err: MeteredClientError<E>) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(err))
+    }
+}
+
+/// Wraps a JSON-RPC client and records the serialized size of each request's
+/// params and response. Sizes are of the JSON payload before any transport
+/// compression.
+#[derive(Debug, Clone)]
+pub struct MeteredClient<C> {
+    inner: C,
+}
+
+impl<C> MeteredClient<C> {
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: JsonRpcClient + 'static> JsonRpcClient for MeteredClient<C> {
+    type Error = MeteredClientError<C::Error>;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> std::result::Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let request_bytes = serde_json::to_vec(&params)?.len();
+        let response: serde_json::Value = self.inner
+            .request(method, params)
+            .await
+            .map_err(MeteredClientError::Client)?;
+        let response_bytes = serde_json::to_vec(&response)?.len();
+
+        Metrics::record_rpc_payload_size(method, request_bytes, response_bytes);
+        Ok(serde_json::from_value(response)?)
+    }
+}
+
+/// Methods whose params carry signed payloads or keys and are never logged.
+const SENSITIVE_METHODS: [&str; 6] = [
+    "eth_sendRawTransaction",
+    "eth_sign",
+    "eth_signTransaction",
+    "eth_signTypedData_v4",
+    "personal_sign",
+    "personal_unlockAccount",
+];
+
+/// Responses longer than this many characters are cut in logs.
+const MAX_LOGGED_RESPONSE_CHARS: usize = 256;
+
+/// Wraps a JSON-RPC client and, when `verbose` is set, logs each request's
+/// method, params and (truncated) response at `debug` level. Params of
+/// signing methods are redacted. With `verbose` off, or debug logging
+/// disabled, requests go straight through without any serialization.
+#[derive(Debug, Clone)]
+pub struct LoggingClient<C> {
+    inner: C,
+    verbose: bool,
+}
+
+impl<C> LoggingClient<C> {
+    pub fn new(inner: C, verbose: bool) -> Self {
+        Self { inner, verbose }
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: JsonRpcClient + 'static> JsonRpcClient for LoggingClient<C> {
+    type Error = MeteredClientError<C::Error>;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> std::result::Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        if !self.verbose || !tracing::enabled!(tracing::Level::DEBUG) {
+            return self.inner.request(method, params).await.map_err(MeteredClientError::Client);
+        }
+
+        if SENSITIVE_METHODS.contains(&method) {
+            debug!(method, params = "<redacted>", "RPC request");
+        } else {
+            let params = serde_json::to_string(&params)?;
+            debug!(method, params = %params, "RPC request");
+        }
+
+        let response: serde_json::Value = match self.inner.request(method, params).await {
+            Ok(response) => response,
+            Err(e) => {
+                debug!(method, error = %e, "RPC error");
+                return Err(MeteredClientError::Client(e));
+            }
+        };
+
+        let rendered = response.to_string();
+        let truncated: String = rendered.chars().take(MAX_LOGGED_RESPONSE_CHARS).collect();
+        if truncated.len() < rendered.len() {
+            debug!(method, response = %format!("{}...", truncated), "RPC response");
+        } else {
+            debug!(method, response = %truncated, "RPC response");
+        }
+
+        Ok(serde_json::from_value(response)?)
+    }
+}
+
+/// Methods that submit something. A request that failed at the transport
+/// level may still have reached the node, so these are never resent.
+const NON_IDEMPOTENT_METHODS: [&str; 4] = [
+    "eth_sendPrivateTransaction",
+    "eth_sendRawTransaction",
+    "eth_sendTransaction",
+    "eth_sendUserOperation",
+];
+
+/// Wraps a JSON-RPC client and transparently retries requests that failed
+/// at the transport level, such as a reset connection. This sits below
+/// `with_retry`: a retried blip never uses up an op-level attempt, and
+/// JSON-RPC error responses are returned untouched for `with_retry` to judge.
+/// Submissions (`NON_IDEMPOTENT_METHODS`) are sent once.
+#[derive(Debug, Clone)]
+pub struct ConnectionRetryClient<C> {
+    inner: C,
+    max_retries: u32,
+    delay: Duration,
+}
+
+impl<C> ConnectionRetryClient<C> {
+    /// Retries each request up to `max_retries` times, `delay` apart.
+    pub fn new(inner: C, max_retries: u32, delay: Duration) -> Self {
+        Self { inner, max_retries, delay }
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: JsonRpcClient + 'static> JsonRpcClient for ConnectionRetryClient<C> {
+    type Error = C::Error;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> std::result::Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        if NON_IDEMPOTENT_METHODS.contains(&method) {
+            return self.inner.request(method, params).await;
+        }
+
+        let mut retries = 0;
+        loop {
+            match self.inner.request(method, &params).await {
+                Err(e) if retries < self.max_retries && is_transport_error(&e) => {
+                    retries += 1;
+                    warn!(
+                        "{} failed at the transport level, retrying ({}/{}): {}",
+                        method, retries, self.max_retries, e
+                    );
+                    sleep(self.delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A provider that rebuilds its underlying client after connection-class
+/// errors and retries the failed call once. Submissions
+/// (`NON_IDEMPOTENT_METHODS`) are never retried; their connection errors
+/// only trigger the reconnect for later calls.
+pub struct ReconnectingProvider<P = RpcClient> {
+    url: String,
+    connect: Connector<P>,
+    inner: RwLock<Provider<P>>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_reconnect_attempts: u32,
+}
+
+impl ReconnectingProvider<RpcClient> {
+    /// Connects with `rpc_provider`, with the same compression and logging flags.
+    pub fn new(url: &str, compression: bool, log_requests: bool) -> Result<Self> {
+        Self::with_connector(
+            url,
+            Arc::new(move |url: &str| rpc_provider(url, compression, log_requests, &HashMap::new())),
+        )
+    }
+}
+
+impl<P: JsonRpcClient + Clone> ReconnectingProvider<P> {
+    pub fn with_connector(url: &str, connect: Connector<P>) -> Result<Self> {
+        let provider = connect(url)?;
+
+        Ok(Self {
+            url: url.to_string(),
+            connect,
+            inner: RwLock::new(provider),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            max_reconnect_attempts: 3,
+        })
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration, max_attempts: u32) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self.max_reconnect_attempts = max_attempts;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The current underlying provider.
+    pub fn provider(&self) -> Provider<P> {
+        self.inner.read().expect("provider lock poisoned").clone()
+    }
+
+    /// Rebuilds the underlying provider, backing off between failed attempts.
+    pub async fn reconnect(&self) -> Result<Provider<P>> {
+        let mut backoff = ExponentialBackoffBuilder::new()
+            .with_initial_interval(self.initial_backoff)
+            .with_max_interval(self.max_backoff)
+            .with_max_elapsed_time(None)
+            .build();
+
+        let mut last_error = None;
+        for _ in 0..self.max_reconnect_attempts {
+            if let Some(delay) = backoff.next_backoff() {
+                sleep(delay).await;
+            }
+
+            match (self.connect)(&self.url) {
+                Ok(provider) => {
+                    *self.inner.write().expect("provider lock poisoned") = provider.clone();
+                    return Ok(provider);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| UserOpError::RPC(format!("Failed to reconnect to {}", self.url))))
+    }
+
+    /// Runs `operation`, reconnecting and retrying once on connection errors.
+    pub async fn call<R, F, Fut>(&self, operation: F) -> Result<R>
+    where
+        F: Fn(Provider<P>) -> Fut,
+        Fut: Future<Output = std::result::Result<R, ProviderError>>,
+    {
+        match operation(self.provider()).await {
+            Ok(value) => Ok(value),
+            Err(e) if is_connection_error(&e) => {
+                warn!("Connection to {} failed, reconnecting: {}", self.url, e);
+                let provider = self.reconnect().await?;
+                operation(provider)
+                    .await
+                    .map_err(|e| UserOpError::RPC(e.to_string()))
+            }
+            Err(e) => Err(UserOpError::RPC(e.to_string())),
+        }
+    }
+
+    /// Runs `operation` once. A connection error still rebuilds the
+    /// provider for later calls, but the error is returned as is, since the
+    /// operation may have reached the node before the connection dropped.
+    pub async fn call_once<R, F, Fut>(&self, operation: F) -> Result<R>
+    where
+        F: FnOnce(Provider<P>) -> Fut,
+        Fut: Future<Output = std::result::Result<R, ProviderError>>,
+    {
+        let error = match operation(self.provider()).await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        if is_connection_error(&error) {
+            warn!("Connection to {} failed, reconnecting for later calls: {}", self.url, error);
+            if let Err(e) = self.reconnect().await {
+                warn!("Reconnecting to {} failed: {}", self.url, e);
+            }
+        }
+        Err(UserOpError::RPC(error.to_string()))
+    }
+
+    pub async fn request<T, R>(&self, method: &str, params: T) -> Result<R>
+    where
+        T: Debug + Serialize + Send + Sync + Clone,
+        R: Debug + Serialize + DeserializeOwned + Send,
+    {
+        if NON_IDEMPOTENT_METHODS.contains(&method) {
+            return self
+                .call_once(|provider| async move { provider.request(method, params).await })
+                .await;
+        }
+        self.call(|provider| {
+            let params = params.clone();
+            async move { provider.request(method, params).await }
+        }).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    fn counting_connector(
+        providers: Vec<Provider<MockProvider>>,
+    ) -> (Connector<MockProvider>, Arc<AtomicUsize>) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let providers = Mutex::new(providers);
+        let counter = connects.clone();
+
+        let connector: Connector<MockProvider> = Arc::new(move |_url: &str| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(providers.lock().unwrap().remove(0))
+        });
+
+        (connector, connects)
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_connection_drop() {
+        // The first mock has no queued responses, which surfaces as a transport error
+        let (dropped, _dropped_mock) = Provider::mocked();
+        let (recovered, recovered_mock) = Provider::mocked();
+        recovered_mock.push(U64::from(1234)).unwrap();
+
+        let (connector, connects) = counting_connector(vec![dropped, recovered]);
+        let provider = ReconnectingProvider::with_connector("http://localhost:8545", connector)
+            .unwrap()
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(5), 3);
+
+        let block: U64 = provider.request("eth_blockNumber", ()).await.unwrap();
+
+        assert_eq!(block, U64::from(1234));
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_submission_is_not_resent_after_reconnecting() {
+        let (dropped, _dropped_mock) = Provider::mocked();
+        let (recovered, recovered_mock) = Provider::mocked();
+        recovered_mock.push(H256::from_low_u64_be(1)).unwrap();
+        recovered_mock.push(U64::from(1234)).unwrap();
+
+        let (connector, connects) = counting_connector(vec![dropped, recovered]);
+        let provider = ReconnectingProvider::with_connector("http://localhost:8545", connector)
+            .unwrap()
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(5), 3);
+
+        let result: Result<H256> = provider.request("eth_sendRawTransaction", ["0xdeadbeef"]).await;
+        assert!(matches!(result, Err(UserOpError::RPC(_))), "{:?}", result);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+        // Later calls go through the rebuilt provider, which never saw the send
+        let block: U64 = provider.request("eth_blockNumber", ()).await.unwrap();
+        assert_eq!(block, U64::from(1234));
+        assert!(recovered_mock.assert_request("eth_blockNumber", ()).is_ok());
+    }
+
+    /// Fails the first `resets` requests as if the connection dropped, then
+    /// answers from `inner`.
+    #[derive(Debug, Clone)]
+    struct ResettingClient {
+        inner: MockProvider,
+        resets: Arc<AtomicUsize>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl JsonRpcClient for ResettingClient {
+        type Error = MockError;
+
+        async fn request<T, R>(&self, method: &str, params: T) -> std::result::Result<R, MockError>
+        where
+            T: Debug + Serialize + Send + Sync,
+            R: DeserializeOwned + Send,
+        {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            if self.resets.load(Ordering::SeqCst) > 0 {
+                self.resets.fetch_sub(1, Ordering::SeqCst);
+                return Err(MockError::EmptyResponses);
+            }
+            JsonRpcClient::request(&self.inner, method, params).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_reset_is_absorbed_below_op_retries() {
+        use crate::metrics::RpcMethod;
+        use crate::retry::{with_retry, RetryConfig};
+
+        let mock = MockProvider::new();
+        mock.push(U64::from(1234)).unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let client = ResettingClient {
+            inner: mock,
+            resets: Arc::new(AtomicUsize::new(1)),
+            requests: requests.clone(),
+        };
+        let provider = Provider::new(ConnectionRetryClient::new(client, 2, Duration::from_millis(1)));
+        let config = RetryConfig {
+            max_attempts: 1,
+            ..RetryConfig::default()
+        };
+        let op_attempts = AtomicUsize::new(0);
+
+        let block: U64 = with_retry(
+            1,
+            RpcMethod::Call,
+            || async {
+                op_attempts.fetch_add(1, Ordering::SeqCst);
+                provider
+                    .request("eth_blockNumber", ())
+                    .await
+                    .map_err(|e| UserOpError::RPC(e.to_string()))
+            },
+            &config,
+        ).await.unwrap();
+
+        assert_eq!(block, U64::from(1234));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(op_attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_submissions_are_not_resent_after_a_reset() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let resets = Arc::new(AtomicUsize::new(0));
+        let client = ResettingClient {
+            inner: MockProvider::new(),
+            resets: resets.clone(),
+            requests: requests.clone(),
+        };
+        let provider = Provider::new(ConnectionRetryClient::new(client, 2, Duration::from_millis(1)));
+
+        for method in NON_IDEMPOTENT_METHODS {
+            requests.store(0, Ordering::SeqCst);
+            resets.store(1, Ordering::SeqCst);
+            let result: std::result::Result<H256, _> = provider.request(method, ["0xdeadbeef"]).await;
+            assert!(result.is_err(), "{}", method);
+            assert_eq!(requests.load(Ordering::SeqCst), 1, "{} was resent", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_application_errors_are_not_retried() {
+        let (provider, mock) = Provider::mocked();
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: None,
+        }));
+
+        let (connector, connects) = counting_connector(vec![provider]);
+        let provider = ReconnectingProvider::with_connector("http://localhost:8545", connector)
+            .unwrap()
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(5), 3);
+
+        let result: Result<Bytes> = provider.request("eth_call", ()).await;
+
+        assert!(matches!(result, Err(UserOpError::RPC(_))));
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    /// Answers JSON-RPC over HTTP on a local socket with `respond(method)`
+    /// until the test ends, returning the URL.
+    pub(crate) async fn serve_rpc<F>(respond: F) -> String
+    where
+        F: Fn(&str) -> serde_json::Value + Send + Sync + 'static,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let respond = respond.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 4096];
+                    loop {
+                        // Keep reading until a whole request is buffered
+                        let body = loop {
+                            if let Some(header_end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                                let headers = String::from_utf8_lossy(&buf[..header_end]).to_lowercase();
+                                let length: usize = headers
+                                    .lines()
+                                    .find_map(|line| line.strip_prefix("content-length:"))
+                                    .map_or(0, |length| length.trim().parse().unwrap());
+                                if buf.len() >= header_end + 4 + length {
+                                    let request: Vec<u8> = buf.drain(..header_end + 4 + length).collect();
+                                    break request[header_end + 4..].to_vec();
+                                }
+                            }
+                            match socket.read(&mut chunk).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                            }
+                        };
+                        let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                        let response = serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "id": request["id"],
+                            "result": respond(request["method"].as_str().unwrap()),
+                        })
+                        .to_string();
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                            response.len(), response
+                        );
+                        socket.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    /// Accepts one HTTP request on a local socket, answering it with `0x1`.
+    /// Returns the socket's URL and a handle resolving to the request headers.
+    async fn serve_one_request() -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            let body = r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                body.len(), body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_lowercase()
+        });
+
+        (url, server)
+    }
+
+    /// Sends one request through `http_provider_with_headers` and returns its headers.
+    async fn capture_request_headers(compression: bool, headers: &HashMap<String, String>) -> String {
+        let (url, server) = serve_one_request().await;
+        let provider = http_provider_with_headers(&url, compression, headers).unwrap();
+        let _: U256 = provider.request("eth_chainId", ()).await.unwrap();
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_compression_is_negotiated_when_enabled() {
+        let headers = capture_request_headers(true, &HashMap::new()).await;
+        let accept_encoding = headers
+            .lines()
+            .find(|line| line.starts_with("accept-encoding:"))
+            .expect("missing Accept-Encoding header");
+        assert!(accept_encoding.contains("gzip"));
+        assert!(accept_encoding.contains("deflate"));
+
+        let headers = capture_request_headers(false, &HashMap::new()).await;
+        assert!(!headers.contains("accept-encoding:"));
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_provider_negotiates_compression() {
+        let (url, server) = serve_one_request().await;
+        let provider = ReconnectingProvider::new(&url, true, false).unwrap();
+
+        let _: U256 = provider.request("eth_chainId", ()).await.unwrap();
+        assert!(server.await.unwrap().contains("accept-encoding:"));
+    }
+
+    #[tokio::test]
+    async fn test_rpc_provider_records_payload_sizes() {
+        use crate::metrics::tests::test_handle;
+
+        let handle = test_handle();
+        let (url, server) = serve_one_request().await;
+        let provider = rpc_provider(&url, false, false, &HashMap::new()).unwrap();
+
+        let _: U256 = provider.request("eth_chainId", ()).await.unwrap();
+        server.await.unwrap();
+        assert!(handle.render().contains(r#"rpc_response_bytes_count{method="eth_chainId"}"#));
+    }
+
+    #[tokio::test]
+    async fn test_configured_headers_are_sent() {
+        let configured = HashMap::from([("X-Api-Key".to_string(), "secret-key".to_string())]);
+
+        let headers = capture_request_headers(false, &configured).await;
+        assert!(headers.lines().any(|line| line == "x-api-key: secret-key"), "{}", headers);
+
+        let result = http_provider_with_headers("http://localhost:8545", false, &HashMap::from([
+            ("X-Api-Key".to_string(), "bad\nvalue".to_string()),
+        ]));
+        assert!(matches!(result, Err(UserOpError::RPC(message)) if !message.contains("bad")));
+    }
+
+    /// Collects formatted log output for inspection.
+    #[derive(Clone, Default)]
+    pub(crate) struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        pub(crate) fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_logging_client_logs_requests_when_verbose() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mock = MockProvider::new();
+        mock.push(H256::from_low_u64_be(7)).unwrap();
+        mock.push(U64::from(1234)).unwrap();
+        let provider = Provider::new(LoggingClient::new(mock, true));
+
+        let block: U64 = provider.request("eth_blockNumber", ["latest"]).await.unwrap();
+        assert_eq!(block, U64::from(1234));
+        let _: H256 = provider.request("eth_sendRawTransaction", ["0xdeadbeef"]).await.unwrap();
+
+        let text = logs.text();
+        assert!(text.contains(r#"method="eth_blockNumber" params=["latest"]"#), "{}", text);
+        assert!(text.contains(r#"response="0x4d2""#), "{}", text);
+        assert!(text.contains(r#"method="eth_sendRawTransaction" params="<redacted>""#), "{}", text);
+        assert!(!text.contains("deadbeef"));
+
+        // Quiet unless verbose
+        let quiet_logs = logs.text().len();
+        let mock = MockProvider::new();
+        mock.push(U64::from(1)).unwrap();
+        let provider = Provider::new(LoggingClient::new(mock, false));
+        let _: U64 = provider.request("eth_blockNumber", ()).await.unwrap();
+        assert_eq!(logs.text().len(), quiet_logs);
+    }
+
+    #[tokio::test]
+    async fn test_metered_client_records_payload_sizes() {
+        use crate::metrics::tests::test_handle;
+
+        let handle = test_handle();
+        let mock = MockProvider::new();
+        mock.push(U256::from(42)).unwrap();
+        let provider = Provider::new(MeteredClient::new(mock));
+
+        let block: U256 = provider.request("eth_blockNumber", ()).await.unwrap();
+        assert_eq!(block, U256::from(42));
+
+        let rendered = handle.render();
+        assert!(rendered.contains("rpc_request_bytes"));
+        assert!(rendered.contains("rpc_response_bytes"));
+        assert!(rendered.contains("method=\"eth_blockNumber\""));
+    }
+}
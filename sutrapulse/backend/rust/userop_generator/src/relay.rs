@@ -0,0 +1,91 @@
+use ethers::prelude::*;
+use serde::Serialize;
+use crate::error::{Result, UserOpError};
+
+/// How a signed `handleOps` transaction reaches the network.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SubmissionRoute {
+    /// Broadcast through the chain provider's public mempool.
+    #[default]
+    Public,
+    /// Send to a flashbots-style relay via `eth_sendPrivateTransaction`,
+    /// keeping the bundle out of the public mempool.
+    PrivateRelay { url: String },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PrivateTransactionParams {
+    tx: Bytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_block_number: Option<U64>,
+}
+
+pub struct RelayClient<P = Http> {
+    provider: Provider<P>,
+    max_block_number: Option<U64>,
+}
+
+impl RelayClient<Http> {
+    pub fn new(url: &str) -> Result<Self> {
+        let provider = Provider::<Http>::try_from(url)
+            .map_err(|e| UserOpError::Config(format!("Invalid relay URL: {}", e)))?;
+        Ok(Self::with_provider(provider))
+    }
+}
+
+impl<P: JsonRpcClient> RelayClient<P> {
+    pub fn with_provider(provider: Provider<P>) -> Self {
+        Self {
+            provider,
+            max_block_number: None,
+        }
+    }
+
+    /// Drops the transaction if it isn't included by `block`.
+    pub fn with_max_block_number(mut self, block: U64) -> Self {
+        self.max_block_number = Some(block);
+        self
+    }
+
+    pub async fn send_private_transaction(&self, raw_tx: Bytes) -> Result<H256> {
+        let params = PrivateTransactionParams {
+            tx: raw_tx,
+            max_block_number: self.max_block_number,
+        };
+
+        self.provider
+            .request("eth_sendPrivateTransaction", [params])
+            .await
+            .map_err(|e| UserOpError::RPC(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_private_relay_endpoint_is_used() {
+        let (provider, mock) = Provider::mocked();
+        let tx_hash = H256::from_low_u64_be(42);
+        mock.push(tx_hash).unwrap();
+
+        let relay = RelayClient::with_provider(provider).with_max_block_number(U64::from(100));
+        let raw_tx = Bytes::from(vec![0x02, 0xf8, 0x70]);
+
+        let result = relay.send_private_transaction(raw_tx).await.unwrap();
+        assert_eq!(result, tx_hash);
+
+        mock.assert_request(
+            "eth_sendPrivateTransaction",
+            [json!({ "tx": "0x02f870", "maxBlockNumber": "0x64" })],
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_default_route_is_public() {
+        assert_eq!(SubmissionRoute::default(), SubmissionRoute::Public);
+    }
+}
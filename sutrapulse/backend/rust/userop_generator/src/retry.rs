@@ -1,16 +1,20 @@
 use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
 use std::time::Duration;
-use tokio::time::sleep;
 use dashmap::DashMap;
+use ethers::providers::{ProviderError, RpcError};
 use std::sync::Arc;
-use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use crate::clock::{Clock, SystemClock};
 use crate::error::{Result, UserOpError};
-use crate::metrics::Timer;
+use crate::health::ProviderHealth;
+use crate::metrics::{RpcMethod, Timer};
 
 pub struct RateLimiter {
     requests: DashMap<u64, Vec<Instant>>,
     window: Duration,
     pub max_requests: usize,
+    clock: Arc<dyn Clock>,
 }
 
 impl RateLimiter {
@@ -19,12 +23,18 @@ impl RateLimiter {
             requests: DashMap::new(),
             window: Duration::from_secs(window_secs),
             max_requests,
+            clock: Arc::new(SystemClock),
         }
     }
 
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub async fn check_and_record(&self, chain_id: u64) -> bool {
-        let now = Instant::now();
-        let mut requests = self.requests.entry(chain_id).or_insert_with(Vec::new);
+        let now = self.clock.now();
+        let mut requests = self.requests.entry(chain_id).or_default();
         
         // Remove old requests
         requests.retain(|&time| now.duration_since(time) <= self.window);
@@ -38,13 +48,63 @@ impl RateLimiter {
     }
 }
 
+/// Caps the number of simultaneous in-flight requests per chain.
+pub struct ConcurrencyLimiter {
+    semaphores: DashMap<u64, Arc<Semaphore>>,
+    pub max_concurrent: usize,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            semaphores: DashMap::new(),
+            max_concurrent,
+        }
+    }
+
+    fn semaphore(&self, chain_id: u64) -> Arc<Semaphore> {
+        self.semaphores
+            .entry(chain_id)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_concurrent)))
+            .clone()
+    }
+
+    /// Waits for a request slot on `chain_id`, held until the permit drops.
+    pub async fn acquire(&self, chain_id: u64) -> OwnedSemaphorePermit {
+        let permit = self.semaphore(chain_id)
+            .acquire_owned()
+            .await
+            .expect("concurrency semaphore is never closed");
+        crate::metrics::Metrics::record_rpc_permits_available(chain_id, self.available(chain_id));
+        permit
+    }
+
+    pub fn available(&self, chain_id: u64) -> usize {
+        self.semaphore(chain_id).available_permits()
+    }
+
+    /// Requests currently holding a slot on `chain_id`.
+    pub fn in_flight(&self, chain_id: u64) -> usize {
+        self.max_concurrent.saturating_sub(self.available(chain_id))
+    }
+}
+
 #[derive(Clone)]
 pub struct RetryConfig {
     pub max_attempts: u32,
     pub initial_interval: Duration,
     pub max_interval: Duration,
     pub multiplier: f64,
+    /// Total time an operation may spend retrying, measured on `clock`.
+    /// A retry that would back off past it fails instead. `None` allows
+    /// `max_interval * max_attempts`.
+    pub max_elapsed_time: Option<Duration>,
     pub rate_limiter: Arc<RateLimiter>,
+    pub concurrency_limiter: Arc<ConcurrencyLimiter>,
+    /// Drives the backoff and rate limit waits.
+    pub clock: Arc<dyn Clock>,
+    /// Told the outcome of every operation.
+    pub health: Arc<ProviderHealth>,
 }
 
 impl Default for RetryConfig {
@@ -54,13 +114,72 @@ impl Default for RetryConfig {
             initial_interval: Duration::from_millis(100),
             max_interval: Duration::from_secs(10),
             multiplier: 2.0,
+            max_elapsed_time: None,
             rate_limiter: Arc::new(RateLimiter::new(1, 100)), // 100 requests per second by default
+            concurrency_limiter: Arc::new(ConcurrencyLimiter::new(32)), // 32 in-flight requests per chain
+            clock: Arc::new(SystemClock),
+            health: Arc::new(ProviderHealth::new()),
         }
     }
 }
 
+impl RetryConfig {
+    /// The retry time budget: `max_elapsed_time`, else `max_interval * max_attempts`.
+    pub fn elapsed_budget(&self) -> Duration {
+        self.max_elapsed_time.unwrap_or(self.max_interval * self.max_attempts)
+    }
+}
+
+/// Extracts a `Retry-After` delay (in seconds) from an error message.
+pub fn parse_retry_after(message: &str) -> Option<Duration> {
+    let lower = message.to_ascii_lowercase();
+    let start = lower.find("retry-after")? + "retry-after".len();
+    let value = lower[start..].trim_start_matches(|c: char| c == ':' || c == '=' || c == '"' || c.is_whitespace());
+    let seconds: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    seconds.parse().ok().map(Duration::from_secs)
+}
+
+/// JSON-RPC error codes providers use for rate limiting: HTTP's 429, used
+/// by Alchemy among others, and EIP-1474's "limit exceeded".
+const RATE_LIMIT_ERROR_CODES: [i64; 2] = [429, -32005];
+
+/// Whether the RPC rejected the call for rate limiting, judged by the HTTP
+/// status or the JSON-RPC error code and never by the message text.
+fn is_rate_limited(err: &ProviderError) -> bool {
+    match err {
+        ProviderError::HTTPError(e) => e.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS),
+        ProviderError::JsonRpcClientError(e) => e
+            .as_error_response()
+            .is_some_and(|response| RATE_LIMIT_ERROR_CODES.contains(&response.code)),
+        _ => false,
+    }
+}
+
+/// The `Retry-After` delay a rate limit error carries, looked for in the
+/// JSON-RPC error's message and data.
+fn rate_limit_retry_after(err: &ProviderError) -> Option<Duration> {
+    let response = err.as_error_response()?;
+    parse_retry_after(&response.message)
+        .or_else(|| response.data.as_ref().and_then(|data| parse_retry_after(&data.to_string())))
+}
+
+/// Maps a provider error to `UserOpError::RateLimit` (with any `Retry-After`
+/// delay) when the RPC rejected the call for rate limiting, or to `fallback`
+/// otherwise.
+pub fn classify_provider_error(err: ProviderError, fallback: fn(String) -> UserOpError) -> UserOpError {
+    if is_rate_limited(&err) {
+        UserOpError::RateLimit {
+            retry_after: rate_limit_retry_after(&err),
+            message: err.to_string(),
+        }
+    } else {
+        fallback(err.to_string())
+    }
+}
+
 pub async fn with_retry<T, F, Fut>(
     chain_id: u64,
+    method: RpcMethod,
     operation: F,
     config: &RetryConfig,
 ) -> Result<T>
@@ -72,49 +191,302 @@ where
         .with_initial_interval(config.initial_interval)
         .with_max_interval(config.max_interval)
         .with_multiplier(config.multiplier)
-        .with_max_elapsed_time(Some(config.max_interval * config.max_attempts))
+        // Enforced below on `config.clock` instead
+        .with_max_elapsed_time(None)
         .build();
 
+    let budget = config.elapsed_budget();
+    let started = config.clock.now();
     let timer = Timer::new();
     let mut attempt = 0;
+    let mut backed_off = Duration::ZERO;
 
     loop {
         attempt += 1;
 
         // Check rate limit
         if !config.rate_limiter.check_and_record(chain_id).await {
-            sleep(Duration::from_millis(100)).await;
+            config.clock.sleep(Duration::from_millis(100)).await;
             continue;
         }
 
-        match operation().await {
+        crate::metrics::Metrics::record_retry_attempt(chain_id, method.as_str(), attempt);
+        let permit = config.concurrency_limiter.acquire(chain_id).await;
+        let outcome = operation().await;
+        drop(permit);
+
+        match outcome {
             Ok(value) => {
                 // Record successful operation metrics
                 crate::metrics::Metrics::record_rpc_call(
                     chain_id,
-                    "operation",
+                    method.as_str(),
                     true,
                     timer.elapsed(),
                 );
+                crate::metrics::Metrics::record_retry_backoff(chain_id, method.as_str(), backed_off.as_secs_f64());
+                config.health.record_success(chain_id);
                 return Ok(value);
             }
             Err(e) => {
-                if attempt >= config.max_attempts {
+                let delay = if attempt >= config.max_attempts {
+                    None
+                } else {
+                    let next_backoff = backoff.next_backoff()
+                        .ok_or_else(|| UserOpError::RPC("Retry limit exceeded".to_string()))?;
+
+                    // Honor the server's Retry-After over our own schedule
+                    let delay = match &e {
+                        UserOpError::RateLimit { retry_after: Some(retry_after), .. } => *retry_after,
+                        _ => next_backoff,
+                    };
+                    let elapsed = config.clock.now().duration_since(started);
+                    (elapsed + delay <= budget).then_some(delay)
+                };
+
+                let Some(delay) = delay else {
                     // Record failed operation metrics
                     crate::metrics::Metrics::record_rpc_call(
                         chain_id,
-                        "operation",
+                        method.as_str(),
                         false,
                         timer.elapsed(),
                     );
+                    crate::metrics::Metrics::record_retry_backoff(chain_id, method.as_str(), backed_off.as_secs_f64());
+                    config.health.record_failure(chain_id);
                     return Err(e);
-                }
+                };
 
-                let next_backoff = backoff.next_backoff()
-                    .ok_or_else(|| UserOpError::RPC("Retry limit exceeded".to_string()))?;
-                
-                sleep(next_backoff).await;
+                config.clock.sleep(delay).await;
+                backed_off += delay;
             }
         }
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use ethers::providers::{HttpClientError, JsonRpcError};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("HTTP 429 Too Many Requests, Retry-After: 2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_retry_after(r#"{"retry-after":"15"}"#), Some(Duration::from_secs(15)));
+        assert_eq!(parse_retry_after("429 Too Many Requests"), None);
+    }
+
+    fn rpc_error(code: i64, message: &str, data: Option<serde_json::Value>) -> ProviderError {
+        let error = JsonRpcError { code, message: message.to_string(), data };
+        HttpClientError::JsonRpcError(error).into()
+    }
+
+    #[test]
+    fn test_rate_limits_are_classified_by_error_code() {
+        let limited = classify_provider_error(
+            rpc_error(429, "Too Many Requests", Some(serde_json::json!({ "retry-after": 2 }))),
+            UserOpError::GasEstimation,
+        );
+        assert!(
+            matches!(limited, UserOpError::RateLimit { retry_after: Some(d), .. } if d == Duration::from_secs(2)),
+            "{:?}",
+            limited
+        );
+
+        let limit_exceeded = classify_provider_error(rpc_error(-32005, "limit exceeded", None), UserOpError::GasEstimation);
+        assert!(matches!(limit_exceeded, UserOpError::RateLimit { retry_after: None, .. }), "{:?}", limit_exceeded);
+
+        // A "429" in a revert message or data is not a rate limit
+        let revert = classify_provider_error(
+            rpc_error(3, "execution reverted: amount 429 too low", Some(serde_json::json!("0x429429"))),
+            UserOpError::GasEstimation,
+        );
+        assert!(matches!(revert, UserOpError::GasEstimation(_)), "{:?}", revert);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_after_is_honored() {
+        let attempts = AtomicU32::new(0);
+        let config = RetryConfig {
+            initial_interval: Duration::from_millis(10),
+            ..RetryConfig::default()
+        };
+
+        let start = tokio::time::Instant::now();
+        let result = with_retry(
+            1,
+            RpcMethod::EstimateGas,
+            || async {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err(UserOpError::RateLimit {
+                        message: "429 Too Many Requests".to_string(),
+                        retry_after: parse_retry_after("Retry-After: 2"),
+                    })
+                } else {
+                    Ok(7)
+                }
+            },
+            &config,
+        ).await;
+
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_bounds_in_flight_requests() {
+        let config = RetryConfig {
+            concurrency_limiter: Arc::new(ConcurrencyLimiter::new(2)),
+            ..RetryConfig::default()
+        };
+        let in_flight = AtomicU32::new(0);
+        let peak = AtomicU32::new(0);
+
+        let calls = (0..6).map(|_| {
+            with_retry(
+                1,
+                RpcMethod::EstimateGas,
+                || async {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<_, UserOpError>(())
+                },
+                &config,
+            )
+        });
+        let results = futures::future::join_all(calls).await;
+
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(config.concurrency_limiter.available(1), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_window_expires() {
+        let clock = Arc::new(MockClock::new());
+        let rate_limiter = RateLimiter::new(1, 2).with_clock(clock.clone());
+
+        assert!(rate_limiter.check_and_record(1).await);
+        clock.advance(Duration::from_millis(600));
+        assert!(rate_limiter.check_and_record(1).await);
+        assert!(!rate_limiter.check_and_record(1).await);
+
+        // Only the first request has left the window
+        clock.advance(Duration::from_millis(401));
+        assert!(rate_limiter.check_and_record(1).await);
+        assert!(!rate_limiter.check_and_record(1).await);
+
+        // Windows are tracked per chain
+        assert!(rate_limiter.check_and_record(137).await);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_retry_waits_on_clock() {
+        let clock = Arc::new(MockClock::new());
+        let rate_limiter = Arc::new(RateLimiter::new(1, 1).with_clock(clock.clone()));
+        let config = RetryConfig {
+            rate_limiter: rate_limiter.clone(),
+            clock: clock.clone(),
+            ..RetryConfig::default()
+        };
+        assert!(rate_limiter.check_and_record(1).await);
+
+        let result = with_retry(1, RpcMethod::EstimateGas, || async { Ok::<_, UserOpError>(3) }, &config).await;
+
+        assert_eq!(result.unwrap(), 3);
+        // Polls every 100ms until the first request is more than 1s old
+        assert_eq!(clock.slept(), Duration::from_millis(1100));
+    }
+
+    #[tokio::test]
+    async fn test_retry_metrics_count_every_attempt() {
+        use crate::metrics::tests::test_handle;
+
+        let handle = test_handle();
+        let config = RetryConfig {
+            initial_interval: Duration::from_secs(1),
+            multiplier: 1.0,
+            clock: Arc::new(MockClock::new()),
+            ..RetryConfig::default()
+        };
+
+        let result = with_retry(
+            9901,
+            RpcMethod::GetBalance,
+            || async { Err::<(), _>(UserOpError::RPC("unavailable".to_string())) },
+            &config,
+        ).await;
+        assert!(result.is_err());
+
+        let rendered = handle.render();
+        let labels = r#"{chain="9901",method="eth_getBalance"}"#;
+        assert!(
+            rendered.contains(&format!("retry_attempts_total{} {}", labels, config.max_attempts)),
+            "{}", rendered
+        );
+        assert!(rendered.contains(&format!("retry_backoff_seconds_count{} 1", labels)));
+    }
+
+    #[tokio::test]
+    async fn test_backoff_sleeps_on_clock() {
+        let clock = Arc::new(MockClock::new());
+        let config = RetryConfig {
+            initial_interval: Duration::from_secs(1),
+            multiplier: 1.0,
+            clock: clock.clone(),
+            ..RetryConfig::default()
+        };
+        let attempts = AtomicU32::new(0);
+
+        let result = with_retry(
+            1,
+            RpcMethod::EstimateGas,
+            || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>(UserOpError::RPC("unavailable".to_string()))
+            },
+            &config,
+        ).await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(clock.slept() >= Duration::from_secs(1), "slept {:?}", clock.slept());
+    }
+
+    #[tokio::test]
+    async fn test_max_elapsed_time_bounds_retrying() {
+        let clock = Arc::new(MockClock::new());
+        let config = RetryConfig {
+            max_attempts: 100,
+            initial_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(60),
+            multiplier: 1.0,
+            max_elapsed_time: Some(Duration::from_secs(5)),
+            clock: clock.clone(),
+            ..RetryConfig::default()
+        };
+        assert_eq!(config.elapsed_budget(), Duration::from_secs(5));
+        assert_eq!(RetryConfig::default().elapsed_budget(), Duration::from_secs(30));
+        let attempts = AtomicU32::new(0);
+
+        let result = with_retry(
+            1,
+            RpcMethod::EstimateGas,
+            || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>(UserOpError::RPC("unavailable".to_string()))
+            },
+            &config,
+        ).await;
+
+        // Gives up on the budget long before 100 attempts or 100 minutes
+        assert!(matches!(result, Err(UserOpError::RPC(msg)) if msg == "unavailable"));
+        assert!(clock.slept() <= Duration::from_secs(5), "slept {:?}", clock.slept());
+        assert!(attempts.load(Ordering::SeqCst) < 100);
+    }
+}
@@ -0,0 +1,1136 @@
+use async_trait::async_trait;
+use ethers::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info_span, warn, Instrument};
+use crate::config::Config;
+use crate::contracts::Contracts;
+use crate::error::{AaError, Result, UserOpError};
+use crate::gas::bump_fee;
+use crate::nonce::split_nonce;
+use crate::shutdown::OpTracker;
+use crate::provider::RpcClient;
+use crate::userop::{HashSigner, UserOperation, UserOpGenerator};
+
+/// Observes the lifecycle of ops run through `UserOpService`. Every method
+/// defaults to a no-op so hooks only implement the stages they care about.
+/// Each event carries the userOpHash of the op as it stands at that stage,
+/// which changes once gas is estimated and whenever the op is resubmitted
+/// with a new nonce or fees.
+pub trait EventHook: Send + Sync {
+    fn on_generated(&self, _user_op_hash: H256, _chain_id: u64) {}
+    fn on_estimated(&self, _user_op_hash: H256, _chain_id: u64) {}
+    fn on_signed(&self, _user_op_hash: H256, _chain_id: u64) {}
+    fn on_submitted(&self, _user_op_hash: H256, _chain_id: u64, _tx_hash: H256) {}
+    fn on_confirmed(&self, _user_op_hash: H256, _chain_id: u64, _tx_hash: H256) {}
+    /// The confirmed `tx_hash` left its block, so the op must be resubmitted.
+    fn on_reorged(&self, _user_op_hash: H256, _chain_id: u64, _tx_hash: H256) {}
+    /// `user_op_hash` is `None` when the op failed before it was built.
+    fn on_failed(&self, _user_op_hash: Option<H256>, _chain_id: u64, _error: &UserOpError) {}
+}
+
+/// Hook that ignores every event.
+pub struct NoopHook;
+
+impl EventHook for NoopHook {}
+
+/// Sends signed ops on-chain and waits for their inclusion.
+#[async_trait]
+pub trait Submitter: Send + Sync {
+    async fn submit(&self, user_op: UserOperation, beneficiary: Address) -> Result<H256>;
+    async fn wait_for_confirmation(&self, tx_hash: H256) -> Result<()>;
+    /// The EntryPoint's next nonce for `sender` under `key`.
+    async fn fetch_nonce(&self, sender: Address, key: U256) -> Result<U256>;
+    /// Hash of the block `tx_hash` is currently included in, `None` if it
+    /// isn't. Submitters that can't look receipts up never report a reorg.
+    async fn receipt_block_hash(&self, _tx_hash: H256) -> Result<Option<H256>> {
+        Ok(None)
+    }
+
+    /// Transaction and block hash of wherever `user_op_hash` is currently
+    /// included, `None` if it isn't. Submitters that can't look ops up
+    /// report them as not included.
+    async fn user_op_inclusion(&self, _user_op_hash: H256) -> Result<Option<(H256, H256)>> {
+        Ok(None)
+    }
+
+    /// Submits `user_op` as a replacement for the still-pending `replaced`
+    /// bundle, with the bundle's own fees raised by `bump_percent`.
+    /// Submitters that can't replace transactions send a fresh bundle.
+    async fn resubmit(
+        &self,
+        user_op: UserOperation,
+        beneficiary: Address,
+        _replaced: H256,
+        _bump_percent: u64,
+    ) -> Result<H256> {
+        self.submit(user_op, beneficiary).await
+    }
+}
+
+#[async_trait]
+impl Submitter for Contracts {
+    async fn submit(&self, user_op: UserOperation, beneficiary: Address) -> Result<H256> {
+        self.submit_user_op(user_op, beneficiary).await
+    }
+
+    async fn wait_for_confirmation(&self, tx_hash: H256) -> Result<()> {
+        self.wait_for_receipt(tx_hash).await.map(|_| ())
+    }
+
+    async fn fetch_nonce(&self, sender: Address, key: U256) -> Result<U256> {
+        self.get_entry_point_nonce(sender, key).await
+    }
+
+    async fn receipt_block_hash(&self, tx_hash: H256) -> Result<Option<H256>> {
+        self.receipt_block_hash(tx_hash).await
+    }
+
+    async fn user_op_inclusion(&self, user_op_hash: H256) -> Result<Option<(H256, H256)>> {
+        self.user_op_inclusion(user_op_hash).await
+    }
+
+    async fn resubmit(
+        &self,
+        user_op: UserOperation,
+        beneficiary: Address,
+        replaced: H256,
+        bump_percent: u64,
+    ) -> Result<H256> {
+        self.resubmit_user_op(user_op, beneficiary, replaced, bump_percent).await
+    }
+}
+
+#[async_trait]
+impl<T: Submitter + ?Sized> Submitter for Arc<T> {
+    async fn submit(&self, user_op: UserOperation, beneficiary: Address) -> Result<H256> {
+        (**self).submit(user_op, beneficiary).await
+    }
+
+    async fn wait_for_confirmation(&self, tx_hash: H256) -> Result<()> {
+        (**self).wait_for_confirmation(tx_hash).await
+    }
+
+    async fn fetch_nonce(&self, sender: Address, key: U256) -> Result<U256> {
+        (**self).fetch_nonce(sender, key).await
+    }
+
+    async fn receipt_block_hash(&self, tx_hash: H256) -> Result<Option<H256>> {
+        (**self).receipt_block_hash(tx_hash).await
+    }
+
+    async fn user_op_inclusion(&self, user_op_hash: H256) -> Result<Option<(H256, H256)>> {
+        (**self).user_op_inclusion(user_op_hash).await
+    }
+
+    async fn resubmit(
+        &self,
+        user_op: UserOperation,
+        beneficiary: Address,
+        replaced: H256,
+        bump_percent: u64,
+    ) -> Result<H256> {
+        (**self).resubmit(user_op, beneficiary, replaced, bump_percent).await
+    }
+}
+
+/// Smallest fee bump bundlers accept for replacing an op with the same nonce.
+pub const MIN_BUMP_PERCENT: u64 = 10;
+
+/// Resubmits an op with bumped fees when it isn't included in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResubmitPolicy {
+    /// Bumped resubmissions after the first submit.
+    pub max_rounds: u32,
+    /// Raised to `MIN_BUMP_PERCENT` if lower.
+    pub bump_percent: u64,
+    /// How long each submission gets to be included.
+    pub round_timeout: Duration,
+}
+
+impl Default for ResubmitPolicy {
+    fn default() -> Self {
+        Self {
+            max_rounds: 3,
+            bump_percent: MIN_BUMP_PERCENT,
+            round_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Keeps watching a confirmed op's transaction for a reorg that drops it
+/// from its block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorgWatch {
+    /// How often the receipt is re-read.
+    pub poll_interval: Duration,
+    /// How long after confirmation to keep watching.
+    pub max_duration: Duration,
+}
+
+impl Default for ReorgWatch {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(12),
+            max_duration: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Outcome of watching a confirmed transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReorgStatus {
+    /// Still in the same block when the watch ended.
+    Stable,
+    /// Reorged out and not included anywhere else; the op needs
+    /// resubmission.
+    NeedsResubmission,
+}
+
+/// Raises both fees by `percent`, rounding up so the bump is never short.
+pub fn bump_fees(user_op: &mut UserOperation, percent: u64) {
+    user_op.max_fee_per_gas = bump_fee(user_op.max_fee_per_gas, percent);
+    user_op.max_priority_fee_per_gas = bump_fee(user_op.max_priority_fee_per_gas, percent);
+}
+
+/// Whether a submit failed because the op's nonce was already used.
+pub fn is_nonce_too_low(err: &UserOpError) -> bool {
+    err.aa_error() == Some(AaError::InvalidNonce)
+        || err.to_string().to_ascii_lowercase().contains("nonce too low")
+}
+
+/// Picks the `handleOps` beneficiary: the explicit one, else the configured
+/// default, else the signer. The zero address is never accepted since
+/// refunds sent there are lost.
+pub fn resolve_beneficiary(
+    explicit: Option<Address>,
+    configured: Option<Address>,
+    signer: Address,
+) -> Result<Address> {
+    let beneficiary = explicit.or(configured).unwrap_or(signer);
+    if beneficiary.is_zero() {
+        return Err(UserOpError::Validation("Beneficiary cannot be the zero address".to_string()));
+    }
+    Ok(beneficiary)
+}
+
+/// Identifies one op's run through `UserOpService` in logs. Every span
+/// opened while the op is processed sits under an `op` span carrying it,
+/// so interleaved lines from concurrent ops can be told apart. Metrics
+/// aren't tagged: the Prometheus exporter has no exemplar support, and a
+/// per-op label would be unbounded.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CorrelationId(String);
+
+impl CorrelationId {
+    /// A random 16 hex digit ID.
+    pub fn generate() -> Self {
+        Self(ethers::utils::hex::encode(H64::random().as_bytes()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for CorrelationId {
+    fn from(id: String) -> Self {
+        Self(id)
+    }
+}
+
+impl From<&str> for CorrelationId {
+    fn from(id: &str) -> Self {
+        Self(id.to_string())
+    }
+}
+
+impl std::fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Runs ops end to end: generate and estimate, sign, submit, confirm.
+pub struct UserOpService<P = RpcClient, S = Contracts> {
+    generator: UserOpGenerator<P>,
+    submitter: Arc<S>,
+    entry_point: Address,
+    hooks: Vec<Arc<dyn EventHook>>,
+    default_beneficiary: Option<Address>,
+    chain_beneficiaries: HashMap<u64, Address>,
+    tracker: Option<Arc<OpTracker>>,
+    nonce_recovery: bool,
+    resubmit: Option<ResubmitPolicy>,
+    reorg_watch: Option<ReorgWatch>,
+}
+
+impl<P: JsonRpcClient, S: Submitter + 'static> UserOpService<P, S> {
+    pub fn new(generator: UserOpGenerator<P>, submitter: S, entry_point: Address) -> Self {
+        Self {
+            generator,
+            submitter: Arc::new(submitter),
+            entry_point,
+            hooks: Vec::new(),
+            default_beneficiary: None,
+            chain_beneficiaries: HashMap::new(),
+            tracker: None,
+            nonce_recovery: false,
+            resubmit: None,
+            reorg_watch: None,
+        }
+    }
+
+    /// After confirmation, watches the transaction's block in the background
+    /// for `watch.max_duration`, reporting an op a reorg drops through
+    /// `EventHook::on_reorged`. `execute` doesn't wait for the watch, but a
+    /// tracker's `drain` does.
+    pub fn with_reorg_watch(mut self, watch: ReorgWatch) -> Self {
+        self.reorg_watch = Some(watch);
+        self
+    }
+
+    /// Bumps fees, re-signs and resubmits with the same nonce whenever a
+    /// submission isn't included within the policy's round timeout.
+    pub fn with_resubmit_policy(mut self, policy: ResubmitPolicy) -> Self {
+        self.resubmit = Some(policy);
+        self
+    }
+
+    /// On a "nonce too low" submit failure, refetch the nonce, re-sign and
+    /// resubmit once.
+    pub fn with_nonce_recovery(mut self, enabled: bool) -> Self {
+        self.nonce_recovery = enabled;
+        self
+    }
+
+    /// Counts each `execute` call as in flight on `tracker`, so shutdown can
+    /// drain them. Calls made after draining starts are rejected.
+    pub fn with_tracker(mut self, tracker: Arc<OpTracker>) -> Self {
+        self.tracker = Some(tracker);
+        self
+    }
+
+    pub fn with_default_beneficiary(mut self, beneficiary: Address) -> Self {
+        self.default_beneficiary = Some(beneficiary);
+        self
+    }
+
+    /// Default beneficiary for `chain_id` only, taking precedence over
+    /// `with_default_beneficiary`.
+    pub fn with_chain_beneficiary(mut self, chain_id: u64, beneficiary: Address) -> Self {
+        self.chain_beneficiaries.insert(chain_id, beneficiary);
+        self
+    }
+
+    /// Applies the global and per-chain default beneficiaries in `config`.
+    pub fn with_config(mut self, config: &Config) -> Result<Self> {
+        self.default_beneficiary = config.get_default_beneficiary()?.or(self.default_beneficiary);
+        for chain_id in config.chains.keys() {
+            if let Some(beneficiary) = config.get_chain_beneficiary(*chain_id)? {
+                self.chain_beneficiaries.insert(*chain_id, beneficiary);
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn with_hook(mut self, hook: Arc<dyn EventHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    fn emit(&self, event: impl Fn(&dyn EventHook)) {
+        for hook in &self.hooks {
+            event(hook.as_ref());
+        }
+    }
+
+    /// Executes `call_data` from `sender` on `chain_id`, returning the
+    /// `handleOps` transaction hash once it is confirmed. Without an explicit
+    /// `beneficiary`, refunds go to the configured default or the signer.
+    /// Logs carry a freshly generated `CorrelationId`.
+    pub async fn execute<K: HashSigner>(
+        &self,
+        sender: Address,
+        call_data: Bytes,
+        chain_id: u64,
+        signer: &K,
+        beneficiary: Option<Address>,
+    ) -> Result<H256> {
+        self.execute_with_correlation_id(sender, call_data, chain_id, signer, beneficiary, CorrelationId::generate())
+            .await
+    }
+
+    /// `execute`, logging under the caller's `correlation_id`, e.g. one taken
+    /// from an incoming request.
+    pub async fn execute_with_correlation_id<K: HashSigner>(
+        &self,
+        sender: Address,
+        call_data: Bytes,
+        chain_id: u64,
+        signer: &K,
+        beneficiary: Option<Address>,
+        correlation_id: CorrelationId,
+    ) -> Result<H256> {
+        let _in_flight = self.tracker.as_ref().map(|tracker| tracker.begin()).transpose()?;
+
+        let span = info_span!("op", correlation_id = %correlation_id, chain_id);
+        let mut user_op_hash = None;
+        let result = self
+            .run(&mut user_op_hash, sender, call_data, chain_id, signer, beneficiary)
+            .instrument(span.clone())
+            .await;
+
+        if let Err(e) = &result {
+            let _entered = span.enter();
+            self.emit(|hook| hook.on_failed(user_op_hash, chain_id, e));
+        }
+
+        result
+    }
+
+    async fn run<K: HashSigner>(
+        &self,
+        user_op_hash: &mut Option<H256>,
+        sender: Address,
+        call_data: Bytes,
+        chain_id: u64,
+        signer: &K,
+        beneficiary: Option<Address>,
+    ) -> Result<H256> {
+        let mut user_op = self.generator
+            .generate_user_op_observed(sender, call_data, chain_id, None, |user_op| {
+                let hash = *user_op_hash.insert(self.generator.hash_user_op(user_op, self.entry_point, chain_id)?);
+                self.emit(|hook| hook.on_generated(hash, chain_id));
+                Ok(())
+            })
+            .instrument(info_span!("generate"))
+            .await?;
+
+        let user_op_hash = user_op_hash.insert(self.generator.hash_user_op(&user_op, self.entry_point, chain_id)?);
+        let mut hash = *user_op_hash;
+        self.emit(|hook| hook.on_estimated(hash, chain_id));
+
+        self.generator
+            .sign_user_op(&mut user_op, signer, self.entry_point, chain_id)
+            .await?;
+        self.emit(|hook| hook.on_signed(hash, chain_id));
+
+        // The policy may have changed since the op was generated
+        self.generator.check_sender(sender)?;
+        let configured = self.chain_beneficiaries.get(&chain_id).copied().or(self.default_beneficiary);
+        let beneficiary = resolve_beneficiary(beneficiary, configured, signer.address())?;
+        let submitted = self.submitter
+            .submit(user_op.clone(), beneficiary)
+            .instrument(info_span!("submit"))
+            .await;
+        let tx_hash = match submitted {
+            Err(e) if self.nonce_recovery && is_nonce_too_low(&e) => {
+                warn!("Nonce too low for {:?} on chain {}, refetching: {}", sender, chain_id, e);
+                self.refresh_nonce(&mut user_op, chain_id, signer).await?;
+                *user_op_hash = self.generator.hash_user_op(&user_op, self.entry_point, chain_id)?;
+                hash = *user_op_hash;
+                self.submitter
+                    .submit(user_op.clone(), beneficiary)
+                    .instrument(info_span!("submit"))
+                    .await?
+            }
+            result => result?,
+        };
+        self.emit(|hook| hook.on_submitted(hash, chain_id, tx_hash));
+
+        let tx_hash = self
+            .confirm(user_op, user_op_hash, tx_hash, chain_id, signer, beneficiary)
+            .instrument(info_span!("confirm"))
+            .await?;
+        hash = *user_op_hash;
+        self.emit(|hook| hook.on_confirmed(hash, chain_id, tx_hash));
+
+        if let Some(watch) = self.reorg_watch {
+            // Held by the watch so `drain` waits for it too. Once draining
+            // has started there's no point starting one.
+            let Ok(in_flight) = self.tracker.as_ref().map(|tracker| tracker.begin()).transpose() else {
+                warn!("Shutting down, not watching {:?} on chain {} for reorgs", tx_hash, chain_id);
+                return Ok(tx_hash);
+            };
+            let submitter = self.submitter.clone();
+            let hooks = self.hooks.clone();
+            tokio::spawn(
+                async move {
+                    let _in_flight = in_flight;
+                    if let Err(e) = watch_for_reorg(submitter.as_ref(), &hooks, hash, chain_id, tx_hash, watch).await {
+                        warn!("Reorg watch for {:?} on chain {} failed: {}", tx_hash, chain_id, e);
+                    }
+                }
+                .instrument(info_span!("reorg_watch")),
+            );
+        }
+
+        Ok(tx_hash)
+    }
+
+    /// Polls `tx_hash`'s containing block until `watch.max_duration` has
+    /// passed. When the block changes or the receipt disappears, the op is
+    /// looked up by `user_op_hash` and reported as needing resubmission only
+    /// if it is no longer included anywhere.
+    pub async fn watch_for_reorg(
+        &self,
+        user_op_hash: H256,
+        chain_id: u64,
+        tx_hash: H256,
+        watch: ReorgWatch,
+    ) -> Result<ReorgStatus> {
+        watch_for_reorg(self.submitter.as_ref(), &self.hooks, user_op_hash, chain_id, tx_hash, watch).await
+    }
+
+    /// Waits for `tx_hash`. With a resubmit policy, bumps and resubmits
+    /// `user_op` each time a round times out, leaving the nonce alone so
+    /// every round replaces the previous one. Returns the transaction that
+    /// was included.
+    async fn confirm<K: HashSigner>(
+        &self,
+        mut user_op: UserOperation,
+        user_op_hash: &mut H256,
+        mut tx_hash: H256,
+        chain_id: u64,
+        signer: &K,
+        beneficiary: Address,
+    ) -> Result<H256> {
+        let policy = match self.resubmit {
+            Some(policy) => policy,
+            None => {
+                self.submitter.wait_for_confirmation(tx_hash).await?;
+                return Ok(tx_hash);
+            }
+        };
+        let bump_percent = policy.bump_percent.max(MIN_BUMP_PERCENT);
+        // Any earlier bundle can still land after being replaced, so every
+        // round waits on all of them.
+        let mut submitted = vec![(tx_hash, *user_op_hash)];
+
+        for round in 0..=policy.max_rounds {
+            let waits = submitted.iter().map(|&(tx_hash, op_hash)| {
+                Box::pin(async move {
+                    self.submitter.wait_for_confirmation(tx_hash).await?;
+                    Ok::<_, UserOpError>((tx_hash, op_hash))
+                })
+            });
+            match tokio::time::timeout(policy.round_timeout, futures::future::select_ok(waits)).await {
+                Ok(Ok(((confirmed_tx, confirmed_op), _))) => {
+                    *user_op_hash = confirmed_op;
+                    return Ok(confirmed_tx);
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) if round == policy.max_rounds => break,
+                Err(_) => {}
+            }
+
+            warn!(
+                "Op {:?} not included within {:?}, bumping fees by {}% (round {}/{})",
+                user_op_hash, policy.round_timeout, bump_percent, round + 1, policy.max_rounds
+            );
+            bump_fees(&mut user_op, bump_percent);
+            self.generator
+                .sign_user_op(&mut user_op, signer, self.entry_point, chain_id)
+                .await?;
+            *user_op_hash = self.generator.hash_user_op(&user_op, self.entry_point, chain_id)?;
+
+            tx_hash = self
+                .submitter
+                .resubmit(user_op.clone(), beneficiary, tx_hash, bump_percent)
+                .await?;
+            submitted.push((tx_hash, *user_op_hash));
+            let hash = *user_op_hash;
+            self.emit(|hook| hook.on_submitted(hash, chain_id, tx_hash));
+        }
+
+        Err(UserOpError::Retry(format!(
+            "Op {:?} not included after {} resubmission rounds",
+            user_op_hash, policy.max_rounds
+        )))
+    }
+
+    /// Replaces the op's nonce with the on-chain one and re-signs it.
+    async fn refresh_nonce<K: HashSigner>(
+        &self,
+        user_op: &mut UserOperation,
+        chain_id: u64,
+        signer: &K,
+    ) -> Result<()> {
+        let gas_cache = self.generator.gas_estimator().gas_cache();
+        gas_cache.invalidate_nonce(chain_id, user_op.sender).await;
+
+        let (key, _) = split_nonce(user_op.nonce);
+        let nonce = self.submitter.fetch_nonce(user_op.sender, key).await?;
+        gas_cache.set_nonce(chain_id, user_op.sender, nonce).await;
+
+        user_op.nonce = nonce;
+        self.generator
+            .sign_user_op(user_op, signer, self.entry_point, chain_id)
+            .await
+    }
+}
+
+impl<P: JsonRpcClient> UserOpService<P, Contracts> {
+    /// A service submitting through `Contracts::from_config` for `chain_id`,
+    /// so `Config::min_confirmations` applies, with `with_config` applied.
+    pub fn from_config(generator: UserOpGenerator<P>, config: &Config, chain_id: u64) -> Result<Self> {
+        let entry_point = config.get_contract_addresses(chain_id)?.entry_point;
+        Self::new(generator, Contracts::from_config(config, chain_id)?, entry_point).with_config(config)
+    }
+}
+
+async fn watch_for_reorg<S: Submitter + ?Sized>(
+    submitter: &S,
+    hooks: &[Arc<dyn EventHook>],
+    user_op_hash: H256,
+    chain_id: u64,
+    mut tx_hash: H256,
+    watch: ReorgWatch,
+) -> Result<ReorgStatus> {
+    let mut included_in = match submitter.receipt_block_hash(tx_hash).await? {
+        Some(block_hash) => block_hash,
+        None => {
+            warn!("No receipt for {:?}, skipping reorg watch", tx_hash);
+            return Ok(ReorgStatus::Stable);
+        }
+    };
+
+    let deadline = tokio::time::Instant::now() + watch.max_duration;
+    while tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(watch.poll_interval).await;
+
+        let current = submitter.receipt_block_hash(tx_hash).await?;
+        if current == Some(included_in) {
+            continue;
+        }
+        warn!(
+            "Transaction {:?} on chain {} moved from block {:?} to {:?}",
+            tx_hash, chain_id, included_in, current
+        );
+        crate::metrics::Metrics::record_reorg_detected(chain_id);
+
+        // The op may have landed again, in this bundle or another one
+        match submitter.user_op_inclusion(user_op_hash).await? {
+            Some((reincluded_tx, block_hash)) => {
+                tx_hash = reincluded_tx;
+                included_in = block_hash;
+            }
+            None => {
+                for hook in hooks {
+                    hook.on_reorged(user_op_hash, chain_id, tx_hash);
+                }
+                return Ok(ReorgStatus::NeedsResubmission);
+            }
+        }
+    }
+
+    Ok(ReorgStatus::Stable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{GasCache, RpcCache};
+    use crate::gas::{ChainProviders, GasEstimator};
+    use crate::policy::SenderPolicy;
+    use crate::provider::tests::CapturedLogs;
+    use crate::retry::RetryConfig;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct RecordingHook {
+        events: Mutex<Vec<String>>,
+        /// `(user_op_hash, tx_hash)` of every submit.
+        submitted: Mutex<Vec<(H256, H256)>>,
+        /// `(user_op_hash, tx_hash)` of every confirmation.
+        confirmed: Mutex<Vec<(H256, H256)>>,
+        /// `user_op_hash` of every failure.
+        failed: Mutex<Vec<Option<H256>>>,
+    }
+
+    impl RecordingHook {
+        fn record(&self, event: &str, chain_id: u64) {
+            self.events.lock().unwrap().push(format!("{}:{}", event, chain_id));
+        }
+    }
+
+    impl EventHook for RecordingHook {
+        fn on_generated(&self, _user_op_hash: H256, chain_id: u64) { self.record("generated", chain_id) }
+        fn on_estimated(&self, _user_op_hash: H256, chain_id: u64) { self.record("estimated", chain_id) }
+        fn on_signed(&self, _user_op_hash: H256, chain_id: u64) { self.record("signed", chain_id) }
+        fn on_submitted(&self, user_op_hash: H256, chain_id: u64, tx_hash: H256) {
+            self.submitted.lock().unwrap().push((user_op_hash, tx_hash));
+            self.record("submitted", chain_id)
+        }
+        fn on_confirmed(&self, user_op_hash: H256, chain_id: u64, tx_hash: H256) {
+            self.confirmed.lock().unwrap().push((user_op_hash, tx_hash));
+            self.record("confirmed", chain_id)
+        }
+        fn on_reorged(&self, _user_op_hash: H256, chain_id: u64, _tx_hash: H256) { self.record("reorged", chain_id) }
+        fn on_failed(&self, user_op_hash: Option<H256>, chain_id: u64, _error: &UserOpError) {
+            self.failed.lock().unwrap().push(user_op_hash);
+            self.record("failed", chain_id)
+        }
+    }
+
+    /// Stand-in for the chain shared by the tests. Each submit is recorded
+    /// and, unless `submit_errors` has an error left to return, gets the
+    /// submission count as its transaction hash.
+    #[derive(Default)]
+    struct FakeSubmitter {
+        submitted: Mutex<Vec<UserOperation>>,
+        beneficiaries: Mutex<Vec<Address>>,
+        /// Returned by the first submits, in order.
+        submit_errors: Mutex<Vec<UserOpError>>,
+        nonce: U256,
+        /// When listed transactions are included, `None` for never. The
+        /// rest confirm immediately.
+        included_at: HashMap<H256, Option<tokio::time::Instant>>,
+        /// The block reported for any transaction, one per lookup,
+        /// repeating the last. No receipt while empty.
+        block_hashes: Mutex<Vec<H256>>,
+        /// Where the op is found by userOpHash.
+        reincluded: Option<(H256, H256)>,
+    }
+
+    #[async_trait]
+    impl Submitter for FakeSubmitter {
+        async fn submit(&self, user_op: UserOperation, beneficiary: Address) -> Result<H256> {
+            let mut submitted = self.submitted.lock().unwrap();
+            submitted.push(user_op);
+            self.beneficiaries.lock().unwrap().push(beneficiary);
+            let mut submit_errors = self.submit_errors.lock().unwrap();
+            if !submit_errors.is_empty() {
+                return Err(submit_errors.remove(0));
+            }
+            Ok(H256::from_low_u64_be(submitted.len() as u64))
+        }
+
+        async fn wait_for_confirmation(&self, tx_hash: H256) -> Result<()> {
+            match self.included_at.get(&tx_hash) {
+                Some(Some(at)) => tokio::time::sleep_until(*at).await,
+                Some(None) => std::future::pending::<()>().await,
+                None => {}
+            }
+            Ok(())
+        }
+
+        async fn fetch_nonce(&self, _sender: Address, _key: U256) -> Result<U256> {
+            Ok(self.nonce)
+        }
+
+        async fn receipt_block_hash(&self, _tx_hash: H256) -> Result<Option<H256>> {
+            let mut block_hashes = self.block_hashes.lock().unwrap();
+            let block_hash = block_hashes.first().copied();
+            if block_hashes.len() > 1 {
+                block_hashes.remove(0);
+            }
+            Ok(block_hash)
+        }
+
+        async fn user_op_inclusion(&self, _user_op_hash: H256) -> Result<Option<(H256, H256)>> {
+            Ok(self.reincluded)
+        }
+    }
+
+    /// Fails the first submit with "nonce too low", then accepts nonce 5.
+    fn stale_nonce_submitter() -> FakeSubmitter {
+        FakeSubmitter {
+            submit_errors: Mutex::new(vec![UserOpError::RPC("nonce too low".to_string())]),
+            nonce: U256::from(5),
+            ..Default::default()
+        }
+    }
+
+    /// Confirms immediately, then reports `block_hashes` for the receipt.
+    fn reorg_submitter(block_hashes: Vec<H256>, reincluded: Option<(H256, H256)>) -> FakeSubmitter {
+        FakeSubmitter {
+            block_hashes: Mutex::new(block_hashes),
+            reincluded,
+            ..Default::default()
+        }
+    }
+
+    fn estimator_with(gas_cache: Arc<GasCache>) -> (GasEstimator<MockProvider>, MockProvider) {
+        let (provider, mock) = Provider::mocked();
+        let estimator = GasEstimator::new(
+            Arc::new(ChainProviders {
+                ethereum: provider.clone(),
+                polygon: provider.clone(),
+                arbitrum: provider,
+            }),
+            gas_cache,
+            Arc::new(RpcCache::new()),
+            RetryConfig::default(),
+        );
+        (estimator, mock)
+    }
+
+    async fn cached_estimator() -> GasEstimator<MockProvider> {
+        let gas_cache = Arc::new(GasCache::new());
+        gas_cache.set_base_fee(1, U256::from(10)).await;
+        gas_cache.set_priority_fee(1, U256::from(2)).await;
+        let (estimator, mock) = estimator_with(gas_cache);
+        mock.push(U256::from(50_000)).unwrap();
+        estimator
+    }
+
+    fn test_signer() -> LocalWallet {
+        LocalWallet::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hooks_observe_successful_execution() {
+        let hook = Arc::new(RecordingHook::default());
+        let entry_point = Address::from_str("0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789").unwrap();
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), FakeSubmitter::default(), entry_point)
+            .with_hook(hook.clone());
+
+        let signer = test_signer();
+        let sender = Address::from_str("0x1234567890123456789012345678901234567890").unwrap();
+
+        let tx_hash = service
+            .execute(sender, Bytes::default(), 1, &signer, None)
+            .await
+            .unwrap();
+
+        assert_eq!(tx_hash, H256::from_low_u64_be(1));
+        assert_eq!(
+            *hook.events.lock().unwrap(),
+            vec!["generated:1", "estimated:1", "signed:1", "submitted:1", "confirmed:1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_correlation_id_spans_every_stage() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::NEW)
+            .with_max_level(tracing::Level::INFO)
+            .with_ansi(false)
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), FakeSubmitter::default(), Address::zero());
+        let signer = test_signer();
+        service
+            .execute_with_correlation_id(Address::from_low_u64_be(1), Bytes::default(), 1, &signer, None, "req-42".into())
+            .await
+            .unwrap();
+
+        let output = logs.text();
+        for stage in [":generate", ":estimate", ":submit"] {
+            let line = output
+                .lines()
+                .find(|line| line.contains(stage))
+                .unwrap_or_else(|| panic!("No {} span in:\n{}", stage, output));
+            assert!(line.contains("op{correlation_id=req-42 chain_id=1}"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_generated_correlation_ids_differ() {
+        let id = CorrelationId::generate();
+        assert_eq!(id.as_str().len(), 16);
+        assert_ne!(id, CorrelationId::generate());
+    }
+
+    #[tokio::test]
+    async fn test_execute_rejected_while_draining() {
+        let tracker = Arc::new(OpTracker::new());
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), FakeSubmitter::default(), Address::zero())
+            .with_tracker(tracker.clone());
+        assert!(tracker.drain(std::time::Duration::from_secs(1)).await);
+
+        let signer = test_signer();
+        let result = service
+            .execute(Address::from_low_u64_be(1), Bytes::default(), 1, &signer, None)
+            .await;
+
+        assert!(matches!(result, Err(UserOpError::Unknown(_))), "Expected shutdown rejection: {:?}", result);
+        assert_eq!(tracker.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_denied_sender_is_rejected_before_estimation() {
+        let sender = Address::from_low_u64_be(1);
+        let policy = SenderPolicy::default().deny(sender).into_shared();
+        let hook = Arc::new(RecordingHook::default());
+        let generator = UserOpGenerator::new(cached_estimator().await).with_sender_policy(policy.clone());
+        let service = UserOpService::new(generator, FakeSubmitter::default(), Address::zero()).with_hook(hook.clone());
+        let signer = test_signer();
+
+        let result = service.execute(sender, Bytes::default(), 1, &signer, None).await;
+        assert!(matches!(result, Err(UserOpError::Validation(_))), "{:?}", result);
+        assert_eq!(*hook.events.lock().unwrap(), vec!["failed:1"]);
+        assert_eq!(*hook.failed.lock().unwrap(), vec![None]);
+
+        // Swapping the policy takes effect without rebuilding the service
+        *policy.write().unwrap() = SenderPolicy::default().allow(sender);
+        assert!(service.generator.check_sender(sender).is_ok());
+        assert!(service.generator.check_sender(Address::from_low_u64_be(2)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_estimation_reports_the_generated_op() {
+        // Nothing cached and no RPC responses, so estimation fails
+        let (estimator, _mock) = estimator_with(Arc::new(GasCache::new()));
+        let hook = Arc::new(RecordingHook::default());
+        let service = UserOpService::new(UserOpGenerator::new(estimator), FakeSubmitter::default(), Address::zero())
+            .with_hook(hook.clone());
+        let signer = test_signer();
+        let sender = Address::from_low_u64_be(1);
+
+        assert!(service.execute(sender, Bytes::default(), 1, &signer, None).await.is_err());
+
+        assert_eq!(*hook.events.lock().unwrap(), vec!["generated:1", "failed:1"]);
+        let generated = service.generator.hash_user_op(&UserOperation::new(sender), Address::zero(), 1).unwrap();
+        assert_eq!(*hook.failed.lock().unwrap(), vec![Some(generated)]);
+        assert!(service.submitter.submitted.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chain_beneficiary_overrides_global_default() {
+        let global = Address::from_low_u64_be(0xaa);
+        let ethereum = Address::from_low_u64_be(0xbb);
+        let submitter = Arc::new(FakeSubmitter::default());
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), submitter.clone(), Address::zero())
+            .with_default_beneficiary(global)
+            .with_chain_beneficiary(1, ethereum);
+        let signer = test_signer();
+
+        service.execute(Address::from_low_u64_be(1), Bytes::default(), 1, &signer, None).await.unwrap();
+        assert_eq!(*submitter.beneficiaries.lock().unwrap(), vec![ethereum]);
+    }
+
+    #[tokio::test]
+    async fn test_nonce_too_low_is_recovered_once() {
+        let submitter = Arc::new(stale_nonce_submitter());
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), submitter.clone(), Address::zero())
+            .with_nonce_recovery(true);
+        let signer = test_signer();
+
+        let tx_hash = service
+            .execute(Address::from_low_u64_be(1), Bytes::default(), 1, &signer, None)
+            .await
+            .unwrap();
+
+        assert_eq!(tx_hash, H256::from_low_u64_be(2));
+        let submitted = submitter.submitted.lock().unwrap();
+        assert_eq!(submitted.len(), 2);
+        assert_eq!(submitted[1].nonce, U256::from(5));
+        assert_ne!(submitted[1].signature, submitted[0].signature);
+    }
+
+    #[tokio::test]
+    async fn test_nonce_too_low_fails_without_recovery() {
+        let submitter = Arc::new(stale_nonce_submitter());
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), submitter.clone(), Address::zero());
+        let signer = test_signer();
+
+        let result = service
+            .execute(Address::from_low_u64_be(1), Bytes::default(), 1, &signer, None)
+            .await;
+
+        assert!(matches!(result, Err(ref e) if is_nonce_too_low(e)));
+        assert_eq!(submitter.submitted.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timed_out_op_is_bumped_and_resubmitted() {
+        // The first submission is never included
+        let submitter = Arc::new(FakeSubmitter {
+            included_at: HashMap::from([(H256::from_low_u64_be(1), None)]),
+            ..Default::default()
+        });
+        let hook = Arc::new(RecordingHook::default());
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), submitter.clone(), Address::zero())
+            .with_hook(hook.clone())
+            .with_resubmit_policy(ResubmitPolicy {
+                max_rounds: 2,
+                bump_percent: 5,
+                round_timeout: Duration::from_secs(30),
+            });
+        let signer = test_signer();
+
+        let tx_hash = service
+            .execute(Address::from_low_u64_be(1), Bytes::default(), 1, &signer, None)
+            .await
+            .unwrap();
+
+        assert_eq!(tx_hash, H256::from_low_u64_be(2));
+        let submitted = submitter.submitted.lock().unwrap();
+        assert_eq!(submitted.len(), 2);
+        assert_eq!(submitted[1].nonce, submitted[0].nonce);
+        // 5% is below the replacement minimum, so 10% is applied
+        assert_eq!(submitted[0].max_fee_per_gas, U256::from(12));
+        assert_eq!(submitted[1].max_fee_per_gas, U256::from(14));
+        assert_eq!(submitted[1].max_priority_fee_per_gas, U256::from(3));
+        assert_ne!(submitted[1].signature, submitted[0].signature);
+        assert_eq!(
+            *hook.events.lock().unwrap(),
+            vec!["generated:1", "estimated:1", "signed:1", "submitted:1", "submitted:1", "confirmed:1"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replaced_bundle_that_lands_late_is_confirmed() {
+        // Only the first bundle is ever included, in the second round
+        let submitter = Arc::new(FakeSubmitter {
+            included_at: HashMap::from([
+                (H256::from_low_u64_be(1), Some(tokio::time::Instant::now() + Duration::from_secs(45))),
+                (H256::from_low_u64_be(2), None),
+            ]),
+            ..Default::default()
+        });
+        let hook = Arc::new(RecordingHook::default());
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), submitter.clone(), Address::zero())
+            .with_hook(hook.clone())
+            .with_resubmit_policy(ResubmitPolicy {
+                max_rounds: 2,
+                bump_percent: 10,
+                round_timeout: Duration::from_secs(30),
+            });
+        let signer = test_signer();
+
+        let tx_hash = service
+            .execute(Address::from_low_u64_be(1), Bytes::default(), 1, &signer, None)
+            .await
+            .unwrap();
+
+        // Included after it had been replaced
+        assert_eq!(tx_hash, H256::from_low_u64_be(1));
+        assert_eq!(submitter.submitted.lock().unwrap().len(), 2);
+        let first = hook.submitted.lock().unwrap()[0];
+        assert_eq!(*hook.confirmed.lock().unwrap(), vec![first]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reorged_op_needs_resubmission() {
+        let (included, reorged) = (H256::from_low_u64_be(100), H256::from_low_u64_be(101));
+        let submitter = reorg_submitter(vec![included, included, reorged], None);
+        let hook = Arc::new(RecordingHook::default());
+        let watch = ReorgWatch {
+            poll_interval: Duration::from_secs(12),
+            max_duration: Duration::from_secs(120),
+        };
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), submitter, Address::zero())
+            .with_hook(hook.clone())
+            .with_reorg_watch(watch);
+        let signer = test_signer();
+
+        let start = tokio::time::Instant::now();
+        let tx_hash = service
+            .execute(Address::from_low_u64_be(1), Bytes::default(), 1, &signer, None)
+            .await
+            .unwrap();
+
+        // The watch runs after `execute` has returned
+        assert_eq!(tx_hash, H256::from_low_u64_be(1));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        tokio::time::sleep(Duration::from_secs(23)).await;
+        assert!(!hook.events.lock().unwrap().contains(&"reorged:1".to_string()));
+
+        // Caught on the second poll, not at the end of the watch
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            *hook.events.lock().unwrap(),
+            vec!["generated:1", "estimated:1", "signed:1", "submitted:1", "confirmed:1", "reorged:1"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_waits_for_reorg_watch() {
+        let submitter = reorg_submitter(vec![H256::from_low_u64_be(100)], None);
+        let tracker = Arc::new(OpTracker::new());
+        let watch = ReorgWatch {
+            poll_interval: Duration::from_secs(12),
+            max_duration: Duration::from_secs(60),
+        };
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), submitter, Address::zero())
+            .with_tracker(tracker.clone())
+            .with_reorg_watch(watch);
+        let signer = test_signer();
+
+        service
+            .execute(Address::from_low_u64_be(1), Bytes::default(), 1, &signer, None)
+            .await
+            .unwrap();
+
+        // The watch is still running after `execute` returns
+        assert_eq!(tracker.in_flight(), 1);
+        assert!(!tracker.drain(Duration::from_secs(30)).await);
+        assert!(tracker.drain(Duration::from_secs(31)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reincluded_op_is_not_reported_as_reorged() {
+        let (included, reorged) = (H256::from_low_u64_be(100), H256::from_low_u64_be(101));
+        let submitter = reorg_submitter(vec![included, included, reorged], Some((H256::from_low_u64_be(1), reorged)));
+        let hook = Arc::new(RecordingHook::default());
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), submitter, Address::zero())
+            .with_hook(hook.clone());
+        let watch = ReorgWatch {
+            poll_interval: Duration::from_secs(12),
+            max_duration: Duration::from_secs(60),
+        };
+
+        let status = service
+            .watch_for_reorg(H256::zero(), 1, H256::from_low_u64_be(1), watch)
+            .await
+            .unwrap();
+
+        assert_eq!(status, ReorgStatus::Stable);
+        assert!(hook.events.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reorg_watch_ends_after_max_duration() {
+        let submitter = reorg_submitter(vec![H256::from_low_u64_be(100)], None);
+        let service = UserOpService::new(UserOpGenerator::new(cached_estimator().await), submitter, Address::zero());
+        let watch = ReorgWatch {
+            poll_interval: Duration::from_secs(12),
+            max_duration: Duration::from_secs(60),
+        };
+
+        let start = tokio::time::Instant::now();
+        let status = service
+            .watch_for_reorg(H256::zero(), 1, H256::from_low_u64_be(1), watch)
+            .await
+            .unwrap();
+
+        assert_eq!(status, ReorgStatus::Stable);
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_bump_fees_rounds_up() {
+        let mut user_op = UserOperation::new(Address::from_low_u64_be(1));
+        user_op.max_fee_per_gas = U256::from(1_000_000_001u64);
+        user_op.max_priority_fee_per_gas = U256::from(1);
+
+        bump_fees(&mut user_op, MIN_BUMP_PERCENT);
+
+        assert_eq!(user_op.max_fee_per_gas, U256::from(1_100_000_002u64));
+        assert_eq!(user_op.max_priority_fee_per_gas, U256::from(2));
+    }
+
+    #[test]
+    fn test_beneficiary_defaulting() {
+        let explicit = Address::from_low_u64_be(1);
+        let configured = Address::from_low_u64_be(2);
+        let signer = Address::from_low_u64_be(3);
+
+        assert_eq!(resolve_beneficiary(Some(explicit), Some(configured), signer).unwrap(), explicit);
+        assert_eq!(resolve_beneficiary(None, Some(configured), signer).unwrap(), configured);
+        assert_eq!(resolve_beneficiary(None, None, signer).unwrap(), signer);
+
+        let result = resolve_beneficiary(Some(Address::zero()), Some(configured), signer);
+        assert!(matches!(result, Err(UserOpError::Validation(_))));
+    }
+}
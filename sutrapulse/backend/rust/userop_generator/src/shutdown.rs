@@ -0,0 +1,129 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+use crate::error::{Result, UserOpError};
+
+/// Counts in-flight operations so shutdown can wait for them to finish.
+#[derive(Debug, Default)]
+pub struct OpTracker {
+    in_flight: AtomicUsize,
+    draining: AtomicBool,
+    idle: Notify,
+}
+
+/// Marks one operation as in flight until dropped.
+#[derive(Debug)]
+pub struct OpGuard {
+    tracker: Arc<OpTracker>,
+}
+
+impl Drop for OpGuard {
+    fn drop(&mut self) {
+        self.tracker.finish();
+    }
+}
+
+impl OpTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new operation, failing once draining has started.
+    pub fn begin(self: &Arc<Self>) -> Result<OpGuard> {
+        // Increment before checking the flag so `drain` can never observe a
+        // zero count while an op is slipping in.
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        if self.draining.load(Ordering::SeqCst) {
+            self.finish();
+            return Err(UserOpError::Unknown("shutting down".to_string()));
+        }
+
+        Ok(OpGuard { tracker: self.clone() })
+    }
+
+    fn finish(&self) {
+        if self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting new operations and waits up to `deadline` for the
+    /// in-flight ones to finish. Returns false if the deadline passed first.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        self.draining.store(true, Ordering::SeqCst);
+        info!("Draining {} in-flight operations", self.in_flight());
+
+        let wait_idle = async {
+            loop {
+                let notified = self.idle.notified();
+                if self.in_flight() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+
+        match tokio::time::timeout(deadline, wait_idle).await {
+            Ok(()) => true,
+            Err(_) => {
+                warn!("Drain deadline passed with {} operations in flight", self.in_flight());
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_in_flight_op() {
+        let tracker = Arc::new(OpTracker::new());
+        let completed = Arc::new(AtomicBool::new(false));
+
+        let guard = tracker.begin().unwrap();
+        let op_completed = completed.clone();
+        let op = tokio::spawn(async move {
+            let _guard = guard;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            op_completed.store(true, Ordering::SeqCst);
+        });
+
+        assert!(tracker.drain(Duration::from_secs(5)).await);
+        assert!(completed.load(Ordering::SeqCst));
+        assert_eq!(tracker.in_flight(), 0);
+        op.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_new_ops_rejected_while_draining() {
+        let tracker = Arc::new(OpTracker::new());
+        assert!(tracker.drain(Duration::from_secs(1)).await);
+
+        match tracker.begin() {
+            Err(UserOpError::Unknown(msg)) => assert_eq!(msg, "shutting down"),
+            other => panic!("Expected shutdown rejection, got {:?}", other),
+        }
+        assert_eq!(tracker.in_flight(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_gives_up_at_deadline() {
+        let tracker = Arc::new(OpTracker::new());
+        let _guard = tracker.begin().unwrap();
+
+        assert!(!tracker.drain(Duration::from_secs(10)).await);
+        assert_eq!(tracker.in_flight(), 1);
+    }
+}
@@ -0,0 +1,149 @@
+use ethers::types::U256;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
+use crate::error::{Result, UserOpError};
+
+/// Wei in one gwei.
+pub const WEI_PER_GWEI: u64 = 1_000_000_000;
+
+/// Decimal places of gwei below which there are no more wei.
+const GWEI_DECIMALS: usize = 9;
+
+pub fn gwei_to_wei(gwei: u64) -> U256 {
+    U256::from(gwei) * U256::from(WEI_PER_GWEI)
+}
+
+/// `wei` as a decimal number of gwei, without trailing zeros: 1.5 gwei is
+/// `"1.5"`, 30 gwei is `"30"`.
+pub fn wei_to_gwei(wei: U256) -> String {
+    let (whole, fraction) = wei.div_mod(U256::from(WEI_PER_GWEI));
+    if fraction.is_zero() {
+        return whole.to_string();
+    }
+    let fraction = format!("{:0>width$}", fraction.to_string(), width = GWEI_DECIMALS);
+    format!("{}.{}", whole, fraction.trim_end_matches('0'))
+}
+
+/// Parses a decimal gwei amount such as `"30"` or `"0.25"` into wei. Fails
+/// with `UserOpError::Validation` on anything finer than one wei.
+pub fn parse_gwei(gwei: &str) -> Result<U256> {
+    let invalid = || UserOpError::Validation(format!("Invalid gwei amount {:?}", gwei));
+
+    let gwei = gwei.trim();
+    let (whole, fraction) = gwei.split_once('.').unwrap_or((gwei, ""));
+    if (whole.is_empty() && fraction.is_empty())
+        || fraction.len() > GWEI_DECIMALS
+        || !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+
+    let whole = if whole.is_empty() { U256::zero() } else { U256::from_dec_str(whole).map_err(|_| invalid())? };
+    let fraction = if fraction.is_empty() {
+        U256::zero()
+    } else {
+        U256::from_dec_str(&format!("{:0<width$}", fraction, width = GWEI_DECIMALS)).map_err(|_| invalid())?
+    };
+
+    whole
+        .checked_mul(U256::from(WEI_PER_GWEI))
+        .and_then(|wei| wei.checked_add(fraction))
+        .ok_or_else(invalid)
+}
+
+/// A wei amount that reads and prints in gwei, for fees written by people:
+/// `"1.5".parse::<GweiU256>()` holds 1_500_000_000 wei and displays as
+/// `1.5 gwei`. Serializes as a gwei string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GweiU256(U256);
+
+impl GweiU256 {
+    pub fn from_gwei(gwei: u64) -> Self {
+        Self(gwei_to_wei(gwei))
+    }
+
+    pub fn from_wei(wei: U256) -> Self {
+        Self(wei)
+    }
+
+    pub fn wei(&self) -> U256 {
+        self.0
+    }
+}
+
+impl From<GweiU256> for U256 {
+    fn from(gwei: GweiU256) -> Self {
+        gwei.0
+    }
+}
+
+impl FromStr for GweiU256 {
+    type Err = UserOpError;
+
+    /// Accepts an optional ` gwei` suffix, so `Display` output parses back.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let amount = s.strip_suffix("gwei").unwrap_or(s);
+        parse_gwei(amount).map(Self)
+    }
+}
+
+impl std::fmt::Display for GweiU256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} gwei", wei_to_gwei(self.0))
+    }
+}
+
+impl Serialize for GweiU256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&wei_to_gwei(self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for GweiU256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trips_common_values() {
+        for gwei in [0u64, 1, 30, 100, 1_000_000] {
+            let wei = gwei_to_wei(gwei);
+            assert_eq!(wei, U256::from(gwei) * U256::exp10(9));
+            assert_eq!(wei_to_gwei(wei), gwei.to_string());
+            assert_eq!(parse_gwei(&gwei.to_string()).unwrap(), wei);
+        }
+        assert_eq!(GweiU256::from_gwei(30).wei(), U256::from(30_000_000_000u64));
+        assert_eq!(GweiU256::from_gwei(30).to_string(), "30 gwei");
+        assert_eq!("30 gwei".parse::<GweiU256>().unwrap(), GweiU256::from_gwei(30));
+    }
+
+    #[test]
+    fn test_fractional_gwei() {
+        assert_eq!(parse_gwei("1.5").unwrap(), U256::from(1_500_000_000u64));
+        assert_eq!(parse_gwei("0.000000001").unwrap(), U256::one());
+        assert_eq!(parse_gwei(".25").unwrap(), U256::from(250_000_000u64));
+        assert_eq!(wei_to_gwei(U256::from(1_500_000_000u64)), "1.5");
+        assert_eq!(wei_to_gwei(U256::one()), "0.000000001");
+
+        let fee: GweiU256 = "0.1".parse().unwrap();
+        assert_eq!(U256::from(fee), U256::from(100_000_000u64));
+        assert_eq!(fee.to_string(), "0.1 gwei");
+        assert_eq!(serde_json::to_string(&fee).unwrap(), "\"0.1\"");
+        assert_eq!(serde_json::from_str::<GweiU256>("\"0.1\"").unwrap(), fee);
+    }
+
+    #[test]
+    fn test_rejects_invalid_amounts() {
+        for amount in ["", ".", "1.0000000001", "-1", "1e9", "abc", "1.2.3"] {
+            assert!(matches!(parse_gwei(amount), Err(UserOpError::Validation(_))), "{:?}", amount);
+        }
+    }
+}
@@ -1,23 +1,135 @@
 use ethers::prelude::*;
-use ethers::abi::Token;
+use ethers::abi::{ParamType, Token};
+use ethers::types::transaction::eip2930::AccessList;
+use std::sync::Arc;
 use serde::{Deserialize, Serialize};
+use tracing::Instrument;
 use crate::error::{Result, UserOpError};
-use crate::gas::GasEstimator;
-use crate::contracts::{UserOperationCall, IEntryPointCalls};
+use crate::gas::{GasEstimator, GasParams};
+use crate::hasher::{EthersKeccak, Hasher};
+use crate::metrics::Metrics;
+use crate::paymaster::{PaymasterAndData, TokenPaymaster};
+use crate::policy::SharedSenderPolicy;
+use crate::provider::RpcClient;
+use crate::contracts::{EntryPointVersion, UserOperationCall};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Serializes with the camelCase keys bundlers expect. The snake_case keys
+/// it was previously written with are still accepted when deserializing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct UserOperation {
     pub sender: Address,
     pub nonce: U256,
+    #[serde(alias = "init_code")]
     pub init_code: Bytes,
+    #[serde(alias = "call_data")]
     pub call_data: Bytes,
+    #[serde(alias = "call_gas_limit")]
     pub call_gas_limit: U256,
+    #[serde(alias = "verification_gas_limit")]
     pub verification_gas_limit: U256,
+    #[serde(alias = "pre_verification_gas")]
     pub pre_verification_gas: U256,
+    #[serde(alias = "max_fee_per_gas")]
     pub max_fee_per_gas: U256,
+    #[serde(alias = "max_priority_fee_per_gas")]
     pub max_priority_fee_per_gas: U256,
+    #[serde(alias = "paymaster_and_data")]
     pub paymaster_and_data: Bytes,
     pub signature: Bytes,
+    /// Delegation for an EIP-7702 sender, sent alongside the op to
+    /// EntryPoint v0.8 bundlers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eip7702_auth: Option<Authorization>,
+    /// EIP-2930 access list passed to `eth_estimateGas` when estimating the
+    /// call gas limit. Local only; never sent to bundlers.
+    #[serde(skip)]
+    pub access_list: Option<AccessList>,
+    /// Whether `paymaster_and_data` already carries the v0.7 paymaster gas
+    /// limits, set by `with_paymaster_gas_limits`. Local only.
+    #[serde(skip)]
+    pub paymaster_gas_limits_folded: bool,
+}
+
+/// A signed EIP-7702 authorization setting an EOA's code to delegate to
+/// `address`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Authorization {
+    pub chain_id: U256,
+    pub address: Address,
+    pub nonce: U256,
+    pub y_parity: U64,
+    pub r: U256,
+    pub s: U256,
+}
+
+/// `initCode` prefix marking an EIP-7702 sender in EntryPoint v0.8. The
+/// rest of the init code, if any, is passed to the delegate on first use.
+pub const INITCODE_EIP7702_MARKER: [u8; 20] = [
+    0x77, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// A field that differs between two UserOperations, as reported by
+/// `UserOperation::diff`. Values are rendered for display: integers in
+/// decimal, addresses and bytes as 0x-prefixed hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+impl std::fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.old, self.new)
+    }
+}
+
+/// Maximum byte sizes accepted for variable-length UserOperation fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    pub max_call_data_bytes: usize,
+    pub max_init_code_bytes: usize,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self {
+            max_call_data_bytes: 64 * 1024,
+            max_init_code_bytes: 32 * 1024,
+        }
+    }
+}
+
+fn check_size(field: &str, len: usize, max: usize) -> Result<()> {
+    if len > max {
+        return Err(UserOpError::Validation(format!(
+            "{} is {} bytes, exceeding the {} byte limit by {} bytes",
+            field, len, max, len - max
+        )));
+    }
+    Ok(())
+}
+
+/// Backend behind `pack` and `hash`, so both always hash the same way.
+const DEFAULT_HASHER: &dyn Hasher = &EthersKeccak;
+
+/// The EntryPoint's `UserOperation` tuple type, in field order.
+fn user_op_param_type() -> ParamType {
+    ParamType::Tuple(vec![
+        ParamType::Address,
+        ParamType::Uint(256),
+        ParamType::Bytes,
+        ParamType::Bytes,
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+        ParamType::Bytes,
+        ParamType::Bytes,
+    ])
 }
 
 impl From<UserOperation> for UserOperationCall {
@@ -38,6 +150,28 @@ impl From<UserOperation> for UserOperationCall {
     }
 }
 
+/// Converts without cloning the whole op: the `Bytes` fields are
+/// reference-counted handles, so only those are bumped, and the
+/// EIP-7702 authorization and access list, which aren't part of the
+/// EntryPoint tuple, are never copied.
+impl From<&UserOperation> for UserOperationCall {
+    fn from(op: &UserOperation) -> Self {
+        UserOperationCall {
+            sender: op.sender,
+            nonce: op.nonce,
+            init_code: op.init_code.clone(),
+            call_data: op.call_data.clone(),
+            call_gas_limit: op.call_gas_limit,
+            verification_gas_limit: op.verification_gas_limit,
+            pre_verification_gas: op.pre_verification_gas,
+            max_fee_per_gas: op.max_fee_per_gas,
+            max_priority_fee_per_gas: op.max_priority_fee_per_gas,
+            paymaster_and_data: op.paymaster_and_data.clone(),
+            signature: op.signature.clone(),
+        }
+    }
+}
+
 impl UserOperation {
     pub fn new(sender: Address) -> Self {
         Self {
@@ -52,9 +186,18 @@ impl UserOperation {
             max_priority_fee_per_gas: U256::zero(),
             paymaster_and_data: Bytes::default(),
             signature: Bytes::default(),
+            eip7702_auth: None,
+            access_list: None,
+            paymaster_gas_limits_folded: false,
         }
     }
 
+    /// The op as the EntryPoint's tuple, moving its fields. Borrow with
+    /// `UserOperationCall::from(&op)` when the op is still needed.
+    pub fn into_call(self) -> UserOperationCall {
+        self.into()
+    }
+
     pub fn with_nonce(mut self, nonce: U256) -> Self {
         self.nonce = nonce;
         self
@@ -70,19 +213,440 @@ impl UserOperation {
         self
     }
 
+    pub fn with_eip7702_auth(mut self, auth: Authorization) -> Self {
+        self.eip7702_auth = Some(auth);
+        self
+    }
+
+    pub fn with_access_list(mut self, access_list: AccessList) -> Self {
+        self.access_list = Some(access_list);
+        self
+    }
+
+    /// Whether `init_code` starts with `INITCODE_EIP7702_MARKER`.
+    pub fn is_eip7702(&self) -> bool {
+        self.init_code.len() >= 20 && self.init_code[..20] == INITCODE_EIP7702_MARKER
+    }
+
+    /// `init_code` as `version` hashes it: from v0.8 on, EIP-7702 senders
+    /// have the marker replaced by the authorized delegate address.
+    fn hashed_init_code(&self, version: EntryPointVersion) -> Vec<u8> {
+        match &self.eip7702_auth {
+            Some(auth) if version == EntryPointVersion::V08 && self.is_eip7702() => {
+                [auth.address.as_bytes(), &self.init_code[20..]].concat()
+            }
+            _ => self.init_code.to_vec(),
+        }
+    }
+
     pub fn with_paymaster(mut self, paymaster: Address, paymaster_data: Bytes) -> Self {
         self.paymaster_and_data = Bytes::from([paymaster.as_bytes(), paymaster_data.as_ref()].concat());
+        self.paymaster_gas_limits_folded = false;
+        self
+    }
+
+    /// Attaches a verifying paymaster in the reference layout:
+    /// `paymaster (20) | abi.encode(uint48 validUntil, uint48 validAfter) (64) | signature`.
+    /// A `valid_until` of 0 means the sponsorship never expires.
+    pub fn with_verifying_paymaster(
+        mut self,
+        paymaster: Address,
+        valid_until: u64,
+        valid_after: u64,
+        signature: Bytes,
+    ) -> Result<Self> {
+        let max_uint48 = (1u64 << 48) - 1;
+        if valid_until > max_uint48 || valid_after > max_uint48 {
+            return Err(UserOpError::Validation("Paymaster validity timestamps must fit in uint48".to_string()));
+        }
+        if valid_until != 0 && valid_after >= valid_until {
+            return Err(UserOpError::Validation(format!(
+                "Paymaster valid_after ({}) must be before valid_until ({})",
+                valid_after, valid_until
+            )));
+        }
+
+        let timestamps = ethers::abi::encode(&[
+            Token::Uint(U256::from(valid_until)),
+            Token::Uint(U256::from(valid_after)),
+        ]);
+        self.paymaster_and_data = Bytes::from(
+            [paymaster.as_bytes(), timestamps.as_slice(), signature.as_ref()].concat(),
+        );
+        self.paymaster_gas_limits_folded = false;
+        Ok(self)
+    }
+
+    pub fn with_gas_params(mut self, gas_params: &GasParams) -> Self {
+        self.call_gas_limit = gas_params.call_gas_limit;
+        self.verification_gas_limit = gas_params.verification_gas_limit;
+        self.pre_verification_gas = gas_params.pre_verification_gas;
+        self.max_fee_per_gas = gas_params.max_fee_per_gas;
+        self.max_priority_fee_per_gas = gas_params.max_priority_fee_per_gas;
         self
     }
+
+    /// Folds the paymaster gas limits from `gas_params` into
+    /// `paymaster_and_data`, rewriting the `paymaster | data` layout built by
+    /// `with_paymaster` into the v0.7 one. The paymaster verification limit
+    /// falls back to the op's own. Calling it again, e.g. after
+    /// re-estimating, replaces the limits folded in before. No-op for v0.6
+    /// or without a paymaster.
+    pub fn with_paymaster_gas_limits(mut self, gas_params: &GasParams, version: EntryPointVersion) -> Result<Self> {
+        if version == EntryPointVersion::V06 || self.paymaster().is_none() {
+            return Ok(self);
+        }
+
+        let layout = if self.paymaster_gas_limits_folded { version } else { EntryPointVersion::V06 };
+        let mut paymaster_and_data = PaymasterAndData::parse(&self.paymaster_and_data, layout)?;
+        paymaster_and_data.paymaster_verification_gas_limit = gas_params
+            .paymaster_verification_gas_limit
+            .or(Some(gas_params.verification_gas_limit));
+        paymaster_and_data.paymaster_post_op_gas_limit = gas_params.paymaster_post_op_gas_limit;
+        self.paymaster_and_data = paymaster_and_data.to_bytes();
+        self.paymaster_gas_limits_folded = true;
+        Ok(self)
+    }
+
+    /// The paymaster address, if `paymaster_and_data` is set.
+    pub fn paymaster(&self) -> Option<Address> {
+        (self.paymaster_and_data.len() >= 20)
+            .then(|| Address::from_slice(&self.paymaster_and_data[..20]))
+    }
+
+    /// Most gas EntryPoint `version` may use for the op, see
+    /// `GasParams::max_gas`. From v0.7 on that includes the paymaster limits
+    /// folded into `paymaster_and_data`. Saturates at `U256::MAX` rather
+    /// than overflowing on absurd limits.
+    pub fn max_gas(&self, version: EntryPointVersion) -> U256 {
+        self.gas_params_for(version).max_gas(version, self.paymaster().is_some())
+    }
+
+    /// `requiredPreFund` as computed by EntryPoint `version`: `max_gas`
+    /// times `max_fee_per_gas`.
+    pub fn required_prefund(&self, version: EntryPointVersion) -> U256 {
+        self.gas_params_for(version).required_prefund(version, self.paymaster().is_some())
+    }
+
+    /// `gas_params` plus the paymaster limits `version` reads from
+    /// `paymaster_and_data`, once they've been folded in.
+    fn gas_params_for(&self, version: EntryPointVersion) -> GasParams {
+        let mut gas_params = self.gas_params();
+        if version != EntryPointVersion::V06 && self.paymaster_gas_limits_folded {
+            if let Ok(paymaster_and_data) = PaymasterAndData::parse(&self.paymaster_and_data, version) {
+                gas_params.paymaster_verification_gas_limit = paymaster_and_data.paymaster_verification_gas_limit;
+                gas_params.paymaster_post_op_gas_limit = paymaster_and_data.paymaster_post_op_gas_limit;
+            }
+        }
+        gas_params
+    }
+
+    /// The op's gas limits and fees, the inverse of `with_gas_params`.
+    /// Paymaster limits packed into `paymaster_and_data` are left out.
+    pub fn gas_params(&self) -> GasParams {
+        GasParams {
+            call_gas_limit: self.call_gas_limit,
+            verification_gas_limit: self.verification_gas_limit,
+            pre_verification_gas: self.pre_verification_gas,
+            max_fee_per_gas: self.max_fee_per_gas,
+            max_priority_fee_per_gas: self.max_priority_fee_per_gas,
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        }
+    }
+
+    /// The op as the EntryPoint's `UserOperation` tuple.
+    pub fn to_token(&self) -> Token {
+        Token::Tuple(vec![
+            Token::Address(self.sender),
+            Token::Uint(self.nonce),
+            Token::Bytes(self.init_code.to_vec()),
+            Token::Bytes(self.call_data.to_vec()),
+            Token::Uint(self.call_gas_limit),
+            Token::Uint(self.verification_gas_limit),
+            Token::Uint(self.pre_verification_gas),
+            Token::Uint(self.max_fee_per_gas),
+            Token::Uint(self.max_priority_fee_per_gas),
+            Token::Bytes(self.paymaster_and_data.to_vec()),
+            Token::Bytes(self.signature.to_vec()),
+        ])
+    }
+
+    /// `abi.encode(userOp)` with the EntryPoint tuple type.
+    pub fn encode(&self) -> Vec<u8> {
+        ethers::abi::encode(&[self.to_token()])
+    }
+
+    /// Inverse of `encode`.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let invalid = |e: String| UserOpError::Validation(format!("Invalid UserOperation encoding: {}", e));
+
+        let tokens = ethers::abi::decode(&[user_op_param_type()], data)
+            .map_err(|e| invalid(e.to_string()))?;
+        let fields = match tokens.into_iter().next() {
+            Some(Token::Tuple(fields)) if fields.len() == 11 => fields,
+            other => return Err(invalid(format!("expected an 11-field tuple, got {:?}", other))),
+        };
+
+        let mut fields = fields.into_iter();
+        let mut next = || fields.next().expect("tuple length checked above");
+        let address = |token: Token| token.into_address().ok_or_else(|| invalid("expected address".into()));
+        let uint = |token: Token| token.into_uint().ok_or_else(|| invalid("expected uint256".into()));
+        let bytes = |token: Token| token.into_bytes().map(Bytes::from).ok_or_else(|| invalid("expected bytes".into()));
+
+        Ok(Self {
+            sender: address(next())?,
+            nonce: uint(next())?,
+            init_code: bytes(next())?,
+            call_data: bytes(next())?,
+            call_gas_limit: uint(next())?,
+            verification_gas_limit: uint(next())?,
+            pre_verification_gas: uint(next())?,
+            max_fee_per_gas: uint(next())?,
+            max_priority_fee_per_gas: uint(next())?,
+            paymaster_and_data: bytes(next())?,
+            signature: bytes(next())?,
+            eip7702_auth: None,
+            access_list: None,
+            paymaster_gas_limits_folded: false,
+        })
+    }
+
+    /// ABI-encodes the op the way `UserOperationLib.pack` does in the v0.6
+    /// EntryPoint: the dynamic fields are replaced by their keccak256 and the
+    /// signature is left out, so every word is static.
+    ///
+    /// `abi.encode(sender, nonce, keccak(initCode), keccak(callData),
+    /// callGasLimit, verificationGasLimit, preVerificationGas, maxFeePerGas,
+    /// maxPriorityFeePerGas, keccak(paymasterAndData))`
+    pub fn pack(&self) -> Vec<u8> {
+        self.pack_with(DEFAULT_HASHER)
+    }
+
+    /// `pack` with a custom keccak backend.
+    pub fn pack_with(&self, hasher: &dyn Hasher) -> Vec<u8> {
+        self.pack_for_version(hasher, EntryPointVersion::V06)
+    }
+
+    /// `pack` as `version` does it. For EIP-7702 senders v0.8 hashes
+    /// `initCode` with the delegate address in place of the marker.
+    fn pack_for_version(&self, hasher: &dyn Hasher, version: EntryPointVersion) -> Vec<u8> {
+        ethers::abi::encode(&[
+            Token::Address(self.sender),
+            Token::Uint(self.nonce),
+            Token::FixedBytes(hasher.keccak256(&self.hashed_init_code(version)).to_vec()),
+            Token::FixedBytes(hasher.keccak256(&self.call_data).to_vec()),
+            Token::Uint(self.call_gas_limit),
+            Token::Uint(self.verification_gas_limit),
+            Token::Uint(self.pre_verification_gas),
+            Token::Uint(self.max_fee_per_gas),
+            Token::Uint(self.max_priority_fee_per_gas),
+            Token::FixedBytes(hasher.keccak256(&self.paymaster_and_data).to_vec()),
+        ])
+    }
+
+    /// The userOpHash as `EntryPoint.getUserOpHash` computes it:
+    /// `keccak256(abi.encode(keccak256(pack(op)), entryPoint, chainId))`.
+    pub fn hash(&self, entry_point: Address, chain_id: u64) -> H256 {
+        self.hash_with(DEFAULT_HASHER, entry_point, chain_id)
+    }
+
+    /// `hash` with a custom keccak backend.
+    pub fn hash_with(&self, hasher: &dyn Hasher, entry_point: Address, chain_id: u64) -> H256 {
+        self.hash_for_version_with(hasher, EntryPointVersion::V06, entry_point, chain_id)
+    }
+
+    /// The userOpHash as EntryPoint `version` computes it. Only v0.8 differs,
+    /// and only for EIP-7702 senders.
+    pub fn hash_for_version(&self, version: EntryPointVersion, entry_point: Address, chain_id: u64) -> H256 {
+        self.hash_for_version_with(DEFAULT_HASHER, version, entry_point, chain_id)
+    }
+
+    /// `hash_for_version` with a custom keccak backend.
+    pub fn hash_for_version_with(
+        &self,
+        hasher: &dyn Hasher,
+        version: EntryPointVersion,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> H256 {
+        let encoded = ethers::abi::encode(&[
+            Token::FixedBytes(hasher.keccak256(&self.pack_for_version(hasher, version)).to_vec()),
+            Token::Address(entry_point),
+            Token::Uint(U256::from(chain_id)),
+        ]);
+
+        hasher.keccak256(&encoded).into()
+    }
+
+    /// Fields whose values differ from `other`, in declaration order.
+    pub fn diff(&self, other: &UserOperation) -> Vec<FieldChange> {
+        let mut changes = Vec::new();
+        let mut compare = |field: &'static str, old: String, new: String| {
+            if old != new {
+                changes.push(FieldChange { field, old, new });
+            }
+        };
+
+        compare("sender", format!("{:?}", self.sender), format!("{:?}", other.sender));
+        compare("nonce", self.nonce.to_string(), other.nonce.to_string());
+        compare("init_code", self.init_code.to_string(), other.init_code.to_string());
+        compare("call_data", self.call_data.to_string(), other.call_data.to_string());
+        compare("call_gas_limit", self.call_gas_limit.to_string(), other.call_gas_limit.to_string());
+        compare("verification_gas_limit", self.verification_gas_limit.to_string(), other.verification_gas_limit.to_string());
+        compare("pre_verification_gas", self.pre_verification_gas.to_string(), other.pre_verification_gas.to_string());
+        compare("max_fee_per_gas", self.max_fee_per_gas.to_string(), other.max_fee_per_gas.to_string());
+        compare("max_priority_fee_per_gas", self.max_priority_fee_per_gas.to_string(), other.max_priority_fee_per_gas.to_string());
+        compare("paymaster_and_data", self.paymaster_and_data.to_string(), other.paymaster_and_data.to_string());
+        compare("signature", self.signature.to_string(), other.signature.to_string());
+        compare("eip7702_auth", format!("{:?}", self.eip7702_auth), format!("{:?}", other.eip7702_auth));
+        compare("access_list", format!("{:?}", self.access_list), format!("{:?}", other.access_list));
+
+        changes
+    }
+
+    /// Checks `call_data` and `init_code` against the given size limits.
+    pub fn validate(&self, limits: &SizeLimits) -> Result<()> {
+        check_size("call_data", self.call_data.len(), limits.max_call_data_bytes)?;
+        check_size("init_code", self.init_code.len(), limits.max_init_code_bytes)
+    }
+}
+
+/// How `sign_user_op` signs the userOpHash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashSigningMode {
+    /// Sign with the EIP-191 personal-sign prefix, as `eth_sign` does.
+    #[default]
+    Eip191,
+    /// Sign the 32-byte digest directly, for wallets that recover from the
+    /// bare userOpHash.
+    Raw,
+}
+
+/// A signer that may also sign a digest without the EIP-191 prefix.
+/// Implemented for every `Signer`, so any signer works with
+/// `HashSigningMode::Eip191`; `sign_digest` is only supported by
+/// `LocalWallet`, since `Signer` has no way to sign a bare hash.
+#[async_trait::async_trait]
+pub trait HashSigner: Signer {
+    async fn sign_digest(&self, digest: H256) -> Result<Signature>;
+}
+
+#[async_trait::async_trait]
+impl<S: Signer + 'static> HashSigner for S {
+    async fn sign_digest(&self, digest: H256) -> Result<Signature> {
+        match (self as &dyn std::any::Any).downcast_ref::<LocalWallet>() {
+            Some(wallet) => wallet.sign_hash(digest).map_err(|e| UserOpError::Signature(e.to_string())),
+            None => Err(UserOpError::Signature(format!(
+                "{} can't sign a bare digest; use HashSigningMode::Eip191",
+                std::any::type_name::<S>()
+            ))),
+        }
+    }
+}
+
+/// Order of the secp256k1 curve.
+const SECP256K1_N: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+
+/// Rewrites a signature into canonical form: `s` in the lower half of the
+/// curve order (flipping the recovery id to match) and `v` as 27 or 28.
+/// Handles raw (0/1) and EIP-155 (`35 + 2 * chainId + recId`) `v` values;
+/// any other `v` fails with `UserOpError::Signature`.
+pub fn normalize_signature(signature: Signature) -> Result<Signature> {
+    let n = U256::from_big_endian(&SECP256K1_N);
+    let recovery_id = match signature.v {
+        0 | 1 => signature.v,
+        27 | 28 => signature.v - 27,
+        v if v >= 35 => (v - 35) % 2,
+        v => return Err(UserOpError::Signature(format!("Signature v {} is not a valid recovery id", v))),
+    };
+
+    let (s, recovery_id) = if signature.s > n / 2 {
+        (n - signature.s, recovery_id ^ 1)
+    } else {
+        (signature.s, recovery_id)
+    };
+
+    Ok(Signature { r: signature.r, s, v: recovery_id + 27 })
 }
 
-pub struct UserOpGenerator {
-    gas_estimator: GasEstimator,
+pub struct UserOpGenerator<P = RpcClient> {
+    gas_estimator: GasEstimator<P>,
+    size_limits: SizeLimits,
+    normalize_signatures: bool,
+    hash_signing_mode: HashSigningMode,
+    hasher: Arc<dyn Hasher>,
+    sender_policy: Option<SharedSenderPolicy>,
+    entry_point_version: EntryPointVersion,
 }
 
-impl UserOpGenerator {
-    pub fn new(gas_estimator: GasEstimator) -> Self {
-        Self { gas_estimator }
+impl<P: JsonRpcClient> UserOpGenerator<P> {
+    pub fn new(gas_estimator: GasEstimator<P>) -> Self {
+        Self {
+            gas_estimator,
+            size_limits: SizeLimits::default(),
+            normalize_signatures: true,
+            hash_signing_mode: HashSigningMode::default(),
+            hasher: Arc::new(EthersKeccak),
+            sender_policy: None,
+            entry_point_version: EntryPointVersion::V06,
+        }
+    }
+
+    /// The EntryPoint release ops are built and hashed for: it decides
+    /// whether paymaster gas limits are estimated and folded into
+    /// `paymaster_and_data`, and how v0.8 EIP-7702 ops are hashed. Defaults
+    /// to v0.6; set it from `Contracts::detect_entry_point_version`.
+    pub fn with_entry_point_version(mut self, version: EntryPointVersion) -> Self {
+        self.entry_point_version = version;
+        self.gas_estimator = self.gas_estimator.with_entry_point_version(version);
+        self
+    }
+
+    /// Restricts which senders ops are generated for. The policy is read on
+    /// every call, so writes to it apply to the next op.
+    pub fn with_sender_policy(mut self, policy: SharedSenderPolicy) -> Self {
+        self.sender_policy = Some(policy);
+        self
+    }
+
+    /// Fails with `UserOpError::Validation` if the sender policy rejects
+    /// `sender`. Always passes without a policy.
+    pub fn check_sender(&self, sender: Address) -> Result<()> {
+        match &self.sender_policy {
+            Some(policy) => policy.read().expect("sender policy lock poisoned").check(sender),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the keccak backend used by `hash_user_op`.
+    pub fn with_hasher(mut self, hasher: Arc<dyn Hasher>) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    pub fn gas_estimator(&self) -> &GasEstimator<P> {
+        &self.gas_estimator
+    }
+
+    pub fn with_hash_signing_mode(mut self, mode: HashSigningMode) -> Self {
+        self.hash_signing_mode = mode;
+        self
+    }
+
+    /// Whether `sign_user_op` rewrites signatures to low-s with `v` of 27/28.
+    /// Wallets that accept any valid ECDSA signature can turn this off.
+    pub fn with_signature_normalization(mut self, enabled: bool) -> Self {
+        self.normalize_signatures = enabled;
+        self
+    }
+
+    pub fn with_size_limits(mut self, size_limits: SizeLimits) -> Self {
+        self.size_limits = size_limits;
+        self
     }
 
     pub async fn generate_user_op(
@@ -92,29 +656,73 @@ impl UserOpGenerator {
         chain_id: u64,
         paymaster: Option<(Address, Bytes)>,
     ) -> Result<UserOperation> {
+        self.generate_user_op_observed(sender, call_data, chain_id, paymaster, |_| Ok(()))
+            .await
+    }
+
+    /// `generate_user_op`, calling `on_built` with the op once it is built
+    /// and before its gas is estimated.
+    pub(crate) async fn generate_user_op_observed(
+        &self,
+        sender: Address,
+        call_data: Bytes,
+        chain_id: u64,
+        paymaster: Option<(Address, Bytes)>,
+        on_built: impl FnOnce(&UserOperation) -> Result<()> + Send,
+    ) -> Result<UserOperation> {
+        let result = self.build_user_op(sender, call_data, chain_id, paymaster, on_built).await;
+        Metrics::record_userop_generation(chain_id, result.is_ok());
+        result
+    }
+
+    async fn build_user_op(
+        &self,
+        sender: Address,
+        call_data: Bytes,
+        chain_id: u64,
+        paymaster: Option<(Address, Bytes)>,
+        on_built: impl FnOnce(&UserOperation) -> Result<()> + Send,
+    ) -> Result<UserOperation> {
+        self.check_sender(sender)?;
         let mut user_op = UserOperation::new(sender);
 
         // Set call data
         user_op = user_op.with_call_data(call_data);
+        user_op.validate(&self.size_limits)?;
 
-        // Estimate gas parameters
-        let gas_params = self.gas_estimator.estimate_gas(&user_op, chain_id).await?;
-        
-        user_op.call_gas_limit = gas_params.call_gas_limit;
-        user_op.verification_gas_limit = gas_params.verification_gas_limit;
-        user_op.pre_verification_gas = gas_params.pre_verification_gas;
-        user_op.max_fee_per_gas = gas_params.max_fee_per_gas;
-        user_op.max_priority_fee_per_gas = gas_params.max_priority_fee_per_gas;
-
-        // Add paymaster if provided
+        // Add paymaster if provided, before estimating so its gas is covered
         if let Some((paymaster_addr, paymaster_data)) = paymaster {
             user_op = user_op.with_paymaster(paymaster_addr, paymaster_data);
         }
+        on_built(&user_op)?;
+
+        // Estimate gas parameters
+        let gas_params = self.gas_estimator
+            .estimate_gas(&user_op, chain_id, None)
+            .instrument(tracing::info_span!("estimate"))
+            .await?;
+        user_op = user_op
+            .with_gas_params(&gas_params)
+            .with_paymaster_gas_limits(&gas_params, self.entry_point_version)?;
 
         Ok(user_op)
     }
 
-    pub async fn sign_user_op<S: Signer>(
+    /// Generates an op sponsored by an ERC-20 token paymaster, re-estimating
+    /// gas once the paymaster data is attached.
+    pub async fn generate_token_sponsored_user_op<M: Middleware>(
+        &self,
+        sender: Address,
+        call_data: Bytes,
+        chain_id: u64,
+        paymaster: &TokenPaymaster<M>,
+        token: Address,
+    ) -> Result<UserOperation> {
+        let user_op = self.generate_user_op(sender, call_data, chain_id, None).await?;
+        paymaster.sponsor(user_op, token, &self.gas_estimator, chain_id).await
+    }
+
+    pub async fn sign_user_op<S: HashSigner>(
         &self,
         user_op: &mut UserOperation,
         signer: &S,
@@ -122,36 +730,471 @@ impl UserOpGenerator {
         chain_id: u64,
     ) -> Result<()> {
         let user_op_hash = self.hash_user_op(user_op, entry_point, chain_id)?;
-        let signature = signer
-            .sign_message(user_op_hash)
-            .await
-            .map_err(|e| UserOpError::Signature(e.to_string()))?;
-        
+        let mut signature = match self.hash_signing_mode {
+            HashSigningMode::Eip191 => signer
+                .sign_message(user_op_hash)
+                .await
+                .map_err(|e| UserOpError::Signature(e.to_string()))?,
+            HashSigningMode::Raw => signer.sign_digest(user_op_hash).await?,
+        };
+        if self.normalize_signatures {
+            signature = normalize_signature(signature)?;
+        }
+
         user_op.signature = signature.to_vec().into();
         Ok(())
     }
 
-    fn hash_user_op(
+    /// Computes the userOpHash locally; see `UserOperation::hash` for the
+    /// exact encoding.
+    pub fn hash_user_op(
         &self,
         user_op: &UserOperation,
         entry_point: Address,
         chain_id: u64,
     ) -> Result<H256> {
-        let encoded = ethers::abi::encode(&[
-            Token::Address(user_op.sender),
-            Token::Uint(user_op.nonce),
-            Token::Bytes(user_op.init_code.to_vec()),
-            Token::Bytes(user_op.call_data.to_vec()),
-            Token::Uint(user_op.call_gas_limit),
-            Token::Uint(user_op.verification_gas_limit),
-            Token::Uint(user_op.pre_verification_gas),
-            Token::Uint(user_op.max_fee_per_gas),
-            Token::Uint(user_op.max_priority_fee_per_gas),
-            Token::Bytes(user_op.paymaster_and_data.to_vec()),
-            Token::Uint(U256::from(chain_id)),
-            Token::Address(entry_point),
-        ]);
+        Ok(user_op.hash_for_version_with(self.hasher.as_ref(), self.entry_point_version, entry_point, chain_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::utils::keccak256;
+    use std::str::FromStr;
+
+    fn test_user_op() -> UserOperation {
+        UserOperation::new(Address::from_str("0x1234567890123456789012345678901234567890").unwrap())
+    }
+
+    #[test]
+    fn test_over_limit_call_data_is_rejected() {
+        let limits = SizeLimits { max_call_data_bytes: 4, max_init_code_bytes: 4 };
+        let user_op = test_user_op().with_call_data(Bytes::from(vec![0u8; 10]));
+
+        match user_op.validate(&limits) {
+            Err(UserOpError::Validation(msg)) => {
+                assert!(msg.contains("call_data"));
+                assert!(msg.contains("by 6 bytes"));
+            }
+            other => panic!("Expected validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_at_limit_call_data_passes() {
+        let limits = SizeLimits { max_call_data_bytes: 4, max_init_code_bytes: 4 };
+        let user_op = test_user_op().with_call_data(Bytes::from(vec![0u8; 4]));
+
+        assert!(user_op.validate(&limits).is_ok());
+        assert!(test_user_op().validate(&SizeLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn test_snake_case_json_still_deserializes() {
+        let mut user_op = test_user_op().with_call_data(Bytes::from(vec![0xab]));
+        user_op.call_gas_limit = U256::from(1);
+        user_op.max_fee_per_gas = U256::from(2);
+        let camel = serde_json::to_value(&user_op).unwrap();
+        assert!(camel.get("callGasLimit").is_some());
+
+        let snake = serde_json::json!({
+            "sender": user_op.sender,
+            "nonce": user_op.nonce,
+            "init_code": user_op.init_code,
+            "call_data": user_op.call_data,
+            "call_gas_limit": user_op.call_gas_limit,
+            "verification_gas_limit": user_op.verification_gas_limit,
+            "pre_verification_gas": user_op.pre_verification_gas,
+            "max_fee_per_gas": user_op.max_fee_per_gas,
+            "max_priority_fee_per_gas": user_op.max_priority_fee_per_gas,
+            "paymaster_and_data": user_op.paymaster_and_data,
+            "signature": user_op.signature,
+        });
+        assert_eq!(serde_json::from_value::<UserOperation>(snake).unwrap(), user_op);
+        assert_eq!(serde_json::from_value::<UserOperation>(camel).unwrap(), user_op);
+    }
+
+    #[test]
+    fn test_verifying_paymaster_layout() {
+        let paymaster = Address::from_str("0x2234567890123456789012345678901234567890").unwrap();
+        let signature = Bytes::from(vec![0x11; 65]);
+
+        let user_op = test_user_op()
+            .with_verifying_paymaster(paymaster, 1_700_000_600, 1_700_000_000, signature.clone())
+            .unwrap();
+        let data = user_op.paymaster_and_data;
+
+        assert_eq!(data.len(), 20 + 64 + 65);
+        assert_eq!(&data[..20], paymaster.as_bytes());
+        assert_eq!(U256::from_big_endian(&data[20..52]), U256::from(1_700_000_600u64));
+        assert_eq!(U256::from_big_endian(&data[52..84]), U256::from(1_700_000_000u64));
+        assert_eq!(&data[84..], signature.as_ref());
+    }
+
+    #[test]
+    fn test_verifying_paymaster_rejects_inverted_window() {
+        let result = test_user_op()
+            .with_verifying_paymaster(Address::zero(), 1_700_000_000, 1_700_000_600, Bytes::default());
+        assert!(matches!(result, Err(UserOpError::Validation(_))));
+
+        // No expiry
+        assert!(test_user_op()
+            .with_verifying_paymaster(Address::zero(), 0, 1_700_000_000, Bytes::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_pack_hashes_dynamic_fields() {
+        let user_op = test_user_op()
+            .with_call_data(Bytes::from(vec![0xde, 0xad, 0xbe, 0xef]))
+            .with_signature(Bytes::from(vec![0x11; 65]));
+        let packed = user_op.pack();
+
+        assert_eq!(packed.len(), 10 * 32);
+        assert_eq!(&packed[12..32], user_op.sender.as_bytes());
+        assert_eq!(&packed[64..96], keccak256(&user_op.init_code).as_slice());
+        assert_eq!(&packed[96..128], keccak256(&user_op.call_data).as_slice());
+        assert_eq!(&packed[288..320], keccak256(&user_op.paymaster_and_data).as_slice());
+    }
+
+    #[test]
+    fn test_hash_binds_entry_point_and_chain_but_not_signature() {
+        let entry_point = Address::from_str("0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789").unwrap();
+        let user_op = test_user_op();
+        let hash = user_op.hash(entry_point, 1);
+
+        let signed = user_op.clone().with_signature(Bytes::from(vec![0x11; 65]));
+        assert_eq!(signed.hash(entry_point, 1), hash);
+
+        assert_ne!(user_op.hash(entry_point, 137), hash);
+        assert_ne!(user_op.hash(Address::zero(), 1), hash);
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&keccak256(user_op.pack()));
+        expected.extend_from_slice(H256::from(entry_point).as_bytes());
+        expected.extend_from_slice(&H256::from_low_u64_be(1).0);
+        assert_eq!(hash, H256::from(keccak256(expected)));
+    }
+
+    #[test]
+    fn test_encode_matches_entry_point_calldata() {
+        let user_op = test_user_op()
+            .with_call_data(Bytes::from(vec![0xde, 0xad, 0xbe, 0xef]))
+            .with_signature(Bytes::from(vec![0x11; 65]));
+        let call = crate::contracts::GetUserOpHashCall { user_op: (&user_op).into() };
+        let calldata = ethers::abi::AbiEncode::encode(call);
+
+        assert_eq!(&calldata[4..], user_op.encode().as_slice());
+    }
+
+    #[test]
+    fn test_borrowed_conversion_matches_owned() {
+        let mut user_op = test_user_op()
+            .with_nonce(U256::from(3))
+            .with_call_data(Bytes::from(vec![0xde, 0xad, 0xbe, 0xef]))
+            .with_signature(Bytes::from(vec![0x11; 65]));
+        user_op.init_code = Bytes::from(vec![0x22; 24]);
+        user_op.paymaster_and_data = Bytes::from(vec![0x33; 52]);
+        user_op.call_gas_limit = U256::from(100_000);
+        user_op.max_fee_per_gas = U256::from(30_000_000_000u64);
+
+        let borrowed = UserOperationCall::from(&user_op);
+        let owned = user_op.clone().into_call();
+
+        assert_eq!(borrowed, owned);
+        assert_eq!(ethers::abi::AbiEncode::encode(borrowed), ethers::abi::AbiEncode::encode(owned));
+    }
+
+    #[test]
+    fn test_decode_rejects_truncated_input() {
+        let encoded = test_user_op().encode();
+        let result = UserOperation::decode(&encoded[..encoded.len() - 32]);
+        assert!(matches!(result, Err(UserOpError::Validation(_))));
+    }
+
+    mod round_trip {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn u256() -> impl Strategy<Value = U256> {
+            prop_oneof![
+                Just(U256::zero()),
+                Just(U256::MAX),
+                any::<[u8; 32]>().prop_map(|b| U256::from_big_endian(&b)),
+            ]
+        }
+
+        fn bytes() -> impl Strategy<Value = Bytes> {
+            prop_oneof![
+                Just(Bytes::default()),
+                proptest::collection::vec(any::<u8>(), 0..300).prop_map(Bytes::from),
+            ]
+        }
+
+        fn user_op() -> impl Strategy<Value = UserOperation> {
+            (
+                any::<[u8; 20]>().prop_map(Address::from),
+                u256(),
+                bytes(),
+                bytes(),
+                (u256(), u256(), u256(), u256(), u256()),
+                bytes(),
+                bytes(),
+            )
+                .prop_map(|(sender, nonce, init_code, call_data, gas, paymaster_and_data, signature)| {
+                    UserOperation {
+                        sender,
+                        nonce,
+                        init_code,
+                        call_data,
+                        call_gas_limit: gas.0,
+                        verification_gas_limit: gas.1,
+                        pre_verification_gas: gas.2,
+                        max_fee_per_gas: gas.3,
+                        max_priority_fee_per_gas: gas.4,
+                        paymaster_and_data,
+                        signature,
+                        eip7702_auth: None,
+                        access_list: None,
+                        paymaster_gas_limits_folded: false,
+                    }
+                })
+        }
+
+        proptest! {
+            #[test]
+            fn encode_decode_round_trips(op in user_op()) {
+                let decoded = UserOperation::decode(&op.encode()).unwrap();
+                prop_assert_eq!(decoded, op);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_high_s_signature_is_normalized() {
+        let wallet = LocalWallet::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+        let message = H256::repeat_byte(0x42);
+        let signature = wallet.sign_message(message).await.unwrap();
+        let n = U256::from_big_endian(&SECP256K1_N);
+        assert!(signature.s <= n / 2);
+
+        let high_s = Signature {
+            r: signature.r,
+            s: n - signature.s,
+            v: if signature.v == 27 { 28 } else { 27 },
+        };
+        let normalized = normalize_signature(high_s).unwrap();
+
+        assert_eq!(normalized, signature);
+        assert_eq!(normalized.recover(message.as_bytes()).unwrap(), wallet.address());
+    }
+
+    #[test]
+    fn test_gas_totals_saturate_instead_of_overflowing() {
+        let mut user_op = test_user_op().with_paymaster(Address::repeat_byte(0x11), Bytes::default());
+        user_op.verification_gas_limit = U256::MAX - 1;
+        user_op.max_fee_per_gas = U256::from(2);
+
+        assert_eq!(user_op.max_gas(EntryPointVersion::V06), U256::MAX);
+        assert_eq!(user_op.gas_params().max_gas_cost(), U256::MAX);
+        assert_eq!(user_op.required_prefund(EntryPointVersion::V06), U256::MAX);
+    }
+
+    #[test]
+    fn test_normalize_signature_canonicalizes_v() {
+        let signature = Signature { r: U256::one(), s: U256::one(), v: 1 };
+        assert_eq!(normalize_signature(signature).unwrap().v, 28);
+
+        // EIP-155 v for chain 137, recovery id 0
+        let signature = Signature { r: U256::one(), s: U256::one(), v: 35 + 2 * 137 };
+        assert_eq!(normalize_signature(signature).unwrap().v, 27);
+
+        for v in [2, 26, 29, 34] {
+            let signature = Signature { r: U256::one(), s: U256::one(), v };
+            assert!(matches!(normalize_signature(signature), Err(UserOpError::Signature(_))), "v = {}", v);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_raw_and_eip191_signatures_differ() {
+        let wallet = LocalWallet::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+        let digest = H256::repeat_byte(0x42);
+
+        let prefixed = wallet.sign_message(digest).await.unwrap();
+        let raw = wallet.sign_digest(digest).await.unwrap();
+
+        assert_ne!(prefixed, raw);
+        // The prefixed signature recovers from the EIP-191 message, the raw one from the bare digest
+        assert_eq!(prefixed.recover(digest.as_bytes()).unwrap(), wallet.address());
+        assert_eq!(raw.recover(digest).unwrap(), wallet.address());
+        assert_ne!(raw.recover(digest.as_bytes()).unwrap(), wallet.address());
+    }
+
+    struct FixedHasher;
+
+    impl Hasher for FixedHasher {
+        fn keccak256(&self, _data: &[u8]) -> [u8; 32] {
+            [0x11; 32]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signing_uses_configured_hasher() {
+        let (provider, _mock) = Provider::mocked();
+        let estimator = GasEstimator::builder()
+            .providers(Arc::new(crate::gas::ChainProviders {
+                ethereum: provider.clone(),
+                polygon: provider.clone(),
+                arbitrum: provider,
+            }))
+            .build()
+            .unwrap();
+        let generator = UserOpGenerator::new(estimator).with_hasher(Arc::new(FixedHasher));
+        let wallet = LocalWallet::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+        let entry_point = Address::from_str("0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789").unwrap();
+
+        let mut user_op = test_user_op();
+        let hash = generator.hash_user_op(&user_op, entry_point, 1).unwrap();
+        assert_eq!(hash, H256::repeat_byte(0x11));
+
+        generator.sign_user_op(&mut user_op, &wallet, entry_point, 1).await.unwrap();
+        let signature = Signature::try_from(user_op.signature.as_ref()).unwrap();
+        assert_eq!(signature.recover(hash.as_bytes()).unwrap(), wallet.address());
+    }
+
+    #[test]
+    fn test_eip7702_init_code_hashes_with_delegate() {
+        let delegate = Address::from_str("0x4234567890123456789012345678901234567890").unwrap();
+        let entry_point = Address::from_str("0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789").unwrap();
+        let auth = Authorization {
+            chain_id: U256::one(),
+            address: delegate,
+            nonce: U256::zero(),
+            y_parity: U64::zero(),
+            r: U256::from(1),
+            s: U256::from(2),
+        };
+        let mut init_code = INITCODE_EIP7702_MARKER.to_vec();
+        init_code.extend([0xab, 0xcd]);
+
+        let mut delegated = test_user_op().with_eip7702_auth(auth);
+        delegated.init_code = init_code.into();
+        assert!(delegated.is_eip7702());
+
+        let mut equivalent = test_user_op();
+        equivalent.init_code = [delegate.as_bytes(), &[0xab, 0xcd]].concat().into();
+        assert_eq!(
+            delegated.hash_for_version(EntryPointVersion::V08, entry_point, 1),
+            equivalent.hash(entry_point, 1)
+        );
+
+        // Earlier EntryPoints hash the marker as is
+        for version in [EntryPointVersion::V06, EntryPointVersion::V07] {
+            assert_eq!(
+                delegated.hash_for_version(version, entry_point, 1),
+                keccak256(ethers::abi::encode(&[
+                    Token::FixedBytes(keccak256(delegated.pack()).to_vec()),
+                    Token::Address(entry_point),
+                    Token::Uint(U256::one()),
+                ]))
+                .into()
+            );
+            assert_ne!(delegated.hash_for_version(version, entry_point, 1), equivalent.hash(entry_point, 1));
+        }
+
+        // Without the marker the authorization doesn't affect the hash
+        let unmarked = test_user_op().with_eip7702_auth(delegated.eip7702_auth.clone().unwrap());
+        assert_eq!(
+            unmarked.hash_for_version(EntryPointVersion::V08, entry_point, 1),
+            test_user_op().hash(entry_point, 1)
+        );
+    }
+
+    #[test]
+    fn test_diff_reports_changed_fields() {
+        let original = test_user_op().with_nonce(U256::from(1));
+        let mut bumped = original.clone().with_nonce(U256::from(2));
+        bumped.max_fee_per_gas = U256::from(30_000_000_000u64);
+
+        assert!(original.diff(&original).is_empty());
+        assert_eq!(
+            original.diff(&bumped),
+            vec![
+                FieldChange { field: "nonce", old: "1".to_string(), new: "2".to_string() },
+                FieldChange { field: "max_fee_per_gas", old: "0".to_string(), new: "30000000000".to_string() },
+            ]
+        );
+        assert_eq!(original.diff(&bumped)[0].to_string(), "nonce: 1 -> 2");
+    }
+
+    #[test]
+    fn test_paymaster_gas_limits_fold_into_v07_layout() {
+        let paymaster = Address::from_str("0x2234567890123456789012345678901234567890").unwrap();
+        let user_op = test_user_op().with_paymaster(paymaster, Bytes::from(vec![0xaa, 0xbb]));
+        let gas_params = GasParams {
+            call_gas_limit: U256::from(100_000),
+            verification_gas_limit: U256::from(80_000),
+            pre_verification_gas: U256::from(21_000),
+            max_fee_per_gas: U256::from(30_000_000_000u64),
+            max_priority_fee_per_gas: U256::from(1_000_000_000u64),
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: Some(U256::from(40_000)),
+        };
+
+        let v06 = user_op.clone().with_paymaster_gas_limits(&gas_params, EntryPointVersion::V06).unwrap();
+        assert_eq!(v06.paymaster_and_data, user_op.paymaster_and_data);
+
+        let v07 = user_op.with_paymaster_gas_limits(&gas_params, EntryPointVersion::V07).unwrap();
+        let parsed = PaymasterAndData::parse(&v07.paymaster_and_data, EntryPointVersion::V07).unwrap();
+        assert_eq!(parsed.paymaster, Some(paymaster));
+        assert_eq!(parsed.paymaster_verification_gas_limit, Some(U256::from(80_000)));
+        assert_eq!(parsed.paymaster_post_op_gas_limit, Some(U256::from(40_000)));
+        assert_eq!(parsed.data, Bytes::from(vec![0xaa, 0xbb]));
+
+        // Folding again replaces the limits rather than nesting the header
+        let again = v07.clone().with_paymaster_gas_limits(&gas_params, EntryPointVersion::V07).unwrap();
+        assert_eq!(again.paymaster_and_data, v07.paymaster_and_data);
+
+        let reestimated = GasParams { paymaster_post_op_gas_limit: Some(U256::from(60_000)), ..gas_params };
+        let refolded = v07.with_paymaster_gas_limits(&reestimated, EntryPointVersion::V07).unwrap();
+        let parsed = PaymasterAndData::parse(&refolded.paymaster_and_data, EntryPointVersion::V07).unwrap();
+        assert_eq!(parsed.paymaster_post_op_gas_limit, Some(U256::from(60_000)));
+        assert_eq!(parsed.data, Bytes::from(vec![0xaa, 0xbb]));
+    }
+
+    #[tokio::test]
+    async fn test_generate_counts_in_metrics_snapshot() {
+        use crate::cache::{GasCache, RpcCache};
+        use crate::gas::ChainProviders;
+        use crate::retry::RetryConfig;
+
+        crate::metrics::tests::test_handle();
+        let (provider, mock) = Provider::mocked();
+        let gas_cache = Arc::new(GasCache::new());
+        gas_cache.set_base_fee(1, U256::from(10)).await;
+        gas_cache.set_priority_fee(1, U256::from(2)).await;
+        mock.push(U256::from(50_000)).unwrap(); // eth_estimateGas
+        let generator = UserOpGenerator::new(GasEstimator::new(
+            Arc::new(ChainProviders {
+                ethereum: provider.clone(),
+                polygon: provider.clone(),
+                arbitrum: provider,
+            }),
+            gas_cache,
+            Arc::new(RpcCache::new()),
+            RetryConfig::default(),
+        ));
+        let generated = |snapshot: &crate::metrics::MetricsSnapshot| {
+            snapshot.counter("userop_generation_success", &[("chain", "1")])
+        };
+
+        let before = generated(&Metrics::snapshot());
+        generator
+            .generate_user_op(test_user_op().sender, Bytes::default(), 1, None)
+            .await
+            .unwrap();
 
-        Ok(ethers::utils::keccak256(encoded).into())
+        assert!(generated(&Metrics::snapshot()) >= before + 1.0);
     }
 }